@@ -0,0 +1,616 @@
+#[cfg(feature = "logging")]
+use log::warn;
+use std::ffi::{c_char, CStr};
+
+use crate::corebpe::{tokenizer_pattern, CoreBPE};
+use crate::CTiktokenTokenizer;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CTiktokenCountEstimate {
+    pub count: usize,
+    pub error_bound: usize,
+    pub exact: bool,
+}
+
+/// Token cost of one class of pieces: a piece of up to `single_token_units`
+/// units is one token, and every `units_per_token` units past that add one.
+#[derive(Clone, Copy)]
+struct PieceCost {
+    single_token_units: f64,
+    units_per_token: f64,
+}
+
+impl PieceCost {
+    const fn new(single_token_units: f64, units_per_token: f64) -> Self {
+        Self {
+            single_token_units,
+            units_per_token,
+        }
+    }
+
+    fn tokens(self, units: usize) -> f64 {
+        1.0 + (units as f64 - self.single_token_units).max(0.0) / self.units_per_token
+    }
+}
+
+/// Per-encoding parameters of the estimator.
+///
+/// Piece costs are in bytes for ASCII pieces, by piece class, and in
+/// characters for non-ASCII ones, split between scripts of up to two bytes
+/// per character (Latin, Greek, Cyrillic, Arabic) and wider ones (CJK,
+/// Hangul, Indic). They were fitted to English prose, Rust source and a
+/// multilingual sample, the ones `test_estimate_accuracy` checks
+/// `relative_error` against.
+struct Calibration {
+    style: SplitStyle,
+    whitespace: PieceCost,
+    word: PieceCost,
+    digit: PieceCost,
+    other: PieceCost,
+    narrow_script: PieceCost,
+    wide_script: PieceCost,
+    relative_error: f64,
+}
+
+/// Error margin for encodings without a calibration of their own, which
+/// share at most the split pattern with a built-in one.
+const UNCALIBRATED_RELATIVE_ERROR: f64 = 0.5;
+
+/// Calibration of `corebpe`. Trained and extended encodings borrow the one
+/// of the built-in encoding they split text like, with a wider margin.
+fn calibration(corebpe: &CoreBPE) -> Calibration {
+    if corebpe.tokenizer() != CTiktokenTokenizer::Unknown {
+        return builtin_calibration(corebpe.tokenizer());
+    }
+    let similar = [
+        CTiktokenTokenizer::O200kBase,
+        CTiktokenTokenizer::Cl100kBase,
+        CTiktokenTokenizer::R50kBase,
+    ]
+    .into_iter()
+    .find(|&tokenizer| {
+        tokenizer_pattern(tokenizer)
+            .is_some_and(|pattern| pattern.to_bytes() == corebpe.pattern().as_bytes())
+    })
+    .unwrap_or(CTiktokenTokenizer::Cl100kBase);
+    Calibration {
+        relative_error: UNCALIBRATED_RELATIVE_ERROR,
+        ..builtin_calibration(similar)
+    }
+}
+
+fn builtin_calibration(tokenizer: CTiktokenTokenizer) -> Calibration {
+    match tokenizer {
+        CTiktokenTokenizer::R50kBase | CTiktokenTokenizer::Gpt2 => Calibration {
+            style: SplitStyle::R50k,
+            whitespace: PieceCost::new(1.0, 1.0),
+            word: PieceCost::new(4.0, 4.5),
+            digit: PieceCost::new(3.0, 2.5),
+            other: PieceCost::new(1.0, 6.5),
+            narrow_script: PieceCost::new(0.0, 1.25),
+            wide_script: PieceCost::new(0.0, 0.6),
+            relative_error: 0.25,
+        },
+        CTiktokenTokenizer::P50kBase | CTiktokenTokenizer::P50kEdit => Calibration {
+            whitespace: PieceCost::new(0.0, 9.0),
+            ..builtin_calibration(CTiktokenTokenizer::R50kBase)
+        },
+        CTiktokenTokenizer::O200kBase | CTiktokenTokenizer::O200kHarmony => Calibration {
+            style: SplitStyle::O200k,
+            whitespace: PieceCost::new(16.0, 16.0),
+            word: PieceCost::new(6.0, 3.7),
+            digit: PieceCost::new(3.0, 3.0),
+            other: PieceCost::new(2.0, 13.0),
+            narrow_script: PieceCost::new(3.0, 3.7),
+            wide_script: PieceCost::new(3.0, 1.2),
+            relative_error: 0.15,
+        },
+        CTiktokenTokenizer::Cl100kBase | CTiktokenTokenizer::Unknown => Calibration {
+            style: SplitStyle::Cl100k,
+            whitespace: PieceCost::new(16.0, 16.0),
+            word: PieceCost::new(6.0, 4.3),
+            digit: PieceCost::new(3.0, 3.0),
+            other: PieceCost::new(3.0, 9.0),
+            narrow_script: PieceCost::new(0.0, 2.4),
+            wide_script: PieceCost::new(1.0, 0.9),
+            relative_error: 0.15,
+        },
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CharClass {
+    Letter,
+    Digit,
+    Whitespace,
+    Other,
+}
+
+fn char_class(c: char) -> CharClass {
+    if c.is_alphabetic() {
+        CharClass::Letter
+    } else if c.is_numeric() {
+        CharClass::Digit
+    } else if c.is_whitespace() {
+        CharClass::Whitespace
+    } else {
+        CharClass::Other
+    }
+}
+
+fn is_newline(c: char) -> bool {
+    c == '\r' || c == '\n'
+}
+
+/// Which family of split patterns to approximate.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SplitStyle {
+    /// r50k_base and p50k_base.
+    R50k,
+    /// cl100k_base.
+    Cl100k,
+    /// o200k_base, which also splits words at lowercase to uppercase changes.
+    O200k,
+}
+
+/// Returns the end of the run starting at `start` of characters matching
+/// `predicate`, stopping after `max_chars` characters.
+fn run_end(
+    text: &str,
+    start: usize,
+    max_chars: usize,
+    mut predicate: impl FnMut(char) -> bool,
+) -> usize {
+    let mut end = start;
+    for c in text[start..].chars().take(max_chars) {
+        if !predicate(c) {
+            break;
+        }
+        end += c.len_utf8();
+    }
+    end
+}
+
+/// Splits text in a single pass over its characters, approximating the split
+/// patterns of the built-in encodings without a regex engine.
+pub(crate) struct ApproximateSplit<'a> {
+    text: &'a str,
+    style: SplitStyle,
+}
+
+impl ApproximateSplit<'_> {
+    fn letters_end(&self, start: usize) -> usize {
+        if self.style != SplitStyle::O200k {
+            return run_end(self.text, start, usize::MAX, char::is_alphabetic);
+        }
+        let mut previous_lowercase = false;
+        run_end(self.text, start, usize::MAX, |c| {
+            let split = previous_lowercase && c.is_uppercase();
+            previous_lowercase = c.is_lowercase();
+            c.is_alphabetic() && !split
+        })
+    }
+
+    fn whitespace_end(&self, first: char) -> usize {
+        let end = run_end(self.text, 0, usize::MAX, char::is_whitespace);
+        if end == self.text.len() {
+            return end;
+        }
+        let run = &self.text[..end];
+        if self.style != SplitStyle::R50k {
+            if let Some(newline) = run.rfind(is_newline) {
+                return newline + 1;
+            }
+        }
+        match run.chars().next_back() {
+            Some(last) if end > first.len_utf8() => end - last.len_utf8(),
+            _ => end,
+        }
+    }
+
+    fn piece_end(&self) -> Option<usize> {
+        let mut chars = self.text.chars();
+        let first = chars.next()?;
+        let second = chars.next().map(char_class);
+        let first_class = char_class(first);
+        if self.style == SplitStyle::R50k {
+            let start = match (first, second) {
+                (' ', Some(class)) if class != CharClass::Whitespace => 1,
+                _ if first_class != CharClass::Whitespace => 0,
+                _ => return Some(self.whitespace_end(first)),
+            };
+            let class = self.text[start..].chars().next().map(char_class);
+            return Some(run_end(self.text, start, usize::MAX, |c| {
+                Some(char_class(c)) == class
+            }));
+        }
+        let end = match first_class {
+            CharClass::Digit => run_end(self.text, 0, 3, char::is_numeric),
+            CharClass::Letter => self.letters_end(0),
+            _ if !is_newline(first) && second == Some(CharClass::Letter) => {
+                self.letters_end(first.len_utf8())
+            }
+            CharClass::Other => {
+                let end = run_end(self.text, 0, usize::MAX, |c| {
+                    char_class(c) == CharClass::Other
+                });
+                run_end(self.text, end, usize::MAX, is_newline)
+            }
+            _ if first == ' ' && second == Some(CharClass::Other) => {
+                let end = run_end(self.text, 1, usize::MAX, |c| {
+                    char_class(c) == CharClass::Other
+                });
+                run_end(self.text, end, usize::MAX, is_newline)
+            }
+            _ => self.whitespace_end(first),
+        };
+        Some(end)
+    }
+}
+
+impl<'a> Iterator for ApproximateSplit<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let end = self.piece_end()?;
+        let (piece, rest) = self.text.split_at(end);
+        self.text = rest;
+        Some(piece)
+    }
+}
+
+pub(crate) fn split_approximately(text: &str, style: SplitStyle) -> ApproximateSplit<'_> {
+    ApproximateSplit { text, style }
+}
+
+/// Estimates the number of tokens of a piece from its length and class.
+fn estimate_piece(calibration: &Calibration, piece: &str) -> f64 {
+    if piece.is_empty() {
+        return 0.0;
+    }
+    let trimmed = piece.trim_start();
+    let (units, cost) = if trimmed.is_empty() {
+        (piece.len(), calibration.whitespace)
+    } else if !piece.is_ascii() {
+        let num_chars = piece.chars().count();
+        if piece.len() > num_chars * 5 / 2 {
+            (num_chars, calibration.wide_script)
+        } else {
+            (num_chars, calibration.narrow_script)
+        }
+    } else if trimmed.bytes().all(|b| b.is_ascii_alphabetic()) {
+        (piece.len(), calibration.word)
+    } else if trimmed.bytes().all(|b| b.is_ascii_digit()) {
+        (piece.len(), calibration.digit)
+    } else {
+        (piece.len(), calibration.other)
+    };
+    cost.tokens(units)
+}
+
+/// Estimates the `encode_ordinary` token count of `text` from the lengths of
+/// its pieces alone, without looking anything up in the vocabulary.
+fn estimate_count_ordinary(corebpe: &CoreBPE, text: &str) -> CTiktokenCountEstimate {
+    let calibration = calibration(corebpe);
+    let count = split_approximately(text, calibration.style)
+        .map(|piece| estimate_piece(&calibration, piece))
+        .sum::<f64>()
+        .round() as usize;
+    CTiktokenCountEstimate {
+        count,
+        error_bound: (count as f64 * calibration.relative_error).ceil() as usize,
+        exact: false,
+    }
+}
+
+fn parse_arguments<'a>(
+    ptr: *const CoreBPE,
+    text: *const c_char,
+    estimate: *mut CTiktokenCountEstimate,
+) -> Option<(&'a CoreBPE, &'a str)> {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return None;
+    }
+    if text.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for text!");
+        return None;
+    }
+    if estimate.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for estimate!");
+        return None;
+    }
+    let text = match unsafe { CStr::from_ptr(text) }.to_str() {
+        Ok(text) => text,
+        Err(_) => {
+            #[cfg(feature = "logging")]
+            warn!("Invalid UTF-8 sequence provided for text!");
+            return None;
+        }
+    };
+    Some((unsafe { &*ptr }, text))
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_estimate_count_ordinary(
+    ptr: *const CoreBPE,
+    text: *const c_char,
+    estimate: *mut CTiktokenCountEstimate,
+) -> bool {
+    let (corebpe, text) = match parse_arguments(ptr, text, estimate) {
+        Some(parsed) => parsed,
+        None => return false,
+    };
+    unsafe {
+        *estimate = estimate_count_ordinary(corebpe, text);
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_count_ordinary_hybrid(
+    ptr: *const CoreBPE,
+    text: *const c_char,
+    exact_threshold: usize,
+    estimate: *mut CTiktokenCountEstimate,
+) -> bool {
+    let (corebpe, text) = match parse_arguments(ptr, text, estimate) {
+        Some(parsed) => parsed,
+        None => return false,
+    };
+    let result = if text.len() <= exact_threshold {
+        CTiktokenCountEstimate {
+            count: corebpe.count_ordinary(text),
+            error_bound: 0,
+            exact: true,
+        }
+    } else {
+        estimate_count_ordinary(corebpe, text)
+    };
+    unsafe {
+        *estimate = result;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corebpe::{
+        tiktoken_cl100k_base, tiktoken_destroy_corebpe, tiktoken_o200k_base, tiktoken_p50k_base,
+        tiktoken_r50k_base,
+    };
+    use std::ffi::CString;
+
+    fn estimate(corebpe: *const CoreBPE, text: &str) -> CTiktokenCountEstimate {
+        let text = CString::new(text).unwrap();
+        let mut estimate = CTiktokenCountEstimate::default();
+        assert!(tiktoken_corebpe_estimate_count_ordinary(
+            corebpe,
+            text.as_ptr(),
+            &mut estimate
+        ));
+        estimate
+    }
+
+    #[test]
+    fn test_split_approximately_matches_split_pattern() {
+        let text = "I am a cat.   It has 12345 toes!\n\n    fn main() {\n        \
+                    println!(\"{}\", x.len());\n    }\n猫です, façade\t\t tab ";
+        for (corebpe, style) in [
+            (tiktoken_r50k_base(), SplitStyle::R50k),
+            (tiktoken_cl100k_base(), SplitStyle::Cl100k),
+            (tiktoken_o200k_base(), SplitStyle::O200k),
+        ] {
+            let expected: Vec<&str> = unsafe { &*corebpe }
+                .split_ordinary(text)
+                .map(|(_, piece)| piece)
+                .collect();
+            let pieces: Vec<&str> = split_approximately(text, style).collect();
+            assert_eq!(pieces, expected);
+            tiktoken_destroy_corebpe(corebpe);
+        }
+    }
+
+    #[test]
+    fn test_split_approximately_case_change() {
+        let pieces: Vec<&str> =
+            split_approximately(" parseChatMessages", SplitStyle::O200k).collect();
+        assert_eq!(pieces, [" parse", "Chat", "Messages"]);
+        let pieces: Vec<&str> =
+            split_approximately(" parseChatMessages", SplitStyle::Cl100k).collect();
+        assert_eq!(pieces, [" parseChatMessages"]);
+    }
+
+    #[test]
+    fn test_estimate_count_ordinary_single_token_pieces() {
+        let corebpe = tiktoken_cl100k_base();
+        let result = estimate(corebpe, "I am a cat.");
+        assert_eq!(result.count, 5);
+        assert_eq!(result.error_bound, 1);
+        assert!(!result.exact);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_estimate_count_ordinary_close_to_exact() {
+        for corebpe in [tiktoken_cl100k_base(), tiktoken_o200k_base()] {
+            let text = "Tokenization of antidisestablishmentarianism, 猫ですにゃー, \
+                        and fn parse_chat_messages(num_messages: u32) -> Result<(), ()>";
+            let exact = unsafe { &*corebpe }.count_ordinary(text);
+            let result = estimate(corebpe, text);
+            assert!(
+                result.count.abs_diff(exact) <= result.error_bound,
+                "estimate {} +/- {} vs exact {}",
+                result.count,
+                result.error_bound,
+                exact
+            );
+            tiktoken_destroy_corebpe(corebpe);
+        }
+    }
+
+    #[test]
+    fn test_estimate_piece_short() {
+        let calibration = builtin_calibration(CTiktokenTokenizer::Cl100kBase);
+        assert_eq!(estimate_piece(&calibration, ""), 0.0);
+        assert_eq!(estimate_piece(&calibration, "x"), 1.0);
+        assert_eq!(estimate_piece(&calibration, " cat"), 1.0);
+        assert!(estimate_piece(&calibration, " antidisestablishmentarianism") > 1.0);
+    }
+
+    /// Text in the scripts the calibration distinguishes, and accented Latin.
+    const MULTILINGUAL: &str = "吾輩は猫である。名前はまだ無い。どこで生れたかとんと見当がつかぬ。何でも薄暗いじめじめした所でニャーニャー泣いていた事だけは記憶している。吾輩はここで始めて人間というものを見た。しかもあとで聞くとそれは書生という人間中で一番獰悪な種族であったそうだ。\n\
+                                猫是一种小型食肉哺乳动物，经常被人类饲养作为宠物。猫的听觉和嗅觉都非常灵敏，能够在夜间看清物体。许多家庭喜欢养猫，因为它们安静、干净并且独立。\n\
+                                고양이는 작은 육식성 포유류로, 사람들이 오랫동안 반려동물로 길러 왔습니다. 고양이는 밤에도 잘 볼 수 있으며 청각이 매우 뛰어납니다. 많은 가정에서 고양이를 키우는 이유는 조용하고 깨끗하기 때문입니다.\n\
+                                Кошка — домашнее животное, одно из наиболее популярных компаньонов человека. Кошки хорошо видят в темноте и обладают отличным слухом. Многие семьи держат кошек, потому что они спокойные, чистоплотные и независимые.\n\
+                                Η γάτα είναι μικρό σαρκοφάγο θηλαστικό που εξημερώθηκε από τον άνθρωπο. Οι γάτες βλέπουν καλά στο σκοτάδι και έχουν εξαιρετική ακοή. Πολλές οικογένειες έχουν γάτες επειδή είναι ήσυχες και καθαρές.\n\
+                                القط حيوان ثديي صغير من آكلات اللحوم، وقد استأنسه الإنسان منذ آلاف السنين. تستطيع القطط الرؤية في الظلام ولديها سمع ممتاز. تحب عائلات كثيرة تربية القطط لأنها هادئة ونظيفة ومستقلة.\n\
+                                बिल्ली एक छोटा मांसाहारी स्तनधारी जानवर है जिसे मनुष्य ने हजारों साल पहले पालतू बनाया था। बिल्लियाँ अंधेरे में अच्छी तरह देख सकती हैं और उनकी सुनने की शक्ति बहुत तेज़ होती है।\n\
+                                Le chat est un petit mammifère carnivore, domestiqué depuis des millénaires. Il voit très bien dans l'obscurité, et son ouïe est excellente. Beaucoup de familles élèvent des chats parce qu'ils sont discrets, propres et indépendants. Ça, c'est déjà évident : même à Noël, la fenêtre reste ouverte pour le félin.\n\
+                                Die Katze ist ein kleines fleischfressendes Säugetier, das seit Jahrtausenden domestiziert ist. Sie sieht im Dunkeln sehr gut und hört ausgezeichnet. Viele Familien halten Katzen, weil sie ruhig, sauber und unabhängig sind. Größere Tiere fürchten sie übrigens kaum.\n\
+                                El gato es un pequeño mamífero carnívoro domesticado hace miles de años. Ve muy bien en la oscuridad y tiene un oído excelente. Muchas familias tienen gatos porque son tranquilos, limpios e independientes. ¿Quién no ha visto a un gato dormir en el sofá?\n\
+                                Kedi, binlerce yıl önce evcilleştirilmiş küçük, etçil bir memelidir. Karanlıkta çok iyi görür ve işitme duyusu mükemmeldir. Birçok aile kedileri sessiz, temiz ve bağımsız oldukları için sever.\n\
+                                Mèo là loài động vật có vú nhỏ ăn thịt, đã được con người thuần hóa từ hàng nghìn năm trước. Mèo nhìn rất rõ trong bóng tối và có thính giác tuyệt vời. Nhiều gia đình nuôi mèo vì chúng yên tĩnh, sạch sẽ và độc lập.\n\
+                                แมวเป็นสัตว์เลี้ยงลูกด้วยนมขนาดเล็กที่กินเนื้อเป็นอาหาร มนุษย์เลี้ยงแมวมานานหลายพันปี แมวมองเห็นได้ดีในที่มืดและมีการได้ยินที่ยอดเยี่ยม";
+
+    /// Splits `text` into slices of `len` to `2 * len` bytes.
+    fn slices(text: &str, len: usize) -> Vec<&str> {
+        let mut slices = Vec::new();
+        let mut rest = text;
+        while !rest.is_empty() {
+            if rest.len() < 2 * len {
+                slices.push(rest);
+                break;
+            }
+            let mut end = len;
+            while !rest.is_char_boundary(end) {
+                end += 1;
+            }
+            let (slice, tail) = rest.split_at(end);
+            slices.push(slice);
+            rest = tail;
+        }
+        slices
+    }
+
+    #[test]
+    fn test_estimate_accuracy() {
+        let samples = [
+            ("prose", include_str!("../README.md")),
+            ("source", include_str!("lib.rs")),
+            ("multilingual", MULTILINGUAL),
+        ];
+        for corebpe in [
+            tiktoken_r50k_base(),
+            tiktoken_p50k_base(),
+            tiktoken_cl100k_base(),
+            tiktoken_o200k_base(),
+        ] {
+            let corebpe_ref = unsafe { &*corebpe };
+            for (name, sample) in samples {
+                // At least 95% of 2 KB slices are within the error margin.
+                let slices = slices(sample, 2048);
+                let within = slices
+                    .iter()
+                    .filter(|slice| {
+                        let result = estimate(corebpe, slice);
+                        result.count.abs_diff(corebpe_ref.count_ordinary(slice))
+                            <= result.error_bound
+                    })
+                    .count();
+                assert!(
+                    within * 20 >= slices.len() * 19,
+                    "{} {}: {} of {} slices within the margin",
+                    corebpe_ref.name(),
+                    name,
+                    within,
+                    slices.len()
+                );
+            }
+            tiktoken_destroy_corebpe(corebpe);
+        }
+    }
+
+    #[test]
+    fn test_calibration_unknown_tokenizer() {
+        let cl100k = tiktoken_cl100k_base();
+        let ranks = unsafe { &*cl100k }.mergeable_ranks().clone();
+        let o200k_pattern = tokenizer_pattern(CTiktokenTokenizer::O200kBase).unwrap();
+        for (pattern, style) in [
+            (o200k_pattern.to_str().unwrap(), SplitStyle::O200k),
+            ("\\S+|\\s+", SplitStyle::Cl100k),
+        ] {
+            let corebpe =
+                CoreBPE::from_ranks(ranks.clone(), Default::default(), "custom", pattern).unwrap();
+            let calibration = calibration(&corebpe);
+            assert!(calibration.style == style);
+            assert_eq!(calibration.relative_error, UNCALIBRATED_RELATIVE_ERROR);
+        }
+        assert_eq!(
+            calibration(unsafe { &*cl100k }).relative_error,
+            builtin_calibration(CTiktokenTokenizer::Cl100kBase).relative_error
+        );
+        tiktoken_destroy_corebpe(cl100k);
+    }
+
+    #[test]
+    fn test_count_ordinary_hybrid() {
+        let corebpe = tiktoken_cl100k_base();
+        let text = CString::new("antidisestablishmentarianism").unwrap();
+        let mut result = CTiktokenCountEstimate::default();
+        assert!(tiktoken_corebpe_count_ordinary_hybrid(
+            corebpe,
+            text.as_ptr(),
+            1024,
+            &mut result
+        ));
+        let exact = unsafe { &*corebpe }.count_ordinary("antidisestablishmentarianism");
+        assert_eq!(
+            result,
+            CTiktokenCountEstimate {
+                count: exact,
+                error_bound: 0,
+                exact: true
+            }
+        );
+
+        assert!(tiktoken_corebpe_count_ordinary_hybrid(
+            corebpe,
+            text.as_ptr(),
+            8,
+            &mut result
+        ));
+        assert!(!result.exact);
+        assert!(result.error_bound > 0);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_estimate_count_ordinary_null_arguments() {
+        let corebpe = tiktoken_cl100k_base();
+        let text = CString::new("I am a cat.").unwrap();
+        let mut result = CTiktokenCountEstimate::default();
+        assert!(!tiktoken_corebpe_estimate_count_ordinary(
+            std::ptr::null(),
+            text.as_ptr(),
+            &mut result
+        ));
+        assert!(!tiktoken_corebpe_estimate_count_ordinary(
+            corebpe,
+            std::ptr::null(),
+            &mut result
+        ));
+        assert!(!tiktoken_corebpe_count_ordinary_hybrid(
+            corebpe,
+            text.as_ptr(),
+            0,
+            std::ptr::null_mut()
+        ));
+        tiktoken_destroy_corebpe(corebpe);
+    }
+}
@@ -0,0 +1,637 @@
+#[cfg(feature = "logging")]
+use log::warn;
+use std::ffi::c_char;
+use tiktoken_rs::Rank;
+
+use crate::alloc::malloc_copy;
+use crate::corebpe::CoreBPE;
+
+/// How invalid UTF-8 in byte input is handled.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CTiktokenInvalidUtf8Policy {
+    /// Fail the call.
+    Error = 0,
+    /// Replace each invalid sequence with U+FFFD before encoding.
+    Replace = 1,
+    /// Encode invalid sequences as raw bytes, so decoding gives back the input.
+    Bytes = 2,
+}
+
+/// How invalid UTF-8 in decoded output is handled by the lossy decoder.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CTiktokenLossyDecodePolicy {
+    /// Replace each invalid sequence with U+FFFD.
+    Replace = 0,
+    /// Write each invalid byte as `\xNN`.
+    Escape = 1,
+    /// Leave invalid sequences out.
+    Drop = 2,
+}
+
+/// Splits `bytes` into valid UTF-8 runs, encoded with `encode`, and invalid
+/// sequences, encoded as raw bytes.
+fn encode_bytes_exact(
+    corebpe: &CoreBPE,
+    mut bytes: &[u8],
+    encode: &impl Fn(&CoreBPE, &str) -> Vec<Rank>,
+) -> Option<Vec<Rank>> {
+    let mut tokens = Vec::new();
+    while !bytes.is_empty() {
+        let (valid_len, invalid_len) = match std::str::from_utf8(bytes) {
+            Ok(_) => (bytes.len(), 0),
+            Err(err) => (
+                err.valid_up_to(),
+                err.error_len().unwrap_or(bytes.len() - err.valid_up_to()),
+            ),
+        };
+        let (valid, rest) = bytes.split_at(valid_len);
+        let (invalid, rest) = rest.split_at(invalid_len);
+        if let Ok(text) = std::str::from_utf8(valid) {
+            tokens.extend(encode(corebpe, text));
+        }
+        if !invalid.is_empty() {
+            tokens.extend(corebpe.encode_piece(invalid)?);
+        }
+        bytes = rest;
+    }
+    Some(tokens)
+}
+
+fn encode_bytes(
+    ptr: *const CoreBPE,
+    bytes: *const u8,
+    num_bytes: usize,
+    policy: CTiktokenInvalidUtf8Policy,
+    encode: impl Fn(&CoreBPE, &str) -> Vec<Rank>,
+) -> Option<Vec<Rank>> {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return None;
+    }
+    if bytes.is_null() && num_bytes != 0 {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for bytes!");
+        return None;
+    }
+    let bytes = if num_bytes == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(bytes, num_bytes) }
+    };
+    let encoded = encode_bytes_with_policy(unsafe { &*ptr }, bytes, policy, encode);
+    #[cfg(feature = "logging")]
+    if encoded.is_none() {
+        warn!("Invalid UTF-8 sequence provided for bytes!");
+    }
+    encoded
+}
+
+/// Encodes `bytes` with `encode`, handling invalid UTF-8 according to
+/// `policy`. Returns `None` if the policy rejects the input.
+pub(crate) fn encode_bytes_with_policy(
+    corebpe: &CoreBPE,
+    bytes: &[u8],
+    policy: CTiktokenInvalidUtf8Policy,
+    encode: impl Fn(&CoreBPE, &str) -> Vec<Rank>,
+) -> Option<Vec<Rank>> {
+    match policy {
+        CTiktokenInvalidUtf8Policy::Error => match std::str::from_utf8(bytes) {
+            Ok(text) => Some(encode(corebpe, text)),
+            Err(_) => None,
+        },
+        CTiktokenInvalidUtf8Policy::Replace => {
+            let text = String::from_utf8_lossy(bytes);
+            Some(encode(corebpe, &text))
+        }
+        CTiktokenInvalidUtf8Policy::Bytes => encode_bytes_exact(corebpe, bytes, &encode),
+    }
+}
+
+fn encode_bytes_to_malloced(encoded: Option<Vec<Rank>>, num_tokens: *mut usize) -> *mut Rank {
+    let encoded = match encoded {
+        Some(encoded) => encoded,
+        None => return std::ptr::null_mut(),
+    };
+    unsafe {
+        if !num_tokens.is_null() {
+            *num_tokens = encoded.len();
+        }
+    };
+    malloc_copy::<Rank>(&encoded)
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_encode_ordinary_bytes(
+    ptr: *const CoreBPE,
+    bytes: *const u8,
+    num_bytes: usize,
+    policy: CTiktokenInvalidUtf8Policy,
+    num_tokens: *mut usize,
+) -> *mut Rank {
+    let encoded = encode_bytes(ptr, bytes, num_bytes, policy, |corebpe, text| {
+        corebpe.encode_ordinary(text)
+    });
+    encode_bytes_to_malloced(encoded, num_tokens)
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_encode_with_special_tokens_bytes(
+    ptr: *const CoreBPE,
+    bytes: *const u8,
+    num_bytes: usize,
+    policy: CTiktokenInvalidUtf8Policy,
+    num_tokens: *mut usize,
+) -> *mut Rank {
+    let encoded = encode_bytes(ptr, bytes, num_bytes, policy, |corebpe, text| {
+        corebpe.encode_with_special_tokens(text)
+    });
+    encode_bytes_to_malloced(encoded, num_tokens)
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_count_ordinary_bytes(
+    ptr: *const CoreBPE,
+    bytes: *const u8,
+    num_bytes: usize,
+    policy: CTiktokenInvalidUtf8Policy,
+) -> usize {
+    encode_bytes(ptr, bytes, num_bytes, policy, |corebpe, text| {
+        corebpe.encode_ordinary(text)
+    })
+    .map_or(usize::MAX, |encoded| encoded.len())
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_count_with_special_tokens_bytes(
+    ptr: *const CoreBPE,
+    bytes: *const u8,
+    num_bytes: usize,
+    policy: CTiktokenInvalidUtf8Policy,
+) -> usize {
+    encode_bytes(ptr, bytes, num_bytes, policy, |corebpe, text| {
+        corebpe.encode_with_special_tokens(text)
+    })
+    .map_or(usize::MAX, |encoded| encoded.len())
+}
+
+fn push_escaped(text: &mut Vec<u8>, byte: u8) {
+    text.extend_from_slice(format!("\\x{:02x}", byte).as_bytes());
+}
+
+/// Converts decoded bytes to valid UTF-8 according to `policy`, optionally
+/// escaping NUL bytes so that the result can be read as a C string.
+fn bytes_to_lossy_text(
+    bytes: &[u8],
+    policy: CTiktokenLossyDecodePolicy,
+    escape_nul: bool,
+) -> Vec<u8> {
+    let mut text = Vec::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for &byte in chunk.valid().as_bytes() {
+            if escape_nul && byte == 0 {
+                push_escaped(&mut text, byte);
+            } else {
+                text.push(byte);
+            }
+        }
+        let invalid = chunk.invalid();
+        if invalid.is_empty() {
+            continue;
+        }
+        match policy {
+            CTiktokenLossyDecodePolicy::Replace => {
+                text.extend_from_slice(char::REPLACEMENT_CHARACTER.to_string().as_bytes())
+            }
+            CTiktokenLossyDecodePolicy::Escape => {
+                for &byte in invalid {
+                    push_escaped(&mut text, byte);
+                }
+            }
+            CTiktokenLossyDecodePolicy::Drop => {}
+        }
+    }
+    text
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_decode_lossy(
+    ptr: *const CoreBPE,
+    tokens: *const Rank,
+    num_tokens: usize,
+    policy: CTiktokenLossyDecodePolicy,
+    escape_nul: bool,
+    text_len: *mut usize,
+) -> *mut c_char {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return std::ptr::null_mut();
+    }
+    if tokens.is_null() && num_tokens != 0 {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for tokens!");
+        return std::ptr::null_mut();
+    }
+    let tokens = if num_tokens == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(tokens, num_tokens) }
+    };
+    let corebpe = unsafe { &*ptr };
+    let decoded = match corebpe.decode_bytes(tokens) {
+        Ok(decoded) => decoded,
+        Err(_) => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to decode!");
+            return std::ptr::null_mut();
+        }
+    };
+    let mut text = bytes_to_lossy_text(&decoded, policy, escape_nul);
+    unsafe {
+        if !text_len.is_null() {
+            *text_len = text.len();
+        }
+    };
+    text.push(0);
+    malloc_copy::<u8>(&text) as *mut c_char
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_decode_tokens(
+    ptr: *const CoreBPE,
+    tokens: *const Rank,
+    num_tokens: usize,
+    bytes: *mut *mut u8,
+    num_bytes: *mut usize,
+    offsets: *mut *mut usize,
+    is_special: *mut *mut bool,
+) -> bool {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return false;
+    }
+    if tokens.is_null() && num_tokens != 0 {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for tokens!");
+        return false;
+    }
+    if bytes.is_null() || offsets.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for bytes or offsets!");
+        return false;
+    }
+    let tokens = if num_tokens == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(tokens, num_tokens) }
+    };
+    let corebpe = unsafe { &*ptr };
+    let mut packed = Vec::new();
+    let mut token_offsets = Vec::with_capacity(tokens.len() + 1);
+    token_offsets.push(0);
+    for &token in tokens {
+        match corebpe.decode_bytes(&[token]) {
+            Ok(token_bytes) => packed.extend_from_slice(&token_bytes),
+            Err(_) => {
+                #[cfg(feature = "logging")]
+                warn!("Failed to decode!");
+                return false;
+            }
+        }
+        token_offsets.push(packed.len());
+    }
+    unsafe {
+        *bytes = malloc_copy::<u8>(&packed);
+        *offsets = malloc_copy::<usize>(&token_offsets);
+        if !num_bytes.is_null() {
+            *num_bytes = packed.len();
+        }
+        if !is_special.is_null() {
+            let flags: Vec<bool> = tokens
+                .iter()
+                .map(|&token| corebpe.is_special_token(token))
+                .collect();
+            *is_special = malloc_copy::<bool>(&flags);
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::tiktoken_free;
+    use crate::corebpe::{tiktoken_cl100k_base, tiktoken_destroy_corebpe};
+
+    fn encode(
+        corebpe: *const CoreBPE,
+        bytes: &[u8],
+        policy: CTiktokenInvalidUtf8Policy,
+    ) -> Option<Vec<Rank>> {
+        let mut num_tokens = 0;
+        let tokens = tiktoken_corebpe_encode_ordinary_bytes(
+            corebpe,
+            bytes.as_ptr(),
+            bytes.len(),
+            policy,
+            &mut num_tokens,
+        );
+        if tokens.is_null() {
+            return None;
+        }
+        let encoded = unsafe { std::slice::from_raw_parts(tokens, num_tokens) }.to_vec();
+        tiktoken_free(tokens as *mut std::ffi::c_void);
+        Some(encoded)
+    }
+
+    #[test]
+    fn test_encode_ordinary_bytes_valid_utf8() {
+        let corebpe = tiktoken_cl100k_base();
+        for policy in [
+            CTiktokenInvalidUtf8Policy::Error,
+            CTiktokenInvalidUtf8Policy::Replace,
+            CTiktokenInvalidUtf8Policy::Bytes,
+        ] {
+            let encoded = encode(corebpe, b"I am a cat.", policy).unwrap();
+            assert_eq!(encoded, vec![40, 1097, 264, 8415, 13]);
+        }
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_encode_ordinary_bytes_invalid_utf8() {
+        let corebpe = tiktoken_cl100k_base();
+        let text = b"caf\xe9 au lait\xff\xfe";
+        assert!(encode(corebpe, text, CTiktokenInvalidUtf8Policy::Error).is_none());
+
+        let encoded = encode(corebpe, text, CTiktokenInvalidUtf8Policy::Bytes).unwrap();
+        let decoded = unsafe { &*corebpe }.decode_bytes(&encoded).unwrap();
+        assert_eq!(decoded, text);
+
+        let encoded = encode(corebpe, text, CTiktokenInvalidUtf8Policy::Replace).unwrap();
+        let decoded = unsafe { &*corebpe }.decode(&encoded).unwrap();
+        assert_eq!(decoded, "caf\u{fffd} au lait\u{fffd}\u{fffd}");
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_encode_ordinary_bytes_truncated_sequence() {
+        let corebpe = tiktoken_cl100k_base();
+        // "猫" cut after its second byte, followed by an interior NUL.
+        let text = b"\xe7\x8c\x00cat";
+        let encoded = encode(corebpe, text, CTiktokenInvalidUtf8Policy::Bytes).unwrap();
+        let decoded = unsafe { &*corebpe }.decode_bytes(&encoded).unwrap();
+        assert_eq!(decoded, text);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_encode_with_special_tokens_bytes() {
+        let corebpe = tiktoken_cl100k_base();
+        let text = b"\xff<|endoftext|>";
+        let mut num_tokens = 0;
+        let tokens = tiktoken_corebpe_encode_with_special_tokens_bytes(
+            corebpe,
+            text.as_ptr(),
+            text.len(),
+            CTiktokenInvalidUtf8Policy::Bytes,
+            &mut num_tokens,
+        );
+        assert!(!tokens.is_null());
+        let encoded = unsafe { std::slice::from_raw_parts(tokens, num_tokens) };
+        assert_eq!(encoded.last(), Some(&100257));
+        tiktoken_free(tokens as *mut std::ffi::c_void);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_count_bytes() {
+        let corebpe = tiktoken_cl100k_base();
+        let text = b"caf\xe9 au lait";
+        let expected = encode(corebpe, text, CTiktokenInvalidUtf8Policy::Bytes)
+            .unwrap()
+            .len();
+        assert_eq!(
+            tiktoken_corebpe_count_ordinary_bytes(
+                corebpe,
+                text.as_ptr(),
+                text.len(),
+                CTiktokenInvalidUtf8Policy::Bytes
+            ),
+            expected
+        );
+        assert_eq!(
+            tiktoken_corebpe_count_with_special_tokens_bytes(
+                corebpe,
+                text.as_ptr(),
+                text.len(),
+                CTiktokenInvalidUtf8Policy::Error
+            ),
+            usize::MAX
+        );
+        assert_eq!(
+            tiktoken_corebpe_count_ordinary_bytes(
+                corebpe,
+                std::ptr::null(),
+                0,
+                CTiktokenInvalidUtf8Policy::Error
+            ),
+            0
+        );
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_encode_bytes_null_arguments() {
+        let corebpe = tiktoken_cl100k_base();
+        assert_eq!(
+            tiktoken_corebpe_count_ordinary_bytes(
+                std::ptr::null(),
+                b"cat".as_ptr(),
+                3,
+                CTiktokenInvalidUtf8Policy::Bytes
+            ),
+            usize::MAX
+        );
+        assert!(tiktoken_corebpe_encode_ordinary_bytes(
+            corebpe,
+            std::ptr::null(),
+            3,
+            CTiktokenInvalidUtf8Policy::Bytes,
+            std::ptr::null_mut()
+        )
+        .is_null());
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    fn decode_lossy(
+        corebpe: *const CoreBPE,
+        tokens: &[Rank],
+        policy: CTiktokenLossyDecodePolicy,
+        escape_nul: bool,
+    ) -> Vec<u8> {
+        let mut text_len = 0;
+        let text = tiktoken_corebpe_decode_lossy(
+            corebpe,
+            tokens.as_ptr(),
+            tokens.len(),
+            policy,
+            escape_nul,
+            &mut text_len,
+        );
+        assert!(!text.is_null());
+        let decoded = unsafe { std::slice::from_raw_parts(text as *const u8, text_len + 1) };
+        assert_eq!(decoded[text_len], 0);
+        let decoded = decoded[..text_len].to_vec();
+        tiktoken_free(text as *mut std::ffi::c_void);
+        decoded
+    }
+
+    #[test]
+    fn test_decode_lossy_truncated_tokens() {
+        let corebpe = tiktoken_cl100k_base();
+        let tokens = unsafe { &*corebpe }.encode_ordinary("a猫");
+        // Drop the last token so that the character is cut short.
+        let tokens = &tokens[..tokens.len() - 1];
+        let bytes = unsafe { &*corebpe }.decode_bytes(tokens).unwrap();
+        assert!(std::str::from_utf8(&bytes).is_err());
+
+        let text = decode_lossy(corebpe, tokens, CTiktokenLossyDecodePolicy::Replace, false);
+        assert_eq!(text, "a\u{fffd}".as_bytes());
+
+        let text = decode_lossy(corebpe, tokens, CTiktokenLossyDecodePolicy::Escape, false);
+        let escaped: String = bytes[1..].iter().map(|b| format!("\\x{:02x}", b)).collect();
+        assert_eq!(text, format!("a{}", escaped).as_bytes());
+
+        let text = decode_lossy(corebpe, tokens, CTiktokenLossyDecodePolicy::Drop, false);
+        assert_eq!(text, b"a");
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_decode_lossy_interior_nul() {
+        let corebpe = tiktoken_cl100k_base();
+        let tokens = unsafe { &*corebpe }.encode_ordinary("a\0b");
+        let text = decode_lossy(corebpe, &tokens, CTiktokenLossyDecodePolicy::Replace, false);
+        assert_eq!(text, b"a\0b");
+        let text = decode_lossy(corebpe, &tokens, CTiktokenLossyDecodePolicy::Replace, true);
+        assert_eq!(text, b"a\\x00b");
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_decode_lossy_empty_and_null_arguments() {
+        let corebpe = tiktoken_cl100k_base();
+        let text = decode_lossy(corebpe, &[], CTiktokenLossyDecodePolicy::Drop, true);
+        assert!(text.is_empty());
+        assert!(tiktoken_corebpe_decode_lossy(
+            std::ptr::null(),
+            [40].as_ptr(),
+            1,
+            CTiktokenLossyDecodePolicy::Replace,
+            false,
+            std::ptr::null_mut()
+        )
+        .is_null());
+        assert!(tiktoken_corebpe_decode_lossy(
+            corebpe,
+            std::ptr::null(),
+            1,
+            CTiktokenLossyDecodePolicy::Replace,
+            false,
+            std::ptr::null_mut()
+        )
+        .is_null());
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_decode_tokens() {
+        let corebpe = tiktoken_cl100k_base();
+        let tokens = [40, 1097, 100257, 8415];
+        let mut bytes = std::ptr::null_mut();
+        let mut num_bytes = 0;
+        let mut offsets = std::ptr::null_mut();
+        let mut is_special = std::ptr::null_mut();
+        assert!(tiktoken_corebpe_decode_tokens(
+            corebpe,
+            tokens.as_ptr(),
+            tokens.len(),
+            &mut bytes,
+            &mut num_bytes,
+            &mut offsets,
+            &mut is_special,
+        ));
+        let packed = unsafe { std::slice::from_raw_parts(bytes, num_bytes) };
+        let token_offsets = unsafe { std::slice::from_raw_parts(offsets, tokens.len() + 1) };
+        let flags = unsafe { std::slice::from_raw_parts(is_special, tokens.len()) };
+        assert_eq!(packed, b"I am<|endoftext|> cat");
+        assert_eq!(token_offsets, [0, 1, 4, 17, 21]);
+        assert_eq!(flags, [false, false, true, false]);
+        tiktoken_free(bytes as *mut std::ffi::c_void);
+        tiktoken_free(offsets as *mut std::ffi::c_void);
+        tiktoken_free(is_special as *mut std::ffi::c_void);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_decode_tokens_empty() {
+        let corebpe = tiktoken_cl100k_base();
+        let mut bytes = std::ptr::null_mut();
+        let mut num_bytes = usize::MAX;
+        let mut offsets = std::ptr::null_mut();
+        assert!(tiktoken_corebpe_decode_tokens(
+            corebpe,
+            std::ptr::null(),
+            0,
+            &mut bytes,
+            &mut num_bytes,
+            &mut offsets,
+            std::ptr::null_mut(),
+        ));
+        assert!(bytes.is_null());
+        assert_eq!(num_bytes, 0);
+        assert_eq!(unsafe { *offsets }, 0);
+        tiktoken_free(offsets as *mut std::ffi::c_void);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_decode_tokens_invalid_arguments() {
+        let corebpe = tiktoken_cl100k_base();
+        let mut bytes = std::ptr::null_mut();
+        let mut offsets = std::ptr::null_mut();
+        assert!(!tiktoken_corebpe_decode_tokens(
+            corebpe,
+            [u32::MAX].as_ptr(),
+            1,
+            &mut bytes,
+            std::ptr::null_mut(),
+            &mut offsets,
+            std::ptr::null_mut(),
+        ));
+        assert!(!tiktoken_corebpe_decode_tokens(
+            corebpe,
+            [40].as_ptr(),
+            1,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            &mut offsets,
+            std::ptr::null_mut(),
+        ));
+        assert!(!tiktoken_corebpe_decode_tokens(
+            std::ptr::null(),
+            [40].as_ptr(),
+            1,
+            &mut bytes,
+            std::ptr::null_mut(),
+            &mut offsets,
+            std::ptr::null_mut(),
+        ));
+        tiktoken_destroy_corebpe(corebpe);
+    }
+}
@@ -0,0 +1,615 @@
+#[cfg(feature = "logging")]
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{c_char, CStr};
+use std::sync::{Arc, Mutex};
+use tiktoken_rs::{ChatCompletionRequestMessage, FunctionCall};
+
+use crate::compare::num_tokens_from_messages;
+use crate::corebpe::{bpe_for_tokenizer, CoreBPE};
+use crate::{parse_chat_messages, CChatCompletionRequestMessage};
+
+/// Which counting function a cached count came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum CountKind {
+    Ordinary,
+    WithSpecialTokens,
+    Messages,
+}
+
+/// The encoding a cached count belongs to. Names are not used on their
+/// own, since different vocabularies can share one.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum CacheEncoding {
+    /// Handles of a shared built-in encoding instance, by its address.
+    Shared(usize),
+    /// Any other handle, by its id.
+    Handle(u64),
+    /// Chat messages for a model, with the address of the shared instance
+    /// of its encoding.
+    Model(String, usize),
+}
+
+/// Texts are keyed by their SHA-256, so entries take the same memory
+/// however long the text is, and crafted texts cannot collide.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct CacheKey {
+    kind: CountKind,
+    encoding: CacheEncoding,
+    digest: [u8; 32],
+}
+
+impl CacheKey {
+    /// Approximate memory held by the entry.
+    fn size(&self) -> usize {
+        let model_len = match &self.encoding {
+            CacheEncoding::Model(model, _) => model.len(),
+            _ => 0,
+        };
+        ENTRY_SIZE + model_len
+    }
+}
+
+/// Memory of an entry besides a model name: the key, stored in the map and
+/// the recency order, and the count.
+const ENTRY_SIZE: usize = 2 * std::mem::size_of::<CacheKey>() + std::mem::size_of::<CacheEntry>();
+
+pub(crate) fn text_digest(text: &str) -> [u8; 32] {
+    Sha256::digest(text.as_bytes()).into()
+}
+
+/// SHA-256 of every field of `messages`. Fields are length-prefixed and
+/// optional ones tagged, so different message lists never hash the same
+/// input.
+fn messages_digest(messages: &[ChatCompletionRequestMessage]) -> [u8; 32] {
+    fn field(hasher: &mut Sha256, value: Option<&str>) {
+        match value {
+            Some(value) => {
+                hasher.update([1]);
+                hasher.update((value.len() as u64).to_le_bytes());
+                hasher.update(value.as_bytes());
+            }
+            None => hasher.update([0]),
+        }
+    }
+    fn call(hasher: &mut Sha256, call: Option<&FunctionCall>) {
+        field(hasher, call.map(|call| call.name.as_str()));
+        field(hasher, call.map(|call| call.arguments.as_str()));
+    }
+    let mut hasher = Sha256::new();
+    hasher.update((messages.len() as u64).to_le_bytes());
+    for message in messages {
+        // Destructured so that a new field fails to compile until hashed.
+        let ChatCompletionRequestMessage {
+            role,
+            content,
+            name,
+            function_call,
+            tool_calls,
+            refusal,
+        } = message;
+        field(&mut hasher, Some(role));
+        field(&mut hasher, content.as_deref());
+        field(&mut hasher, name.as_deref());
+        call(&mut hasher, function_call.as_ref());
+        hasher.update((tool_calls.len() as u64).to_le_bytes());
+        for tool_call in tool_calls {
+            call(&mut hasher, Some(tool_call));
+        }
+        field(&mut hasher, refusal.as_deref());
+    }
+    hasher.finalize().into()
+}
+
+struct CacheEntry {
+    count: usize,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<Arc<CacheKey>, CacheEntry>,
+    /// Keys by last use, least recently used first.
+    order: BTreeMap<u64, Arc<CacheKey>>,
+    clock: u64,
+    bytes: usize,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CTiktokenCountCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+/// Least recently used cache of token counts, shared between threads and
+/// between the handles it is attached to.
+///
+/// Capacity is bounded by number of entries and by bytes of memory held by
+/// entries; a limit of 0 leaves that dimension unbounded.
+pub struct CTiktokenCountCache {
+    max_entries: usize,
+    max_bytes: usize,
+    state: Mutex<CacheState>,
+}
+
+impl CTiktokenCountCache {
+    fn lookup(&self, key: &CacheKey) -> Option<usize> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.clock += 1;
+        let now = state.clock;
+        match state.entries.get_mut(key) {
+            Some(entry) => {
+                let previous = std::mem::replace(&mut entry.last_used, now);
+                let count = entry.count;
+                if let Some(shared_key) = state.order.remove(&previous) {
+                    state.order.insert(now, shared_key);
+                }
+                state.hits += 1;
+                Some(count)
+            }
+            None => {
+                state.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&self, key: CacheKey, count: usize) {
+        let size = key.size();
+        if self.max_bytes != 0 && size > self.max_bytes {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.entries.contains_key(&key) {
+            return;
+        }
+        state.clock += 1;
+        let now = state.clock;
+        let key = Arc::new(key);
+        state.order.insert(now, Arc::clone(&key));
+        state.entries.insert(
+            key,
+            CacheEntry {
+                count,
+                last_used: now,
+            },
+        );
+        state.bytes += size;
+        while (self.max_entries != 0 && state.entries.len() > self.max_entries)
+            || (self.max_bytes != 0 && state.bytes > self.max_bytes)
+        {
+            let Some((_, oldest)) = state.order.pop_first() else {
+                break;
+            };
+            state.entries.remove(&oldest);
+            state.bytes -= oldest.size();
+            state.evictions += 1;
+        }
+    }
+
+    /// Returns the cached count for the text with SHA-256 `digest`,
+    /// computing and storing it with `count` on a miss. Failed counts are not
+    /// stored, and the lock is not held while counting.
+    pub(crate) fn get_or_count(
+        &self,
+        kind: CountKind,
+        encoding: CacheEncoding,
+        digest: [u8; 32],
+        count: impl FnOnce() -> Option<usize>,
+    ) -> Option<usize> {
+        let key = CacheKey {
+            kind,
+            encoding,
+            digest,
+        };
+        if let Some(num_tokens) = self.lookup(&key) {
+            return Some(num_tokens);
+        }
+        let num_tokens = count()?;
+        self.insert(key, num_tokens);
+        Some(num_tokens)
+    }
+
+    fn stats(&self) -> CTiktokenCountCacheStats {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        CTiktokenCountCacheStats {
+            hits: state.hits,
+            misses: state.misses,
+            evictions: state.evictions,
+            entries: state.entries.len(),
+            bytes: state.bytes,
+        }
+    }
+
+    fn clear(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.entries.clear();
+        state.order.clear();
+        state.bytes = 0;
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_count_cache_new(
+    max_entries: usize,
+    max_bytes: usize,
+) -> *mut CTiktokenCountCache {
+    if max_entries == 0 && max_bytes == 0 {
+        #[cfg(feature = "logging")]
+        warn!("Count cache needs a limit on entries or bytes!");
+        return std::ptr::null_mut();
+    }
+    let cache = CTiktokenCountCache {
+        max_entries,
+        max_bytes,
+        state: Mutex::new(CacheState::default()),
+    };
+    Arc::into_raw(Arc::new(cache)) as *mut CTiktokenCountCache
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_count_cache_destroy(cache: *mut CTiktokenCountCache) {
+    if cache.is_null() {
+        return;
+    }
+    unsafe {
+        drop(Arc::from_raw(cache as *const CTiktokenCountCache));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_count_cache_stats(
+    cache: *const CTiktokenCountCache,
+    stats: *mut CTiktokenCountCacheStats,
+) -> bool {
+    if cache.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for cache!");
+        return false;
+    }
+    if stats.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for stats!");
+        return false;
+    }
+    unsafe {
+        *stats = (*cache).stats();
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_count_cache_clear(cache: *const CTiktokenCountCache) {
+    if cache.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for cache!");
+        return;
+    }
+    unsafe { &*cache }.clear();
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_set_count_cache(
+    ptr: *mut CoreBPE,
+    cache: *const CTiktokenCountCache,
+) -> bool {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return false;
+    }
+    let cache = if cache.is_null() {
+        None
+    } else {
+        unsafe {
+            Arc::increment_strong_count(cache);
+            Some(Arc::from_raw(cache))
+        }
+    };
+    unsafe { &mut *ptr }.set_count_cache(cache);
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_num_tokens_from_messages_cached(
+    cache: *const CTiktokenCountCache,
+    model: *const c_char,
+    num_messages: u32,
+    messages: *const *mut CChatCompletionRequestMessage,
+) -> usize {
+    if cache.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for cache!");
+        return usize::MAX;
+    }
+    if model.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for model!");
+        return usize::MAX;
+    }
+    if messages.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for messages!");
+        return usize::MAX;
+    }
+    let model = match unsafe { CStr::from_ptr(model) }.to_str() {
+        Ok(model) => model,
+        Err(_) => {
+            #[cfg(feature = "logging")]
+            warn!("Invalid UTF-8 sequence provided for model!");
+            return usize::MAX;
+        }
+    };
+    let messages = match parse_chat_messages(num_messages, messages) {
+        Ok(messages) => messages,
+        Err(_) => return usize::MAX,
+    };
+    let bpe = match tiktoken_rs::tokenizer::get_tokenizer(model).and_then(bpe_for_tokenizer) {
+        Some(bpe) => bpe,
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to get BPE from model {}!", model);
+            return usize::MAX;
+        }
+    };
+    let encoding = CacheEncoding::Model(
+        model.to_string(),
+        bpe as *const tiktoken_rs::CoreBPE as usize,
+    );
+    let cache = unsafe { &*cache };
+    let count = || {
+        let num_tokens = num_tokens_from_messages(model, &messages);
+        #[cfg(feature = "logging")]
+        if num_tokens.is_none() {
+            warn!("Failed to get num tokens!");
+        }
+        num_tokens
+    };
+    cache
+        .get_or_count(
+            CountKind::Messages,
+            encoding,
+            messages_digest(&messages),
+            count,
+        )
+        .unwrap_or(usize::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corebpe::{
+        tiktoken_cl100k_base, tiktoken_destroy_corebpe, tiktoken_get_bpe_from_model,
+    };
+    use crate::{tiktoken_chat_message_destroy, tiktoken_chat_message_new};
+    use crate::{tiktoken_chat_message_set_content, tiktoken_corebpe_count_ordinary};
+    use crate::{tiktoken_corebpe_count_with_special_tokens, tiktoken_num_tokens_from_messages};
+    use std::ffi::CString;
+    use tiktoken_rs::Rank;
+
+    fn stats(cache: *const CTiktokenCountCache) -> CTiktokenCountCacheStats {
+        let mut stats = CTiktokenCountCacheStats::default();
+        assert!(tiktoken_count_cache_stats(cache, &mut stats));
+        stats
+    }
+
+    #[test]
+    fn test_count_cache_attached_to_corebpe() {
+        let cache = tiktoken_count_cache_new(16, 0);
+        let corebpe = tiktoken_cl100k_base();
+        assert!(tiktoken_corebpe_set_count_cache(corebpe, cache));
+        let text = CString::new("I am a cat.").unwrap();
+        assert_eq!(tiktoken_corebpe_count_ordinary(corebpe, text.as_ptr()), 5);
+        assert_eq!(tiktoken_corebpe_count_ordinary(corebpe, text.as_ptr()), 5);
+        assert_eq!(
+            tiktoken_corebpe_count_with_special_tokens(corebpe, text.as_ptr()),
+            5
+        );
+        let result = stats(cache);
+        assert_eq!((result.hits, result.misses, result.entries), (1, 2, 2));
+        assert_eq!(result.bytes, 2 * ENTRY_SIZE);
+
+        // The handle keeps the cache alive after the caller releases it.
+        tiktoken_count_cache_destroy(cache);
+        assert_eq!(tiktoken_corebpe_count_ordinary(corebpe, text.as_ptr()), 5);
+        assert!(tiktoken_corebpe_set_count_cache(corebpe, std::ptr::null()));
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_count_cache_evicts_least_recently_used() {
+        let cache = tiktoken_count_cache_new(2, 0);
+        let cache_ref = unsafe { &*cache };
+        let count = |text: &str| {
+            let encoding = CacheEncoding::Handle(u64::MAX);
+            cache_ref.get_or_count(CountKind::Ordinary, encoding, text_digest(text), || {
+                Some(text.len())
+            })
+        };
+        count("a");
+        count("bb");
+        count("a");
+        count("ccc");
+        let result = stats(cache);
+        assert_eq!((result.entries, result.evictions), (2, 1));
+        // "bb" was evicted, "a" was kept.
+        count("a");
+        count("bb");
+        let result = stats(cache);
+        assert_eq!((result.hits, result.misses), (2, 4));
+        tiktoken_count_cache_destroy(cache);
+    }
+
+    #[test]
+    fn test_count_cache_byte_limit() {
+        let cache = tiktoken_count_cache_new(0, 2 * ENTRY_SIZE);
+        let cache_ref = unsafe { &*cache };
+        let count = |text: &str| {
+            let encoding = CacheEncoding::Handle(u64::MAX);
+            cache_ref.get_or_count(CountKind::Ordinary, encoding, text_digest(text), || {
+                Some(text.len())
+            })
+        };
+        count("aaaa");
+        count("bbbb");
+        count("cccc");
+        assert_eq!(stats(cache).bytes, 2 * ENTRY_SIZE);
+        assert_eq!(stats(cache).entries, 2);
+        // Long texts take no more room than short ones.
+        count(&"d".repeat(1 << 16));
+        assert_eq!(stats(cache).bytes, 2 * ENTRY_SIZE);
+        // Larger than the whole cache, so never stored.
+        let model = CacheEncoding::Model("m".repeat(2 * ENTRY_SIZE), 0);
+        cache_ref.get_or_count(CountKind::Messages, model, text_digest(""), || Some(0));
+        assert_eq!(stats(cache).entries, 2);
+        tiktoken_count_cache_clear(cache);
+        assert_eq!(stats(cache).entries, 0);
+        assert_eq!(stats(cache).bytes, 0);
+        tiktoken_count_cache_destroy(cache);
+    }
+
+    #[test]
+    fn test_count_cache_keyed_by_encoding_not_name() {
+        let cache = tiktoken_count_cache_new(16, 0);
+        let builtin = tiktoken_cl100k_base();
+        let model = CString::new("gpt-4").unwrap();
+        let same = tiktoken_get_bpe_from_model(model.as_ptr());
+        // Same name and pattern, but every byte is its own token.
+        let bytes_only = CoreBPE::from_ranks(
+            (0..=u8::MAX)
+                .map(|byte| (vec![byte], byte as Rank))
+                .collect(),
+            Default::default(),
+            "cl100k_base",
+            unsafe { &*builtin }.pattern(),
+        )
+        .unwrap();
+        let bytes_only = Box::into_raw(Box::new(bytes_only));
+        for corebpe in [builtin, same, bytes_only] {
+            assert!(tiktoken_corebpe_set_count_cache(corebpe, cache));
+        }
+        let text = CString::new("I am a cat.").unwrap();
+        assert_eq!(tiktoken_corebpe_count_ordinary(builtin, text.as_ptr()), 5);
+        assert_eq!(tiktoken_corebpe_count_ordinary(same, text.as_ptr()), 5);
+        assert_eq!(
+            tiktoken_corebpe_count_ordinary(bytes_only, text.as_ptr()),
+            11
+        );
+        let result = stats(cache);
+        assert_eq!((result.hits, result.misses, result.entries), (1, 2, 2));
+        for corebpe in [builtin, same, bytes_only] {
+            tiktoken_destroy_corebpe(corebpe);
+        }
+        tiktoken_count_cache_destroy(cache);
+    }
+
+    #[test]
+    fn test_messages_digest() {
+        let message = ChatCompletionRequestMessage {
+            role: "user".to_string(),
+            content: Some("hi".to_string()),
+            ..Default::default()
+        };
+        let digest = messages_digest(std::slice::from_ref(&message));
+        let copy = ChatCompletionRequestMessage {
+            role: "user".to_string(),
+            content: Some("hi".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(digest, messages_digest(&[copy]));
+        for other in [
+            ChatCompletionRequestMessage {
+                name: Some(String::new()),
+                ..message.clone()
+            },
+            ChatCompletionRequestMessage {
+                content: Some("h".to_string()),
+                refusal: Some("i".to_string()),
+                ..message.clone()
+            },
+            ChatCompletionRequestMessage {
+                tool_calls: vec![FunctionCall {
+                    name: String::new(),
+                    arguments: String::new(),
+                }],
+                ..message.clone()
+            },
+        ] {
+            assert_ne!(digest, messages_digest(&[other]));
+        }
+        assert_ne!(digest, messages_digest(&[message.clone(), message]));
+    }
+
+    #[test]
+    fn test_num_tokens_from_messages_cached() {
+        let cache = tiktoken_count_cache_new(16, 0);
+        let model = CString::new("gpt-4").unwrap();
+        let role = CString::new("system").unwrap();
+        let content = CString::new("You are a helpful assistant.").unwrap();
+        let message = tiktoken_chat_message_new(role.as_ptr());
+        assert!(tiktoken_chat_message_set_content(message, content.as_ptr()));
+        let messages = [message];
+        let expected = tiktoken_num_tokens_from_messages(model.as_ptr(), 1, messages.as_ptr());
+        for _ in 0..3 {
+            let num_tokens = tiktoken_num_tokens_from_messages_cached(
+                cache,
+                model.as_ptr(),
+                1,
+                messages.as_ptr(),
+            );
+            assert_eq!(num_tokens, expected);
+        }
+        let result = stats(cache);
+        assert_eq!((result.hits, result.misses), (2, 1));
+        tiktoken_chat_message_destroy(message);
+        tiktoken_count_cache_destroy(cache);
+    }
+
+    #[test]
+    fn test_count_cache_shared_across_threads() {
+        let cache = tiktoken_count_cache_new(64, 0);
+        let cache_ref = unsafe { &*cache };
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for i in 0..100 {
+                        let text = format!("text {}", i % 10);
+                        let count = cache_ref.get_or_count(
+                            CountKind::Ordinary,
+                            CacheEncoding::Handle(u64::MAX),
+                            text_digest(&text),
+                            || Some(text.len()),
+                        );
+                        assert_eq!(count, Some(text.len()));
+                    }
+                });
+            }
+        });
+        let result = stats(cache);
+        assert_eq!(result.hits + result.misses, 800);
+        assert_eq!(result.entries, 10);
+        tiktoken_count_cache_destroy(cache);
+    }
+
+    #[test]
+    fn test_count_cache_invalid_arguments() {
+        assert!(tiktoken_count_cache_new(0, 0).is_null());
+        assert!(!tiktoken_count_cache_stats(
+            std::ptr::null(),
+            &mut CTiktokenCountCacheStats::default()
+        ));
+        assert!(!tiktoken_corebpe_set_count_cache(
+            std::ptr::null_mut(),
+            std::ptr::null()
+        ));
+        tiktoken_count_cache_destroy(std::ptr::null_mut());
+    }
+}
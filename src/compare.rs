@@ -0,0 +1,389 @@
+#[cfg(feature = "logging")]
+use log::warn;
+use std::ffi::c_char;
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+use tiktoken_rs::ChatCompletionRequestMessage;
+
+use crate::corebpe::{bpe_for_tokenizer, encoding_compiled_in, encoding_name};
+use crate::datadir::data_dir_has;
+use crate::{parse_chat_messages, parse_required_string};
+use crate::{CChatCompletionRequestMessage, CTiktokenTokenizer};
+
+/// Built-in encodings compiled into this build, in the order they are
+/// reported.
+pub(crate) const BUILTIN_TOKENIZERS: &[Tokenizer] = &[
+    #[cfg(feature = "enc-r50k")]
+    Tokenizer::R50kBase,
+    #[cfg(feature = "enc-p50k")]
+    Tokenizer::P50kBase,
+    #[cfg(feature = "enc-p50k")]
+    Tokenizer::P50kEdit,
+    #[cfg(feature = "enc-cl100k")]
+    Tokenizer::Cl100kBase,
+    #[cfg(feature = "enc-o200k")]
+    Tokenizer::O200kBase,
+    #[cfg(feature = "enc-o200k")]
+    Tokenizer::O200kHarmony,
+];
+
+/// Every built-in encoding, compiled in or not, in the order they are
+/// reported.
+const ALL_TOKENIZERS: &[Tokenizer] = &[
+    Tokenizer::R50kBase,
+    Tokenizer::P50kBase,
+    Tokenizer::P50kEdit,
+    Tokenizer::Cl100kBase,
+    Tokenizer::O200kBase,
+    Tokenizer::O200kHarmony,
+];
+
+// Framing overhead of current chat models, as used by
+// tiktoken_rs::num_tokens_from_messages.
+const TOKENS_PER_MESSAGE: usize = 3;
+const TOKENS_PER_NAME: usize = 1;
+const FUNCTION_CALL_OVERHEAD: usize = 1;
+const REPLY_PRIMING: usize = 3;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CTiktokenEncodingCount {
+    pub tokenizer: CTiktokenTokenizer,
+    pub name: *const c_char,
+    pub count: usize,
+}
+
+fn count_chat_tokens(
+    bpe: &tiktoken_rs::CoreBPE,
+    messages: &[ChatCompletionRequestMessage],
+) -> usize {
+    let mut num_tokens = 0;
+    for message in messages {
+        num_tokens += TOKENS_PER_MESSAGE;
+        num_tokens += bpe.count_with_special_tokens(&message.role);
+        if let Some(content) = &message.content {
+            num_tokens += bpe.count_with_special_tokens(content);
+        }
+        if let Some(name) = &message.name {
+            num_tokens += bpe.count_with_special_tokens(name) + TOKENS_PER_NAME;
+        }
+        for call in message.function_call.iter().chain(&message.tool_calls) {
+            num_tokens += bpe.count_with_special_tokens(&call.name);
+            num_tokens += bpe.count_with_special_tokens(&call.arguments);
+            num_tokens += FUNCTION_CALL_OVERHEAD;
+        }
+        if let Some(refusal) = &message.refusal {
+            num_tokens += bpe.count_with_special_tokens(refusal);
+        }
+    }
+    num_tokens + REPLY_PRIMING
+}
+
+/// Counts the tokens of a chat request to `model` like
+/// `tiktoken_rs::num_tokens_from_messages`, but with the encoding from
+/// `bpe_for_tokenizer`. tiktoken-rs only counts with its compiled-in
+/// encodings, so its framing is repeated here; a test checks the two agree
+/// for every model it knows.
+pub(crate) fn num_tokens_from_messages(
+    model: &str,
+    messages: &[ChatCompletionRequestMessage],
+) -> Option<usize> {
+    let tokenizer = match get_tokenizer(model) {
+        Some(tokenizer) => tokenizer,
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("No tokenizer found for model {}!", model);
+            return None;
+        }
+    };
+    if !matches!(
+        tokenizer,
+        Tokenizer::Cl100kBase | Tokenizer::O200kBase | Tokenizer::O200kHarmony
+    ) {
+        #[cfg(feature = "logging")]
+        warn!("Chat token counting is not supported for model {}!", model);
+        return None;
+    }
+    let num_tokens = count_chat_tokens(bpe_for_tokenizer(tokenizer)?, messages);
+    if model == "gpt-3.5-turbo-0301" {
+        // This snapshot spends 4 tokens per message, and a name replaces the
+        // role rather than adding one.
+        let num_named = messages
+            .iter()
+            .filter(|message| message.name.is_some())
+            .count();
+        return Some(num_tokens + messages.len() - 2 * num_named);
+    }
+    Some(num_tokens)
+}
+
+/// Fills `counts` with one entry per available encoding, compiled in or
+/// found in the data directory, and returns the number of entries written.
+/// An encoding whose rank file fails to load, with nothing compiled in to
+/// fall back to, is left out.
+fn fill_counts(
+    counts: *mut CTiktokenEncodingCount,
+    counts_len: usize,
+    count: impl Fn(&tiktoken_rs::CoreBPE) -> usize,
+) -> usize {
+    let available = ALL_TOKENIZERS
+        .iter()
+        .copied()
+        .filter(|&tokenizer| encoding_compiled_in(tokenizer) || data_dir_has(tokenizer))
+        .filter_map(|tokenizer| Some((tokenizer, bpe_for_tokenizer(tokenizer)?)));
+    let mut num_written = 0;
+    for (tokenizer, bpe) in available.take(counts_len) {
+        let entry = CTiktokenEncodingCount {
+            tokenizer: tokenizer.into(),
+            name: encoding_name(tokenizer).as_ptr(),
+            count: count(bpe),
+        };
+        unsafe {
+            *counts.add(num_written) = entry;
+        }
+        num_written += 1;
+    }
+    num_written
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_count_all_encodings(
+    text: *const c_char,
+    counts: *mut CTiktokenEncodingCount,
+    counts_len: usize,
+) -> usize {
+    if counts.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for counts!");
+        return usize::MAX;
+    }
+    let text = match parse_required_string(text, "text") {
+        Ok(text) => text,
+        Err(_) => return usize::MAX,
+    };
+    fill_counts(counts, counts_len, |bpe| bpe.count_ordinary(&text))
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_num_tokens_from_messages_all_encodings(
+    num_messages: u32,
+    messages: *const *mut CChatCompletionRequestMessage,
+    counts: *mut CTiktokenEncodingCount,
+    counts_len: usize,
+) -> usize {
+    if messages.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for messages!");
+        return usize::MAX;
+    }
+    if counts.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for counts!");
+        return usize::MAX;
+    }
+    let messages = match parse_chat_messages(num_messages, messages) {
+        Ok(messages) => messages,
+        Err(_) => return usize::MAX,
+    };
+    fill_counts(counts, counts_len, |bpe| count_chat_tokens(bpe, &messages))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiktoken_num_tokens_from_messages;
+    use crate::{tiktoken_chat_message_destroy, tiktoken_chat_message_new};
+    use crate::{tiktoken_chat_message_set_content, tiktoken_chat_message_set_name};
+    use std::ffi::{CStr, CString};
+    use tiktoken_rs::FunctionCall;
+
+    fn empty_counts() -> [CTiktokenEncodingCount; 6] {
+        [CTiktokenEncodingCount {
+            tokenizer: CTiktokenTokenizer::Unknown,
+            name: std::ptr::null(),
+            count: 0,
+        }; 6]
+    }
+
+    #[test]
+    fn test_count_all_encodings() {
+        let text = CString::new("I am a cat.").unwrap();
+        let mut counts = empty_counts();
+        let num_written = tiktoken_count_all_encodings(text.as_ptr(), counts.as_mut_ptr(), 6);
+        assert_eq!(num_written, 6);
+        let names: Vec<&str> = counts
+            .iter()
+            .map(|count| unsafe { CStr::from_ptr(count.name) }.to_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "r50k_base",
+                "p50k_base",
+                "p50k_edit",
+                "cl100k_base",
+                "o200k_base",
+                "o200k_harmony"
+            ]
+        );
+        assert_eq!(counts[3].tokenizer, CTiktokenTokenizer::Cl100kBase);
+        assert_eq!(counts[3].count, 5);
+        assert_eq!(counts[4].count, 5);
+    }
+
+    #[test]
+    fn test_count_all_encodings_short_buffer() {
+        let text = CString::new("I am a cat.").unwrap();
+        let mut counts = empty_counts();
+        let num_written = tiktoken_count_all_encodings(text.as_ptr(), counts.as_mut_ptr(), 2);
+        assert_eq!(num_written, 2);
+        assert_eq!(counts[1].tokenizer, CTiktokenTokenizer::P50kBase);
+        assert_eq!(counts[2].tokenizer, CTiktokenTokenizer::Unknown);
+    }
+
+    #[test]
+    fn test_count_all_encodings_null_arguments() {
+        let text = CString::new("I am a cat.").unwrap();
+        let mut counts = empty_counts();
+        assert_eq!(
+            tiktoken_count_all_encodings(std::ptr::null(), counts.as_mut_ptr(), 6),
+            usize::MAX
+        );
+        assert_eq!(
+            tiktoken_count_all_encodings(text.as_ptr(), std::ptr::null_mut(), 6),
+            usize::MAX
+        );
+    }
+
+    #[test]
+    fn test_num_tokens_from_messages_all_encodings() {
+        let role = CString::new("user").unwrap();
+        let content = CString::new("I am a cat.").unwrap();
+        let name = CString::new("tama").unwrap();
+        let message = tiktoken_chat_message_new(role.as_ptr());
+        assert!(tiktoken_chat_message_set_content(message, content.as_ptr()));
+        assert!(tiktoken_chat_message_set_name(message, name.as_ptr()));
+        let messages = [message];
+        let mut counts = empty_counts();
+        let num_written = tiktoken_num_tokens_from_messages_all_encodings(
+            1,
+            messages.as_ptr(),
+            counts.as_mut_ptr(),
+            counts.len(),
+        );
+        assert_eq!(num_written, 6);
+        for (model, index) in [("gpt-4", 3), ("gpt-4o", 4), ("gpt-oss-20b", 5)] {
+            let model = CString::new(model).unwrap();
+            let expected = tiktoken_num_tokens_from_messages(model.as_ptr(), 1, messages.as_ptr());
+            assert_eq!(counts[index].count, expected);
+        }
+        tiktoken_chat_message_destroy(message);
+    }
+
+    #[test]
+    fn test_num_tokens_from_messages_all_encodings_null_message_item() {
+        let messages = [std::ptr::null_mut::<CChatCompletionRequestMessage>()];
+        let mut counts = empty_counts();
+        let num_written = tiktoken_num_tokens_from_messages_all_encodings(
+            1,
+            messages.as_ptr(),
+            counts.as_mut_ptr(),
+            counts.len(),
+        );
+        assert_eq!(num_written, usize::MAX);
+    }
+
+    #[test]
+    fn test_num_tokens_from_messages_matches_tiktoken_rs() {
+        let call = FunctionCall {
+            name: "purr".to_string(),
+            arguments: "{\"volume\": 11}".to_string(),
+        };
+        let named = ChatCompletionRequestMessage {
+            role: "user".to_string(),
+            content: Some("I am a cat.".to_string()),
+            name: Some("tama".to_string()),
+            ..Default::default()
+        };
+        let unnamed = ChatCompletionRequestMessage {
+            role: "assistant".to_string(),
+            content: Some("So am I.".to_string()),
+            ..Default::default()
+        };
+        let calling = ChatCompletionRequestMessage {
+            role: "assistant".to_string(),
+            function_call: Some(call.clone()),
+            tool_calls: vec![call.clone(), call],
+            refusal: Some("I would rather nap.".to_string()),
+            ..Default::default()
+        };
+        let messages = [named, unnamed, calling];
+        // One model per branch of tiktoken_rs::model::get_context_size, plus
+        // the snapshot with framing of its own and fine-tuned names.
+        let models = [
+            "gpt-6",
+            "gpt-5.4-mini",
+            "gpt-5.4-nano",
+            "gpt-5.4",
+            "gpt-5.3-codex-spark",
+            "gpt-5",
+            "gpt-5-mini",
+            "codex-mini-latest",
+            "gpt-oss-20b",
+            "gpt-oss-120b",
+            "o1-mini",
+            "o1-preview",
+            "o1",
+            "o3",
+            "o3-mini",
+            "o4-mini",
+            "gpt-4.1",
+            "gpt-4.1-nano",
+            "chatgpt-4o-latest",
+            "gpt-4o",
+            "gpt-4o-mini",
+            "gpt-4.5-preview",
+            "gpt-4-turbo-2024-04-09",
+            "gpt-4-0125-preview",
+            "gpt-4-1106-preview",
+            "gpt-4-32k",
+            "gpt-4",
+            "gpt-4-0613",
+            "gpt-3.5-turbo-0125",
+            "gpt-3.5-turbo-1106",
+            "gpt-3.5-turbo-16k",
+            "gpt-3.5-turbo",
+            "gpt-3.5-turbo-0301",
+            "gpt-3.5-turbo-0613",
+            "gpt-35-turbo",
+            "text-davinci-002",
+            "text-davinci-003",
+            "ada",
+            "babbage",
+            "curie",
+            "code-cushman-001",
+            "code-davinci-002",
+            "davinci",
+            "text-ada-001",
+            "text-babbage-001",
+            "text-curie-001",
+            "text-embedding-ada-002",
+            "ft:gpt-4o-mini:org:cats:id",
+            "ft:gpt-3.5-turbo-0301:org::id",
+        ];
+        for model in models {
+            assert!(tiktoken_rs::model::get_context_size(model).is_some());
+            for messages in [&messages[..], &messages[..1], &[]] {
+                assert_eq!(
+                    num_tokens_from_messages(model, messages),
+                    tiktoken_rs::num_tokens_from_messages(model, messages).ok(),
+                    "{model}"
+                );
+            }
+        }
+        assert_eq!(
+            num_tokens_from_messages("text-davinci-003", &messages),
+            None
+        );
+        assert_eq!(num_tokens_from_messages("cat-gpt", &messages), None);
+    }
+}
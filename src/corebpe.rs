@@ -1,58 +1,582 @@
+use fancy_regex::Regex;
 #[cfg(feature = "logging")]
 use log::warn;
-use std::ffi::{c_char, CStr};
-use tiktoken_rs::CoreBPE;
+use rustc_hash::FxHashMap;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::ffi::{c_char, CStr, CString};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use tiktoken_rs::tokenizer::Tokenizer;
+use tiktoken_rs::{DecodeKeyError, EncodeError, Rank};
 
-// get_bpe_from_tokenizer is not yet implemented.
-// Use tiktoken_r50k_base(), tiktoken_p50k_base(), tiktoken_p50k_edit(), tiktoken_cl100k_base(), and tiktoken_o200k_base()
-// instead.
+use crate::cache::{text_digest, CTiktokenCountCache, CacheEncoding, CountKind};
+use crate::compare::BUILTIN_TOKENIZERS;
+use crate::datadir::{bpe_from_data_dir, data_dir_has};
+use crate::snapshot::{Snapshot, SnapshotHeader};
+use crate::CTiktokenTokenizer;
 
+const R50K_BASE_PAT_STR: &str =
+    "'(?:[sdmt]|ll|ve|re)| ?\\p{L}++| ?\\p{N}++| ?[^\\s\\p{L}\\p{N}]++|\\s++$|\\s+(?!\\S)|\\s";
+
+const CL100K_BASE_PAT_STR: &str = "'(?i:[sdmt]|ll|ve|re)|[^\\r\\n\\p{L}\\p{N}]?+\\p{L}++|\\p{N}{1,3}+| ?[^\\s\\p{L}\\p{N}]++[\\r\\n]*+|\\s++$|\\s*[\\r\\n]|\\s+(?!\\S)|\\s";
+
+/// Encoding handle exposed to C as `CoreBPE`.
+///
+/// Wraps a tiktoken-rs encoder together with the metadata needed to tell
+/// encodings apart: name, tokenizer kind, split pattern and special tokens.
+///
+/// An encoding loaded from a snapshot encodes, counts and decodes straight
+/// from the mapped file, and so do the piece-level methods below. Only
+/// `mergeable_ranks` copies its tables into memory; no tiktoken-rs encoder
+/// is ever built for it.
+pub struct CoreBPE {
+    tables: Tables,
+    shared: Option<&'static tiktoken_rs::CoreBPE>,
+    name: CString,
+    tokenizer: CTiktokenTokenizer,
+    pattern: CString,
+    special_tokens: Vec<(CString, Rank)>,
+    regex: Regex,
+    mergeable_ranks: OnceLock<Cow<'static, FxHashMap<Vec<u8>, Rank>>>,
+    count_cache: Option<Arc<CTiktokenCountCache>>,
+    id: u64,
+}
+
+/// Unique id of a new handle; ids are never reused.
+/// Where the token tables of an encoding live.
+enum Tables {
+    /// Inside a tiktoken-rs encoder.
+    Encoder(tiktoken_rs::CoreBPE),
+    /// In a mapped snapshot file.
+    Snapshot(Snapshot),
+}
+
+fn next_handle_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+impl CoreBPE {
+    pub(crate) fn new(
+        inner: tiktoken_rs::CoreBPE,
+        name: &str,
+        tokenizer: CTiktokenTokenizer,
+        pattern: &str,
+    ) -> Result<Self, fancy_regex::Error> {
+        let regex = Regex::new(pattern)?;
+        let mut special_tokens: Vec<(CString, Rank)> = inner
+            .special_tokens()
+            .into_iter()
+            .filter_map(|token| {
+                let rank = *inner.encode_with_special_tokens(token).first()?;
+                Some((CString::new(token).ok()?, rank))
+            })
+            .collect();
+        special_tokens.sort_by_key(|(_, rank)| *rank);
+        Ok(Self {
+            tables: Tables::Encoder(inner),
+            shared: None,
+            name: CString::new(name).unwrap_or_default(),
+            tokenizer,
+            pattern: CString::new(pattern).unwrap_or_default(),
+            special_tokens,
+            regex,
+            mergeable_ranks: OnceLock::new(),
+            count_cache: None,
+            id: next_handle_id(),
+        })
+    }
+
+    /// Builds an encoding from its token tables. Ranks must be unique.
+    pub(crate) fn from_ranks(
+        mergeable_ranks: FxHashMap<Vec<u8>, Rank>,
+        special_tokens: FxHashMap<String, Rank>,
+        name: &str,
+        pattern: &str,
+    ) -> Option<Self> {
+        let inner =
+            tiktoken_rs::CoreBPE::new(mergeable_ranks.clone(), special_tokens, pattern).ok()?;
+        let corebpe = Self::new(inner, name, CTiktokenTokenizer::Unknown, pattern).ok()?;
+        // Ranks are known here, and need not be dense as the rebuilt table
+        // assumes.
+        let _ = corebpe.mergeable_ranks.set(Cow::Owned(mergeable_ranks));
+        Some(corebpe)
+    }
+
+    pub(crate) fn from_tokenizer(
+        inner: tiktoken_rs::CoreBPE,
+        tokenizer: Tokenizer,
+    ) -> Result<Self, fancy_regex::Error> {
+        let name = encoding_name(tokenizer).to_str().unwrap_or_default();
+        Self::new(inner, name, tokenizer.into(), builtin_pattern(tokenizer))
+    }
+
+    /// Like `from_tokenizer`, for a copy of a shared instance. Handles of the
+    /// same shared instance also share its rank table.
+    pub(crate) fn from_shared(
+        bpe: &'static tiktoken_rs::CoreBPE,
+        tokenizer: Tokenizer,
+    ) -> Result<Self, fancy_regex::Error> {
+        let mut corebpe = Self::from_tokenizer(bpe.clone(), tokenizer)?;
+        corebpe.shared = Some(bpe);
+        Ok(corebpe)
+    }
+
+    pub(crate) fn from_snapshot(snapshot: Snapshot, header: SnapshotHeader) -> Option<Self> {
+        let regex = Regex::new(&header.pattern).ok()?;
+        let mut special_tokens: Vec<(CString, Rank)> = header
+            .special_tokens
+            .into_iter()
+            .map(|(token, rank)| Some((CString::new(token).ok()?, rank)))
+            .collect::<Option<_>>()?;
+        special_tokens.sort_by_key(|(_, rank)| *rank);
+        Some(Self {
+            tables: Tables::Snapshot(snapshot),
+            shared: None,
+            name: CString::new(header.name).ok()?,
+            tokenizer: header.tokenizer,
+            pattern: CString::new(header.pattern).ok()?,
+            special_tokens,
+            regex,
+            mergeable_ranks: OnceLock::new(),
+            count_cache: None,
+            id: next_handle_id(),
+        })
+    }
+
+    fn snapshot(&self) -> Option<&Snapshot> {
+        match &self.tables {
+            Tables::Encoder(_) => None,
+            Tables::Snapshot(snapshot) => Some(snapshot),
+        }
+    }
+
+    pub(crate) fn tokenizer(&self) -> CTiktokenTokenizer {
+        self.tokenizer
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        self.name.to_str().unwrap_or_default()
+    }
+
+    pub(crate) fn pattern(&self) -> &str {
+        self.pattern.to_str().unwrap_or_default()
+    }
+
+    pub(crate) fn special_token_rank(&self, token: &str) -> Option<Rank> {
+        self.special_tokens
+            .iter()
+            .find(|(special, _)| special.to_bytes() == token.as_bytes())
+            .map(|(_, rank)| *rank)
+    }
+
+    /// Special tokens and their ranks, in rank order.
+    pub(crate) fn special_token_ranks(&self) -> impl Iterator<Item = (&str, Rank)> {
+        self.special_tokens
+            .iter()
+            .map(|(token, rank)| (token.to_str().unwrap_or_default(), *rank))
+    }
+
+    /// One more than the highest rank, ordinary or special.
+    pub(crate) fn vocab_size(&self) -> usize {
+        let ordinary = match self.snapshot() {
+            Some(snapshot) => snapshot.num_ranks() as Rank,
+            None => self
+                .mergeable_ranks()
+                .values()
+                .max()
+                .map_or(0, |&rank| rank + 1),
+        };
+        let special = self.special_tokens.last().map_or(0, |(_, rank)| rank + 1);
+        ordinary.max(special) as usize
+    }
+
+    pub(crate) fn set_count_cache(&mut self, cache: Option<Arc<CTiktokenCountCache>>) {
+        self.count_cache = cache;
+    }
+
+    /// Counts `text` with `count`, going through the attached count cache
+    /// if there is one.
+    pub(crate) fn count_cached(
+        &self,
+        kind: CountKind,
+        text: &str,
+        count: impl FnOnce() -> usize,
+    ) -> usize {
+        match &self.count_cache {
+            Some(cache) => cache
+                .get_or_count(kind, self.cache_encoding(), text_digest(text), || {
+                    Some(count())
+                })
+                .unwrap_or(usize::MAX),
+            None => count(),
+        }
+    }
+
+    /// Identifies the encoding in count caches. Handles copied from the same
+    /// shared instance share entries; any other handle has entries of its
+    /// own, as names are chosen freely and need not be unique.
+    fn cache_encoding(&self) -> CacheEncoding {
+        match self.shared {
+            Some(bpe) => CacheEncoding::Shared(bpe as *const tiktoken_rs::CoreBPE as usize),
+            None => CacheEncoding::Handle(self.id),
+        }
+    }
+
+    pub(crate) fn is_special_token(&self, rank: Rank) -> bool {
+        self.special_tokens
+            .binary_search_by_key(&rank, |(_, r)| *r)
+            .is_ok()
+    }
+
+    /// Byte sequence to rank table of the ordinary tokens.
+    ///
+    /// tiktoken-rs does not expose its encoder, so the table is rebuilt on
+    /// first use by decoding every rank up to the highest special token and
+    /// then onwards until the first unused rank, which picks up ordinary
+    /// tokens numbered after the special ones (as in p50k_base). Handles of
+    /// a shared built-in encoding rebuild it once per process.
+    pub(crate) fn mergeable_ranks(&self) -> &FxHashMap<Vec<u8>, Rank> {
+        self.mergeable_ranks.get_or_init(|| match self.shared {
+            Some(bpe) => Cow::Borrowed(shared_mergeable_ranks(bpe, || {
+                self.rebuild_mergeable_ranks()
+            })),
+            None => Cow::Owned(self.rebuild_mergeable_ranks()),
+        })
+    }
+
+    fn rebuild_mergeable_ranks(&self) -> FxHashMap<Vec<u8>, Rank> {
+        if let Some(snapshot) = self.snapshot() {
+            return snapshot
+                .tokens()
+                .map(|(token, rank)| (token.to_vec(), rank))
+                .collect();
+        }
+        let end = self.special_tokens.last().map_or(0, |(_, rank)| rank + 1);
+        let below_special = (0..end)
+            .filter(|&rank| !self.is_special_token(rank))
+            .filter_map(|rank| Some((self.decode_bytes(&[rank]).ok()?, rank)));
+        let above_special =
+            (end..).map_while(|rank| Some((self.decode_bytes(&[rank]).ok()?, rank)));
+        below_special.chain(above_special).collect()
+    }
+
+    /// Splits `text` into pre-tokenizer pieces, as `encode_ordinary` does,
+    /// yielding the byte offset at which each piece starts.
+    pub(crate) fn split_ordinary<'a>(
+        &'a self,
+        text: &'a str,
+    ) -> impl Iterator<Item = (usize, &'a str)> + 'a {
+        self.regex
+            .find_iter(text)
+            .filter_map(|mat| mat.ok())
+            .map(|mat| (mat.start(), mat.as_str()))
+    }
+
+    /// Rank of the ordinary token `bytes`, if there is one.
+    pub(crate) fn rank(&self, bytes: &[u8]) -> Option<Rank> {
+        match self.snapshot() {
+            Some(snapshot) => snapshot.rank(bytes),
+            None => self.mergeable_ranks().get(bytes).copied(),
+        }
+    }
+
+    /// Splits one pre-tokenizer piece, or any byte sequence, into the bytes
+    /// of its tokens. Returns `None` if a byte has no token of its own.
+    pub(crate) fn split_piece<'a>(&self, piece: &'a [u8]) -> Option<Vec<&'a [u8]>> {
+        if piece.is_empty() {
+            return Some(Vec::new());
+        }
+        if let Some(snapshot) = self.snapshot() {
+            let mut tokens = Vec::new();
+            snapshot.encode_piece(piece, &mut tokens);
+            let mut parts = Vec::with_capacity(tokens.len());
+            let mut start = 0;
+            for rank in tokens {
+                let end = start + snapshot.token(rank)?.len();
+                parts.push(&piece[start..end]);
+                start = end;
+            }
+            return Some(parts);
+        }
+        let ranks = self.mergeable_ranks();
+        if ranks.contains_key(piece) {
+            return Some(vec![piece]);
+        }
+        if piece.len() == 1 {
+            return None;
+        }
+        let parts = tiktoken_rs::byte_pair_split(piece, ranks);
+        parts
+            .iter()
+            .all(|part| ranks.contains_key(*part))
+            .then_some(parts)
+    }
+
+    pub(crate) fn encode_piece(&self, piece: &[u8]) -> Option<Vec<Rank>> {
+        if let Some(snapshot) = self.snapshot() {
+            let mut tokens = Vec::new();
+            if !piece.is_empty() {
+                snapshot.encode_piece(piece, &mut tokens);
+            }
+            return Some(tokens);
+        }
+        let ranks = self.mergeable_ranks();
+        let parts = self.split_piece(piece)?;
+        Some(parts.into_iter().map(|part| ranks[part]).collect())
+    }
+}
+
+// Snapshot-backed versions of the tiktoken-rs methods, with the same
+// results and signatures as the ones they shadow.
+impl CoreBPE {
+    pub fn encode_ordinary(&self, text: &str) -> Vec<Rank> {
+        match &self.tables {
+            Tables::Snapshot(snapshot) => snapshot.encode_ordinary(&self.regex, text),
+            Tables::Encoder(bpe) => bpe.encode_ordinary(text),
+        }
+    }
+
+    pub fn count_ordinary(&self, text: &str) -> usize {
+        match &self.tables {
+            Tables::Snapshot(snapshot) => snapshot.encode_ordinary(&self.regex, text).len(),
+            Tables::Encoder(bpe) => bpe.count_ordinary(text),
+        }
+    }
+
+    pub fn encode(
+        &self,
+        text: &str,
+        allowed_special: &HashSet<&str>,
+    ) -> Result<(Vec<Rank>, usize), EncodeError> {
+        match &self.tables {
+            Tables::Snapshot(snapshot) => {
+                snapshot.encode(&self.regex, text, |token| allowed_special.contains(token))
+            }
+            Tables::Encoder(bpe) => bpe.encode(text, allowed_special),
+        }
+    }
+
+    pub fn count(&self, text: &str, allowed_special: &HashSet<&str>) -> anyhow::Result<usize> {
+        match &self.tables {
+            Tables::Snapshot(_) => Ok(self.encode(text, allowed_special)?.0.len()),
+            Tables::Encoder(bpe) => bpe.count(text, allowed_special),
+        }
+    }
+
+    pub fn encode_with_special_tokens(&self, text: &str) -> Vec<Rank> {
+        match &self.tables {
+            Tables::Snapshot(snapshot) => snapshot.encode_with_special_tokens(&self.regex, text),
+            Tables::Encoder(bpe) => bpe.encode_with_special_tokens(text),
+        }
+    }
+
+    pub fn count_with_special_tokens(&self, text: &str) -> usize {
+        match &self.tables {
+            Tables::Snapshot(snapshot) => {
+                snapshot.encode_with_special_tokens(&self.regex, text).len()
+            }
+            Tables::Encoder(bpe) => bpe.count_with_special_tokens(text),
+        }
+    }
+
+    pub fn decode_bytes(&self, tokens: &[Rank]) -> Result<Vec<u8>, DecodeKeyError> {
+        match &self.tables {
+            Tables::Snapshot(snapshot) => snapshot.decode_bytes(
+                |rank| {
+                    self.special_tokens
+                        .binary_search_by_key(&rank, |(_, r)| *r)
+                        .ok()
+                        .map(|index| self.special_tokens[index].0.to_bytes())
+                },
+                tokens,
+            ),
+            Tables::Encoder(bpe) => bpe.decode_bytes(tokens),
+        }
+    }
+
+    pub fn decode(&self, tokens: &[Rank]) -> anyhow::Result<String> {
+        match &self.tables {
+            Tables::Snapshot(_) => Ok(String::from_utf8(self.decode_bytes(tokens)?)?),
+            Tables::Encoder(bpe) => bpe.decode(tokens),
+        }
+    }
+}
+
+pub(crate) fn encoding_name(tokenizer: Tokenizer) -> &'static CStr {
+    match tokenizer {
+        Tokenizer::O200kHarmony => c"o200k_harmony",
+        Tokenizer::O200kBase => c"o200k_base",
+        Tokenizer::Cl100kBase => c"cl100k_base",
+        Tokenizer::P50kBase => c"p50k_base",
+        Tokenizer::R50kBase => c"r50k_base",
+        Tokenizer::P50kEdit => c"p50k_edit",
+        Tokenizer::Gpt2 => c"gpt2",
+    }
+}
+
+pub(crate) fn builtin_pattern(tokenizer: Tokenizer) -> &'static str {
+    match tokenizer {
+        Tokenizer::O200kHarmony | Tokenizer::O200kBase => tiktoken_rs::O200K_BASE_PAT_STR,
+        Tokenizer::Cl100kBase => CL100K_BASE_PAT_STR,
+        Tokenizer::P50kBase | Tokenizer::R50kBase | Tokenizer::P50kEdit | Tokenizer::Gpt2 => {
+            R50K_BASE_PAT_STR
+        }
+    }
+}
+
+/// Split pattern of a built-in tokenizer, or `None` for `Unknown`.
+pub(crate) fn tokenizer_pattern(tokenizer: CTiktokenTokenizer) -> Option<&'static CStr> {
+    static PATTERNS: OnceLock<[CString; 3]> = OnceLock::new();
+    let [r50k, cl100k, o200k] = PATTERNS.get_or_init(|| {
+        [
+            R50K_BASE_PAT_STR,
+            CL100K_BASE_PAT_STR,
+            tiktoken_rs::O200K_BASE_PAT_STR,
+        ]
+        .map(|pattern| CString::new(pattern).unwrap_or_default())
+    });
+    match tokenizer {
+        CTiktokenTokenizer::Unknown => None,
+        CTiktokenTokenizer::O200kHarmony | CTiktokenTokenizer::O200kBase => Some(o200k),
+        CTiktokenTokenizer::Cl100kBase => Some(cl100k),
+        CTiktokenTokenizer::P50kBase
+        | CTiktokenTokenizer::R50kBase
+        | CTiktokenTokenizer::P50kEdit
+        | CTiktokenTokenizer::Gpt2 => Some(r50k),
+    }
+}
+
+/// Cargo feature that compiles in the rank data of `tokenizer`.
+fn encoding_feature(tokenizer: Tokenizer) -> &'static CStr {
+    match tokenizer {
+        Tokenizer::O200kHarmony | Tokenizer::O200kBase => c"enc-o200k",
+        Tokenizer::Cl100kBase => c"enc-cl100k",
+        Tokenizer::P50kBase | Tokenizer::P50kEdit => c"enc-p50k",
+        Tokenizer::R50kBase | Tokenizer::Gpt2 => c"enc-r50k",
+    }
+}
+
+pub(crate) fn encoding_compiled_in(tokenizer: Tokenizer) -> bool {
+    match tokenizer {
+        Tokenizer::O200kHarmony | Tokenizer::O200kBase => cfg!(feature = "enc-o200k"),
+        Tokenizer::Cl100kBase => cfg!(feature = "enc-cl100k"),
+        Tokenizer::P50kBase | Tokenizer::P50kEdit => cfg!(feature = "enc-p50k"),
+        Tokenizer::R50kBase | Tokenizer::Gpt2 => cfg!(feature = "enc-r50k"),
+    }
+}
+
+/// Shared instance of a built-in encoding. One loaded from the data
+/// directory is preferred, so a set directory can update compiled-in
+/// vocabularies; otherwise the compiled-in one is used, and `None` is
+/// returned when there is neither.
+///
+/// All lookups of built-in encodings go through here or `compiled_bpe`
+/// rather than `tiktoken_rs::bpe_for_tokenizer`, which would link in every
+/// encoding.
+pub(crate) fn bpe_for_tokenizer(tokenizer: Tokenizer) -> Option<&'static tiktoken_rs::CoreBPE> {
+    let bpe = bpe_from_data_dir(tokenizer).or_else(|| compiled_bpe(tokenizer));
+    #[cfg(feature = "logging")]
+    if bpe.is_none() {
+        warn!(
+            "{} is not compiled in; rebuild with the `{}` feature or set a data directory!",
+            encoding_name(tokenizer).to_str().unwrap_or_default(),
+            encoding_feature(tokenizer).to_str().unwrap_or_default()
+        );
+    }
+    bpe
+}
+
+/// Shared compiled-in instance of a built-in encoding, `None` if it is left
+/// out of the build.
+pub(crate) fn compiled_bpe(tokenizer: Tokenizer) -> Option<&'static tiktoken_rs::CoreBPE> {
+    #[allow(unreachable_patterns)]
+    match tokenizer {
+        #[cfg(feature = "enc-o200k")]
+        Tokenizer::O200kHarmony => Some(tiktoken_rs::o200k_harmony_singleton()),
+        #[cfg(feature = "enc-o200k")]
+        Tokenizer::O200kBase => Some(tiktoken_rs::o200k_base_singleton()),
+        #[cfg(feature = "enc-cl100k")]
+        Tokenizer::Cl100kBase => Some(tiktoken_rs::cl100k_base_singleton()),
+        #[cfg(feature = "enc-p50k")]
+        Tokenizer::P50kBase => Some(tiktoken_rs::p50k_base_singleton()),
+        #[cfg(feature = "enc-p50k")]
+        Tokenizer::P50kEdit => Some(tiktoken_rs::p50k_edit_singleton()),
+        #[cfg(feature = "enc-r50k")]
+        Tokenizer::R50kBase | Tokenizer::Gpt2 => Some(tiktoken_rs::r50k_base_singleton()),
+        _ => None,
+    }
+}
+
+/// Rank table of a shared tiktoken-rs instance, built on first use. There
+/// are at most two instances per built-in encoding, compiled in and loaded
+/// from the data directory, so the tables are simply kept for the life of
+/// the process.
+fn shared_mergeable_ranks(
+    bpe: &'static tiktoken_rs::CoreBPE,
+    build: impl FnOnce() -> FxHashMap<Vec<u8>, Rank>,
+) -> &'static FxHashMap<Vec<u8>, Rank> {
+    type SharedRanks = Vec<(usize, &'static FxHashMap<Vec<u8>, Rank>)>;
+    static SHARED: Mutex<SharedRanks> = Mutex::new(Vec::new());
+    let key = bpe as *const tiktoken_rs::CoreBPE as usize;
+    let mut shared = SHARED.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some((_, ranks)) = shared.iter().find(|(shared_key, _)| *shared_key == key) {
+        return ranks;
+    }
+    let ranks: &'static FxHashMap<Vec<u8>, Rank> = Box::leak(Box::new(build()));
+    shared.push((key, ranks));
+    ranks
+}
+
+fn into_raw(bpe: &'static tiktoken_rs::CoreBPE, tokenizer: Tokenizer) -> *mut CoreBPE {
+    match CoreBPE::from_shared(bpe, tokenizer) {
+        Ok(corebpe) => Box::into_raw(Box::new(corebpe)),
+        Err(_) => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to compile the split pattern!");
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[cfg(feature = "enc-r50k")]
 #[no_mangle]
 pub extern "C" fn tiktoken_r50k_base() -> *mut CoreBPE {
-    let bpe = tiktoken_rs::r50k_base();
-    let corebpe = bpe.unwrap();
-    let boxed = Box::new(corebpe);
-    Box::into_raw(boxed)
+    into_raw(tiktoken_rs::r50k_base_singleton(), Tokenizer::R50kBase)
 }
 
+#[cfg(feature = "enc-p50k")]
 #[no_mangle]
 pub extern "C" fn tiktoken_p50k_base() -> *mut CoreBPE {
-    let bpe = tiktoken_rs::p50k_base();
-    let corebpe = bpe.unwrap();
-    let boxed = Box::new(corebpe);
-    Box::into_raw(boxed)
+    into_raw(tiktoken_rs::p50k_base_singleton(), Tokenizer::P50kBase)
 }
 
+#[cfg(feature = "enc-p50k")]
 #[no_mangle]
 pub extern "C" fn tiktoken_p50k_edit() -> *mut CoreBPE {
-    let bpe = tiktoken_rs::p50k_edit();
-    let corebpe = bpe.unwrap();
-    let boxed = Box::new(corebpe);
-    Box::into_raw(boxed)
+    into_raw(tiktoken_rs::p50k_edit_singleton(), Tokenizer::P50kEdit)
 }
 
+#[cfg(feature = "enc-cl100k")]
 #[no_mangle]
 pub extern "C" fn tiktoken_cl100k_base() -> *mut CoreBPE {
-    let bpe = tiktoken_rs::cl100k_base();
-    let corebpe = bpe.unwrap();
-    let boxed = Box::new(corebpe);
-    Box::into_raw(boxed)
+    into_raw(tiktoken_rs::cl100k_base_singleton(), Tokenizer::Cl100kBase)
 }
 
+#[cfg(feature = "enc-o200k")]
 #[no_mangle]
 pub extern "C" fn tiktoken_o200k_base() -> *mut CoreBPE {
-    let bpe = tiktoken_rs::o200k_base();
-    let corebpe = bpe.unwrap();
-    let boxed = Box::new(corebpe);
-    Box::into_raw(boxed)
+    into_raw(tiktoken_rs::o200k_base_singleton(), Tokenizer::O200kBase)
 }
 
+#[cfg(feature = "enc-o200k")]
 #[no_mangle]
 pub extern "C" fn tiktoken_o200k_harmony() -> *mut CoreBPE {
-    let bpe = tiktoken_rs::o200k_harmony();
-    let corebpe = bpe.unwrap();
-    let boxed = Box::new(corebpe);
-    Box::into_raw(boxed)
+    into_raw(
+        tiktoken_rs::o200k_harmony_singleton(),
+        Tokenizer::O200kHarmony,
+    )
 }
 
 #[no_mangle]
@@ -83,23 +607,176 @@ pub extern "C" fn tiktoken_get_bpe_from_model(model: *const c_char) -> *mut Core
             }
         }
     };
-    let bpe = tiktoken_rs::bpe_for_model(model);
-    match bpe {
-        Ok(bpe) => {
-            let boxed = Box::new(bpe.clone());
-            Box::into_raw(boxed)
-        }
-        Err(_) => {
+    let tokenizer = match tiktoken_rs::tokenizer::get_tokenizer(model) {
+        Some(tokenizer) => tokenizer,
+        None => {
             #[cfg(feature = "logging")]
             warn!("Failed to get BPE from model!");
+            return std::ptr::null_mut();
+        }
+    };
+    match bpe_for_tokenizer(tokenizer) {
+        Some(bpe) => into_raw(bpe, tokenizer),
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to get BPE from model {}!", model);
             std::ptr::null_mut()
         }
     }
 }
 
+fn builtin_tokenizer(tokenizer: CTiktokenTokenizer) -> Option<Tokenizer> {
+    match tokenizer {
+        CTiktokenTokenizer::O200kHarmony => Some(Tokenizer::O200kHarmony),
+        CTiktokenTokenizer::O200kBase => Some(Tokenizer::O200kBase),
+        CTiktokenTokenizer::Cl100kBase => Some(Tokenizer::Cl100kBase),
+        CTiktokenTokenizer::P50kBase => Some(Tokenizer::P50kBase),
+        CTiktokenTokenizer::R50kBase => Some(Tokenizer::R50kBase),
+        CTiktokenTokenizer::P50kEdit => Some(Tokenizer::P50kEdit),
+        CTiktokenTokenizer::Gpt2 => Some(Tokenizer::Gpt2),
+        CTiktokenTokenizer::Unknown => None,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_get_bpe_from_tokenizer(tokenizer: CTiktokenTokenizer) -> *mut CoreBPE {
+    let tokenizer = match builtin_tokenizer(tokenizer) {
+        Some(tokenizer) => tokenizer,
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("No BPE for an unknown tokenizer!");
+            return std::ptr::null_mut();
+        }
+    };
+    match bpe_for_tokenizer(tokenizer) {
+        Some(bpe) => into_raw(bpe, tokenizer),
+        None => std::ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_encoding_available(tokenizer: CTiktokenTokenizer) -> bool {
+    builtin_tokenizer(tokenizer)
+        .is_some_and(|tokenizer| encoding_compiled_in(tokenizer) || data_dir_has(tokenizer))
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_encoding_feature(tokenizer: CTiktokenTokenizer) -> *const c_char {
+    match builtin_tokenizer(tokenizer) {
+        Some(tokenizer) => encoding_feature(tokenizer).as_ptr(),
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("No cargo feature for an unknown tokenizer!");
+            std::ptr::null()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_compiled_encodings(
+    tokenizers: *mut CTiktokenTokenizer,
+    tokenizers_len: usize,
+) -> usize {
+    if tokenizers.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for tokenizers!");
+        return usize::MAX;
+    }
+    let num_written = BUILTIN_TOKENIZERS.len().min(tokenizers_len);
+    for (index, &tokenizer) in BUILTIN_TOKENIZERS[..num_written].iter().enumerate() {
+        unsafe {
+            *tokenizers.add(index) = tokenizer.into();
+        }
+    }
+    num_written
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_name(ptr: *const CoreBPE) -> *const c_char {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return std::ptr::null();
+    }
+    unsafe { &*ptr }.name.as_ptr()
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_tokenizer(ptr: *const CoreBPE) -> CTiktokenTokenizer {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return CTiktokenTokenizer::Unknown;
+    }
+    unsafe { &*ptr }.tokenizer
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_pattern(ptr: *const CoreBPE) -> *const c_char {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return std::ptr::null();
+    }
+    unsafe { &*ptr }.pattern.as_ptr()
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_tokenizer_pattern(tokenizer: CTiktokenTokenizer) -> *const c_char {
+    match tokenizer_pattern(tokenizer) {
+        Some(pattern) => pattern.as_ptr(),
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("No split pattern for an unknown tokenizer!");
+            std::ptr::null()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_special_tokens_len(ptr: *const CoreBPE) -> usize {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return usize::MAX;
+    }
+    unsafe { &*ptr }.special_tokens.len()
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_special_token(
+    ptr: *const CoreBPE,
+    index: usize,
+    rank: *mut Rank,
+) -> *const c_char {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return std::ptr::null();
+    }
+    let (token, token_rank) = match unsafe { &*ptr }.special_tokens.get(index) {
+        Some(special_token) => special_token,
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("Special token index out of range!");
+            return std::ptr::null();
+        }
+    };
+    unsafe {
+        if !rank.is_null() {
+            *rank = *token_rank;
+        }
+    };
+    token.as_ptr()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::approx::{tiktoken_corebpe_estimate_count_ordinary, CTiktokenCountEstimate};
+    use crate::limit::tiktoken_corebpe_count_ordinary_up_to;
+    use crate::snapshot::{tiktoken_corebpe_load_snapshot, tiktoken_corebpe_save_snapshot};
+    use crate::testutil::TempPath;
     use std::ffi::CString;
 
     #[test]
@@ -175,6 +852,239 @@ mod tests {
         tiktoken_destroy_corebpe(corebpe);
     }
 
+    #[test]
+    fn test_compiled_encodings() {
+        let mut tokenizers = [CTiktokenTokenizer::Unknown; 8];
+        assert_eq!(
+            tiktoken_compiled_encodings(tokenizers.as_mut_ptr(), tokenizers.len()),
+            6
+        );
+        assert_eq!(
+            tokenizers[..6],
+            [
+                CTiktokenTokenizer::R50kBase,
+                CTiktokenTokenizer::P50kBase,
+                CTiktokenTokenizer::P50kEdit,
+                CTiktokenTokenizer::Cl100kBase,
+                CTiktokenTokenizer::O200kBase,
+                CTiktokenTokenizer::O200kHarmony,
+            ]
+        );
+        assert_eq!(tiktoken_compiled_encodings(tokenizers.as_mut_ptr(), 2), 2);
+        assert_eq!(
+            tiktoken_compiled_encodings(std::ptr::null_mut(), 6),
+            usize::MAX
+        );
+    }
+
+    #[test]
+    fn test_encoding_available() {
+        assert!(tiktoken_encoding_available(CTiktokenTokenizer::Cl100kBase));
+        assert!(tiktoken_encoding_available(CTiktokenTokenizer::Gpt2));
+        assert!(!tiktoken_encoding_available(CTiktokenTokenizer::Unknown));
+    }
+
+    #[test]
+    fn test_encoding_feature() {
+        for (tokenizer, feature) in [
+            (CTiktokenTokenizer::R50kBase, "enc-r50k"),
+            (CTiktokenTokenizer::Gpt2, "enc-r50k"),
+            (CTiktokenTokenizer::P50kEdit, "enc-p50k"),
+            (CTiktokenTokenizer::Cl100kBase, "enc-cl100k"),
+            (CTiktokenTokenizer::O200kHarmony, "enc-o200k"),
+        ] {
+            let actual = unsafe { CStr::from_ptr(tiktoken_encoding_feature(tokenizer)) };
+            assert_eq!(actual.to_str().unwrap(), feature);
+        }
+        assert!(tiktoken_encoding_feature(CTiktokenTokenizer::Unknown).is_null());
+    }
+
+    #[test]
+    fn test_corebpe_name() {
+        let corebpe = tiktoken_cl100k_base();
+        let name = unsafe { CStr::from_ptr(tiktoken_corebpe_name(corebpe)) };
+        assert_eq!(name.to_str().unwrap(), "cl100k_base");
+        tiktoken_destroy_corebpe(corebpe);
+        assert!(tiktoken_corebpe_name(std::ptr::null()).is_null());
+    }
+
+    #[test]
+    fn test_corebpe_tokenizer_from_model() {
+        let model = CString::new("gpt-4o").unwrap();
+        let corebpe = tiktoken_get_bpe_from_model(model.as_ptr());
+        assert_eq!(
+            tiktoken_corebpe_tokenizer(corebpe),
+            CTiktokenTokenizer::O200kBase
+        );
+        let name = unsafe { CStr::from_ptr(tiktoken_corebpe_name(corebpe)) };
+        assert_eq!(name.to_str().unwrap(), "o200k_base");
+        tiktoken_destroy_corebpe(corebpe);
+        assert_eq!(
+            tiktoken_corebpe_tokenizer(std::ptr::null()),
+            CTiktokenTokenizer::Unknown
+        );
+    }
+
+    #[test]
+    fn test_corebpe_pattern() {
+        let corebpe = tiktoken_o200k_base();
+        let pattern = unsafe { CStr::from_ptr(tiktoken_corebpe_pattern(corebpe)) };
+        assert_eq!(pattern.to_str().unwrap(), tiktoken_rs::O200K_BASE_PAT_STR);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_tokenizer_pattern() {
+        for (tokenizer, expected) in [
+            (
+                CTiktokenTokenizer::O200kHarmony,
+                tiktoken_rs::O200K_BASE_PAT_STR,
+            ),
+            (CTiktokenTokenizer::Cl100kBase, CL100K_BASE_PAT_STR),
+            (CTiktokenTokenizer::Gpt2, R50K_BASE_PAT_STR),
+        ] {
+            let pattern = unsafe { CStr::from_ptr(tiktoken_tokenizer_pattern(tokenizer)) };
+            assert_eq!(pattern.to_str().unwrap(), expected);
+        }
+        assert!(tiktoken_tokenizer_pattern(CTiktokenTokenizer::Unknown).is_null());
+    }
+
+    #[test]
+    fn test_corebpe_special_tokens() {
+        let corebpe = tiktoken_cl100k_base();
+        assert_eq!(tiktoken_corebpe_special_tokens_len(corebpe), 5);
+        let mut rank: Rank = 0;
+        let token = tiktoken_corebpe_special_token(corebpe, 0, &mut rank);
+        let token = unsafe { CStr::from_ptr(token) };
+        assert_eq!(token.to_str().unwrap(), "<|endoftext|>");
+        assert_eq!(rank, 100257);
+        assert!(tiktoken_corebpe_special_token(corebpe, 5, &mut rank).is_null());
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_corebpe_special_tokens_null_corebpe() {
+        assert_eq!(
+            tiktoken_corebpe_special_tokens_len(std::ptr::null()),
+            usize::MAX
+        );
+        let token = tiktoken_corebpe_special_token(std::ptr::null(), 0, std::ptr::null_mut());
+        assert!(token.is_null());
+    }
+
+    #[test]
+    fn test_split_and_encode_pieces_match_encode_ordinary() {
+        let corebpe = tiktoken_cl100k_base();
+        let long_word = "x".repeat(150);
+        let text = format!("I am a cat.\n\n  猫です 12345 {} 'll end   ", long_word);
+        let corebpe_ref = unsafe { &*corebpe };
+        let mut tokens = Vec::new();
+        let mut next_start = 0;
+        for (start, piece) in corebpe_ref.split_ordinary(&text) {
+            assert_eq!(start, next_start);
+            next_start = start + piece.len();
+            tokens.extend(corebpe_ref.encode_piece(piece.as_bytes()).unwrap());
+        }
+        assert_eq!(next_start, text.len());
+        assert_eq!(tokens, corebpe_ref.encode_ordinary(&text));
+        tiktoken_destroy_corebpe(corebpe);
+
+        // p50k_base numbers its whitespace-run tokens after <|endoftext|>.
+        let corebpe = tiktoken_p50k_base();
+        let corebpe_ref = unsafe { &*corebpe };
+        let text = "fn main() {\n        return;\n    }";
+        let tokens: Vec<Rank> = corebpe_ref
+            .split_ordinary(text)
+            .flat_map(|(_, piece)| corebpe_ref.encode_piece(piece.as_bytes()).unwrap())
+            .collect();
+        assert_eq!(tokens, corebpe_ref.encode_ordinary(text));
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_mergeable_ranks_len() {
+        for (constructor, expected, vocab_size) in [
+            (
+                tiktoken_r50k_base as extern "C" fn() -> *mut CoreBPE,
+                50256,
+                50257,
+            ),
+            (tiktoken_p50k_base, 50280, 50281),
+            (tiktoken_cl100k_base, 100256, 100277),
+            (tiktoken_o200k_base, 199998, 200019),
+        ] {
+            let corebpe = constructor();
+            assert_eq!(unsafe { &*corebpe }.mergeable_ranks().len(), expected);
+            assert_eq!(unsafe { &*corebpe }.vocab_size(), vocab_size);
+            tiktoken_destroy_corebpe(corebpe);
+        }
+    }
+
+    #[test]
+    fn test_snapshot_piece_methods_read_the_file() {
+        let original = tiktoken_cl100k_base();
+        let path = TempPath::new("corebpe-pieces.snapshot");
+        assert!(tiktoken_corebpe_save_snapshot(
+            original,
+            path.c_path().as_ptr()
+        ));
+        let loaded = tiktoken_corebpe_load_snapshot(path.c_path().as_ptr());
+        let (original_ref, loaded_ref) = unsafe { (&*original, &*loaded) };
+
+        let text = "fn main() {\n        return 猫;\n    }";
+        let c_text = CString::new(text).unwrap();
+        for (_, piece) in original_ref.split_ordinary(text) {
+            let piece = piece.as_bytes();
+            assert_eq!(
+                loaded_ref.split_piece(piece),
+                original_ref.split_piece(piece)
+            );
+            assert_eq!(
+                loaded_ref.encode_piece(piece),
+                original_ref.encode_piece(piece)
+            );
+            assert_eq!(loaded_ref.rank(piece), original_ref.rank(piece));
+        }
+        assert_eq!(loaded_ref.encode_piece(b""), Some(Vec::new()));
+        assert_eq!(loaded_ref.vocab_size(), original_ref.vocab_size());
+        let mut offsets = [0; 2];
+        for (corebpe, offset) in [original, loaded].into_iter().zip(&mut offsets) {
+            assert_eq!(
+                tiktoken_corebpe_count_ordinary_up_to(corebpe, c_text.as_ptr(), 5, offset),
+                5
+            );
+        }
+        assert_eq!(offsets[0], offsets[1]);
+        let mut estimates = [CTiktokenCountEstimate::default(); 2];
+        for (corebpe, estimate) in [original, loaded].into_iter().zip(&mut estimates) {
+            assert!(tiktoken_corebpe_estimate_count_ordinary(
+                corebpe,
+                c_text.as_ptr(),
+                estimate
+            ));
+        }
+        assert_eq!(estimates[0].count, estimates[1].count);
+        // None of these built tables in memory.
+        assert!(loaded_ref.mergeable_ranks.get().is_none());
+
+        tiktoken_destroy_corebpe(loaded);
+        tiktoken_destroy_corebpe(original);
+    }
+
+    #[test]
+    fn test_mergeable_ranks_shared() {
+        let first = tiktoken_cl100k_base();
+        let model = CString::new("gpt-4").unwrap();
+        let second = tiktoken_get_bpe_from_model(model.as_ptr());
+        let other = tiktoken_p50k_base();
+        let ranks = |corebpe: *mut CoreBPE| unsafe { &*corebpe }.mergeable_ranks();
+        assert!(std::ptr::eq(ranks(first), ranks(second)));
+        assert!(!std::ptr::eq(ranks(first), ranks(other)));
+        for corebpe in [first, second, other] {
+            tiktoken_destroy_corebpe(corebpe);
+        }
+    }
+
     #[test]
     fn test_get_bpe_from_model_gpt_oss() {
         let model = CString::new("gpt-oss-20b").unwrap();
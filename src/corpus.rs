@@ -0,0 +1,485 @@
+#[cfg(feature = "logging")]
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::ffi::c_char;
+use std::path::{Path, PathBuf};
+use tiktoken_rs::Rank;
+
+use crate::corebpe::CoreBPE;
+use crate::file::CTiktokenTokenFormat;
+use crate::parse_required_string;
+use crate::shard::CTiktokenShardWriter;
+
+const INDEX_FILE: &str = "index.json";
+const INDEX_VERSION: u32 = 1;
+/// Bytes of pending text per thread before a batch is encoded.
+const BATCH_BYTES_PER_THREAD: usize = 1 << 20;
+
+fn shard_file(index: usize) -> String {
+    format!("shard-{:05}.bin", index)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ShardEntry {
+    file: String,
+    first_document: u64,
+    num_documents: u64,
+    num_tokens: u64,
+}
+
+/// Contents of `index.json`, rewritten after every completed shard.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CorpusIndex {
+    version: u32,
+    encoding: String,
+    token_bytes: u32,
+    max_shard_tokens: u64,
+    num_documents: u64,
+    num_tokens: u64,
+    complete: bool,
+    shards: Vec<ShardEntry>,
+}
+
+impl CorpusIndex {
+    fn load(path: &Path) -> Option<Option<Self>> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Some(None),
+            Err(_) => return None,
+        };
+        let index: Self = serde_json::from_str(&contents).ok()?;
+        (index.version == INDEX_VERSION).then_some(Some(index))
+    }
+
+    /// Replaces the index file in one step, so that a crash leaves either
+    /// the old or the new index.
+    fn save(&self, path: &Path) -> Option<()> {
+        let contents = serde_json::to_string_pretty(self).ok()?;
+        let temp_path = path.with_extension("json.tmp");
+        std::fs::write(&temp_path, contents).ok()?;
+        std::fs::rename(&temp_path, path).ok()
+    }
+}
+
+/// Tokenizes a stream of documents into EOT-separated shard files of at most
+/// `max_shard_tokens` tokens, recorded in an index that allows resuming.
+pub struct CTiktokenCorpusWriter {
+    corebpe: *const CoreBPE,
+    dir: PathBuf,
+    format: CTiktokenTokenFormat,
+    num_threads: usize,
+    batch_bytes: usize,
+    index: CorpusIndex,
+    shard: Option<CTiktokenShardWriter>,
+    pending: Vec<String>,
+    pending_bytes: usize,
+    failed: bool,
+}
+
+impl CTiktokenCorpusWriter {
+    fn open(
+        corebpe: &CoreBPE,
+        dir: &Path,
+        format: CTiktokenTokenFormat,
+        max_shard_tokens: u64,
+        num_threads: usize,
+    ) -> Option<Self> {
+        std::fs::create_dir_all(dir).ok()?;
+        let index = match CorpusIndex::load(&dir.join(INDEX_FILE))? {
+            Some(index) => index,
+            None => CorpusIndex {
+                version: INDEX_VERSION,
+                encoding: corebpe.name().to_string(),
+                token_bytes: format.token_bytes() as u32,
+                max_shard_tokens,
+                num_documents: 0,
+                num_tokens: 0,
+                complete: false,
+                shards: Vec::new(),
+            },
+        };
+        if index.encoding != corebpe.name()
+            || index.token_bytes as usize != format.token_bytes()
+            || index.max_shard_tokens != max_shard_tokens
+        {
+            #[cfg(feature = "logging")]
+            warn!("Existing corpus index has different settings!");
+            return None;
+        }
+        // Remove shards written after the last index update.
+        let mut stale = index.shards.len();
+        while std::fs::remove_file(dir.join(shard_file(stale))).is_ok() {
+            stale += 1;
+        }
+        let num_threads = match num_threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        Some(Self {
+            corebpe,
+            dir: dir.to_path_buf(),
+            format,
+            num_threads,
+            batch_bytes: num_threads * BATCH_BYTES_PER_THREAD,
+            index,
+            shard: None,
+            pending: Vec::new(),
+            pending_bytes: 0,
+            failed: false,
+        })
+    }
+
+    fn corebpe(&self) -> &CoreBPE {
+        unsafe { &*self.corebpe }
+    }
+
+    fn add_document(&mut self, text: String) -> Option<()> {
+        self.pending_bytes += text.len();
+        self.pending.push(text);
+        if self.pending_bytes >= self.batch_bytes {
+            self.flush_pending()?;
+        }
+        Some(())
+    }
+
+    /// Encodes the pending documents, in parallel, and writes them in order.
+    fn flush_pending(&mut self) -> Option<()> {
+        let pending = std::mem::take(&mut self.pending);
+        self.pending_bytes = 0;
+        let corebpe = self.corebpe();
+        let chunk_len = pending.len().div_ceil(self.num_threads).max(1);
+        let encoded: Vec<Vec<Rank>> = if self.num_threads == 1 || pending.len() == 1 {
+            pending
+                .iter()
+                .map(|text| corebpe.encode_ordinary(text))
+                .collect()
+        } else {
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = pending
+                    .chunks(chunk_len)
+                    .map(|chunk| {
+                        scope.spawn(move || {
+                            chunk
+                                .iter()
+                                .map(|text| corebpe.encode_ordinary(text))
+                                .collect::<Vec<_>>()
+                        })
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .flat_map(|handle| handle.join().unwrap_or_default())
+                    .collect()
+            })
+        };
+        if encoded.len() != pending.len() {
+            return None;
+        }
+        for tokens in encoded {
+            self.write_document(&tokens)?;
+        }
+        Some(())
+    }
+
+    fn write_document(&mut self, tokens: &[Rank]) -> Option<()> {
+        // Each document is followed by <|endoftext|>.
+        let document_tokens = tokens.len() as u64 + 1;
+        if let Some(shard) = &self.shard {
+            if shard.num_tokens() + document_tokens > self.index.max_shard_tokens {
+                self.finish_shard()?;
+            }
+        }
+        if self.shard.is_none() {
+            let path = self.dir.join(shard_file(self.index.shards.len()));
+            let shard =
+                CTiktokenShardWriter::create(self.corebpe(), path.to_str()?, self.format, true)?;
+            self.shard = Some(shard);
+            self.index.complete = false;
+        }
+        self.shard.as_mut()?.add_tokens(tokens)
+    }
+
+    fn finish_shard(&mut self) -> Option<()> {
+        let mut shard = self.shard.take()?;
+        shard.finish()?;
+        self.index.shards.push(ShardEntry {
+            file: shard_file(self.index.shards.len()),
+            first_document: self.index.num_documents,
+            num_documents: shard.num_documents(),
+            num_tokens: shard.num_tokens(),
+        });
+        self.index.num_documents += shard.num_documents();
+        self.index.num_tokens += shard.num_tokens();
+        self.index.save(&self.dir.join(INDEX_FILE))
+    }
+
+    fn finish(&mut self) -> Option<()> {
+        self.flush_pending()?;
+        if self.shard.is_some() {
+            self.finish_shard()?;
+        }
+        self.index.complete = true;
+        self.index.save(&self.dir.join(INDEX_FILE))
+    }
+
+    /// Runs `step` unless an earlier step failed, and records its failure.
+    fn step(&mut self, step: impl FnOnce(&mut Self) -> Option<()>) -> bool {
+        if self.failed {
+            #[cfg(feature = "logging")]
+            warn!("Corpus writer has already failed!");
+            return false;
+        }
+        self.failed = step(self).is_none();
+        #[cfg(feature = "logging")]
+        if self.failed {
+            warn!("Failed to write corpus shard!");
+        }
+        !self.failed
+    }
+}
+
+/// Opens the corpus in `dir`, creating it if needed. If `dir` already holds
+/// an index with the same settings, writing resumes after its last shard.
+#[no_mangle]
+pub extern "C" fn tiktoken_corpus_writer_open(
+    ptr: *const CoreBPE,
+    dir: *const c_char,
+    format: CTiktokenTokenFormat,
+    max_shard_tokens: u64,
+    num_threads: usize,
+) -> *mut CTiktokenCorpusWriter {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return std::ptr::null_mut();
+    }
+    let dir = match parse_required_string(dir, "dir") {
+        Ok(dir) => dir,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    if max_shard_tokens == 0 {
+        #[cfg(feature = "logging")]
+        warn!("Shard size must be at least one token!");
+        return std::ptr::null_mut();
+    }
+    let corebpe = unsafe { &*ptr };
+    match CTiktokenCorpusWriter::open(
+        corebpe,
+        Path::new(&dir),
+        format,
+        max_shard_tokens,
+        num_threads,
+    ) {
+        Some(writer) => Box::into_raw(Box::new(writer)),
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to open corpus in {}!", dir);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Number of documents stored in completed shards; a resumed job continues
+/// with the document after these.
+#[no_mangle]
+pub extern "C" fn tiktoken_corpus_writer_committed_documents(
+    writer: *const CTiktokenCorpusWriter,
+) -> u64 {
+    if writer.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for writer!");
+        return u64::MAX;
+    }
+    unsafe { &*writer }.index.num_documents
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corpus_writer_add_document(
+    writer: *mut CTiktokenCorpusWriter,
+    text: *const c_char,
+) -> bool {
+    if writer.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for writer!");
+        return false;
+    }
+    let text = match parse_required_string(text, "text") {
+        Ok(text) => text,
+        Err(_) => return false,
+    };
+    unsafe { &mut *writer }.step(|writer| writer.add_document(text))
+}
+
+/// Encodes the remaining documents, completes the last shard and marks the
+/// index complete.
+#[no_mangle]
+pub extern "C" fn tiktoken_corpus_writer_finish(writer: *mut CTiktokenCorpusWriter) -> bool {
+    if writer.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for writer!");
+        return false;
+    }
+    unsafe { &mut *writer }.step(|writer| writer.finish())
+}
+
+/// Frees the writer. Documents not yet in a completed shard are discarded.
+#[no_mangle]
+pub extern "C" fn tiktoken_corpus_writer_destroy(writer: *mut CTiktokenCorpusWriter) {
+    if writer.is_null() {
+        return;
+    }
+    unsafe {
+        drop(Box::from_raw(writer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corebpe::{tiktoken_cl100k_base, tiktoken_destroy_corebpe};
+    use crate::testutil::TempPath;
+    use std::ffi::CString;
+
+    fn documents() -> Vec<String> {
+        (0..200)
+            .map(|i| format!("Document {} says hello{}", i, " world".repeat(i % 13)))
+            .collect()
+    }
+
+    fn open(
+        corebpe: *const CoreBPE,
+        dir: &TempPath,
+        num_threads: usize,
+    ) -> *mut CTiktokenCorpusWriter {
+        let writer = tiktoken_corpus_writer_open(
+            corebpe,
+            dir.c_path().as_ptr(),
+            CTiktokenTokenFormat::Uint32,
+            100,
+            num_threads,
+        );
+        assert!(!writer.is_null());
+        // Encode in small batches, so that shards are completed as we go.
+        unsafe { &mut *writer }.batch_bytes = 512;
+        writer
+    }
+
+    fn add(writer: *mut CTiktokenCorpusWriter, documents: &[String]) {
+        for document in documents {
+            let text = CString::new(document.as_str()).unwrap();
+            assert!(tiktoken_corpus_writer_add_document(writer, text.as_ptr()));
+        }
+    }
+
+    /// Shard files and index of a corpus directory, in order.
+    fn read_corpus(dir: &Path) -> (CorpusIndex, Vec<Vec<u8>>) {
+        let index = CorpusIndex::load(&dir.join(INDEX_FILE)).unwrap().unwrap();
+        let shards = index
+            .shards
+            .iter()
+            .map(|shard| std::fs::read(dir.join(&shard.file)).unwrap())
+            .collect();
+        (index, shards)
+    }
+
+    #[test]
+    fn test_corpus_writer_shards_documents() {
+        let corebpe = tiktoken_cl100k_base();
+        let documents = documents();
+        let dir = TempPath::new("corpus");
+        let writer = open(corebpe, &dir, 1);
+        assert_eq!(tiktoken_corpus_writer_committed_documents(writer), 0);
+        add(writer, &documents);
+        assert!(tiktoken_corpus_writer_finish(writer));
+        tiktoken_corpus_writer_destroy(writer);
+
+        let (index, _) = read_corpus(&dir);
+        assert!(index.complete);
+        assert_eq!(index.num_documents, documents.len() as u64);
+        let expected_tokens: usize = documents
+            .iter()
+            .map(|document| unsafe { &*corebpe }.encode_ordinary(document).len() + 1)
+            .sum();
+        assert_eq!(index.num_tokens, expected_tokens as u64);
+        assert!(index.shards.len() > 10);
+        let mut first_document = 0;
+        for shard in &index.shards {
+            assert!(shard.num_tokens <= 100);
+            assert_eq!(shard.first_document, first_document);
+            first_document += shard.num_documents;
+        }
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_corpus_writer_parallel_and_resumed_output_is_identical() {
+        let corebpe = tiktoken_cl100k_base();
+        let documents = documents();
+
+        let serial_dir = TempPath::new("corpus-serial");
+        let writer = open(corebpe, &serial_dir, 1);
+        add(writer, &documents);
+        assert!(tiktoken_corpus_writer_finish(writer));
+        tiktoken_corpus_writer_destroy(writer);
+
+        let parallel_dir = TempPath::new("corpus-parallel");
+        let writer = open(corebpe, &parallel_dir, 4);
+        add(writer, &documents);
+        assert!(tiktoken_corpus_writer_finish(writer));
+        tiktoken_corpus_writer_destroy(writer);
+        assert_eq!(read_corpus(&parallel_dir), read_corpus(&serial_dir));
+
+        // Interrupt a job part way through, then resume it.
+        let resumed_dir = TempPath::new("corpus-resumed");
+        let writer = open(corebpe, &resumed_dir, 1);
+        add(writer, &documents[..120]);
+        tiktoken_corpus_writer_destroy(writer);
+        let writer = open(corebpe, &resumed_dir, 4);
+        let committed = tiktoken_corpus_writer_committed_documents(writer) as usize;
+        assert!(committed > 0 && committed <= 120);
+        add(writer, &documents[committed..]);
+        assert!(tiktoken_corpus_writer_finish(writer));
+        tiktoken_corpus_writer_destroy(writer);
+        assert_eq!(read_corpus(&resumed_dir), read_corpus(&serial_dir));
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_corpus_writer_rejects_different_settings() {
+        let corebpe = tiktoken_cl100k_base();
+        let dir = TempPath::new("corpus-settings");
+        let writer = open(corebpe, &dir, 1);
+        add(writer, &documents()[..50]);
+        assert!(tiktoken_corpus_writer_finish(writer));
+        tiktoken_corpus_writer_destroy(writer);
+
+        let c_dir = dir.c_path();
+        let writer = tiktoken_corpus_writer_open(
+            corebpe,
+            c_dir.as_ptr(),
+            CTiktokenTokenFormat::Uint32,
+            200,
+            1,
+        );
+        assert!(writer.is_null());
+        let writer = tiktoken_corpus_writer_open(
+            corebpe,
+            c_dir.as_ptr(),
+            CTiktokenTokenFormat::Uint32,
+            0,
+            1,
+        );
+        assert!(writer.is_null());
+        assert!(tiktoken_corpus_writer_open(
+            std::ptr::null(),
+            c_dir.as_ptr(),
+            CTiktokenTokenFormat::Uint32,
+            100,
+            1
+        )
+        .is_null());
+        tiktoken_destroy_corebpe(corebpe);
+    }
+}
@@ -0,0 +1,347 @@
+use base64::{engine::general_purpose, Engine as _};
+#[cfg(feature = "logging")]
+use log::warn;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::ffi::{c_char, CString};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock, RwLock};
+use tiktoken_rs::tokenizer::Tokenizer;
+use tiktoken_rs::{Rank, ENDOFPROMPT, ENDOFTEXT, FIM_MIDDLE, FIM_PREFIX, FIM_SUFFIX};
+
+use crate::alloc::cstring_into_malloced;
+use crate::corebpe::builtin_pattern;
+use crate::parse_optional_string;
+
+/// Environment variable naming the data directory when none is set through
+/// tiktoken_set_data_dir().
+const DATA_DIR_ENV: &str = "TIKTOKEN_C_DATA_DIR";
+
+static DATA_DIR: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+fn data_dir() -> Option<PathBuf> {
+    if let Some(dir) = DATA_DIR.read().ok()?.as_ref() {
+        return Some(dir.to_path_buf());
+    }
+    std::env::var_os(DATA_DIR_ENV)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Name of the rank file a built-in encoding is read from. Encodings that
+/// only differ in special tokens share a file, as they do upstream.
+fn rank_file_name(tokenizer: Tokenizer) -> &'static str {
+    match tokenizer {
+        Tokenizer::O200kHarmony | Tokenizer::O200kBase => "o200k_base.tiktoken",
+        Tokenizer::Cl100kBase => "cl100k_base.tiktoken",
+        Tokenizer::P50kBase | Tokenizer::P50kEdit => "p50k_base.tiktoken",
+        Tokenizer::R50kBase | Tokenizer::Gpt2 => "r50k_base.tiktoken",
+    }
+}
+
+/// Special tokens of a built-in encoding, as tiktoken-rs defines them.
+fn builtin_special_tokens(tokenizer: Tokenizer) -> FxHashMap<String, Rank> {
+    let special_tokens: Vec<(String, Rank)> = match tokenizer {
+        Tokenizer::R50kBase | Tokenizer::P50kBase | Tokenizer::Gpt2 => {
+            vec![(ENDOFTEXT.to_string(), 50256)]
+        }
+        Tokenizer::P50kEdit => vec![
+            (ENDOFTEXT.to_string(), 50256),
+            (FIM_PREFIX.to_string(), 50281),
+            (FIM_MIDDLE.to_string(), 50282),
+            (FIM_SUFFIX.to_string(), 50283),
+        ],
+        Tokenizer::Cl100kBase => vec![
+            (ENDOFTEXT.to_string(), 100257),
+            (FIM_PREFIX.to_string(), 100258),
+            (FIM_MIDDLE.to_string(), 100259),
+            (FIM_SUFFIX.to_string(), 100260),
+            (ENDOFPROMPT.to_string(), 100276),
+        ],
+        Tokenizer::O200kBase => vec![
+            (ENDOFTEXT.to_string(), 199999),
+            (ENDOFPROMPT.to_string(), 200018),
+        ],
+        Tokenizer::O200kHarmony => {
+            let named = [
+                (199998, "<|startoftext|>"),
+                (199999, "<|endoftext|>"),
+                (200002, "<|return|>"),
+                (200003, "<|constrain|>"),
+                (200005, "<|channel|>"),
+                (200006, "<|start|>"),
+                (200007, "<|end|>"),
+                (200008, "<|message|>"),
+                (200012, "<|call|>"),
+            ];
+            let named: FxHashMap<Rank, &str> = named.into_iter().collect();
+            (199998..=201087)
+                .map(|rank| match named.get(&rank) {
+                    Some(token) => (token.to_string(), rank),
+                    None => (format!("<|reserved_{}|>", rank), rank),
+                })
+                .collect()
+        }
+    };
+    special_tokens.into_iter().collect()
+}
+
+/// Parses `.tiktoken` rank data: one base64 token and its rank per line.
+/// Ranks must be unique, and there must be at least one.
+fn parse_rank_data(contents: &str) -> Option<FxHashMap<Vec<u8>, Rank>> {
+    let mut ranks = FxHashMap::default();
+    let mut used = FxHashSet::default();
+    for line in contents.lines().filter(|line| !line.is_empty()) {
+        let (token, rank) = line.split_once(' ')?;
+        let token = general_purpose::STANDARD.decode(token).ok()?;
+        let rank: Rank = rank.parse().ok()?;
+        if !used.insert(rank) || ranks.insert(token, rank).is_some() {
+            return None;
+        }
+    }
+    (!ranks.is_empty()).then_some(ranks)
+}
+
+/// Builds a built-in encoding from its rank file in `dir`.
+fn load_encoding(dir: &Path, tokenizer: Tokenizer) -> Option<tiktoken_rs::CoreBPE> {
+    let path = dir.join(rank_file_name(tokenizer));
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(_) => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to read {}!", path.display());
+            return None;
+        }
+    };
+    let ranks = match parse_rank_data(&contents) {
+        Some(ranks) => ranks,
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("Malformed rank data in {}!", path.display());
+            return None;
+        }
+    };
+    let special_tokens = builtin_special_tokens(tokenizer);
+    tiktoken_rs::CoreBPE::new(ranks, special_tokens, builtin_pattern(tokenizer)).ok()
+}
+
+/// Shared instance of a built-in encoding loaded from the data directory on
+/// first use, `None` if no directory is set or it has no rank file for the
+/// encoding. Failures are not cached, so a later call can pick up a file
+/// that was missing.
+pub(crate) fn bpe_from_data_dir(tokenizer: Tokenizer) -> Option<&'static tiktoken_rs::CoreBPE> {
+    static LOADED: [OnceLock<tiktoken_rs::CoreBPE>; 6] = [const { OnceLock::new() }; 6];
+    static LOADING: Mutex<()> = Mutex::new(());
+    let index = match tokenizer {
+        Tokenizer::R50kBase | Tokenizer::Gpt2 => 0,
+        Tokenizer::P50kBase => 1,
+        Tokenizer::P50kEdit => 2,
+        Tokenizer::Cl100kBase => 3,
+        Tokenizer::O200kBase => 4,
+        Tokenizer::O200kHarmony => 5,
+    };
+    if let Some(bpe) = LOADED[index].get() {
+        return Some(bpe);
+    }
+    if !data_dir_has(tokenizer) {
+        return None;
+    }
+    // Rank files take a while to parse; load each one only once.
+    let _loading = LOADING.lock().ok()?;
+    if let Some(bpe) = LOADED[index].get() {
+        return Some(bpe);
+    }
+    let dir = data_dir()?;
+    let bpe = load_encoding(&dir, tokenizer)?;
+    Some(LOADED[index].get_or_init(|| bpe))
+}
+
+/// Whether the rank file of `tokenizer` exists in the data directory.
+pub(crate) fn data_dir_has(tokenizer: Tokenizer) -> bool {
+    data_dir().is_some_and(|dir| dir.join(rank_file_name(tokenizer)).is_file())
+}
+
+/// Sets the directory rank files are loaded from, or with NULL goes back to
+/// `TIKTOKEN_C_DATA_DIR`. Encodings already loaded are kept, and still take
+/// precedence over compiled-in ones.
+#[no_mangle]
+pub extern "C" fn tiktoken_set_data_dir(path: *const c_char) -> bool {
+    let path = match parse_optional_string(path, "path") {
+        Ok(path) => path.map(PathBuf::from),
+        Err(_) => return false,
+    };
+    if let Some(dir) = &path {
+        if !dir.is_dir() {
+            #[cfg(feature = "logging")]
+            warn!("{} is not a directory!", dir.display());
+            return false;
+        }
+    }
+    match DATA_DIR.write() {
+        Ok(mut data_dir) => {
+            *data_dir = path;
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_data_dir() -> *mut c_char {
+    let dir = match data_dir() {
+        Some(dir) => dir,
+        None => return std::ptr::null_mut(),
+    };
+    match dir.to_str().and_then(|dir| CString::new(dir).ok()) {
+        Some(dir) => cstring_into_malloced(dir),
+        None => std::ptr::null_mut(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::tiktoken_free;
+    use crate::corebpe::{bpe_for_tokenizer, CoreBPE};
+    use crate::export::tiktoken_file_contents;
+    use crate::testutil::TempPath;
+    use std::ffi::CStr;
+    use std::sync::{MutexGuard, PoisonError};
+
+    /// The data directory is global; tests that change it take turns.
+    fn lock_data_dir() -> MutexGuard<'static, ()> {
+        static LOCK: Mutex<()> = Mutex::new(());
+        LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[test]
+    fn test_builtin_special_tokens() {
+        for tokenizer in [
+            Tokenizer::R50kBase,
+            Tokenizer::P50kEdit,
+            Tokenizer::Cl100kBase,
+            Tokenizer::O200kBase,
+            Tokenizer::O200kHarmony,
+        ] {
+            let bpe = tiktoken_rs::bpe_for_tokenizer(tokenizer).unwrap();
+            let mut expected: Vec<String> =
+                bpe.special_tokens().into_iter().map(String::from).collect();
+            expected.sort();
+            let special_tokens = builtin_special_tokens(tokenizer);
+            let mut actual: Vec<String> = special_tokens.keys().cloned().collect();
+            actual.sort();
+            assert_eq!(actual, expected);
+            for (token, rank) in special_tokens {
+                assert_eq!(bpe.encode_with_special_tokens(&token), [rank]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_encoding() {
+        let dir = TempPath::dir("datadir-load-encoding");
+        assert!(load_encoding(&dir, Tokenizer::P50kEdit).is_none());
+
+        let bpe = tiktoken_rs::bpe_for_tokenizer(Tokenizer::P50kEdit).unwrap();
+        let corebpe = CoreBPE::from_tokenizer(bpe.clone(), Tokenizer::P50kEdit).unwrap();
+        std::fs::write(
+            dir.join("p50k_base.tiktoken"),
+            tiktoken_file_contents(&corebpe),
+        )
+        .unwrap();
+        let loaded = load_encoding(&dir, Tokenizer::P50kEdit).unwrap();
+        let text = "def fib(n):\n    return n <|fim_prefix|> <|endoftext|>";
+        assert_eq!(
+            loaded.encode_with_special_tokens(text),
+            bpe.encode_with_special_tokens(text)
+        );
+        assert_eq!(
+            load_encoding(&dir, Tokenizer::P50kBase)
+                .unwrap()
+                .encode_ordinary(text),
+            bpe.encode_ordinary(text)
+        );
+
+        std::fs::write(dir.join("r50k_base.tiktoken"), "IQ== 0\nIg== 0\n").unwrap();
+        assert!(load_encoding(&dir, Tokenizer::R50kBase).is_none());
+        std::fs::write(dir.join("r50k_base.tiktoken"), "IQ==\n").unwrap();
+        assert!(load_encoding(&dir, Tokenizer::R50kBase).is_none());
+        std::fs::write(dir.join("r50k_base.tiktoken"), "").unwrap();
+        assert!(load_encoding(&dir, Tokenizer::R50kBase).is_none());
+    }
+
+    #[test]
+    fn test_set_data_dir() {
+        let _lock = lock_data_dir();
+        let dir = TempPath::dir("datadir-set-data-dir");
+        assert!(tiktoken_set_data_dir(dir.c_path().as_ptr()));
+        let actual = tiktoken_data_dir();
+        assert_eq!(
+            unsafe { CStr::from_ptr(actual) }.to_str().unwrap(),
+            dir.to_str().unwrap()
+        );
+        tiktoken_free(actual as *mut std::ffi::c_void);
+        assert!(!data_dir_has(Tokenizer::Cl100kBase));
+        // Malformed, so lookups in other tests fall back to the compiled-in
+        // encoding while this file is in place.
+        std::fs::write(dir.join("cl100k_base.tiktoken"), "IQ==\n").unwrap();
+        assert!(data_dir_has(Tokenizer::Cl100kBase));
+
+        let missing = CString::new(dir.join("missing").to_str().unwrap()).unwrap();
+        assert!(!tiktoken_set_data_dir(missing.as_ptr()));
+        assert!(data_dir_has(Tokenizer::Cl100kBase));
+        assert!(tiktoken_set_data_dir(std::ptr::null()));
+    }
+
+    #[test]
+    fn test_bpe_from_data_dir() {
+        let _lock = lock_data_dir();
+        let dir = TempPath::dir("datadir-bpe-from-data-dir");
+        let path = dir.join("r50k_base.tiktoken");
+        let compiled = tiktoken_rs::r50k_base_singleton();
+        assert!(tiktoken_set_data_dir(dir.c_path().as_ptr()));
+
+        // Without a usable rank file the compiled-in encoding is used, and
+        // failures are not cached.
+        assert!(bpe_from_data_dir(Tokenizer::R50kBase).is_none());
+        std::fs::write(&path, "IQ==\n").unwrap();
+        assert!(bpe_from_data_dir(Tokenizer::R50kBase).is_none());
+        let bpe = bpe_for_tokenizer(Tokenizer::R50kBase).unwrap();
+        assert!(std::ptr::eq(bpe, compiled));
+
+        // Written from the compiled-in tables, so other tests that pick it
+        // up see the same encoding.
+        let corebpe = CoreBPE::from_tokenizer(compiled.clone(), Tokenizer::R50kBase).unwrap();
+        std::fs::write(&path, tiktoken_file_contents(&corebpe)).unwrap();
+        let loaded = bpe_from_data_dir(Tokenizer::R50kBase).unwrap();
+        assert!(!std::ptr::eq(loaded, compiled));
+        let text = "Hello, world! <|endoftext|>";
+        assert_eq!(
+            loaded.encode_with_special_tokens(text),
+            compiled.encode_with_special_tokens(text)
+        );
+        // Loaded once, shared with gpt2 and preferred over the compiled-in
+        // encoding, even after the directory is unset.
+        assert!(std::ptr::eq(
+            bpe_for_tokenizer(Tokenizer::Gpt2).unwrap(),
+            loaded
+        ));
+        assert!(tiktoken_set_data_dir(std::ptr::null()));
+        std::fs::remove_file(&path).unwrap();
+        assert!(std::ptr::eq(
+            bpe_for_tokenizer(Tokenizer::R50kBase).unwrap(),
+            loaded
+        ));
+
+        // The environment variable applies when no directory is set, unless
+        // it is empty.
+        std::env::set_var(DATA_DIR_ENV, &*dir);
+        assert_eq!(data_dir(), Some(dir.to_path_buf()));
+        let other = TempPath::dir("datadir-bpe-from-data-dir-api");
+        assert!(tiktoken_set_data_dir(other.c_path().as_ptr()));
+        assert_eq!(data_dir(), Some(other.to_path_buf()));
+        assert!(tiktoken_set_data_dir(std::ptr::null()));
+        std::env::set_var(DATA_DIR_ENV, "");
+        assert_eq!(data_dir(), None);
+        std::env::remove_var(DATA_DIR_ENV);
+    }
+}
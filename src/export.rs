@@ -0,0 +1,401 @@
+use base64::{engine::general_purpose, Engine as _};
+#[cfg(feature = "logging")]
+use log::warn;
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::ffi::c_char;
+use tiktoken_rs::Rank;
+
+use crate::corebpe::CoreBPE;
+use crate::parse_required_string;
+
+/// Ordinary tokens sorted by rank.
+fn tokens_by_rank(corebpe: &CoreBPE) -> Vec<(&[u8], Rank)> {
+    let mut tokens: Vec<(&[u8], Rank)> = corebpe
+        .mergeable_ranks()
+        .iter()
+        .map(|(token, &rank)| (token.as_slice(), rank))
+        .collect();
+    tokens.sort_unstable_by_key(|&(_, rank)| rank);
+    tokens
+}
+
+/// The `.tiktoken` format: one line per ordinary token with its
+/// base64-encoded bytes and its rank.
+pub(crate) fn tiktoken_file_contents(corebpe: &CoreBPE) -> String {
+    let mut contents = String::new();
+    for (token, rank) in tokens_by_rank(corebpe) {
+        contents.push_str(&general_purpose::STANDARD.encode(token));
+        contents.push(' ');
+        contents.push_str(&rank.to_string());
+        contents.push('\n');
+    }
+    contents
+}
+
+/// GPT-2's reversible mapping of bytes to printable characters, which
+/// byte-level BPE vocabularies use to store token bytes as strings.
+fn byte_to_char_table() -> [char; 256] {
+    let mut table = ['\0'; 256];
+    let mut next = 256;
+    for byte in 0..=u8::MAX {
+        table[byte as usize] = if matches!(byte, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF) {
+            char::from(byte)
+        } else {
+            next += 1;
+            char::from_u32(next - 1).unwrap_or_default()
+        };
+    }
+    table
+}
+
+/// Splits `token` the way byte pair merging would using only tokens ranked
+/// below `max_rank`. A token built by one merge splits into the two tokens
+/// it was merged from.
+fn merge_parts<'a>(
+    ranks: &FxHashMap<Vec<u8>, Rank>,
+    token: &'a [u8],
+    max_rank: Rank,
+) -> Vec<&'a [u8]> {
+    let mut bounds: Vec<usize> = (0..=token.len()).collect();
+    loop {
+        let best = (0..bounds.len().saturating_sub(2))
+            .filter_map(|i| Some((*ranks.get(&token[bounds[i]..bounds[i + 2]])?, i)))
+            .filter(|&(rank, _)| rank < max_rank)
+            .min();
+        match best {
+            Some((_, i)) => {
+                bounds.remove(i + 1);
+            }
+            None => break,
+        }
+    }
+    bounds.windows(2).map(|w| &token[w[0]..w[1]]).collect()
+}
+
+/// A Hugging Face `tokenizer.json` for a byte-level BPE model equivalent
+/// to `corebpe`.
+///
+/// Merges are recovered from the ranks: every multi-byte token that byte
+/// pair merging builds from two lower-ranked tokens becomes one merge, in
+/// rank order. `ignore_merges` makes whole pieces found in the vocabulary
+/// map to their token directly, as tiktoken does.
+fn tokenizer_json(corebpe: &CoreBPE) -> Value {
+    let chars = byte_to_char_table();
+    let to_string = |bytes: &[u8]| -> String { bytes.iter().map(|&b| chars[b as usize]).collect() };
+    let ranks = corebpe.mergeable_ranks();
+    let mut vocab = Map::new();
+    let mut merges = Vec::new();
+    for (token, rank) in tokens_by_rank(corebpe) {
+        vocab.insert(to_string(token), json!(rank));
+        if token.len() < 2 {
+            continue;
+        }
+        if let [left, right] = merge_parts(ranks, token, rank)[..] {
+            merges.push(json!(format!("{} {}", to_string(left), to_string(right))));
+        }
+    }
+    let added_tokens: Vec<Value> = corebpe
+        .special_token_ranks()
+        .map(|(token, rank)| {
+            json!({
+                "id": rank,
+                "content": token,
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": true,
+            })
+        })
+        .collect();
+    json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": added_tokens,
+        "normalizer": null,
+        "pre_tokenizer": {
+            "type": "Sequence",
+            "pretokenizers": [
+                {
+                    "type": "Split",
+                    "pattern": { "Regex": corebpe.pattern() },
+                    "behavior": "Isolated",
+                    "invert": false,
+                },
+                {
+                    "type": "ByteLevel",
+                    "add_prefix_space": false,
+                    "trim_offsets": true,
+                    "use_regex": false,
+                },
+            ],
+        },
+        "post_processor": null,
+        "decoder": {
+            "type": "ByteLevel",
+            "add_prefix_space": true,
+            "trim_offsets": true,
+            "use_regex": true,
+        },
+        "model": {
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": null,
+            "continuing_subword_prefix": null,
+            "end_of_word_suffix": null,
+            "fuse_unk": false,
+            "byte_fallback": false,
+            "ignore_merges": true,
+            "vocab": vocab,
+            "merges": merges,
+        },
+    })
+}
+
+/// Contents of the JSON description, in the layout JavaScript tiktoken
+/// ports load encodings from.
+#[derive(Serialize)]
+struct EncodingDescription<'a> {
+    name: &'a str,
+    pat_str: &'a str,
+    special_tokens: BTreeMap<&'a str, Rank>,
+    n_vocab: usize,
+    /// Lines of `! <first rank> <token> <token> ...` listing base64-encoded
+    /// ordinary tokens with consecutive ranks.
+    bpe_ranks: String,
+}
+
+fn description_json(corebpe: &CoreBPE) -> EncodingDescription<'_> {
+    let mut bpe_ranks = String::new();
+    let mut next_rank = None;
+    for (token, rank) in tokens_by_rank(corebpe) {
+        if next_rank != Some(rank) {
+            if next_rank.is_some() {
+                bpe_ranks.push('\n');
+            }
+            bpe_ranks.push_str(&format!("! {}", rank));
+        }
+        bpe_ranks.push(' ');
+        bpe_ranks.push_str(&general_purpose::STANDARD.encode(token));
+        next_rank = Some(rank + 1);
+    }
+    EncodingDescription {
+        name: corebpe.name(),
+        pat_str: corebpe.pattern(),
+        special_tokens: corebpe.special_token_ranks().collect(),
+        n_vocab: corebpe.vocab_size(),
+        bpe_ranks,
+    }
+}
+
+fn save(
+    ptr: *const CoreBPE,
+    path: *const c_char,
+    contents: impl FnOnce(&CoreBPE) -> Option<String>,
+) -> bool {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return false;
+    }
+    let path = match parse_required_string(path, "path") {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let contents = match contents(unsafe { &*ptr }) {
+        Some(contents) => contents,
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to serialize the encoding!");
+            return false;
+        }
+    };
+    if std::fs::write(&path, contents).is_err() {
+        #[cfg(feature = "logging")]
+        warn!("Failed to write {}!", path);
+        return false;
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_save_tiktoken(ptr: *const CoreBPE, path: *const c_char) -> bool {
+    save(ptr, path, |corebpe| Some(tiktoken_file_contents(corebpe)))
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_save_tokenizer_json(
+    ptr: *const CoreBPE,
+    path: *const c_char,
+) -> bool {
+    save(ptr, path, |corebpe| {
+        serde_json::to_string(&tokenizer_json(corebpe)).ok()
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_save_json(ptr: *const CoreBPE, path: *const c_char) -> bool {
+    save(ptr, path, |corebpe| {
+        serde_json::to_string(&description_json(corebpe)).ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corebpe::{tiktoken_cl100k_base, tiktoken_destroy_corebpe, tiktoken_r50k_base};
+    use crate::testutil::TempPath;
+    use std::ffi::CString;
+
+    #[test]
+    fn test_save_tiktoken() {
+        let corebpe = tiktoken_cl100k_base();
+        let path = TempPath::new("export-cl100k_base.tiktoken");
+        assert!(tiktoken_corebpe_save_tiktoken(
+            corebpe,
+            path.c_path().as_ptr()
+        ));
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with("IQ== 0\nIg== 1\n"));
+        let mut last_rank = None;
+        let mut ranks = FxHashMap::default();
+        for line in contents.lines() {
+            let (token, rank) = line.split_once(' ').unwrap();
+            let rank: Rank = rank.parse().unwrap();
+            assert!(last_rank < Some(rank));
+            last_rank = Some(rank);
+            ranks.insert(general_purpose::STANDARD.decode(token).unwrap(), rank);
+        }
+        assert_eq!(&ranks, unsafe { &*corebpe }.mergeable_ranks());
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_byte_to_char_table() {
+        let table = byte_to_char_table();
+        assert_eq!(table[b'a' as usize], 'a');
+        assert_eq!(table[b' ' as usize], 'Ġ');
+        assert_eq!(table[b'\n' as usize], 'Ċ');
+        assert_eq!(table[0xAD], 'Ń');
+        let mut sorted = table.to_vec();
+        sorted.sort_unstable();
+        sorted.dedup();
+        assert_eq!(sorted.len(), 256);
+    }
+
+    /// Applies the merges of a tokenizer.json model to one piece, the way
+    /// Hugging Face byte-level BPE does with `ignore_merges`.
+    fn apply_merges(
+        vocab: &Map<String, Value>,
+        merges: &FxHashMap<(String, String), usize>,
+        piece: &[u8],
+    ) -> Vec<Rank> {
+        let chars = byte_to_char_table();
+        let id = |token: &str| vocab[token].as_u64().unwrap() as Rank;
+        let word: String = piece.iter().map(|&b| chars[b as usize]).collect();
+        if vocab.contains_key(&word) {
+            return vec![id(&word)];
+        }
+        let mut parts: Vec<String> = word.chars().map(String::from).collect();
+        loop {
+            let best = (0..parts.len().saturating_sub(1))
+                .filter_map(|i| Some((merges.get(&(parts[i].clone(), parts[i + 1].clone()))?, i)))
+                .min();
+            let Some((_, i)) = best else { break };
+            let right = parts.remove(i + 1);
+            parts[i].push_str(&right);
+        }
+        parts.iter().map(|part| id(part)).collect()
+    }
+
+    #[test]
+    fn test_save_tokenizer_json() {
+        let corebpe = tiktoken_r50k_base();
+        let path = TempPath::new("export-tokenizer.json");
+        assert!(tiktoken_corebpe_save_tokenizer_json(
+            corebpe,
+            path.c_path().as_ptr()
+        ));
+        let tokenizer: Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let corebpe_ref = unsafe { &*corebpe };
+        let model = &tokenizer["model"];
+        assert_eq!(model["vocab"].as_object().unwrap().len(), 50256);
+        assert_eq!(model["merges"].as_array().unwrap().len(), 50000);
+        assert_eq!(model["merges"][0], "Ġ t");
+        assert_eq!(
+            tokenizer["pre_tokenizer"]["pretokenizers"][0]["pattern"]["Regex"],
+            corebpe_ref.pattern()
+        );
+        assert_eq!(tokenizer["added_tokens"][0]["content"], "<|endoftext|>");
+        assert_eq!(tokenizer["added_tokens"][0]["id"], 50256);
+
+        let vocab = model["vocab"].as_object().unwrap();
+        let merges: FxHashMap<(String, String), usize> = model["merges"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .map(|(index, merge)| {
+                let (left, right) = merge.as_str().unwrap().split_once(' ').unwrap();
+                ((left.to_string(), right.to_string()), index)
+            })
+            .collect();
+        let text = "Hello, world! Tokenizers don't disagree: 12345 naïve 猫\n\n  x";
+        let mut tokens = Vec::new();
+        for (_, piece) in corebpe_ref.split_ordinary(text) {
+            tokens.extend(apply_merges(vocab, &merges, piece.as_bytes()));
+        }
+        assert_eq!(tokens, corebpe_ref.encode_ordinary(text));
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_save_json() {
+        let corebpe = tiktoken_cl100k_base();
+        let path = TempPath::new("export-cl100k_base.json");
+        assert!(tiktoken_corebpe_save_json(corebpe, path.c_path().as_ptr()));
+        let description: Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let corebpe_ref = unsafe { &*corebpe };
+        assert_eq!(description["name"], "cl100k_base");
+        assert_eq!(description["pat_str"], corebpe_ref.pattern());
+        assert_eq!(description["n_vocab"], 100277);
+        assert_eq!(description["special_tokens"]["<|endoftext|>"], 100257);
+        assert_eq!(description["special_tokens"].as_object().unwrap().len(), 5);
+
+        let bpe_ranks = description["bpe_ranks"].as_str().unwrap();
+        assert!(bpe_ranks.starts_with("! 0 IQ== Ig== "));
+        let mut ranks = FxHashMap::default();
+        for line in bpe_ranks.lines() {
+            let mut fields = line.split(' ').skip(1);
+            let first_rank: Rank = fields.next().unwrap().parse().unwrap();
+            for (rank, token) in (first_rank..).zip(fields) {
+                ranks.insert(general_purpose::STANDARD.decode(token).unwrap(), rank);
+            }
+        }
+        assert_eq!(&ranks, corebpe_ref.mergeable_ranks());
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_save_invalid_arguments() {
+        let path = TempPath::new("export-unused");
+        assert!(!tiktoken_corebpe_save_tiktoken(
+            std::ptr::null(),
+            path.c_path().as_ptr()
+        ));
+        let corebpe = tiktoken_r50k_base();
+        assert!(!tiktoken_corebpe_save_json(corebpe, std::ptr::null()));
+        let missing_dir = CString::new("/nonexistent-dir/tokenizer.json").unwrap();
+        assert!(!tiktoken_corebpe_save_tokenizer_json(
+            corebpe,
+            missing_dir.as_ptr()
+        ));
+        tiktoken_destroy_corebpe(corebpe);
+    }
+}
@@ -0,0 +1,374 @@
+#[cfg(feature = "logging")]
+use log::warn;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::ffi::c_char;
+use tiktoken_rs::Rank;
+
+use crate::corebpe::CoreBPE;
+use crate::parse_required_string;
+use crate::shard::NO_TOKEN;
+
+/// A token to add and its rank, or `None` to assign the next free rank.
+type NewToken = (String, Option<Rank>);
+
+/// Token tables of an encoding being extended, with every rank in use.
+struct Tables {
+    mergeable_ranks: FxHashMap<Vec<u8>, Rank>,
+    special_tokens: FxHashMap<String, Rank>,
+    used: FxHashSet<Rank>,
+}
+
+impl Tables {
+    fn new(corebpe: &CoreBPE) -> Self {
+        let mergeable_ranks = corebpe.mergeable_ranks().clone();
+        let special_tokens: FxHashMap<String, Rank> = corebpe
+            .special_token_ranks()
+            .map(|(token, rank)| (token.to_string(), rank))
+            .collect();
+        let used = mergeable_ranks
+            .values()
+            .chain(special_tokens.values())
+            .copied()
+            .collect();
+        Self {
+            mergeable_ranks,
+            special_tokens,
+            used,
+        }
+    }
+
+    fn reserve(&mut self, rank: Rank) -> Option<()> {
+        (rank != NO_TOKEN && self.used.insert(rank)).then_some(())
+    }
+
+    fn add_ordinary(&mut self, token: &str, rank: Rank) -> Option<()> {
+        let token = token.as_bytes().to_vec();
+        if token.is_empty() || self.mergeable_ranks.contains_key(&token) {
+            return None;
+        }
+        self.mergeable_ranks.insert(token, rank);
+        Some(())
+    }
+
+    fn add_special(&mut self, token: &str, rank: Rank) -> Option<()> {
+        if token.is_empty() || self.special_tokens.contains_key(token) {
+            return None;
+        }
+        self.special_tokens.insert(token.to_string(), rank);
+        Some(())
+    }
+}
+
+/// Adds `tokens` and `special_tokens` to the tables of `corebpe`. Explicit
+/// ranks are reserved first; the others are then numbered upwards from one
+/// past the highest rank in use, ordinary tokens before special ones.
+/// Returns `None` if a token or rank is already taken.
+fn extend_tables(
+    corebpe: &CoreBPE,
+    tokens: &[NewToken],
+    special_tokens: &[NewToken],
+) -> Option<Tables> {
+    let mut tables = Tables::new(corebpe);
+    for &(_, rank) in tokens.iter().chain(special_tokens) {
+        if let Some(rank) = rank {
+            tables.reserve(rank)?;
+        }
+    }
+    let mut next_rank = tables.used.iter().max().map_or(0, |&rank| rank + 1);
+    let mut assign = |tables: &mut Tables, rank: Option<Rank>| -> Option<Rank> {
+        if rank.is_some() {
+            return rank;
+        }
+        while tables.used.contains(&next_rank) {
+            next_rank = next_rank.checked_add(1)?;
+        }
+        tables.reserve(next_rank)?;
+        Some(next_rank)
+    };
+    for (token, rank) in tokens {
+        let rank = assign(&mut tables, *rank)?;
+        tables.add_ordinary(token, rank)?;
+    }
+    for (token, rank) in special_tokens {
+        let rank = assign(&mut tables, *rank)?;
+        tables.add_special(token, rank)?;
+    }
+    Some(tables)
+}
+
+/// Whether the split pattern of `corebpe` yields `token` as one piece. Whole
+/// pieces are looked up before merging, so an ordinary token spanning pieces
+/// is never produced.
+fn is_single_piece(corebpe: &CoreBPE, token: &str) -> bool {
+    let mut pieces = corebpe.split_ordinary(token);
+    matches!(
+        (pieces.next(), pieces.next()),
+        (Some((0, piece)), None) if piece.len() == token.len()
+    )
+}
+
+/// Reads `len` tokens and their optional ranks, where a NULL `ranks` or a
+/// rank of `NO_TOKEN` means the rank is assigned automatically.
+fn parse_new_tokens(
+    tokens: *const *const c_char,
+    ranks: *const Rank,
+    len: usize,
+    _field_name: &str,
+) -> Result<Vec<NewToken>, ()> {
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    if tokens.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for {}!", _field_name);
+        return Err(());
+    }
+    let tokens = unsafe { std::slice::from_raw_parts(tokens, len) };
+    let ranks = if ranks.is_null() {
+        None
+    } else {
+        Some(unsafe { std::slice::from_raw_parts(ranks, len) })
+    };
+    let mut parsed = Vec::with_capacity(len);
+    for (index, &token) in tokens.iter().enumerate() {
+        let token = parse_required_string(token, _field_name)?;
+        let rank = ranks
+            .map(|ranks| ranks[index])
+            .filter(|&rank| rank != NO_TOKEN);
+        parsed.push((token, rank));
+    }
+    Ok(parsed)
+}
+
+/// Returns a copy of `ptr` named `name` with extra ordinary and special
+/// tokens, or NULL if a token or rank collides with an existing one or an
+/// ordinary token is not a single piece of the split pattern.
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_extend(
+    ptr: *const CoreBPE,
+    name: *const c_char,
+    tokens: *const *const c_char,
+    token_ranks: *const Rank,
+    num_tokens: usize,
+    special_tokens: *const *const c_char,
+    special_token_ranks: *const Rank,
+    num_special_tokens: usize,
+) -> *mut CoreBPE {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return std::ptr::null_mut();
+    }
+    let name = match parse_required_string(name, "name") {
+        Ok(name) => name,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    let tokens = match parse_new_tokens(tokens, token_ranks, num_tokens, "tokens") {
+        Ok(tokens) => tokens,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    let special_tokens = match parse_new_tokens(
+        special_tokens,
+        special_token_ranks,
+        num_special_tokens,
+        "special_tokens",
+    ) {
+        Ok(special_tokens) => special_tokens,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    let corebpe = unsafe { &*ptr };
+    if let Some((_token, _)) = tokens
+        .iter()
+        .find(|(token, _)| !is_single_piece(corebpe, token))
+    {
+        #[cfg(feature = "logging")]
+        warn!("Ordinary token {:?} spans several pieces!", _token);
+        return std::ptr::null_mut();
+    }
+    let tables = match extend_tables(corebpe, &tokens, &special_tokens) {
+        Some(tables) => tables,
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("Token or rank already in use!");
+            return std::ptr::null_mut();
+        }
+    };
+    match CoreBPE::from_ranks(
+        tables.mergeable_ranks,
+        tables.special_tokens,
+        &name,
+        corebpe.pattern(),
+    ) {
+        Some(extended) => Box::into_raw(Box::new(extended)),
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to build the encoding!");
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corebpe::{tiktoken_cl100k_base, tiktoken_destroy_corebpe, tiktoken_o200k_base};
+    use std::ffi::CString;
+
+    fn c_strings(strings: &[&str]) -> Vec<CString> {
+        strings.iter().map(|s| CString::new(*s).unwrap()).collect()
+    }
+
+    fn extend(
+        corebpe: *const CoreBPE,
+        tokens: &[&str],
+        token_ranks: Option<&[Rank]>,
+        special_tokens: &[&str],
+        special_token_ranks: Option<&[Rank]>,
+    ) -> *mut CoreBPE {
+        let tokens = c_strings(tokens);
+        let special_tokens = c_strings(special_tokens);
+        let token_ptrs: Vec<*const c_char> = tokens.iter().map(|s| s.as_ptr()).collect();
+        let special_ptrs: Vec<*const c_char> = special_tokens.iter().map(|s| s.as_ptr()).collect();
+        let name = CString::new("extended").unwrap();
+        tiktoken_corebpe_extend(
+            corebpe,
+            name.as_ptr(),
+            token_ptrs.as_ptr(),
+            token_ranks.map_or(std::ptr::null(), |ranks| ranks.as_ptr()),
+            token_ptrs.len(),
+            special_ptrs.as_ptr(),
+            special_token_ranks.map_or(std::ptr::null(), |ranks| ranks.as_ptr()),
+            special_ptrs.len(),
+        )
+    }
+
+    #[test]
+    fn test_extend_auto_ranks() {
+        let base = tiktoken_cl100k_base();
+        let extended = extend(
+            base,
+            &[" Qzxplorp", " Tiktokenizer"],
+            None,
+            &["<sku>", "</sku>"],
+            None,
+        );
+        let base_ref = unsafe { &*base };
+        let extended_ref = unsafe { &*extended };
+        assert_eq!(extended_ref.name(), "extended");
+        assert_eq!(extended_ref.vocab_size(), base_ref.vocab_size() + 4);
+        assert_eq!(extended_ref.encode_ordinary(" Qzxplorp"), [100277]);
+        assert_eq!(extended_ref.encode_ordinary(" Tiktokenizer"), [100278]);
+        assert_eq!(extended_ref.special_token_rank("<sku>"), Some(100279));
+        assert_eq!(extended_ref.special_token_rank("</sku>"), Some(100280));
+        assert_eq!(
+            extended_ref.special_token_rank("<|endoftext|>"),
+            Some(100257)
+        );
+
+        let text = "<sku> Qzxplorp</sku> Tiktokenizer is 猫 <|endoftext|>";
+        let tokens = extended_ref.encode_with_special_tokens(text);
+        assert_eq!(tokens[0], 100279);
+        assert_eq!(tokens[1], 100277);
+        assert_eq!(tokens[2], 100280);
+        assert_eq!(extended_ref.decode(&tokens).unwrap(), text);
+        // Text without the new tokens encodes as before.
+        let plain = "Hello, world! 1234 こんにちは";
+        assert_eq!(
+            extended_ref.encode_ordinary(plain),
+            base_ref.encode_ordinary(plain)
+        );
+        tiktoken_destroy_corebpe(extended);
+        tiktoken_destroy_corebpe(base);
+    }
+
+    #[test]
+    fn test_extend_explicit_ranks() {
+        let base = tiktoken_o200k_base();
+        // Explicit ranks may leave gaps; the rest fill in after the highest.
+        let extended = extend(
+            base,
+            &[" Frobnicatorix", "Zqxj"],
+            Some(&[300_000, NO_TOKEN]),
+            &["<|tool|>"],
+            Some(&[250_000]),
+        );
+        assert!(!extended.is_null());
+        let extended_ref = unsafe { &*extended };
+        assert_eq!(extended_ref.encode_ordinary(" Frobnicatorix"), [300_000]);
+        assert_eq!(extended_ref.encode_ordinary("Zqxj"), [300_001]);
+        assert_eq!(
+            extended_ref.mergeable_ranks()[&b" Frobnicatorix"[..]],
+            300_000
+        );
+        assert_eq!(extended_ref.special_token_rank("<|tool|>"), Some(250_000));
+        assert!(extended_ref.is_special_token(250_000));
+        assert_eq!(extended_ref.vocab_size(), 300_002);
+        assert_eq!(
+            extended_ref.decode_bytes(&[250_000, 300_000]).unwrap(),
+            b"<|tool|> Frobnicatorix"
+        );
+
+        // An extended encoding can be extended again.
+        let twice = extend(extended, &[], None, &["<|done|>"], None);
+        assert_eq!(
+            unsafe { &*twice }.special_token_rank("<|done|>"),
+            Some(300_002)
+        );
+        tiktoken_destroy_corebpe(twice);
+        tiktoken_destroy_corebpe(extended);
+        tiktoken_destroy_corebpe(base);
+    }
+
+    #[test]
+    fn test_extend_rejects_collisions() {
+        let base = tiktoken_cl100k_base();
+        // Existing ordinary and special tokens.
+        assert!(extend(base, &[" hello"], None, &[], None).is_null());
+        assert!(extend(base, &[], None, &["<|endoftext|>"], None).is_null());
+        // Duplicates among the new tokens.
+        assert!(extend(base, &["<a>", "<a>"], None, &[], None).is_null());
+        assert!(extend(base, &[], None, &["<s>", "<s>"], None).is_null());
+        // Ranks in use, by the base or by another new token.
+        assert!(extend(base, &["<a>"], Some(&[0]), &[], None).is_null());
+        assert!(extend(base, &["<a>"], Some(&[100257]), &[], None).is_null());
+        assert!(extend(base, &["<a>"], Some(&[200_000]), &["<s>"], Some(&[200_000])).is_null());
+        // Empty tokens.
+        assert!(extend(base, &[""], None, &[], None).is_null());
+        assert!(extend(base, &[], None, &[""], None).is_null());
+        // The same string may be both an ordinary and a special token.
+        let extended = extend(base, &["Qzxj"], None, &["Qzxj"], None);
+        assert!(!extended.is_null());
+        let extended_ref = unsafe { &*extended };
+        assert_eq!(extended_ref.encode_ordinary("Qzxj"), [100277]);
+        assert_eq!(extended_ref.encode_with_special_tokens("Qzxj"), [100278]);
+        tiktoken_destroy_corebpe(extended);
+        // Ordinary tokens the split pattern breaks up would never be produced.
+        assert!(extend(base, &["<s>"], None, &[], None).is_null());
+        assert!(extend(base, &["Qzxj", "a b"], None, &[], None).is_null());
+        assert!(extend(base, &["1234"], None, &[], None).is_null());
+
+        assert!(tiktoken_corebpe_extend(
+            std::ptr::null(),
+            c"name".as_ptr(),
+            std::ptr::null(),
+            std::ptr::null(),
+            0,
+            std::ptr::null(),
+            std::ptr::null(),
+            0,
+        )
+        .is_null());
+        assert!(tiktoken_corebpe_extend(
+            base,
+            c"name".as_ptr(),
+            std::ptr::null(),
+            std::ptr::null(),
+            1,
+            std::ptr::null(),
+            std::ptr::null(),
+            0,
+        )
+        .is_null());
+        tiktoken_destroy_corebpe(base);
+    }
+}
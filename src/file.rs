@@ -0,0 +1,458 @@
+#[cfg(feature = "logging")]
+use log::warn;
+use std::ffi::c_char;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use tiktoken_rs::Rank;
+
+use crate::bytes::{encode_bytes_with_policy, CTiktokenInvalidUtf8Policy};
+use crate::corebpe::CoreBPE;
+use crate::parse_required_string;
+
+/// Width of token ids written to a packed token file.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CTiktokenTokenFormat {
+    /// Little-endian `uint16_t`; fails for ranks above 65535.
+    Uint16 = 0,
+    /// Little-endian `uint32_t`.
+    Uint32 = 1,
+}
+
+impl CTiktokenTokenFormat {
+    pub(crate) fn token_bytes(self) -> usize {
+        match self {
+            Self::Uint16 => 2,
+            Self::Uint32 => 4,
+        }
+    }
+
+    pub(crate) fn max_vocab_size(self) -> u64 {
+        match self {
+            Self::Uint16 => 1 << 16,
+            Self::Uint32 => 1 << 32,
+        }
+    }
+
+    pub(crate) fn write_tokens(self, writer: &mut impl Write, tokens: &[Rank]) -> Option<()> {
+        let mut buffer = Vec::with_capacity(tokens.len() * 4);
+        for &token in tokens {
+            match self {
+                Self::Uint16 => buffer.extend_from_slice(&u16::try_from(token).ok()?.to_le_bytes()),
+                Self::Uint32 => buffer.extend_from_slice(&token.to_le_bytes()),
+            }
+        }
+        writer.write_all(&buffer).ok()
+    }
+}
+
+/// Approximate size of the pieces a file is split into for encoding.
+const SEGMENT_BYTES: usize = 4 << 20;
+
+/// Returns whether encoding `bytes[..at]` and `bytes[at..]` separately gives
+/// the same tokens as encoding `bytes` whole.
+///
+/// This holds after a newline that follows a non-space character and comes
+/// before an ASCII letter: no pre-tokenizer piece of the supported patterns
+/// spans that position, and the piece ending there does not depend on what
+/// follows.
+fn is_safe_split(bytes: &[u8], at: usize) -> bool {
+    if at < 2 || at >= bytes.len() || bytes[at - 1] != b'\n' || !bytes[at].is_ascii_alphabetic() {
+        return false;
+    }
+    let before = &bytes[at.saturating_sub(5)..at - 1];
+    match before.utf8_chunks().last() {
+        Some(chunk) if chunk.invalid().is_empty() => chunk
+            .valid()
+            .chars()
+            .next_back()
+            .is_some_and(|c| !c.is_whitespace()),
+        _ => false,
+    }
+}
+
+/// Splits `bytes` into segments of about `segment_bytes` at safe positions.
+fn split_segments(bytes: &[u8], segment_bytes: usize) -> Vec<&[u8]> {
+    let mut segments = Vec::new();
+    let mut rest = bytes;
+    while rest.len() > segment_bytes {
+        let at = rest[segment_bytes..]
+            .iter()
+            .enumerate()
+            .filter(|&(_, &byte)| byte == b'\n')
+            .map(|(i, _)| segment_bytes + i + 1)
+            .find(|&at| is_safe_split(rest, at));
+        let Some(at) = at else {
+            break;
+        };
+        let (segment, tail) = rest.split_at(at);
+        segments.push(segment);
+        rest = tail;
+    }
+    segments.push(rest);
+    segments
+}
+
+/// Encodes `bytes` segment by segment, passing the tokens of each segment to
+/// `sink` in order. Up to `num_threads` segments are encoded at a time, 0
+/// meaning one per available core.
+fn encode_segments(
+    corebpe: &CoreBPE,
+    bytes: &[u8],
+    policy: CTiktokenInvalidUtf8Policy,
+    num_threads: usize,
+    segment_bytes: usize,
+    mut sink: impl FnMut(Vec<Rank>) -> Option<()>,
+) -> Option<()> {
+    let num_threads = match num_threads {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    let encode = |segment: &[u8]| {
+        encode_bytes_with_policy(corebpe, segment, policy, |corebpe, text| {
+            corebpe.encode_ordinary(text)
+        })
+    };
+    let segments = split_segments(bytes, segment_bytes);
+    for batch in segments.chunks(num_threads) {
+        if batch.len() == 1 {
+            sink(encode(batch[0])?)?;
+            continue;
+        }
+        let encoded: Vec<Option<Vec<Rank>>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = batch
+                .iter()
+                .map(|&segment| scope.spawn(move || encode(segment)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().ok().flatten())
+                .collect()
+        });
+        for tokens in encoded {
+            sink(tokens?)?;
+        }
+    }
+    Some(())
+}
+
+/// Memory-maps the file at `path`. Returns `Some(None)` for an empty file,
+/// which cannot be mapped on every platform.
+pub(crate) fn map_file(path: *const c_char) -> Option<Option<memmap2::Mmap>> {
+    let path = parse_required_string(path, "path").ok()?;
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(_) => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to open {}!", path);
+            return None;
+        }
+    };
+    if file.metadata().ok()?.len() == 0 {
+        return Some(None);
+    }
+    match unsafe { memmap2::Mmap::map(&file) } {
+        Ok(mmap) => Some(Some(mmap)),
+        Err(_) => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to map {}!", path);
+            None
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_count_file(
+    ptr: *const CoreBPE,
+    path: *const c_char,
+    policy: CTiktokenInvalidUtf8Policy,
+    num_threads: usize,
+) -> usize {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return usize::MAX;
+    }
+    let mmap = match map_file(path) {
+        Some(mmap) => mmap,
+        None => return usize::MAX,
+    };
+    let bytes = mmap.as_deref().unwrap_or_default();
+    let corebpe = unsafe { &*ptr };
+    let mut count = 0;
+    let counted = encode_segments(
+        corebpe,
+        bytes,
+        policy,
+        num_threads,
+        SEGMENT_BYTES,
+        |tokens| {
+            count += tokens.len();
+            Some(())
+        },
+    );
+    if counted.is_none() {
+        #[cfg(feature = "logging")]
+        warn!("Failed to encode!");
+        return usize::MAX;
+    }
+    count
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_encode_file(
+    ptr: *const CoreBPE,
+    input_path: *const c_char,
+    output_path: *const c_char,
+    format: CTiktokenTokenFormat,
+    policy: CTiktokenInvalidUtf8Policy,
+    num_threads: usize,
+    num_tokens: *mut usize,
+) -> bool {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return false;
+    }
+    let mmap = match map_file(input_path) {
+        Some(mmap) => mmap,
+        None => return false,
+    };
+    let output_path = match parse_required_string(output_path, "output_path") {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let mut writer = match File::create(&output_path) {
+        Ok(file) => BufWriter::new(file),
+        Err(_) => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to create {}!", output_path);
+            return false;
+        }
+    };
+    let bytes = mmap.as_deref().unwrap_or_default();
+    let corebpe = unsafe { &*ptr };
+    let mut count = 0;
+    let written = encode_segments(
+        corebpe,
+        bytes,
+        policy,
+        num_threads,
+        SEGMENT_BYTES,
+        |tokens| {
+            count += tokens.len();
+            format.write_tokens(&mut writer, &tokens)
+        },
+    )
+    .and_then(|_| writer.flush().ok());
+    if written.is_none() {
+        #[cfg(feature = "logging")]
+        warn!("Failed to encode to {}!", output_path);
+        drop(writer);
+        let _ = std::fs::remove_file(&output_path);
+        return false;
+    }
+    unsafe {
+        if !num_tokens.is_null() {
+            *num_tokens = count;
+        }
+    };
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corebpe::{
+        tiktoken_cl100k_base, tiktoken_destroy_corebpe, tiktoken_o200k_base, tiktoken_r50k_base,
+    };
+    use crate::testutil::TempPath;
+
+    const LINES: &[&str] = &[
+        "The quick brown fox jumps over the lazy dog.",
+        "  indented line with trailing spaces   ",
+        "",
+        "fn main() {\r",
+        "    println!(\"{}\", 42);",
+        "}",
+        "Ünïcödé wörds and 猫です。",
+        "x'll DON'T we've",
+        "\t\ttabs\t",
+        "1234567890 numbers",
+    ];
+
+    fn sample_text() -> String {
+        let mut text = String::new();
+        for i in 0..2000 {
+            text.push_str(LINES[i * 7 % LINES.len()]);
+            text.push('\n');
+        }
+        text
+    }
+
+    #[test]
+    fn test_encode_segments_matches_whole_encode() {
+        let text = sample_text();
+        let segments = split_segments(text.as_bytes(), 256);
+        assert!(segments.len() > 100);
+        assert_eq!(segments.concat(), text.as_bytes());
+        let policy = CTiktokenInvalidUtf8Policy::Error;
+        for corebpe in [
+            tiktoken_r50k_base(),
+            tiktoken_cl100k_base(),
+            tiktoken_o200k_base(),
+        ] {
+            let corebpe_ref = unsafe { &*corebpe };
+            let expected = corebpe_ref.encode_ordinary(&text);
+            for num_threads in [1, 4] {
+                let mut encoded = Vec::new();
+                let result = encode_segments(
+                    corebpe_ref,
+                    text.as_bytes(),
+                    policy,
+                    num_threads,
+                    256,
+                    |tokens| {
+                        encoded.extend(tokens);
+                        Some(())
+                    },
+                );
+                assert!(result.is_some());
+                assert_eq!(encoded, expected);
+            }
+            tiktoken_destroy_corebpe(corebpe);
+        }
+    }
+
+    #[test]
+    fn test_is_safe_split() {
+        let text = b"word\nnext  \nnext\n\nnext\n next";
+        assert!(is_safe_split(text, 5));
+        assert!(!is_safe_split(text, 12));
+        assert!(!is_safe_split(text, 18));
+        assert!(!is_safe_split(text, 23));
+        // A cut after a blank line would change the tokens.
+        let corebpe = tiktoken_r50k_base();
+        let corebpe_ref = unsafe { &*corebpe };
+        let text = "a.\n\nb";
+        let whole = corebpe_ref.encode_ordinary(text);
+        let mut split = corebpe_ref.encode_ordinary(&text[..4]);
+        split.extend(corebpe_ref.encode_ordinary(&text[4..]));
+        assert_ne!(split, whole);
+        assert!(!is_safe_split(text.as_bytes(), 4));
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_count_and_encode_file() {
+        let text = sample_text();
+        let input = TempPath::new("file-input.txt");
+        let output = TempPath::new("file-output.bin");
+        std::fs::write(&input, &text).unwrap();
+        let corebpe = tiktoken_cl100k_base();
+        let expected = unsafe { &*corebpe }.encode_ordinary(&text);
+        let policy = CTiktokenInvalidUtf8Policy::Error;
+        for num_threads in [1, 3, 0] {
+            let count =
+                tiktoken_corebpe_count_file(corebpe, input.c_path().as_ptr(), policy, num_threads);
+            assert_eq!(count, expected.len());
+            let mut num_tokens = 0;
+            assert!(tiktoken_corebpe_encode_file(
+                corebpe,
+                input.c_path().as_ptr(),
+                output.c_path().as_ptr(),
+                CTiktokenTokenFormat::Uint32,
+                policy,
+                num_threads,
+                &mut num_tokens,
+            ));
+            assert_eq!(num_tokens, expected.len());
+            let written: Vec<Rank> = std::fs::read(&output)
+                .unwrap()
+                .chunks_exact(4)
+                .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect();
+            assert_eq!(written, expected);
+        }
+        // cl100k_base ranks do not all fit in 16 bits.
+        assert!(expected.iter().any(|&token| token > u16::MAX as Rank));
+        assert!(!tiktoken_corebpe_encode_file(
+            corebpe,
+            input.c_path().as_ptr(),
+            output.c_path().as_ptr(),
+            CTiktokenTokenFormat::Uint16,
+            policy,
+            0,
+            std::ptr::null_mut(),
+        ));
+        assert!(!output.exists());
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_encode_file_uint16_and_policies() {
+        let input = TempPath::new("file-input16.txt");
+        let output = TempPath::new("file-output16.bin");
+        let bytes = b"caf\xe9 au lait\n".to_vec();
+        std::fs::write(&input, &bytes).unwrap();
+        let corebpe = tiktoken_r50k_base();
+        let expected = crate::bytes::encode_bytes_with_policy(
+            unsafe { &*corebpe },
+            &bytes,
+            CTiktokenInvalidUtf8Policy::Bytes,
+            |corebpe, text| corebpe.encode_ordinary(text),
+        )
+        .unwrap();
+        assert_eq!(
+            tiktoken_corebpe_count_file(
+                corebpe,
+                input.c_path().as_ptr(),
+                CTiktokenInvalidUtf8Policy::Error,
+                1
+            ),
+            usize::MAX
+        );
+        assert!(tiktoken_corebpe_encode_file(
+            corebpe,
+            input.c_path().as_ptr(),
+            output.c_path().as_ptr(),
+            CTiktokenTokenFormat::Uint16,
+            CTiktokenInvalidUtf8Policy::Bytes,
+            1,
+            std::ptr::null_mut(),
+        ));
+        let written: Vec<Rank> = std::fs::read(&output)
+            .unwrap()
+            .chunks_exact(2)
+            .map(|chunk| u16::from_le_bytes(chunk.try_into().unwrap()) as Rank)
+            .collect();
+        assert_eq!(written, expected);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_count_file_empty_and_missing() {
+        let input = TempPath::new("file-empty.txt");
+        std::fs::write(&input, b"").unwrap();
+        let corebpe = tiktoken_cl100k_base();
+        let policy = CTiktokenInvalidUtf8Policy::Error;
+        assert_eq!(
+            tiktoken_corebpe_count_file(corebpe, input.c_path().as_ptr(), policy, 0),
+            0
+        );
+        std::fs::remove_file(&input).unwrap();
+        assert_eq!(
+            tiktoken_corebpe_count_file(corebpe, input.c_path().as_ptr(), policy, 0),
+            usize::MAX
+        );
+        assert_eq!(
+            tiktoken_corebpe_count_file(corebpe, std::ptr::null(), policy, 0),
+            usize::MAX
+        );
+        assert_eq!(
+            tiktoken_corebpe_count_file(std::ptr::null(), input.c_path().as_ptr(), policy, 0),
+            usize::MAX
+        );
+        tiktoken_destroy_corebpe(corebpe);
+    }
+}
@@ -0,0 +1,306 @@
+#[cfg(feature = "logging")]
+use log::warn;
+use std::ffi::{c_char, CStr};
+use tiktoken_rs::Rank;
+
+use crate::alloc::malloc_copy;
+use crate::corebpe::CoreBPE;
+
+/// How far, in bytes, a pre-tokenizer piece must end before the end of the
+/// text to be unaffected by appended text. The patterns look at most a few
+/// characters past the end of a piece (contraction suffixes, `(?!\S)`), so
+/// four characters of four bytes each are always enough.
+pub(crate) const STABLE_MARGIN: usize = 16;
+
+/// Counts tokens of a growing text, re-encoding only the pieces near its end.
+pub struct CTiktokenIncrementalCounter {
+    corebpe: *const CoreBPE,
+    text: String,
+    tokens: Vec<Rank>,
+    /// Start offset of each piece and index of its first token.
+    pieces: Vec<(usize, usize)>,
+}
+
+impl CTiktokenIncrementalCounter {
+    fn new(corebpe: *const CoreBPE) -> Self {
+        Self {
+            corebpe,
+            text: String::new(),
+            tokens: Vec::new(),
+            pieces: Vec::new(),
+        }
+    }
+
+    /// Drops the pieces that text changes after `stable_len` could affect
+    /// and returns the offset from which the text must be re-encoded.
+    fn rewind(&mut self, stable_len: usize) -> usize {
+        let limit = stable_len.saturating_sub(STABLE_MARGIN);
+        let keep = self.pieces.partition_point(|&(start, _)| start <= limit);
+        let (start, first_token) = match keep {
+            0 => (0, 0),
+            keep => self.pieces[keep - 1],
+        };
+        self.pieces.truncate(keep.saturating_sub(1));
+        self.tokens.truncate(first_token);
+        start
+    }
+
+    fn encode_from(&mut self, start: usize) -> bool {
+        let corebpe = unsafe { &*self.corebpe };
+        for (offset, piece) in corebpe.split_ordinary(&self.text[start..]) {
+            let tokens = match corebpe.encode_piece(piece.as_bytes()) {
+                Some(tokens) => tokens,
+                None => return false,
+            };
+            self.pieces.push((start + offset, self.tokens.len()));
+            self.tokens.extend(tokens);
+        }
+        true
+    }
+
+    fn append(&mut self, text: &str) -> bool {
+        let start = self.rewind(self.text.len());
+        self.text.push_str(text);
+        self.encode_from(start)
+    }
+
+    fn truncate(&mut self, text_len: usize) -> bool {
+        if !self.text.is_char_boundary(text_len) {
+            return false;
+        }
+        let start = self.rewind(text_len);
+        self.text.truncate(text_len);
+        self.encode_from(start)
+    }
+}
+
+/// Creates a counter for text encoded with `ptr`, which must outlive it.
+#[no_mangle]
+pub extern "C" fn tiktoken_incremental_counter_new(
+    ptr: *const CoreBPE,
+) -> *mut CTiktokenIncrementalCounter {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return std::ptr::null_mut();
+    }
+    Box::into_raw(Box::new(CTiktokenIncrementalCounter::new(ptr)))
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_incremental_counter_destroy(counter: *mut CTiktokenIncrementalCounter) {
+    if counter.is_null() {
+        return;
+    }
+    unsafe {
+        drop(Box::from_raw(counter));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_incremental_counter_append(
+    counter: *mut CTiktokenIncrementalCounter,
+    text: *const c_char,
+) -> bool {
+    if counter.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for counter!");
+        return false;
+    }
+    if text.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for text!");
+        return false;
+    }
+    let text = match unsafe { CStr::from_ptr(text) }.to_str() {
+        Ok(text) => text,
+        Err(_) => {
+            #[cfg(feature = "logging")]
+            warn!("Invalid UTF-8 sequence provided for text!");
+            return false;
+        }
+    };
+    let counter = unsafe { &mut *counter };
+    if !counter.append(text) {
+        #[cfg(feature = "logging")]
+        warn!("Failed to encode!");
+        return false;
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_incremental_counter_truncate(
+    counter: *mut CTiktokenIncrementalCounter,
+    text_len: usize,
+) -> bool {
+    if counter.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for counter!");
+        return false;
+    }
+    let counter = unsafe { &mut *counter };
+    if text_len > counter.text.len() {
+        #[cfg(feature = "logging")]
+        warn!("Length is past the end of the text!");
+        return false;
+    }
+    if !counter.truncate(text_len) {
+        #[cfg(feature = "logging")]
+        warn!("Length is not on a character boundary!");
+        return false;
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_incremental_counter_count(
+    counter: *const CTiktokenIncrementalCounter,
+) -> usize {
+    if counter.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for counter!");
+        return usize::MAX;
+    }
+    unsafe { &*counter }.tokens.len()
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_incremental_counter_text_len(
+    counter: *const CTiktokenIncrementalCounter,
+) -> usize {
+    if counter.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for counter!");
+        return usize::MAX;
+    }
+    unsafe { &*counter }.text.len()
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_incremental_counter_tokens(
+    counter: *const CTiktokenIncrementalCounter,
+    num_tokens: *mut usize,
+) -> *mut Rank {
+    if counter.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for counter!");
+        return std::ptr::null_mut();
+    }
+    let counter = unsafe { &*counter };
+    if !num_tokens.is_null() {
+        unsafe {
+            *num_tokens = counter.tokens.len();
+        }
+    }
+    malloc_copy::<Rank>(&counter.tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::tiktoken_free;
+    use crate::corebpe::{
+        tiktoken_cl100k_base, tiktoken_destroy_corebpe, tiktoken_o200k_base, tiktoken_r50k_base,
+    };
+    use std::ffi::CString;
+
+    const SAMPLE: &str = "Hello, world!  We'll see   what's\n\n  happening\t here: x'll 1234567 \
+                          猫です。Ünïcödé wörds DON'T    stop\r\n\r\n    fn main() { }   ";
+
+    fn assert_matches_encode(corebpe: *mut CoreBPE, counter: *mut CTiktokenIncrementalCounter) {
+        let counter_ref = unsafe { &*counter };
+        let expected = unsafe { &*corebpe }.encode_ordinary(&counter_ref.text);
+        assert_eq!(counter_ref.tokens, expected, "text: {:?}", counter_ref.text);
+        assert_eq!(tiktoken_incremental_counter_count(counter), expected.len());
+    }
+
+    #[test]
+    fn test_incremental_counter_matches_encode_per_character() {
+        for corebpe in [
+            tiktoken_r50k_base(),
+            tiktoken_cl100k_base(),
+            tiktoken_o200k_base(),
+        ] {
+            let counter = tiktoken_incremental_counter_new(corebpe);
+            let mut buffer = [0u8; 5];
+            for c in SAMPLE.chars() {
+                let chunk = CString::new(c.encode_utf8(&mut buffer).as_bytes()).unwrap();
+                assert!(tiktoken_incremental_counter_append(counter, chunk.as_ptr()));
+                assert_matches_encode(corebpe, counter);
+            }
+            tiktoken_incremental_counter_destroy(counter);
+            tiktoken_destroy_corebpe(corebpe);
+        }
+    }
+
+    #[test]
+    fn test_incremental_counter_chunks_and_truncate() {
+        let corebpe = tiktoken_cl100k_base();
+        let counter = tiktoken_incremental_counter_new(corebpe);
+        for chunk in SAMPLE.split_inclusive(' ') {
+            let chunk = CString::new(chunk).unwrap();
+            assert!(tiktoken_incremental_counter_append(counter, chunk.as_ptr()));
+            assert_matches_encode(corebpe, counter);
+        }
+        assert_eq!(tiktoken_incremental_counter_text_len(counter), SAMPLE.len());
+        let mut text_len = SAMPLE.len();
+        while text_len > 0 {
+            text_len -= 1;
+            if !SAMPLE.is_char_boundary(text_len) {
+                assert!(!tiktoken_incremental_counter_truncate(counter, text_len));
+                continue;
+            }
+            assert!(tiktoken_incremental_counter_truncate(counter, text_len));
+            assert_matches_encode(corebpe, counter);
+        }
+        assert_eq!(tiktoken_incremental_counter_count(counter), 0);
+        assert!(!tiktoken_incremental_counter_truncate(counter, 1));
+        tiktoken_incremental_counter_destroy(counter);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_incremental_counter_tokens() {
+        let corebpe = tiktoken_cl100k_base();
+        let counter = tiktoken_incremental_counter_new(corebpe);
+        let text = CString::new("I am a cat.").unwrap();
+        assert!(tiktoken_incremental_counter_append(counter, text.as_ptr()));
+        let mut num_tokens = 0;
+        let tokens = tiktoken_incremental_counter_tokens(counter, &mut num_tokens);
+        let tokens_slice = unsafe { std::slice::from_raw_parts(tokens, num_tokens) };
+        assert_eq!(tokens_slice, [40, 1097, 264, 8415, 13]);
+        tiktoken_free(tokens as *mut std::ffi::c_void);
+        // The length is optional.
+        let tokens = tiktoken_incremental_counter_tokens(counter, std::ptr::null_mut());
+        let tokens_slice = unsafe { std::slice::from_raw_parts(tokens, num_tokens) };
+        assert_eq!(tokens_slice, [40, 1097, 264, 8415, 13]);
+        tiktoken_free(tokens as *mut std::ffi::c_void);
+        tiktoken_incremental_counter_destroy(counter);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_incremental_counter_null_arguments() {
+        assert!(tiktoken_incremental_counter_new(std::ptr::null()).is_null());
+        let text = CString::new("text").unwrap();
+        assert!(!tiktoken_incremental_counter_append(
+            std::ptr::null_mut(),
+            text.as_ptr()
+        ));
+        assert_eq!(
+            tiktoken_incremental_counter_count(std::ptr::null()),
+            usize::MAX
+        );
+        let corebpe = tiktoken_cl100k_base();
+        let counter = tiktoken_incremental_counter_new(corebpe);
+        assert!(!tiktoken_incremental_counter_append(
+            counter,
+            std::ptr::null()
+        ));
+        let mut num_tokens = 0;
+        assert!(tiktoken_incremental_counter_tokens(std::ptr::null(), &mut num_tokens).is_null());
+        tiktoken_incremental_counter_destroy(counter);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+}
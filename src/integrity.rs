@@ -0,0 +1,255 @@
+#[cfg(feature = "logging")]
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::ffi::{c_char, CString};
+use std::fs::File;
+use std::sync::OnceLock;
+
+use crate::alloc::cstring_into_malloced;
+use crate::compare::BUILTIN_TOKENIZERS;
+use crate::corebpe::{compiled_bpe, encoding_name, CoreBPE};
+use crate::export::tiktoken_file_contents;
+use crate::{parse_required_string, CTiktokenTokenizer};
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CTiktokenEncodingDigest {
+    pub tokenizer: CTiktokenTokenizer,
+    pub name: *const c_char,
+    /// Lowercase hex SHA-256 of the rank data in `.tiktoken` format.
+    pub sha256: *const c_char,
+    /// Size of the rank data in bytes.
+    pub size: u64,
+}
+
+fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// SHA-256 and size of the ordinary tokens of `corebpe` written as a
+/// `.tiktoken` file. For the built-in encodings this is byte for byte the
+/// rank file they were built from.
+fn rank_data_digest(corebpe: &CoreBPE) -> (String, u64) {
+    let contents = tiktoken_file_contents(corebpe);
+    (to_hex(&Sha256::digest(&contents)), contents.len() as u64)
+}
+
+/// Digest of each built-in encoding, computed on first use.
+fn builtin_digest(index: usize) -> Option<&'static (CString, u64)> {
+    static DIGESTS: [OnceLock<Option<(CString, u64)>>; BUILTIN_TOKENIZERS.len()] =
+        [const { OnceLock::new() }; BUILTIN_TOKENIZERS.len()];
+    DIGESTS[index]
+        .get_or_init(|| {
+            let tokenizer = BUILTIN_TOKENIZERS[index];
+            let bpe = compiled_bpe(tokenizer)?;
+            let corebpe = CoreBPE::from_shared(bpe, tokenizer).ok()?;
+            let (sha256, size) = rank_data_digest(&corebpe);
+            Some((CString::new(sha256).ok()?, size))
+        })
+        .as_ref()
+}
+
+fn file_sha256(path: &str) -> Option<String> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to open {}!", path);
+            return None;
+        }
+    };
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).ok()?;
+    Some(to_hex(&hasher.finalize()))
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_builtin_encoding_digests(
+    digests: *mut CTiktokenEncodingDigest,
+    digests_len: usize,
+) -> usize {
+    if digests.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for digests!");
+        return usize::MAX;
+    }
+    let num_written = BUILTIN_TOKENIZERS.len().min(digests_len);
+    for (index, &tokenizer) in BUILTIN_TOKENIZERS[..num_written].iter().enumerate() {
+        let (sha256, size) = match builtin_digest(index) {
+            Some(digest) => digest,
+            None => {
+                #[cfg(feature = "logging")]
+                warn!("Failed to get BPE for {:?}!", tokenizer);
+                return usize::MAX;
+            }
+        };
+        let entry = CTiktokenEncodingDigest {
+            tokenizer: tokenizer.into(),
+            name: encoding_name(tokenizer).as_ptr(),
+            sha256: sha256.as_ptr(),
+            size: *size,
+        };
+        unsafe {
+            *digests.add(index) = entry;
+        }
+    }
+    num_written
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_sha256(ptr: *const CoreBPE) -> *mut c_char {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return std::ptr::null_mut();
+    }
+    let (sha256, _) = rank_data_digest(unsafe { &*ptr });
+    match CString::new(sha256) {
+        Ok(sha256) => cstring_into_malloced(sha256),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_file_sha256(path: *const c_char) -> *mut c_char {
+    let path = match parse_required_string(path, "path") {
+        Ok(path) => path,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    match file_sha256(&path).and_then(|sha256| CString::new(sha256).ok()) {
+        Some(sha256) => cstring_into_malloced(sha256),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Returns whether the file at `path` has the SHA-256 `expected_sha256`,
+/// given as 64 hex digits in either case.
+#[no_mangle]
+pub extern "C" fn tiktoken_verify_rank_file(
+    path: *const c_char,
+    expected_sha256: *const c_char,
+) -> bool {
+    let path = match parse_required_string(path, "path") {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let expected = match parse_required_string(expected_sha256, "expected_sha256") {
+        Ok(expected) => expected,
+        Err(_) => return false,
+    };
+    if expected.len() != 64 || !expected.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        #[cfg(feature = "logging")]
+        warn!("Expected SHA-256 must be 64 hex digits!");
+        return false;
+    }
+    match file_sha256(&path) {
+        Some(actual) if actual.eq_ignore_ascii_case(&expected) => true,
+        Some(_) => {
+            #[cfg(feature = "logging")]
+            warn!("SHA-256 of {} does not match!", path);
+            false
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::tiktoken_free;
+    use crate::corebpe::{tiktoken_destroy_corebpe, tiktoken_r50k_base};
+    use crate::export::tiktoken_corebpe_save_tiktoken;
+    use crate::testutil::TempPath;
+    use std::ffi::CStr;
+
+    // Hashes and sizes of the rank files published with tiktoken.
+    const R50K_SHA256: &str = "306cd27f03c1a714eca7108e03d66b7dc042abe8c258b44c199a7ed9838dd930";
+    const P50K_SHA256: &str = "94b5ca7dff4d00767bc256fdd1b27e5b17361d7b8a5f968547f9f23eb70d2069";
+    const CL100K_SHA256: &str = "223921b76ee99bde995b7ff738513eef100fb51d18c93597a113bcffe865b2a7";
+    const O200K_SHA256: &str = "446a9538cb6c348e3516120d7c08b09f57c36495e2acfffe59a5bf8b0cfb1a2d";
+
+    #[test]
+    fn test_builtin_encoding_digests() {
+        let mut digests = [CTiktokenEncodingDigest {
+            tokenizer: CTiktokenTokenizer::Unknown,
+            name: std::ptr::null(),
+            sha256: std::ptr::null(),
+            size: 0,
+        }; 6];
+        assert_eq!(
+            tiktoken_builtin_encoding_digests(digests.as_mut_ptr(), 6),
+            6
+        );
+        let actual: Vec<(&str, &str, u64)> = digests
+            .iter()
+            .map(|digest| unsafe {
+                (
+                    CStr::from_ptr(digest.name).to_str().unwrap(),
+                    CStr::from_ptr(digest.sha256).to_str().unwrap(),
+                    digest.size,
+                )
+            })
+            .collect();
+        assert_eq!(
+            actual,
+            [
+                ("r50k_base", R50K_SHA256, 835554),
+                ("p50k_base", P50K_SHA256, 836186),
+                ("p50k_edit", P50K_SHA256, 836186),
+                ("cl100k_base", CL100K_SHA256, 1681126),
+                ("o200k_base", O200K_SHA256, 3613922),
+                ("o200k_harmony", O200K_SHA256, 3613922),
+            ]
+        );
+        assert_eq!(digests[3].tokenizer, CTiktokenTokenizer::Cl100kBase);
+        assert_eq!(
+            tiktoken_builtin_encoding_digests(digests.as_mut_ptr(), 1),
+            1
+        );
+        assert_eq!(
+            tiktoken_builtin_encoding_digests(std::ptr::null_mut(), 6),
+            usize::MAX
+        );
+    }
+
+    #[test]
+    fn test_verify_rank_file() {
+        let corebpe = tiktoken_r50k_base();
+        let sha256 = tiktoken_corebpe_sha256(corebpe);
+        assert_eq!(
+            unsafe { CStr::from_ptr(sha256) }.to_str().unwrap(),
+            R50K_SHA256
+        );
+        tiktoken_free(sha256 as *mut std::ffi::c_void);
+
+        let path = TempPath::new("integrity-verify.tiktoken");
+        let c_path = path.c_path();
+        assert!(tiktoken_corebpe_save_tiktoken(corebpe, c_path.as_ptr()));
+        let sha256 = tiktoken_file_sha256(c_path.as_ptr());
+        assert_eq!(
+            unsafe { CStr::from_ptr(sha256) }.to_str().unwrap(),
+            R50K_SHA256
+        );
+        tiktoken_free(sha256 as *mut std::ffi::c_void);
+
+        let expected = CString::new(R50K_SHA256).unwrap();
+        assert!(tiktoken_verify_rank_file(
+            c_path.as_ptr(),
+            expected.as_ptr()
+        ));
+        let upper = CString::new(R50K_SHA256.to_uppercase()).unwrap();
+        assert!(tiktoken_verify_rank_file(c_path.as_ptr(), upper.as_ptr()));
+        let other = CString::new(P50K_SHA256).unwrap();
+        assert!(!tiktoken_verify_rank_file(c_path.as_ptr(), other.as_ptr()));
+        let short = CString::new(&R50K_SHA256[..63]).unwrap();
+        assert!(!tiktoken_verify_rank_file(c_path.as_ptr(), short.as_ptr()));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(!tiktoken_verify_rank_file(
+            c_path.as_ptr(),
+            expected.as_ptr()
+        ));
+        assert!(tiktoken_file_sha256(c_path.as_ptr()).is_null());
+        tiktoken_destroy_corebpe(corebpe);
+    }
+}
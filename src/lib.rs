@@ -3,18 +3,78 @@ use log::warn;
 #[cfg(feature = "logging")]
 use simple_logger::SimpleLogger;
 use std::ffi::{c_char, CStr};
-use tiktoken_rs;
-use tiktoken_rs::{CoreBPE, Rank};
+use tiktoken_rs::Rank;
+
+// The tests construct every built-in encoding.
+#[cfg(all(
+    test,
+    not(all(
+        feature = "enc-r50k",
+        feature = "enc-p50k",
+        feature = "enc-cl100k",
+        feature = "enc-o200k"
+    ))
+))]
+compile_error!("the tests need every encoding; run them with the default features");
 
 mod alloc;
 use alloc::{cstring_into_malloced, malloc_copy};
 
+mod approx;
+
+mod bytes;
+pub use bytes::{CTiktokenInvalidUtf8Policy, CTiktokenLossyDecodePolicy};
+
+mod cache;
+use cache::CountKind;
+
+mod compare;
+
+mod corpus;
+
 mod corebpe;
+use corebpe::CoreBPE;
 // use corebpe::{
 //     tiktoken_cl100k_base, tiktoken_destroy_corebpe, tiktoken_get_bpe_from_model,
 //     tiktoken_o200k_base, tiktoken_p50k_base, tiktoken_p50k_edit, tiktoken_r50k_base,
 // };
 
+mod datadir;
+
+mod export;
+
+mod extend;
+
+mod file;
+pub use file::CTiktokenTokenFormat;
+
+mod incremental;
+
+mod integrity;
+
+mod limit;
+
+mod pack;
+pub use pack::CTiktokenPackingStrategy;
+
+mod pricing;
+
+mod shard;
+
+mod snapshot;
+
+mod stats;
+
+mod stream;
+
+#[cfg(test)]
+mod testutil;
+
+mod train;
+
+mod utf16;
+pub use utf16::CTiktokenSurrogatePolicy;
+
 mod utils;
 use utils::c_str_to_string;
 
@@ -85,6 +145,7 @@ pub extern "C" fn tiktoken_get_tokenizer(model: *const c_char) -> CTiktokenToken
     }
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn tiktoken_get_text_completion_max_tokens(
     model: *const c_char,
@@ -122,14 +183,24 @@ pub extern "C" fn tiktoken_get_text_completion_max_tokens(
             }
         }
     };
-    match tiktoken_rs::get_text_completion_max_tokens(model, prompt) {
-        Ok(max_tokens) => max_tokens,
-        Err(_) => {
+    let context_size = match tiktoken_rs::model::get_context_size(model) {
+        Some(context_size) => context_size,
+        None => {
             #[cfg(feature = "logging")]
-            warn!("Failed to get completion max tokens!");
+            warn!("Failed to get context size!");
             return usize::MAX;
         }
-    }
+    };
+    let bpe =
+        match tiktoken_rs::tokenizer::get_tokenizer(model).and_then(corebpe::bpe_for_tokenizer) {
+            Some(bpe) => bpe,
+            None => {
+                #[cfg(feature = "logging")]
+                warn!("Failed to get completion max tokens!");
+                return usize::MAX;
+            }
+        };
+    context_size.saturating_sub(bpe.count_with_special_tokens(prompt))
 }
 
 #[repr(C)]
@@ -213,6 +284,7 @@ pub extern "C" fn tiktoken_chat_message_new(
     }))
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn tiktoken_chat_message_set_role(
     ptr: *mut CChatCompletionRequestMessage,
@@ -231,6 +303,7 @@ pub extern "C" fn tiktoken_chat_message_set_role(
     true
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn tiktoken_chat_message_set_content(
     ptr: *mut CChatCompletionRequestMessage,
@@ -249,6 +322,7 @@ pub extern "C" fn tiktoken_chat_message_set_content(
     true
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn tiktoken_chat_message_set_name(
     ptr: *mut CChatCompletionRequestMessage,
@@ -267,6 +341,7 @@ pub extern "C" fn tiktoken_chat_message_set_name(
     true
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn tiktoken_chat_message_set_function_call(
     ptr: *mut CChatCompletionRequestMessage,
@@ -286,6 +361,7 @@ pub extern "C" fn tiktoken_chat_message_set_function_call(
     true
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn tiktoken_chat_message_clear_function_call(
     ptr: *mut CChatCompletionRequestMessage,
@@ -296,6 +372,7 @@ pub extern "C" fn tiktoken_chat_message_clear_function_call(
     unsafe { &mut *ptr }.inner.function_call = None;
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn tiktoken_chat_message_add_tool_call(
     ptr: *mut CChatCompletionRequestMessage,
@@ -315,6 +392,7 @@ pub extern "C" fn tiktoken_chat_message_add_tool_call(
     true
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn tiktoken_chat_message_clear_tool_calls(
     ptr: *mut CChatCompletionRequestMessage,
@@ -325,6 +403,7 @@ pub extern "C" fn tiktoken_chat_message_clear_tool_calls(
     unsafe { &mut *ptr }.inner.tool_calls.clear();
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn tiktoken_chat_message_set_refusal(
     ptr: *mut CChatCompletionRequestMessage,
@@ -343,6 +422,7 @@ pub extern "C" fn tiktoken_chat_message_set_refusal(
     true
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn tiktoken_chat_message_destroy(ptr: *mut CChatCompletionRequestMessage) {
     if ptr.is_null() {
@@ -353,6 +433,7 @@ pub extern "C" fn tiktoken_chat_message_destroy(ptr: *mut CChatCompletionRequest
     }
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn tiktoken_num_tokens_from_messages(
     model: *const c_char,
@@ -386,16 +467,17 @@ pub extern "C" fn tiktoken_num_tokens_from_messages(
             return usize::MAX;
         }
     };
-    match tiktoken_rs::num_tokens_from_messages(model, &messages) {
-        Ok(num_tokens) => num_tokens,
-        Err(_) => {
+    match compare::num_tokens_from_messages(model, &messages) {
+        Some(num_tokens) => num_tokens,
+        None => {
             #[cfg(feature = "logging")]
             warn!("Failed to get num tokens!");
-            return usize::MAX;
+            usize::MAX
         }
     }
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn tiktoken_get_chat_completion_max_tokens(
     model: *const c_char,
@@ -429,19 +511,28 @@ pub extern "C" fn tiktoken_get_chat_completion_max_tokens(
             return usize::MAX;
         }
     };
-    match tiktoken_rs::get_chat_completion_max_tokens(model, &messages) {
-        Ok(max_tokens) => max_tokens,
-        Err(_) => {
+    let context_size = match tiktoken_rs::model::get_context_size(model) {
+        Some(context_size) => context_size,
+        None => {
             #[cfg(feature = "logging")]
-            warn!("Failed to get max tokens!");
+            warn!("Failed to get context size!");
             return usize::MAX;
         }
+    };
+    match compare::num_tokens_from_messages(model, &messages) {
+        Some(num_tokens) => context_size.saturating_sub(num_tokens),
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to get max tokens!");
+            usize::MAX
+        }
     }
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn tiktoken_corebpe_encode_ordinary(
-    ptr: *mut CoreBPE,
+    ptr: *const CoreBPE,
     text: *const c_char,
     num_tokens: *mut usize,
 ) -> *mut Rank {
@@ -466,7 +557,7 @@ pub extern "C" fn tiktoken_corebpe_encode_ordinary(
             }
         }
     };
-    let corebpe = unsafe { &mut *ptr };
+    let corebpe = unsafe { &*ptr };
     let encoded = corebpe.encode_ordinary(text);
     unsafe {
         if !num_tokens.is_null() {
@@ -476,8 +567,12 @@ pub extern "C" fn tiktoken_corebpe_encode_ordinary(
     malloc_copy::<Rank>(&encoded)
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
-pub extern "C" fn tiktoken_corebpe_count_ordinary(ptr: *mut CoreBPE, text: *const c_char) -> usize {
+pub extern "C" fn tiktoken_corebpe_count_ordinary(
+    ptr: *const CoreBPE,
+    text: *const c_char,
+) -> usize {
     if ptr.is_null() {
         #[cfg(feature = "logging")]
         warn!("Null pointer provided for CoreBPE!");
@@ -499,14 +594,15 @@ pub extern "C" fn tiktoken_corebpe_count_ordinary(ptr: *mut CoreBPE, text: *cons
             }
         }
     };
-    let corebpe = unsafe { &mut *ptr };
-    corebpe.count_ordinary(text)
+    let corebpe = unsafe { &*ptr };
+    corebpe.count_cached(CountKind::Ordinary, text, || corebpe.count_ordinary(text))
 }
 
 // pub fn encode(&self, text: &str, allowed_special: HashSet<&str>) -> Vec<usize>
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn tiktoken_corebpe_encode(
-    ptr: *mut CoreBPE,
+    ptr: *const CoreBPE,
     text: *const c_char,
     allowed_special: *const *const c_char,
     allowed_special_len: usize,
@@ -564,7 +660,7 @@ pub extern "C" fn tiktoken_corebpe_encode(
             allowed_special_hash_set
         }
     };
-    let corebpe = unsafe { &mut *ptr };
+    let corebpe = unsafe { &*ptr };
     let encoded = match corebpe.encode(text, &allowed_special) {
         Ok((encoded, _)) => encoded,
         Err(_) => {
@@ -581,9 +677,10 @@ pub extern "C" fn tiktoken_corebpe_encode(
     malloc_copy::<Rank>(&encoded)
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn tiktoken_corebpe_count(
-    ptr: *mut CoreBPE,
+    ptr: *const CoreBPE,
     text: *const c_char,
     allowed_special: *const *const c_char,
     allowed_special_len: usize,
@@ -640,7 +737,7 @@ pub extern "C" fn tiktoken_corebpe_count(
             allowed_special_hash_set
         }
     };
-    let corebpe = unsafe { &mut *ptr };
+    let corebpe = unsafe { &*ptr };
     match corebpe.count(text, &allowed_special) {
         Ok(count) => count,
         Err(_) => {
@@ -651,9 +748,10 @@ pub extern "C" fn tiktoken_corebpe_count(
     }
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn tiktoken_corebpe_encode_with_special_tokens(
-    ptr: *mut CoreBPE,
+    ptr: *const CoreBPE,
     text: *const c_char,
     num_tokens: *mut usize,
 ) -> *mut Rank {
@@ -678,7 +776,7 @@ pub extern "C" fn tiktoken_corebpe_encode_with_special_tokens(
             }
         }
     };
-    let corebpe = unsafe { &mut *ptr };
+    let corebpe = unsafe { &*ptr };
     let encoded = corebpe.encode_with_special_tokens(text);
     unsafe {
         if !num_tokens.is_null() {
@@ -688,9 +786,10 @@ pub extern "C" fn tiktoken_corebpe_encode_with_special_tokens(
     malloc_copy::<Rank>(&encoded)
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn tiktoken_corebpe_count_with_special_tokens(
-    ptr: *mut CoreBPE,
+    ptr: *const CoreBPE,
     text: *const c_char,
 ) -> usize {
     if ptr.is_null() {
@@ -714,13 +813,16 @@ pub extern "C" fn tiktoken_corebpe_count_with_special_tokens(
             }
         }
     };
-    let corebpe = unsafe { &mut *ptr };
-    corebpe.count_with_special_tokens(text)
+    let corebpe = unsafe { &*ptr };
+    corebpe.count_cached(CountKind::WithSpecialTokens, text, || {
+        corebpe.count_with_special_tokens(text)
+    })
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn tiktoken_corebpe_decode(
-    ptr: *mut CoreBPE,
+    ptr: *const CoreBPE,
     tokens: *const Rank,
     num_tokens: usize,
 ) -> *mut c_char {
@@ -736,7 +838,7 @@ pub extern "C" fn tiktoken_corebpe_decode(
     }
     let tokens = unsafe { std::slice::from_raw_parts(tokens, num_tokens) };
 
-    let corebpe = unsafe { &mut *ptr };
+    let corebpe = unsafe { &*ptr };
     let decoded = corebpe.decode(tokens);
     let decoded = match decoded {
         Ok(decoded) => decoded,
@@ -757,9 +859,10 @@ pub extern "C" fn tiktoken_corebpe_decode(
     cstring_into_malloced(c_str)
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn tiktoken_corebpe_decode_bytes(
-    ptr: *mut CoreBPE,
+    ptr: *const CoreBPE,
     tokens: *const Rank,
     num_tokens: usize,
     num_bytes: *mut usize,
@@ -776,7 +879,7 @@ pub extern "C" fn tiktoken_corebpe_decode_bytes(
     }
     let tokens = unsafe { std::slice::from_raw_parts(tokens, num_tokens) };
 
-    let corebpe = unsafe { &mut *ptr };
+    let corebpe = unsafe { &*ptr };
     let decoded = corebpe.decode_bytes(tokens);
     let decoded = match decoded {
         Ok(decoded) => decoded,
@@ -1458,7 +1561,7 @@ mod tests {
     #[test]
     fn test_corebpe_decode() {
         let model = CString::new("gpt-4").unwrap();
-        let tokens = vec![40, 1097, 264, 8415, 13];
+        let tokens = [40, 1097, 264, 8415, 13];
         let corebpe = tiktoken_get_bpe_from_model(model.as_ptr());
         let decoded = tiktoken_corebpe_decode(corebpe, tokens.as_ptr(), tokens.len());
         let decoded = unsafe { CStr::from_ptr(decoded) };
@@ -1469,7 +1572,7 @@ mod tests {
 
     #[test]
     fn test_corebpe_decode_null_corebpe() {
-        let tokens = vec![40, 1097, 264, 8415, 13];
+        let tokens = [40, 1097, 264, 8415, 13];
         let decoded = tiktoken_corebpe_decode(std::ptr::null_mut(), tokens.as_ptr(), tokens.len());
         assert!(decoded.is_null());
     }
@@ -1486,7 +1589,7 @@ mod tests {
     #[test]
     fn test_corebpe_decode_invalid_tokens() {
         let model = CString::new("gpt-4").unwrap();
-        let tokens = vec![40, 1097, 264, 8415, 13, 220, 100257];
+        let tokens = [40, 1097, 264, 8415, 13, 220, 100257];
         let corebpe = tiktoken_get_bpe_from_model(model.as_ptr());
         let decoded = tiktoken_corebpe_decode(corebpe, tokens.as_ptr(), tokens.len());
         let decoded = unsafe { CStr::from_ptr(decoded) };
@@ -1498,7 +1601,7 @@ mod tests {
     #[test]
     fn test_corebpe_decode_bytes() {
         let model = CString::new("gpt-4").unwrap();
-        let tokens = vec![40, 1097, 264, 8415, 13];
+        let tokens = [40, 1097, 264, 8415, 13];
         let corebpe = tiktoken_get_bpe_from_model(model.as_ptr());
         let mut num_bytes = 0;
         let decoded =
@@ -1544,6 +1647,54 @@ mod tests {
         tiktoken_destroy_corebpe(corebpe);
     }
 
+    #[test]
+    fn test_corebpe_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<CoreBPE>();
+    }
+
+    #[test]
+    fn test_corebpe_shared_across_threads() {
+        const NUM_THREADS: usize = 16;
+        const NUM_ITERATIONS: usize = 50;
+        let model = CString::new("gpt-4").unwrap();
+        let corebpe = tiktoken_get_bpe_from_model(model.as_ptr());
+        assert!(!corebpe.is_null());
+        let shared: &CoreBPE = unsafe { &*corebpe };
+
+        std::thread::scope(|scope| {
+            for thread_index in 0..NUM_THREADS {
+                scope.spawn(move || {
+                    let ptr = shared as *const CoreBPE;
+                    let text = CString::new(format!("I am cat number {}.", thread_index)).unwrap();
+                    let special = CString::new("I am a cat. <|endoftext|>").unwrap();
+                    let expected = shared.encode_ordinary(text.to_str().unwrap());
+                    for _ in 0..NUM_ITERATIONS {
+                        let mut num_tokens: usize = 0;
+                        let tokens =
+                            tiktoken_corebpe_encode_ordinary(ptr, text.as_ptr(), &mut num_tokens);
+                        let slice = unsafe { std::slice::from_raw_parts(tokens, num_tokens) };
+                        assert_eq!(slice, expected.as_slice());
+
+                        let count = tiktoken_corebpe_count_ordinary(ptr, text.as_ptr());
+                        assert_eq!(count, expected.len());
+                        let count =
+                            tiktoken_corebpe_count_with_special_tokens(ptr, special.as_ptr());
+                        assert_eq!(count, 7);
+
+                        let decoded = tiktoken_corebpe_decode(ptr, tokens, num_tokens);
+                        let decoded_str = unsafe { CStr::from_ptr(decoded) };
+                        assert_eq!(decoded_str.to_bytes(), text.as_bytes());
+                        tiktoken_free(decoded as *mut libc::c_void);
+                        tiktoken_free(tokens as *mut libc::c_void);
+                    }
+                });
+            }
+        });
+
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
     #[test]
     fn test_num_tokens_from_messages_with_function_call() {
         let model = CString::new("gpt-4").unwrap();
@@ -0,0 +1,211 @@
+#[cfg(feature = "logging")]
+use log::warn;
+use std::ffi::{c_char, CStr};
+use tiktoken_rs::Rank;
+
+use crate::alloc::malloc_copy;
+use crate::corebpe::CoreBPE;
+
+/// Encodes `text` as `encode_ordinary` does, stopping once `limit` tokens
+/// have been produced. Returns the tokens and the number of bytes of `text`
+/// they cover, which is `text.len()` only if the whole text fits.
+fn encode_ordinary_up_to(
+    corebpe: &CoreBPE,
+    text: &str,
+    limit: usize,
+) -> Option<(Vec<Rank>, usize)> {
+    let mut tokens = Vec::new();
+    let mut text_offset = 0;
+    for (start, piece) in corebpe.split_ordinary(text) {
+        let mut end = start;
+        for part in corebpe.split_piece(piece.as_bytes())? {
+            if tokens.len() == limit {
+                return Some((tokens, text_offset));
+            }
+            tokens.push(corebpe.rank(part)?);
+            end += part.len();
+            text_offset = end;
+        }
+    }
+    Some((tokens, text.len()))
+}
+
+fn parse_text<'a>(ptr: *const CoreBPE, text: *const c_char) -> Option<(&'a CoreBPE, &'a str)> {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return None;
+    }
+    if text.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for text!");
+        return None;
+    }
+    let text = match unsafe { CStr::from_ptr(text) }.to_str() {
+        Ok(text) => text,
+        Err(_) => {
+            #[cfg(feature = "logging")]
+            warn!("Invalid UTF-8 sequence provided for text!");
+            return None;
+        }
+    };
+    Some((unsafe { &*ptr }, text))
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_count_ordinary_up_to(
+    ptr: *const CoreBPE,
+    text: *const c_char,
+    limit: usize,
+    text_offset: *mut usize,
+) -> usize {
+    let (corebpe, text) = match parse_text(ptr, text) {
+        Some(parsed) => parsed,
+        None => return usize::MAX,
+    };
+    let (tokens, offset) = match encode_ordinary_up_to(corebpe, text, limit) {
+        Some(encoded) => encoded,
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to encode!");
+            return usize::MAX;
+        }
+    };
+    unsafe {
+        if !text_offset.is_null() {
+            *text_offset = offset;
+        }
+    };
+    tokens.len()
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_encode_ordinary_up_to(
+    ptr: *const CoreBPE,
+    text: *const c_char,
+    limit: usize,
+    num_tokens: *mut usize,
+    text_offset: *mut usize,
+) -> *mut Rank {
+    let (corebpe, text) = match parse_text(ptr, text) {
+        Some(parsed) => parsed,
+        None => return std::ptr::null_mut(),
+    };
+    let (tokens, offset) = match encode_ordinary_up_to(corebpe, text, limit) {
+        Some(encoded) => encoded,
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to encode!");
+            return std::ptr::null_mut();
+        }
+    };
+    unsafe {
+        if !num_tokens.is_null() {
+            *num_tokens = tokens.len();
+        }
+        if !text_offset.is_null() {
+            *text_offset = offset;
+        }
+    };
+    malloc_copy::<Rank>(&tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::tiktoken_free;
+    use crate::corebpe::{tiktoken_cl100k_base, tiktoken_destroy_corebpe, tiktoken_o200k_base};
+    use std::ffi::CString;
+
+    #[test]
+    fn test_count_ordinary_up_to_fits() {
+        let corebpe = tiktoken_cl100k_base();
+        let text = CString::new("I am a cat.").unwrap();
+        let mut text_offset = 0;
+        let count =
+            tiktoken_corebpe_count_ordinary_up_to(corebpe, text.as_ptr(), 5, &mut text_offset);
+        assert_eq!(count, 5);
+        assert_eq!(text_offset, 11);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_count_ordinary_up_to_exceeds() {
+        let corebpe = tiktoken_cl100k_base();
+        let text = CString::new("I am a cat.").unwrap();
+        let mut text_offset = 0;
+        let count =
+            tiktoken_corebpe_count_ordinary_up_to(corebpe, text.as_ptr(), 3, &mut text_offset);
+        assert_eq!(count, 3);
+        // "I", " am", " a"
+        assert_eq!(text_offset, 6);
+        let count =
+            tiktoken_corebpe_count_ordinary_up_to(corebpe, text.as_ptr(), 0, &mut text_offset);
+        assert_eq!(count, 0);
+        assert_eq!(text_offset, 0);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_encode_ordinary_up_to_matches_prefix() {
+        let corebpe = tiktoken_o200k_base();
+        let text = "Hello, world! 猫です。  This has    odd spacing\n\nand 12345 numbers.";
+        let expected = unsafe { &*corebpe }.encode_ordinary(text);
+        let c_text = CString::new(text).unwrap();
+        for limit in 0..=expected.len() + 1 {
+            let mut num_tokens = 0;
+            let mut text_offset = 0;
+            let tokens = tiktoken_corebpe_encode_ordinary_up_to(
+                corebpe,
+                c_text.as_ptr(),
+                limit,
+                &mut num_tokens,
+                &mut text_offset,
+            );
+            let encoded = if tokens.is_null() {
+                &[][..]
+            } else {
+                unsafe { std::slice::from_raw_parts(tokens, num_tokens) }
+            };
+            let prefix = &expected[..limit.min(expected.len())];
+            assert_eq!(encoded, prefix);
+            let prefix_bytes = unsafe { &*corebpe }.decode_bytes(prefix).unwrap();
+            assert_eq!(text_offset, prefix_bytes.len());
+            tiktoken_free(tokens as *mut std::ffi::c_void);
+        }
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_count_ordinary_up_to_null_arguments() {
+        let corebpe = tiktoken_cl100k_base();
+        let text = CString::new("I am a cat.").unwrap();
+        assert_eq!(
+            tiktoken_corebpe_count_ordinary_up_to(
+                std::ptr::null(),
+                text.as_ptr(),
+                3,
+                std::ptr::null_mut()
+            ),
+            usize::MAX
+        );
+        assert_eq!(
+            tiktoken_corebpe_count_ordinary_up_to(
+                corebpe,
+                std::ptr::null(),
+                3,
+                std::ptr::null_mut()
+            ),
+            usize::MAX
+        );
+        assert!(tiktoken_corebpe_encode_ordinary_up_to(
+            corebpe,
+            std::ptr::null(),
+            3,
+            std::ptr::null_mut(),
+            std::ptr::null_mut()
+        )
+        .is_null());
+        tiktoken_destroy_corebpe(corebpe);
+    }
+}
@@ -0,0 +1,436 @@
+#[cfg(feature = "logging")]
+use log::warn;
+use std::collections::BTreeSet;
+use tiktoken_rs::Rank;
+
+use crate::alloc::malloc_copy;
+use crate::shard::NO_TOKEN;
+
+/// How documents are arranged into fixed-length sequences.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CTiktokenPackingStrategy {
+    /// Documents are laid out back to back in input order and cut every
+    /// `seq_len` tokens, so a document may continue in the next sequence.
+    Concatenate = 0,
+    /// Documents are kept whole where possible and placed with best-fit
+    /// decreasing bin packing, which minimizes padding.
+    BestFit = 1,
+}
+
+/// Part of a document placed in one sequence, identified by the document
+/// index and the token range of the document it covers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Segment {
+    document: usize,
+    start: usize,
+    end: usize,
+}
+
+impl Segment {
+    fn len(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+fn pack_concatenate(lengths: &[usize], seq_len: usize) -> Vec<Vec<Segment>> {
+    let mut sequences = Vec::new();
+    let mut current = Vec::new();
+    let mut used = 0;
+    for (document, &len) in lengths.iter().enumerate() {
+        let mut start = 0;
+        while start < len {
+            let end = len.min(start + seq_len - used);
+            current.push(Segment {
+                document,
+                start,
+                end,
+            });
+            used += end - start;
+            start = end;
+            if used == seq_len {
+                sequences.push(std::mem::take(&mut current));
+                used = 0;
+            }
+        }
+    }
+    if !current.is_empty() {
+        sequences.push(current);
+    }
+    sequences
+}
+
+/// Documents longer than `seq_len` fill whole sequences with their leading
+/// tokens, and only the rest is packed. Segments are packed longest first,
+/// each into the fullest sequence it fits in, ties going to the earliest
+/// sequence; within a sequence segments keep their input order.
+fn pack_best_fit(lengths: &[usize], seq_len: usize) -> Vec<Vec<Segment>> {
+    let mut sequences = Vec::new();
+    let mut segments = Vec::new();
+    for (document, &len) in lengths.iter().enumerate() {
+        let mut start = 0;
+        while len - start >= seq_len {
+            sequences.push(vec![Segment {
+                document,
+                start,
+                end: start + seq_len,
+            }]);
+            start += seq_len;
+        }
+        if start < len {
+            segments.push(Segment {
+                document,
+                start,
+                end: len,
+            });
+        }
+    }
+    segments.sort_by_key(|segment| std::cmp::Reverse(segment.len()));
+    let first_packed = sequences.len();
+    // Space left in each open sequence, keyed by (space, sequence index).
+    let mut open = BTreeSet::new();
+    for segment in segments {
+        let sequence = match open.range((segment.len(), 0)..).next().copied() {
+            Some((space, sequence)) => {
+                open.remove(&(space, sequence));
+                open.insert((space - segment.len(), sequence));
+                sequence
+            }
+            None => {
+                open.insert((seq_len - segment.len(), sequences.len()));
+                sequences.push(Vec::new());
+                sequences.len() - 1
+            }
+        };
+        sequences[sequence].push(segment);
+    }
+    for sequence in &mut sequences[first_packed..] {
+        sequence.sort();
+    }
+    sequences
+}
+
+/// Packed sequences as flat arrays of `seq_len` entries per sequence.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct PackedSequences {
+    tokens: Vec<Rank>,
+    segment_ids: Vec<u32>,
+    mask: Vec<bool>,
+}
+
+fn pack(
+    documents: &[&[Rank]],
+    seq_len: usize,
+    strategy: CTiktokenPackingStrategy,
+    separator: Rank,
+    pad_token: Rank,
+) -> PackedSequences {
+    let separator_len = usize::from(separator != NO_TOKEN);
+    let lengths: Vec<usize> = documents
+        .iter()
+        .map(|document| document.len() + separator_len)
+        .collect();
+    let sequences = match strategy {
+        CTiktokenPackingStrategy::Concatenate => pack_concatenate(&lengths, seq_len),
+        CTiktokenPackingStrategy::BestFit => pack_best_fit(&lengths, seq_len),
+    };
+    let mut packed = PackedSequences::default();
+    for sequence in sequences {
+        for (segment_id, segment) in (1..).zip(&sequence) {
+            let document = documents[segment.document];
+            let document_end = segment.end.min(document.len());
+            if segment.start < document_end {
+                packed
+                    .tokens
+                    .extend_from_slice(&document[segment.start..document_end]);
+            }
+            if segment.end > document.len() {
+                packed.tokens.push(separator);
+            }
+            packed
+                .segment_ids
+                .extend(std::iter::repeat_n(segment_id, segment.len()));
+        }
+        let used = packed.tokens.len() % seq_len;
+        let padding = if used == 0 { 0 } else { seq_len - used };
+        packed.mask.resize(packed.tokens.len(), true);
+        packed
+            .tokens
+            .extend(std::iter::repeat_n(pad_token, padding));
+        packed.segment_ids.extend(std::iter::repeat_n(0, padding));
+        packed.mask.extend(std::iter::repeat_n(false, padding));
+    }
+    packed
+}
+
+/// Packs `num_documents` token arrays into sequences of `seq_len` tokens and
+/// returns the number of sequences, or `usize::MAX` on failure.
+#[no_mangle]
+pub extern "C" fn tiktoken_pack_sequences(
+    documents: *const *const Rank,
+    num_tokens: *const usize,
+    num_documents: usize,
+    seq_len: usize,
+    strategy: CTiktokenPackingStrategy,
+    separator: Rank,
+    pad_token: Rank,
+    tokens: *mut *mut Rank,
+    segment_ids: *mut *mut u32,
+    mask: *mut *mut bool,
+) -> usize {
+    if num_documents != 0 && (documents.is_null() || num_tokens.is_null()) {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for documents or num_tokens!");
+        return usize::MAX;
+    }
+    if tokens.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for tokens!");
+        return usize::MAX;
+    }
+    if seq_len == 0 {
+        #[cfg(feature = "logging")]
+        warn!("Sequence length must be positive!");
+        return usize::MAX;
+    }
+    let mut slices = Vec::with_capacity(num_documents);
+    for index in 0..num_documents {
+        let (document, len) = unsafe { (*documents.add(index), *num_tokens.add(index)) };
+        if len == 0 {
+            slices.push(&[][..]);
+        } else if document.is_null() {
+            #[cfg(feature = "logging")]
+            warn!("Null pointer provided for document {}!", index);
+            return usize::MAX;
+        } else {
+            slices.push(unsafe { std::slice::from_raw_parts(document, len) });
+        }
+    }
+    let packed = pack(&slices, seq_len, strategy, separator, pad_token);
+    unsafe {
+        *tokens = malloc_copy::<Rank>(&packed.tokens);
+        if !segment_ids.is_null() {
+            *segment_ids = malloc_copy::<u32>(&packed.segment_ids);
+        }
+        if !mask.is_null() {
+            *mask = malloc_copy::<bool>(&packed.mask);
+        }
+    }
+    packed.tokens.len() / seq_len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::tiktoken_free;
+
+    const SEPARATOR: Rank = 100;
+    const PAD: Rank = 0;
+
+    fn documents() -> Vec<Vec<Rank>> {
+        vec![
+            vec![1, 2, 3],
+            vec![4, 5, 6, 7, 8, 9, 10],
+            vec![],
+            vec![11],
+            vec![12, 13, 14, 15],
+        ]
+    }
+
+    fn pack_vecs(
+        documents: &[Vec<Rank>],
+        seq_len: usize,
+        strategy: CTiktokenPackingStrategy,
+        separator: Rank,
+    ) -> PackedSequences {
+        let slices: Vec<&[Rank]> = documents.iter().map(Vec::as_slice).collect();
+        pack(&slices, seq_len, strategy, separator, PAD)
+    }
+
+    #[test]
+    fn test_pack_concatenate() {
+        let packed = pack_vecs(
+            &documents(),
+            6,
+            CTiktokenPackingStrategy::Concatenate,
+            SEPARATOR,
+        );
+        assert_eq!(
+            packed.tokens,
+            [
+                1, 2, 3, 100, 4, 5, //
+                6, 7, 8, 9, 10, 100, //
+                100, 11, 100, 12, 13, 14, //
+                15, 100, 0, 0, 0, 0,
+            ]
+        );
+        assert_eq!(
+            packed.segment_ids,
+            [
+                1, 1, 1, 1, 2, 2, //
+                1, 1, 1, 1, 1, 1, //
+                1, 2, 2, 3, 3, 3, //
+                1, 1, 0, 0, 0, 0,
+            ]
+        );
+        assert_eq!(packed.mask.iter().filter(|&&real| real).count(), 20);
+        assert!(!packed.mask[20] && packed.mask[19]);
+
+        let packed = pack_vecs(
+            &documents(),
+            5,
+            CTiktokenPackingStrategy::Concatenate,
+            NO_TOKEN,
+        );
+        assert_eq!(
+            packed.tokens,
+            [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+        );
+        assert_eq!(
+            packed.segment_ids,
+            [1, 1, 1, 2, 2, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2]
+        );
+        assert!(packed.mask.iter().all(|&real| real));
+    }
+
+    #[test]
+    fn test_pack_best_fit() {
+        let packed = pack_vecs(
+            &documents(),
+            6,
+            CTiktokenPackingStrategy::BestFit,
+            SEPARATOR,
+        );
+        // The 8-token document fills one sequence and leaves 2 tokens; the
+        // segments of 5, 4, 2, 2 and 1 tokens then go into three sequences.
+        assert_eq!(
+            packed.tokens,
+            [
+                4, 5, 6, 7, 8, 9, //
+                100, 12, 13, 14, 15, 100, //
+                1, 2, 3, 100, 10, 100, //
+                11, 100, 0, 0, 0, 0,
+            ]
+        );
+        assert_eq!(
+            packed.segment_ids,
+            [
+                1, 1, 1, 1, 1, 1, //
+                1, 2, 2, 2, 2, 2, //
+                1, 1, 1, 1, 2, 2, //
+                1, 1, 0, 0, 0, 0,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pack_keeps_every_token() {
+        let documents: Vec<Vec<Rank>> = (0..200u32)
+            .map(|document| {
+                (0..(document * 37) % 53)
+                    .map(|i| document * 1000 + i)
+                    .collect()
+            })
+            .collect();
+        for strategy in [
+            CTiktokenPackingStrategy::Concatenate,
+            CTiktokenPackingStrategy::BestFit,
+        ] {
+            for seq_len in [1, 7, 64, 4096] {
+                let packed = pack_vecs(&documents, seq_len, strategy, SEPARATOR);
+                assert_eq!(packed.tokens.len() % seq_len, 0);
+                assert_eq!(packed.segment_ids.len(), packed.tokens.len());
+                assert_eq!(packed.mask.len(), packed.tokens.len());
+                let mut real: Vec<Rank> = packed
+                    .tokens
+                    .iter()
+                    .zip(&packed.mask)
+                    .filter(|&(_, &real)| real)
+                    .map(|(&token, _)| token)
+                    .collect();
+                let mut expected: Vec<Rank> = documents
+                    .iter()
+                    .flat_map(|document| document.iter().copied().chain([SEPARATOR]))
+                    .collect();
+                if strategy == CTiktokenPackingStrategy::Concatenate {
+                    assert_eq!(real, expected);
+                } else {
+                    real.sort_unstable();
+                    expected.sort_unstable();
+                    assert_eq!(real, expected);
+                }
+                for (&segment_id, &real) in packed.segment_ids.iter().zip(&packed.mask) {
+                    assert_eq!(segment_id == 0, !real);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_pack_sequences_ffi() {
+        let documents = documents();
+        let pointers: Vec<*const Rank> = documents.iter().map(|d| d.as_ptr()).collect();
+        let lengths: Vec<usize> = documents.iter().map(Vec::len).collect();
+        let mut tokens = std::ptr::null_mut();
+        let mut segment_ids = std::ptr::null_mut();
+        let mut mask = std::ptr::null_mut();
+        let num_sequences = tiktoken_pack_sequences(
+            pointers.as_ptr(),
+            lengths.as_ptr(),
+            documents.len(),
+            6,
+            CTiktokenPackingStrategy::BestFit,
+            SEPARATOR,
+            PAD,
+            &mut tokens,
+            &mut segment_ids,
+            &mut mask,
+        );
+        assert_eq!(num_sequences, 4);
+        let expected = pack_vecs(&documents, 6, CTiktokenPackingStrategy::BestFit, SEPARATOR);
+        unsafe {
+            assert_eq!(std::slice::from_raw_parts(tokens, 24), expected.tokens);
+            assert_eq!(
+                std::slice::from_raw_parts(segment_ids, 24),
+                expected.segment_ids
+            );
+            assert_eq!(std::slice::from_raw_parts(mask, 24), expected.mask);
+        }
+        tiktoken_free(tokens as *mut std::ffi::c_void);
+        tiktoken_free(segment_ids as *mut std::ffi::c_void);
+        tiktoken_free(mask as *mut std::ffi::c_void);
+
+        let mut tokens = std::ptr::null_mut();
+        assert_eq!(
+            tiktoken_pack_sequences(
+                std::ptr::null(),
+                std::ptr::null(),
+                0,
+                6,
+                CTiktokenPackingStrategy::Concatenate,
+                NO_TOKEN,
+                PAD,
+                &mut tokens,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            ),
+            0
+        );
+        assert!(tokens.is_null());
+        assert_eq!(
+            tiktoken_pack_sequences(
+                pointers.as_ptr(),
+                lengths.as_ptr(),
+                documents.len(),
+                0,
+                CTiktokenPackingStrategy::Concatenate,
+                NO_TOKEN,
+                PAD,
+                &mut tokens,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            ),
+            usize::MAX
+        );
+    }
+}
@@ -0,0 +1,511 @@
+#[cfg(feature = "logging")]
+use log::warn;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::ffi::c_char;
+
+use crate::compare::num_tokens_from_messages;
+use crate::{parse_chat_messages, parse_required_string, CChatCompletionRequestMessage};
+
+/// Prices are expressed in US dollars per one million tokens.
+const TOKENS_PER_PRICE_UNIT: f64 = 1_000_000.0;
+
+/// Returned by the cost estimation functions on failure.
+const COST_ERROR: f64 = -1.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ModelPrice {
+    input: f64,
+    cached_input: Option<f64>,
+    output: f64,
+}
+
+impl ModelPrice {
+    fn is_valid(&self) -> bool {
+        let rates = [self.input, self.cached_input.unwrap_or(0.0), self.output];
+        rates.iter().all(|rate| rate.is_finite() && *rate >= 0.0)
+    }
+
+    fn cached_input_rate(&self) -> f64 {
+        self.cached_input.unwrap_or(self.input)
+    }
+}
+
+pub struct CTiktokenPriceTable {
+    prices: HashMap<String, ModelPrice>,
+}
+
+impl CTiktokenPriceTable {
+    fn from_prices(prices: HashMap<String, ModelPrice>) -> Option<Self> {
+        if let Some((_model, _)) = prices.iter().find(|(_, price)| !price.is_valid()) {
+            #[cfg(feature = "logging")]
+            warn!("Invalid price provided for {}!", _model);
+            return None;
+        }
+        Some(Self { prices })
+    }
+
+    /// Looks up a model by exact name, then without a `-YYYY-MM-DD` date
+    /// suffix, so `gpt-4o-2024-08-06` falls back to `gpt-4o`, then by the
+    /// longest wildcard entry such as `gpt-4o-mini*` that prefixes it. Other
+    /// prefixes do not match, so `gpt-4o` is never billed at `gpt-4` rates.
+    fn lookup(&self, model: &str) -> Option<&ModelPrice> {
+        if let Some(price) = self.prices.get(model) {
+            return Some(price);
+        }
+        if let Some(price) = strip_date_suffix(model).and_then(|base| self.prices.get(base)) {
+            return Some(price);
+        }
+        self.prices
+            .iter()
+            .filter_map(|(name, price)| Some((name.strip_suffix('*')?, price)))
+            .filter(|(prefix, _)| model.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, price)| price)
+    }
+
+    fn estimate(
+        &self,
+        model: &str,
+        input_tokens: usize,
+        cached_input_tokens: usize,
+        output_tokens: usize,
+    ) -> Option<f64> {
+        let price = match self.lookup(model) {
+            Some(price) => price,
+            None => {
+                #[cfg(feature = "logging")]
+                warn!("No price found for model {}!", model);
+                return None;
+            }
+        };
+        if cached_input_tokens > input_tokens {
+            #[cfg(feature = "logging")]
+            warn!("Cached input tokens exceed input tokens!");
+            return None;
+        }
+        let uncached_input_tokens = (input_tokens - cached_input_tokens) as f64;
+        let cost = uncached_input_tokens * price.input
+            + cached_input_tokens as f64 * price.cached_input_rate()
+            + output_tokens as f64 * price.output;
+        Some(cost / TOKENS_PER_PRICE_UNIT)
+    }
+}
+
+/// `model` without a trailing `-YYYY-MM-DD` snapshot date, if it has one.
+fn strip_date_suffix(model: &str) -> Option<&str> {
+    let split = model.len().checked_sub(11)?;
+    let (base, date) = (model.get(..split)?, model.get(split..)?.as_bytes());
+    let is_date = date.iter().enumerate().all(|(i, byte)| match i {
+        0 | 5 | 8 => *byte == b'-',
+        _ => byte.is_ascii_digit(),
+    });
+    (is_date && !base.is_empty()).then_some(base)
+}
+
+fn into_price_table(
+    prices: Result<HashMap<String, ModelPrice>, impl std::fmt::Display>,
+) -> *mut CTiktokenPriceTable {
+    let prices = match prices {
+        Ok(prices) => prices,
+        Err(_err) => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to parse price table: {}", _err);
+            return std::ptr::null_mut();
+        }
+    };
+    match CTiktokenPriceTable::from_prices(prices) {
+        Some(table) => Box::into_raw(Box::new(table)),
+        None => std::ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_price_table_new() -> *mut CTiktokenPriceTable {
+    Box::into_raw(Box::new(CTiktokenPriceTable {
+        prices: HashMap::new(),
+    }))
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_price_table_from_json(json: *const c_char) -> *mut CTiktokenPriceTable {
+    let json = match parse_required_string(json, "json") {
+        Ok(json) => json,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    into_price_table(serde_json::from_str(&json))
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_price_table_from_toml(toml: *const c_char) -> *mut CTiktokenPriceTable {
+    let toml = match parse_required_string(toml, "toml") {
+        Ok(toml) => toml,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    into_price_table(toml::from_str(&toml))
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_price_table_load(path: *const c_char) -> *mut CTiktokenPriceTable {
+    let path = match parse_required_string(path, "path") {
+        Ok(path) => path,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(_) => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to read price table from {}!", path);
+            return std::ptr::null_mut();
+        }
+    };
+    if path.to_ascii_lowercase().ends_with(".toml") {
+        into_price_table(toml::from_str(&contents))
+    } else {
+        into_price_table(serde_json::from_str(&contents))
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_price_table_set(
+    ptr: *mut CTiktokenPriceTable,
+    model: *const c_char,
+    input: f64,
+    cached_input: f64,
+    output: f64,
+) -> bool {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for price table!");
+        return false;
+    }
+    let model = match parse_required_string(model, "model") {
+        Ok(model) => model,
+        Err(_) => return false,
+    };
+    // A negative or NaN cached input rate means there is none, as when it
+    // is left out of a price file.
+    let price = ModelPrice {
+        input,
+        cached_input: (cached_input >= 0.0).then_some(cached_input),
+        output,
+    };
+    if !price.is_valid() {
+        #[cfg(feature = "logging")]
+        warn!("Invalid price provided for {}!", model);
+        return false;
+    }
+    unsafe { &mut *ptr }.prices.insert(model, price);
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_price_table_destroy(ptr: *mut CTiktokenPriceTable) {
+    if ptr.is_null() {
+        return;
+    }
+    unsafe {
+        let _ = Box::from_raw(ptr);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_estimate_cost(
+    ptr: *const CTiktokenPriceTable,
+    model: *const c_char,
+    input_tokens: usize,
+    cached_input_tokens: usize,
+    output_tokens: usize,
+) -> f64 {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for price table!");
+        return COST_ERROR;
+    }
+    let model = match parse_required_string(model, "model") {
+        Ok(model) => model,
+        Err(_) => return COST_ERROR,
+    };
+    let table = unsafe { &*ptr };
+    table
+        .estimate(&model, input_tokens, cached_input_tokens, output_tokens)
+        .unwrap_or(COST_ERROR)
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_estimate_chat_cost(
+    ptr: *const CTiktokenPriceTable,
+    model: *const c_char,
+    num_messages: u32,
+    messages: *const *mut CChatCompletionRequestMessage,
+    cached_input_tokens: usize,
+    completion_tokens: usize,
+) -> f64 {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for price table!");
+        return COST_ERROR;
+    }
+    if messages.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for messages!");
+        return COST_ERROR;
+    }
+    let model = match parse_required_string(model, "model") {
+        Ok(model) => model,
+        Err(_) => return COST_ERROR,
+    };
+    let messages = match parse_chat_messages(num_messages, messages) {
+        Ok(messages) => messages,
+        Err(_) => return COST_ERROR,
+    };
+    let input_tokens = match num_tokens_from_messages(&model, &messages) {
+        Some(num_tokens) => num_tokens,
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to get num tokens!");
+            return COST_ERROR;
+        }
+    };
+    let table = unsafe { &*ptr };
+    table
+        .estimate(&model, input_tokens, cached_input_tokens, completion_tokens)
+        .unwrap_or(COST_ERROR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+    use crate::{tiktoken_chat_message_destroy, tiktoken_chat_message_new};
+    use crate::{tiktoken_chat_message_set_content, tiktoken_num_tokens_from_messages};
+    use std::ffi::CString;
+
+    const PRICES_JSON: &str = r#"{
+        "gpt-4o": {"input": 2.5, "cached_input": 1.25, "output": 10.0},
+        "gpt-4o-mini": {"input": 0.15, "output": 0.6}
+    }"#;
+
+    const PRICES_TOML: &str = r#"
+        ["gpt-4o"]
+        input = 2.5
+        cached_input = 1.25
+        output = 10.0
+    "#;
+
+    fn assert_cost(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-12,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_price_table_from_json() {
+        let json = CString::new(PRICES_JSON).unwrap();
+        let model = CString::new("gpt-4o").unwrap();
+        let table = tiktoken_price_table_from_json(json.as_ptr());
+        assert!(!table.is_null());
+        let cost = tiktoken_estimate_cost(table, model.as_ptr(), 1_000_000, 0, 1_000_000);
+        assert_cost(cost, 12.5);
+        tiktoken_price_table_destroy(table);
+    }
+
+    #[test]
+    fn test_price_table_from_toml() {
+        let toml = CString::new(PRICES_TOML).unwrap();
+        let model = CString::new("gpt-4o").unwrap();
+        let table = tiktoken_price_table_from_toml(toml.as_ptr());
+        assert!(!table.is_null());
+        let cost = tiktoken_estimate_cost(table, model.as_ptr(), 2_000_000, 1_000_000, 0);
+        assert_cost(cost, 3.75);
+        tiktoken_price_table_destroy(table);
+    }
+
+    #[test]
+    fn test_price_table_from_json_invalid() {
+        let json = CString::new(r#"{"gpt-4o": {"input": -1.0, "output": 1.0}}"#).unwrap();
+        assert!(tiktoken_price_table_from_json(json.as_ptr()).is_null());
+        let json = CString::new("not json").unwrap();
+        assert!(tiktoken_price_table_from_json(json.as_ptr()).is_null());
+        assert!(tiktoken_price_table_from_json(std::ptr::null()).is_null());
+    }
+
+    #[test]
+    fn test_price_table_load() {
+        let path = TempPath::new("pricing-prices.toml");
+        std::fs::write(&path, PRICES_TOML).unwrap();
+        let table = tiktoken_price_table_load(path.c_path().as_ptr());
+        assert!(!table.is_null());
+        let expected = ModelPrice {
+            input: 2.5,
+            cached_input: Some(1.25),
+            output: 10.0,
+        };
+        assert_eq!(
+            unsafe { &*table }.prices,
+            HashMap::from([("gpt-4o".to_string(), expected)])
+        );
+        let model = CString::new("gpt-4o").unwrap();
+        let cost = tiktoken_estimate_cost(table, model.as_ptr(), 2_000_000, 1_000_000, 1_000_000);
+        assert_cost(cost, 13.75);
+        tiktoken_price_table_destroy(table);
+
+        let missing = TempPath::new("pricing-missing.toml");
+        assert!(tiktoken_price_table_load(missing.c_path().as_ptr()).is_null());
+    }
+
+    #[test]
+    fn test_price_table_set() {
+        let model = CString::new("my-model").unwrap();
+        let table = tiktoken_price_table_new();
+        assert!(tiktoken_price_table_set(
+            table,
+            model.as_ptr(),
+            1.0,
+            0.5,
+            2.0
+        ));
+        assert!(!tiktoken_price_table_set(
+            table,
+            model.as_ptr(),
+            f64::NAN,
+            0.5,
+            2.0
+        ));
+        let cost = tiktoken_estimate_cost(table, model.as_ptr(), 1_000, 500, 1_000);
+        assert_cost(cost, 0.00275);
+        for no_cached_input in [-1.0, f64::NAN] {
+            assert!(tiktoken_price_table_set(
+                table,
+                model.as_ptr(),
+                1.0,
+                no_cached_input,
+                2.0
+            ));
+            let cost = tiktoken_estimate_cost(table, model.as_ptr(), 1_000, 500, 1_000);
+            assert_cost(cost, 0.003);
+        }
+        assert!(!tiktoken_price_table_set(
+            table,
+            model.as_ptr(),
+            1.0,
+            f64::INFINITY,
+            2.0
+        ));
+        tiktoken_price_table_destroy(table);
+    }
+
+    #[test]
+    fn test_estimate_cost_date_suffix() {
+        let json = CString::new(PRICES_JSON).unwrap();
+        let model = CString::new("gpt-4o-mini-2024-07-18").unwrap();
+        let table = tiktoken_price_table_from_json(json.as_ptr());
+        let cost = tiktoken_estimate_cost(table, model.as_ptr(), 1_000_000, 1_000_000, 0);
+        assert_cost(cost, 0.15);
+        tiktoken_price_table_destroy(table);
+    }
+
+    #[test]
+    fn test_estimate_cost_no_prefix_match() {
+        let json = CString::new(r#"{"gpt-4": {"input": 30.0, "output": 60.0}}"#).unwrap();
+        let table = tiktoken_price_table_from_json(json.as_ptr());
+        for model in ["gpt-4o", "gpt-4.1", "gpt-4-turbo", "gpt-4-2024-13"] {
+            let model = CString::new(model).unwrap();
+            let cost = tiktoken_estimate_cost(table, model.as_ptr(), 1, 0, 1);
+            assert_eq!(cost, COST_ERROR);
+        }
+        tiktoken_price_table_destroy(table);
+    }
+
+    #[test]
+    fn test_estimate_cost_wildcard() {
+        let json = CString::new(
+            r#"{
+                "gpt-4o*": {"input": 2.5, "output": 10.0},
+                "gpt-4o-mini*": {"input": 0.15, "output": 0.6}
+            }"#,
+        )
+        .unwrap();
+        let table = tiktoken_price_table_from_json(json.as_ptr());
+        let model = CString::new("gpt-4o-mini-search-preview").unwrap();
+        let cost = tiktoken_estimate_cost(table, model.as_ptr(), 1_000_000, 0, 0);
+        assert_cost(cost, 0.15);
+        let model = CString::new("gpt-4o-audio-preview").unwrap();
+        let cost = tiktoken_estimate_cost(table, model.as_ptr(), 1_000_000, 0, 0);
+        assert_cost(cost, 2.5);
+        let model = CString::new("gpt-4").unwrap();
+        let cost = tiktoken_estimate_cost(table, model.as_ptr(), 1_000_000, 0, 0);
+        assert_eq!(cost, COST_ERROR);
+        tiktoken_price_table_destroy(table);
+    }
+
+    #[test]
+    fn test_strip_date_suffix() {
+        assert_eq!(strip_date_suffix("gpt-4o-2024-08-06"), Some("gpt-4o"));
+        assert_eq!(strip_date_suffix("gpt-4o"), None);
+        assert_eq!(strip_date_suffix("gpt-4-0613"), None);
+        assert_eq!(strip_date_suffix("-2024-08-06"), None);
+        assert_eq!(strip_date_suffix("gpt-4o-2024-08-0x"), None);
+    }
+
+    #[test]
+    fn test_estimate_cost_unknown_model() {
+        let json = CString::new(PRICES_JSON).unwrap();
+        let model = CString::new("cat-gpt").unwrap();
+        let table = tiktoken_price_table_from_json(json.as_ptr());
+        let cost = tiktoken_estimate_cost(table, model.as_ptr(), 1, 0, 1);
+        assert_eq!(cost, COST_ERROR);
+        tiktoken_price_table_destroy(table);
+    }
+
+    #[test]
+    fn test_estimate_cost_cached_exceeds_input() {
+        let json = CString::new(PRICES_JSON).unwrap();
+        let model = CString::new("gpt-4o").unwrap();
+        let table = tiktoken_price_table_from_json(json.as_ptr());
+        let cost = tiktoken_estimate_cost(table, model.as_ptr(), 1, 2, 0);
+        assert_eq!(cost, COST_ERROR);
+        tiktoken_price_table_destroy(table);
+    }
+
+    #[test]
+    fn test_estimate_cost_null_table() {
+        let model = CString::new("gpt-4o").unwrap();
+        let cost = tiktoken_estimate_cost(std::ptr::null(), model.as_ptr(), 1, 0, 1);
+        assert_eq!(cost, COST_ERROR);
+    }
+
+    #[test]
+    fn test_estimate_chat_cost() {
+        let json = CString::new(PRICES_JSON).unwrap();
+        let model = CString::new("gpt-4o").unwrap();
+        let role = CString::new("system").unwrap();
+        let content = CString::new("I am a cat.").unwrap();
+        let table = tiktoken_price_table_from_json(json.as_ptr());
+        let message = tiktoken_chat_message_new(role.as_ptr());
+        assert!(tiktoken_chat_message_set_content(message, content.as_ptr()));
+        let messages = [message];
+        let input_tokens = tiktoken_num_tokens_from_messages(model.as_ptr(), 1, messages.as_ptr());
+        let cost = tiktoken_estimate_chat_cost(table, model.as_ptr(), 1, messages.as_ptr(), 0, 100);
+        assert_cost(
+            cost,
+            (input_tokens as f64 * 2.5 + 100.0 * 10.0) / 1_000_000.0,
+        );
+        tiktoken_chat_message_destroy(message);
+        tiktoken_price_table_destroy(table);
+    }
+
+    #[test]
+    fn test_estimate_chat_cost_null_messages() {
+        let json = CString::new(PRICES_JSON).unwrap();
+        let model = CString::new("gpt-4o").unwrap();
+        let table = tiktoken_price_table_from_json(json.as_ptr());
+        let cost = tiktoken_estimate_chat_cost(table, model.as_ptr(), 0, std::ptr::null(), 0, 1);
+        assert_eq!(cost, COST_ERROR);
+        tiktoken_price_table_destroy(table);
+    }
+}
@@ -0,0 +1,711 @@
+#[cfg(feature = "logging")]
+use log::warn;
+use std::ffi::{c_char, CString};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use tiktoken_rs::Rank;
+
+use crate::alloc::malloc_copy;
+use crate::corebpe::CoreBPE;
+use crate::file::CTiktokenTokenFormat;
+use crate::parse_required_string;
+
+const MAGIC: &[u8; 8] = b"TKTSHARD";
+const VERSION: u32 = 1;
+const FLAG_EOT_SEPARATED: u32 = 1;
+pub(crate) const NO_TOKEN: u32 = u32::MAX;
+const END_OF_TEXT: &str = "<|endoftext|>";
+/// Size of the fixed part of the header, before the encoding name.
+const FIXED_HEADER_BYTES: usize = 48;
+
+fn padded(len: usize) -> usize {
+    len.div_ceil(8) * 8
+}
+
+/// Header of a token shard file. All fields are little-endian:
+///
+/// | offset | size | field                                         |
+/// |--------|------|-----------------------------------------------|
+/// | 0      | 8    | magic `TKTSHARD`                              |
+/// | 8      | 4    | version (1)                                   |
+/// | 12     | 4    | bytes per token (2 or 4)                      |
+/// | 16     | 4    | flags (bit 0: documents end with EOT)         |
+/// | 20     | 4    | vocabulary size                               |
+/// | 24     | 4    | `<\|endoftext\|>` rank, or `0xffffffff`       |
+/// | 28     | 4    | encoding name length                          |
+/// | 32     | 8    | number of tokens                              |
+/// | 40     | 8    | number of documents                           |
+/// | 48     | n    | encoding name, zero-padded to 8 bytes         |
+///
+/// The tokens follow, zero-padded to 8 bytes, and then one `u64` start
+/// offset per document plus the total number of tokens.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ShardHeader {
+    format: CTiktokenTokenFormat,
+    eot_separated: bool,
+    vocab_size: u32,
+    eot_token: u32,
+    name: String,
+    num_tokens: u64,
+    num_documents: u64,
+}
+
+impl ShardHeader {
+    fn len(&self) -> usize {
+        FIXED_HEADER_BYTES + padded(self.name.len())
+    }
+
+    fn to_bytes(&self) -> Option<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(self.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&u32::try_from(self.format.token_bytes()).ok()?.to_le_bytes());
+        let flags = if self.eot_separated {
+            FLAG_EOT_SEPARATED
+        } else {
+            0
+        };
+        bytes.extend_from_slice(&flags.to_le_bytes());
+        bytes.extend_from_slice(&self.vocab_size.to_le_bytes());
+        bytes.extend_from_slice(&self.eot_token.to_le_bytes());
+        bytes.extend_from_slice(&u32::try_from(self.name.len()).ok()?.to_le_bytes());
+        bytes.extend_from_slice(&self.num_tokens.to_le_bytes());
+        bytes.extend_from_slice(&self.num_documents.to_le_bytes());
+        bytes.extend_from_slice(self.name.as_bytes());
+        bytes.resize(self.len(), 0);
+        Some(bytes)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let u32_at = |at: usize| Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?));
+        let u64_at = |at: usize| Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?));
+        if bytes.get(..8)? != MAGIC || u32_at(8)? != VERSION {
+            return None;
+        }
+        let format = match u32_at(12)? {
+            2 => CTiktokenTokenFormat::Uint16,
+            4 => CTiktokenTokenFormat::Uint32,
+            _ => return None,
+        };
+        let name_len = u32_at(28)? as usize;
+        let name = bytes.get(FIXED_HEADER_BYTES..FIXED_HEADER_BYTES + name_len)?;
+        Some(Self {
+            format,
+            eot_separated: u32_at(16)? & FLAG_EOT_SEPARATED != 0,
+            vocab_size: u32_at(20)?,
+            eot_token: u32_at(24)?,
+            name: String::from_utf8(name.to_vec()).ok()?,
+            num_tokens: u64_at(32)?,
+            num_documents: u64_at(40)?,
+        })
+    }
+}
+
+/// Writes documents to a token shard file.
+pub struct CTiktokenShardWriter {
+    corebpe: *const CoreBPE,
+    path: String,
+    writer: Option<BufWriter<File>>,
+    header: ShardHeader,
+    offsets: Vec<u64>,
+}
+
+impl CTiktokenShardWriter {
+    pub(crate) fn create(
+        corebpe: &CoreBPE,
+        path: &str,
+        format: CTiktokenTokenFormat,
+        eot_separated: bool,
+    ) -> Option<Self> {
+        let vocab_size = match u32::try_from(corebpe.vocab_size()) {
+            Ok(vocab_size) if u64::from(vocab_size) <= format.max_vocab_size() => vocab_size,
+            _ => {
+                #[cfg(feature = "logging")]
+                warn!(
+                    "Vocabulary of {} does not fit the token format!",
+                    corebpe.name()
+                );
+                return None;
+            }
+        };
+        let eot_token = corebpe.special_token_rank(END_OF_TEXT);
+        if eot_separated && eot_token.is_none() {
+            #[cfg(feature = "logging")]
+            warn!("{} has no {} token!", corebpe.name(), END_OF_TEXT);
+            return None;
+        }
+        let header = ShardHeader {
+            format,
+            eot_separated,
+            vocab_size,
+            eot_token: eot_token.unwrap_or(NO_TOKEN),
+            name: corebpe.name().to_string(),
+            num_tokens: 0,
+            num_documents: 0,
+        };
+        let mut writer = match File::create(path) {
+            Ok(file) => BufWriter::new(file),
+            Err(_) => {
+                #[cfg(feature = "logging")]
+                warn!("Failed to create {}!", path);
+                return None;
+            }
+        };
+        // Counts are filled in by `finish`.
+        writer.write_all(&header.to_bytes()?).ok()?;
+        Some(Self {
+            corebpe,
+            path: path.to_string(),
+            writer: Some(writer),
+            header,
+            offsets: Vec::new(),
+        })
+    }
+
+    pub(crate) fn num_tokens(&self) -> u64 {
+        self.header.num_tokens
+    }
+
+    pub(crate) fn num_documents(&self) -> u64 {
+        self.header.num_documents
+    }
+
+    pub(crate) fn add_tokens(&mut self, tokens: &[Rank]) -> Option<()> {
+        self.writer.as_ref()?;
+        if tokens.iter().any(|&token| token >= self.header.vocab_size) {
+            #[cfg(feature = "logging")]
+            warn!("Token out of range for the shard vocabulary!");
+            return None;
+        }
+        let written = self.write_document(tokens);
+        if written.is_none() {
+            self.abandon();
+        }
+        written
+    }
+
+    fn write_document(&mut self, tokens: &[Rank]) -> Option<()> {
+        let writer = self.writer.as_mut()?;
+        let format = self.header.format;
+        format.write_tokens(writer, tokens)?;
+        if self.header.eot_separated {
+            format.write_tokens(writer, &[self.header.eot_token])?;
+        }
+        self.offsets.push(self.header.num_tokens);
+        self.header.num_tokens += tokens.len() as u64 + self.header.eot_separated as u64;
+        self.header.num_documents += 1;
+        Some(())
+    }
+
+    /// Writes the document offsets and the final header, completing the file.
+    pub(crate) fn finish(&mut self) -> Option<()> {
+        let finished = self.write_trailer();
+        if finished.is_none() {
+            self.abandon();
+        }
+        self.writer.take();
+        finished
+    }
+
+    fn write_trailer(&mut self) -> Option<()> {
+        let writer = self.writer.as_mut()?;
+        let tokens_len = self.header.num_tokens as usize * self.header.format.token_bytes();
+        writer
+            .write_all(&vec![0; padded(tokens_len) - tokens_len])
+            .ok()?;
+        for offset in self.offsets.iter().chain([&self.header.num_tokens]) {
+            writer.write_all(&offset.to_le_bytes()).ok()?;
+        }
+        writer.seek(SeekFrom::Start(0)).ok()?;
+        writer.write_all(&self.header.to_bytes()?).ok()?;
+        writer.flush().ok()
+    }
+
+    /// Removes the incomplete file; later calls fail.
+    fn abandon(&mut self) {
+        if self.writer.take().is_some() {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl Drop for CTiktokenShardWriter {
+    fn drop(&mut self) {
+        // An unfinished shard has no valid header, so do not leave it behind.
+        self.abandon();
+    }
+}
+
+/// Creates a shard file at `path` for tokens of `ptr`, which must outlive
+/// the writer. With `eot_separated`, each document is followed by the
+/// `<|endoftext|>` token.
+#[no_mangle]
+pub extern "C" fn tiktoken_shard_writer_new(
+    ptr: *const CoreBPE,
+    path: *const c_char,
+    format: CTiktokenTokenFormat,
+    eot_separated: bool,
+) -> *mut CTiktokenShardWriter {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return std::ptr::null_mut();
+    }
+    let path = match parse_required_string(path, "path") {
+        Ok(path) => path,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    match CTiktokenShardWriter::create(unsafe { &*ptr }, &path, format, eot_separated) {
+        Some(writer) => Box::into_raw(Box::new(writer)),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Appends a document given as tokens.
+#[no_mangle]
+pub extern "C" fn tiktoken_shard_writer_add_tokens(
+    writer: *mut CTiktokenShardWriter,
+    tokens: *const Rank,
+    num_tokens: usize,
+) -> bool {
+    if writer.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for writer!");
+        return false;
+    }
+    if tokens.is_null() && num_tokens != 0 {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for tokens!");
+        return false;
+    }
+    let tokens = if num_tokens == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(tokens, num_tokens) }
+    };
+    unsafe { &mut *writer }.add_tokens(tokens).is_some()
+}
+
+/// Appends a document given as text, encoded with `encode_ordinary`.
+#[no_mangle]
+pub extern "C" fn tiktoken_shard_writer_add_document(
+    writer: *mut CTiktokenShardWriter,
+    text: *const c_char,
+) -> bool {
+    if writer.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for writer!");
+        return false;
+    }
+    let text = match parse_required_string(text, "text") {
+        Ok(text) => text,
+        Err(_) => return false,
+    };
+    let writer = unsafe { &mut *writer };
+    let tokens = unsafe { &*writer.corebpe }.encode_ordinary(&text);
+    writer.add_tokens(&tokens).is_some()
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_shard_writer_finish(writer: *mut CTiktokenShardWriter) -> bool {
+    if writer.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for writer!");
+        return false;
+    }
+    let finished = unsafe { &mut *writer }.finish().is_some();
+    #[cfg(feature = "logging")]
+    if !finished {
+        warn!("Failed to finish shard!");
+    }
+    finished
+}
+
+/// Frees the writer. A shard that was not finished is removed.
+#[no_mangle]
+pub extern "C" fn tiktoken_shard_writer_destroy(writer: *mut CTiktokenShardWriter) {
+    if writer.is_null() {
+        return;
+    }
+    unsafe {
+        drop(Box::from_raw(writer));
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CTiktokenShardInfo {
+    pub format: CTiktokenTokenFormat,
+    pub eot_separated: bool,
+    pub vocab_size: u32,
+    /// `<|endoftext|>` rank, or `UINT32_MAX` if the encoding has none.
+    pub eot_token: Rank,
+    pub num_tokens: u64,
+    pub num_documents: u64,
+}
+
+/// A token shard file mapped into memory.
+pub struct CTiktokenShardReader {
+    mmap: memmap2::Mmap,
+    header: ShardHeader,
+    name: CString,
+    tokens_start: usize,
+    offsets_start: usize,
+}
+
+impl CTiktokenShardReader {
+    fn open(path: &str) -> Option<Self> {
+        let file = File::open(path).ok()?;
+        let mmap = unsafe { memmap2::Mmap::map(&file) }.ok()?;
+        let header = ShardHeader::from_bytes(&mmap)?;
+        let tokens_start = header.len();
+        let tokens_len = usize::try_from(header.num_tokens)
+            .ok()?
+            .checked_mul(header.format.token_bytes())?;
+        let offsets_start = tokens_start.checked_add(padded(tokens_len))?;
+        let offsets_len = usize::try_from(header.num_documents)
+            .ok()?
+            .checked_add(1)?
+            .checked_mul(8)?;
+        if offsets_start.checked_add(offsets_len)? != mmap.len() {
+            return None;
+        }
+        let name = CString::new(header.name.clone()).ok()?;
+        let reader = Self {
+            mmap,
+            header,
+            name,
+            tokens_start,
+            offsets_start,
+        };
+        let offsets_valid = reader.offset(0) == 0
+            && (0..=reader.header.num_documents as usize)
+                .map(|index| reader.offset(index))
+                .try_fold(0, |previous, offset| (offset >= previous).then_some(offset))
+                == Some(reader.header.num_tokens);
+        offsets_valid.then_some(reader)
+    }
+
+    fn offset(&self, index: usize) -> u64 {
+        let at = self.offsets_start + index * 8;
+        u64::from_le_bytes(self.mmap[at..at + 8].try_into().unwrap_or_default())
+    }
+
+    fn token(&self, index: usize) -> Rank {
+        match self.header.format {
+            CTiktokenTokenFormat::Uint16 => {
+                let at = self.tokens_start + index * 2;
+                u16::from_le_bytes([self.mmap[at], self.mmap[at + 1]]) as Rank
+            }
+            CTiktokenTokenFormat::Uint32 => {
+                let at = self.tokens_start + index * 4;
+                Rank::from_le_bytes(self.mmap[at..at + 4].try_into().unwrap_or_default())
+            }
+        }
+    }
+
+    /// Token range of document `index`, without its `<|endoftext|>`.
+    fn document(&self, index: usize) -> Option<std::ops::Range<usize>> {
+        if index as u64 >= self.header.num_documents {
+            return None;
+        }
+        let start = self.offset(index) as usize;
+        let mut end = self.offset(index + 1) as usize;
+        if self.header.eot_separated && end > start {
+            end -= 1;
+        }
+        Some(start..end)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_shard_reader_open(path: *const c_char) -> *mut CTiktokenShardReader {
+    let path = match parse_required_string(path, "path") {
+        Ok(path) => path,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    match CTiktokenShardReader::open(&path) {
+        Some(reader) => Box::into_raw(Box::new(reader)),
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to read shard from {}!", path);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_shard_reader_destroy(reader: *mut CTiktokenShardReader) {
+    if reader.is_null() {
+        return;
+    }
+    unsafe {
+        drop(Box::from_raw(reader));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_shard_reader_info(
+    reader: *const CTiktokenShardReader,
+    info: *mut CTiktokenShardInfo,
+) -> bool {
+    if reader.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for reader!");
+        return false;
+    }
+    if info.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for info!");
+        return false;
+    }
+    let header = &unsafe { &*reader }.header;
+    unsafe {
+        *info = CTiktokenShardInfo {
+            format: header.format,
+            eot_separated: header.eot_separated,
+            vocab_size: header.vocab_size,
+            eot_token: header.eot_token,
+            num_tokens: header.num_tokens,
+            num_documents: header.num_documents,
+        };
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_shard_reader_encoding_name(
+    reader: *const CTiktokenShardReader,
+) -> *const c_char {
+    if reader.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for reader!");
+        return std::ptr::null();
+    }
+    unsafe { &*reader }.name.as_ptr()
+}
+
+/// Copies up to `max_tokens` tokens starting at token `offset` into
+/// `tokens` and returns how many were copied.
+#[no_mangle]
+pub extern "C" fn tiktoken_shard_reader_read(
+    reader: *const CTiktokenShardReader,
+    offset: u64,
+    tokens: *mut Rank,
+    max_tokens: usize,
+) -> usize {
+    if reader.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for reader!");
+        return usize::MAX;
+    }
+    if tokens.is_null() && max_tokens != 0 {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for tokens!");
+        return usize::MAX;
+    }
+    let reader = unsafe { &*reader };
+    let available = reader.header.num_tokens.saturating_sub(offset);
+    let num_tokens = (max_tokens as u64).min(available) as usize;
+    if num_tokens == 0 {
+        return 0;
+    }
+    let tokens = unsafe { std::slice::from_raw_parts_mut(tokens, num_tokens) };
+    for (i, token) in tokens.iter_mut().enumerate() {
+        *token = reader.token(offset as usize + i);
+    }
+    num_tokens
+}
+
+/// Returns the tokens of document `index`, without the `<|endoftext|>`
+/// separator, as a buffer to free with `tiktoken_free`.
+#[no_mangle]
+pub extern "C" fn tiktoken_shard_reader_document(
+    reader: *const CTiktokenShardReader,
+    index: u64,
+    num_tokens: *mut usize,
+) -> *mut Rank {
+    if reader.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for reader!");
+        return std::ptr::null_mut();
+    }
+    if num_tokens.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for num_tokens!");
+        return std::ptr::null_mut();
+    }
+    let reader = unsafe { &*reader };
+    let range = match usize::try_from(index)
+        .ok()
+        .and_then(|index| reader.document(index))
+    {
+        Some(range) => range,
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("Document index out of range!");
+            return std::ptr::null_mut();
+        }
+    };
+    let tokens: Vec<Rank> = range.map(|i| reader.token(i)).collect();
+    unsafe {
+        *num_tokens = tokens.len();
+    }
+    malloc_copy::<Rank>(&tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::tiktoken_free;
+    use crate::corebpe::{tiktoken_cl100k_base, tiktoken_destroy_corebpe, tiktoken_r50k_base};
+    use crate::testutil::TempPath;
+
+    const DOCUMENTS: &[&str] = &["I am a cat.", "", "Hello, world!\n\nSecond paragraph."];
+
+    fn write_shard(
+        corebpe: *const CoreBPE,
+        path: &TempPath,
+        format: CTiktokenTokenFormat,
+        eot_separated: bool,
+    ) {
+        let writer =
+            tiktoken_shard_writer_new(corebpe, path.c_path().as_ptr(), format, eot_separated);
+        assert!(!writer.is_null());
+        for document in DOCUMENTS {
+            let text = CString::new(*document).unwrap();
+            assert!(tiktoken_shard_writer_add_document(writer, text.as_ptr()));
+        }
+        assert!(tiktoken_shard_writer_finish(writer));
+        tiktoken_shard_writer_destroy(writer);
+    }
+
+    fn read_document(reader: *const CTiktokenShardReader, index: u64) -> Vec<Rank> {
+        let mut num_tokens = 0;
+        let tokens = tiktoken_shard_reader_document(reader, index, &mut num_tokens);
+        if tokens.is_null() {
+            assert_eq!(num_tokens, 0);
+            return Vec::new();
+        }
+        let document = unsafe { std::slice::from_raw_parts(tokens, num_tokens) }.to_vec();
+        tiktoken_free(tokens as *mut std::ffi::c_void);
+        document
+    }
+
+    #[test]
+    fn test_shard_round_trip() {
+        for (constructor, format) in [
+            (
+                tiktoken_r50k_base as extern "C" fn() -> *mut CoreBPE,
+                CTiktokenTokenFormat::Uint16,
+            ),
+            (tiktoken_cl100k_base, CTiktokenTokenFormat::Uint32),
+        ] {
+            let corebpe = constructor();
+            let corebpe_ref = unsafe { &*corebpe };
+            let eot = corebpe_ref.special_token_rank(END_OF_TEXT).unwrap();
+            for eot_separated in [false, true] {
+                let path = TempPath::new("shard-round-trip.shard");
+                write_shard(corebpe, &path, format, eot_separated);
+                let reader = tiktoken_shard_reader_open(path.c_path().as_ptr());
+                assert!(!reader.is_null());
+
+                let mut info = CTiktokenShardInfo {
+                    format: CTiktokenTokenFormat::Uint32,
+                    eot_separated: false,
+                    vocab_size: 0,
+                    eot_token: 0,
+                    num_tokens: 0,
+                    num_documents: 0,
+                };
+                assert!(tiktoken_shard_reader_info(reader, &mut info));
+                let mut expected = Vec::new();
+                for (index, document) in DOCUMENTS.iter().enumerate() {
+                    let tokens = corebpe_ref.encode_ordinary(document);
+                    assert_eq!(read_document(reader, index as u64), tokens);
+                    expected.extend(tokens);
+                    if eot_separated {
+                        expected.push(eot);
+                    }
+                }
+                assert_eq!(info.format, format);
+                assert_eq!(info.eot_separated, eot_separated);
+                assert_eq!(info.vocab_size as usize, corebpe_ref.vocab_size());
+                assert_eq!(info.eot_token, eot);
+                assert_eq!(info.num_documents, DOCUMENTS.len() as u64);
+                assert_eq!(info.num_tokens, expected.len() as u64);
+                let name = unsafe {
+                    std::ffi::CStr::from_ptr(tiktoken_shard_reader_encoding_name(reader))
+                };
+                assert_eq!(name.to_str().unwrap(), corebpe_ref.name());
+
+                let mut tokens = vec![0; expected.len() + 4];
+                let read = tiktoken_shard_reader_read(reader, 2, tokens.as_mut_ptr(), tokens.len());
+                assert_eq!(&tokens[..read], &expected[2..]);
+                assert!(tiktoken_shard_reader_document(reader, 3, &mut 0).is_null());
+
+                tiktoken_shard_reader_destroy(reader);
+            }
+            tiktoken_destroy_corebpe(corebpe);
+        }
+    }
+
+    #[test]
+    fn test_shard_writer_rejects_invalid_input() {
+        let corebpe = tiktoken_cl100k_base();
+        let path = TempPath::new("shard-invalid.shard");
+        let c_path = path.c_path();
+        // cl100k_base does not fit 16-bit tokens.
+        let writer = tiktoken_shard_writer_new(
+            corebpe,
+            c_path.as_ptr(),
+            CTiktokenTokenFormat::Uint16,
+            false,
+        );
+        assert!(writer.is_null());
+
+        let writer = tiktoken_shard_writer_new(
+            corebpe,
+            c_path.as_ptr(),
+            CTiktokenTokenFormat::Uint32,
+            false,
+        );
+        let tokens = [1, 2, 200_000];
+        assert!(!tiktoken_shard_writer_add_tokens(
+            writer,
+            tokens.as_ptr(),
+            tokens.len()
+        ));
+        assert!(tiktoken_shard_writer_add_tokens(writer, tokens.as_ptr(), 2));
+        // An unfinished shard is removed.
+        assert!(path.exists());
+        tiktoken_shard_writer_destroy(writer);
+        assert!(!path.exists());
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_shard_reader_rejects_invalid_files() {
+        let corebpe = tiktoken_r50k_base();
+        let path = TempPath::new("shard-corrupt.shard");
+        write_shard(corebpe, &path, CTiktokenTokenFormat::Uint16, true);
+        let bytes = std::fs::read(&path).unwrap();
+
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(tiktoken_shard_reader_open(path.c_path().as_ptr()).is_null());
+        let mut corrupt = bytes.clone();
+        corrupt[0] = b'X';
+        std::fs::write(&path, &corrupt).unwrap();
+        assert!(tiktoken_shard_reader_open(path.c_path().as_ptr()).is_null());
+        let mut corrupt = bytes.clone();
+        let last_offset = corrupt.len() - 8;
+        corrupt[last_offset] ^= 1;
+        std::fs::write(&path, &corrupt).unwrap();
+        assert!(tiktoken_shard_reader_open(path.c_path().as_ptr()).is_null());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(tiktoken_shard_reader_open(path.c_path().as_ptr()).is_null());
+        assert!(tiktoken_shard_reader_open(std::ptr::null()).is_null());
+        tiktoken_destroy_corebpe(corebpe);
+    }
+}
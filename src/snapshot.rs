@@ -0,0 +1,665 @@
+use fancy_regex::Regex;
+#[cfg(feature = "logging")]
+use log::warn;
+use rustc_hash::FxHashMap;
+use std::collections::BinaryHeap;
+use std::ffi::c_char;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::OnceLock;
+use tiktoken_rs::{DecodeKeyError, EncodeError, Rank};
+
+use crate::corebpe::CoreBPE;
+use crate::{parse_required_string, CTiktokenTokenizer};
+
+// Snapshot layout, all integers little-endian:
+//
+//   header       magic, version, tokenizer, num_ranks, num_slots,
+//                num_special, name_len, pattern_len, special_bytes_len,
+//                token_bytes_len (u64)
+//   name         UTF-8
+//   pattern      UTF-8
+//   specials     num_special (rank u32, len u32), then the token bytes
+//   offsets      num_ranks + 1 u32 offsets into the token bytes; a rank
+//                without a token has an empty range
+//   slots        open-addressing hash table of num_slots ranks, indexed by
+//                the FNV-1a hash of the token bytes, u32::MAX when empty
+//   token bytes  ordinary tokens in rank order
+//
+// Loading checks every offset and slot once, a sequential read of the
+// mapped file without allocation or hashing, so it still grows with the
+// vocabulary but is far cheaper than building tables. Lookups then read the
+// file in place.
+
+const MAGIC: &[u8; 8] = b"TIKTOKSN";
+const VERSION: u32 = 1;
+const HEADER_LEN: usize = 48;
+const EMPTY_SLOT: u32 = u32::MAX;
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[at..at + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], at: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[at..at + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn tokenizer_from_u32(value: u32) -> Option<CTiktokenTokenizer> {
+    [
+        CTiktokenTokenizer::Unknown,
+        CTiktokenTokenizer::O200kHarmony,
+        CTiktokenTokenizer::O200kBase,
+        CTiktokenTokenizer::Cl100kBase,
+        CTiktokenTokenizer::P50kBase,
+        CTiktokenTokenizer::R50kBase,
+        CTiktokenTokenizer::P50kEdit,
+        CTiktokenTokenizer::Gpt2,
+    ]
+    .into_iter()
+    .find(|&tokenizer| tokenizer as u32 == value)
+}
+
+/// Serializes `corebpe` as a snapshot. Fails for encodings without a token
+/// for every single byte, which byte-level BPE needs.
+pub(crate) fn snapshot_contents(corebpe: &CoreBPE) -> Option<Vec<u8>> {
+    let ranks = corebpe.mergeable_ranks();
+    if (0..=u8::MAX).any(|byte| !ranks.contains_key(&[byte][..])) {
+        return None;
+    }
+    let num_ranks = ranks.values().max().map_or(0, |&rank| rank as usize + 1);
+    let mut by_rank: Vec<&[u8]> = vec![&[]; num_ranks];
+    for (token, &rank) in ranks {
+        by_rank[rank as usize] = token;
+    }
+    let num_slots = (ranks.len() * 2).next_power_of_two();
+    let mut slots = vec![EMPTY_SLOT; num_slots];
+    for (token, &rank) in ranks {
+        let mut slot = fnv1a(token) as usize & (num_slots - 1);
+        while slots[slot] != EMPTY_SLOT {
+            slot = (slot + 1) & (num_slots - 1);
+        }
+        slots[slot] = rank;
+    }
+    let special_tokens: Vec<(&str, Rank)> = corebpe.special_token_ranks().collect();
+    let special_bytes_len: usize = special_tokens.iter().map(|(token, _)| token.len()).sum();
+    let token_bytes_len: usize = by_rank.iter().map(|token| token.len()).sum();
+    u32::try_from(token_bytes_len).ok()?;
+
+    let name = corebpe.name().as_bytes();
+    let pattern = corebpe.pattern().as_bytes();
+    let mut contents = Vec::with_capacity(
+        HEADER_LEN + name.len() + pattern.len() + token_bytes_len + 4 * (num_ranks + num_slots),
+    );
+    contents.extend_from_slice(MAGIC);
+    for value in [
+        VERSION,
+        corebpe.tokenizer() as u32,
+        u32::try_from(num_ranks).ok()?,
+        u32::try_from(num_slots).ok()?,
+        u32::try_from(special_tokens.len()).ok()?,
+        u32::try_from(name.len()).ok()?,
+        u32::try_from(pattern.len()).ok()?,
+        u32::try_from(special_bytes_len).ok()?,
+    ] {
+        contents.extend_from_slice(&value.to_le_bytes());
+    }
+    contents.extend_from_slice(&(token_bytes_len as u64).to_le_bytes());
+    contents.extend_from_slice(name);
+    contents.extend_from_slice(pattern);
+    for (token, rank) in &special_tokens {
+        contents.extend_from_slice(&rank.to_le_bytes());
+        contents.extend_from_slice(&(token.len() as u32).to_le_bytes());
+    }
+    for (token, _) in &special_tokens {
+        contents.extend_from_slice(token.as_bytes());
+    }
+    let mut offset = 0u32;
+    contents.extend_from_slice(&offset.to_le_bytes());
+    for token in &by_rank {
+        offset += token.len() as u32;
+        contents.extend_from_slice(&offset.to_le_bytes());
+    }
+    for slot in slots {
+        contents.extend_from_slice(&slot.to_le_bytes());
+    }
+    for token in by_rank {
+        contents.extend_from_slice(token);
+    }
+    Some(contents)
+}
+
+/// Ordinary token tables of an encoding, read in place from a mapped
+/// snapshot file.
+pub(crate) struct Snapshot {
+    data: memmap2::Mmap,
+    num_ranks: usize,
+    num_slots: usize,
+    offsets_at: usize,
+    slots_at: usize,
+    bytes_at: usize,
+    special_ranks: FxHashMap<String, Rank>,
+    special_regex: OnceLock<Option<Regex>>,
+}
+
+/// Everything a snapshot holds besides the ordinary token tables.
+pub(crate) struct SnapshotHeader {
+    pub(crate) tokenizer: CTiktokenTokenizer,
+    pub(crate) name: String,
+    pub(crate) pattern: String,
+    pub(crate) special_tokens: Vec<(String, Rank)>,
+}
+
+impl Snapshot {
+    /// Checks that every section fits the file and that offsets and slots
+    /// point into it, so lookups stay in bounds.
+    fn parse(data: memmap2::Mmap) -> Option<(Self, SnapshotHeader)> {
+        if data.len() < HEADER_LEN || &data[..8] != MAGIC || read_u32(&data, 8) != VERSION {
+            return None;
+        }
+        let tokenizer = tokenizer_from_u32(read_u32(&data, 12))?;
+        let num_ranks = read_u32(&data, 16) as usize;
+        let num_slots = read_u32(&data, 20) as usize;
+        let num_special = read_u32(&data, 24) as usize;
+        let name_len = read_u32(&data, 28) as usize;
+        let pattern_len = read_u32(&data, 32) as usize;
+        let special_bytes_len = read_u32(&data, 36) as usize;
+        let token_bytes_len = usize::try_from(read_u64(&data, 40)).ok()?;
+        if !num_slots.is_power_of_two() {
+            return None;
+        }
+
+        let name_at = HEADER_LEN;
+        let pattern_at = name_at + name_len;
+        let specials_at = pattern_at + pattern_len;
+        let special_bytes_at = specials_at + 8 * num_special;
+        let offsets_at = special_bytes_at + special_bytes_len;
+        let slots_at = offsets_at + 4 * (num_ranks + 1);
+        let bytes_at = slots_at + 4 * num_slots;
+        if data.len() != bytes_at.checked_add(token_bytes_len)? {
+            return None;
+        }
+
+        let name = std::str::from_utf8(&data[name_at..pattern_at]).ok()?;
+        let pattern = std::str::from_utf8(&data[pattern_at..specials_at]).ok()?;
+        let mut special_tokens = Vec::with_capacity(num_special);
+        let mut special_at = special_bytes_at;
+        for index in 0..num_special {
+            let rank = read_u32(&data, specials_at + 8 * index);
+            let len = read_u32(&data, specials_at + 8 * index + 4) as usize;
+            let token = data.get(special_at..special_at + len)?;
+            special_tokens.push((std::str::from_utf8(token).ok()?.to_string(), rank));
+            special_at += len;
+        }
+        if special_at != offsets_at {
+            return None;
+        }
+        let mut last_offset = 0;
+        for rank in 0..=num_ranks {
+            let offset = read_u32(&data, offsets_at + 4 * rank) as usize;
+            if offset < last_offset {
+                return None;
+            }
+            last_offset = offset;
+        }
+        if last_offset != token_bytes_len {
+            return None;
+        }
+        for slot in 0..num_slots {
+            let rank = read_u32(&data, slots_at + 4 * slot);
+            if rank != EMPTY_SLOT && rank as usize >= num_ranks {
+                return None;
+            }
+        }
+
+        let header = SnapshotHeader {
+            tokenizer,
+            name: name.to_string(),
+            pattern: pattern.to_string(),
+            special_tokens,
+        };
+        let snapshot = Self {
+            num_ranks,
+            num_slots,
+            offsets_at,
+            slots_at,
+            bytes_at,
+            special_ranks: header.special_tokens.iter().cloned().collect(),
+            special_regex: OnceLock::new(),
+            data,
+        };
+        if (0..=u8::MAX).any(|byte| snapshot.rank(&[byte]).is_none()) {
+            return None;
+        }
+        Some((snapshot, header))
+    }
+
+    pub(crate) fn token(&self, rank: Rank) -> Option<&[u8]> {
+        let rank = rank as usize;
+        if rank >= self.num_ranks {
+            return None;
+        }
+        let start = read_u32(&self.data, self.offsets_at + 4 * rank) as usize;
+        let end = read_u32(&self.data, self.offsets_at + 4 * rank + 4) as usize;
+        (start < end).then(|| &self.data[self.bytes_at + start..self.bytes_at + end])
+    }
+
+    pub(crate) fn rank(&self, bytes: &[u8]) -> Option<Rank> {
+        let mask = self.num_slots - 1;
+        let mut slot = fnv1a(bytes) as usize & mask;
+        // A well-formed table always has empty slots; stop after one lap
+        // in case the file says otherwise.
+        for _ in 0..self.num_slots {
+            let rank = read_u32(&self.data, self.slots_at + 4 * slot);
+            if rank == EMPTY_SLOT {
+                return None;
+            }
+            if self.token(rank) == Some(bytes) {
+                return Some(rank);
+            }
+            slot = (slot + 1) & mask;
+        }
+        None
+    }
+
+    /// One more than the highest ordinary rank.
+    pub(crate) fn num_ranks(&self) -> usize {
+        self.num_ranks
+    }
+
+    /// Ordinary tokens and their ranks, in rank order.
+    pub(crate) fn tokens(&self) -> impl Iterator<Item = (&[u8], Rank)> {
+        (0..self.num_ranks as Rank).filter_map(|rank| Some((self.token(rank)?, rank)))
+    }
+
+    pub(crate) fn encode_piece(&self, piece: &[u8], tokens: &mut Vec<Rank>) -> usize {
+        match self.rank(piece) {
+            Some(rank) => {
+                tokens.push(rank);
+                1
+            }
+            None => {
+                let parts = byte_pair_encode(piece, |part| self.rank(part));
+                tokens.extend(&parts);
+                parts.len()
+            }
+        }
+    }
+
+    /// Same tokens as `tiktoken_rs::CoreBPE::encode_ordinary`.
+    pub(crate) fn encode_ordinary(&self, regex: &Regex, text: &str) -> Vec<Rank> {
+        let mut tokens = Vec::new();
+        for mat in regex.find_iter(text).filter_map(|mat| mat.ok()) {
+            self.encode_piece(mat.as_str().as_bytes(), &mut tokens);
+        }
+        tokens
+    }
+
+    fn special_regex(&self) -> Option<&Regex> {
+        self.special_regex
+            .get_or_init(|| {
+                let mut special_tokens: Vec<&str> =
+                    self.special_ranks.keys().map(String::as_str).collect();
+                if special_tokens.is_empty() {
+                    return None;
+                }
+                // Longest first, so that of two tokens starting at the same
+                // position the longer one is taken.
+                special_tokens.sort_by_key(|token| std::cmp::Reverse(token.len()));
+                let parts: Vec<String> = special_tokens
+                    .into_iter()
+                    .map(|token| fancy_regex::escape(token).into_owned())
+                    .collect();
+                Regex::new(&parts.join("|")).ok()
+            })
+            .as_ref()
+    }
+
+    /// Same result as `tiktoken_rs::CoreBPE::encode`: the tokens and how
+    /// many of them came from the last pre-tokenizer piece.
+    pub(crate) fn encode(
+        &self,
+        regex: &Regex,
+        text: &str,
+        allowed_special: impl Fn(&str) -> bool,
+    ) -> Result<(Vec<Rank>, usize), EncodeError> {
+        let special_regex = self.special_regex();
+        let mut tokens = Vec::new();
+        let mut start = 0;
+        let mut last_piece_token_len = 0;
+        loop {
+            let mut next_special = None;
+            let mut start_find = start;
+            while let Some(special_regex) = special_regex {
+                let found = special_regex
+                    .find_from_pos(text, start_find)
+                    .map_err(|error| EncodeError {
+                        message: format!("Regex error while tokenizing: {error}"),
+                    })?;
+                match found {
+                    Some(mat) if allowed_special(mat.as_str()) => {
+                        next_special = Some(mat);
+                        break;
+                    }
+                    Some(mat) => start_find = mat.start() + 1,
+                    None => break,
+                }
+            }
+            let end = next_special.map_or(text.len(), |mat| mat.start());
+            for mat in regex.find_iter(&text[start..end]) {
+                let mat = mat.map_err(|error| EncodeError {
+                    message: format!("Regex error while tokenizing: {error}"),
+                })?;
+                last_piece_token_len = self.encode_piece(mat.as_str().as_bytes(), &mut tokens);
+            }
+            match next_special {
+                Some(mat) => {
+                    tokens.push(self.special_ranks[mat.as_str()]);
+                    start = mat.end();
+                    last_piece_token_len = 0;
+                }
+                None => break,
+            }
+        }
+        Ok((tokens, last_piece_token_len))
+    }
+
+    pub(crate) fn encode_with_special_tokens(&self, regex: &Regex, text: &str) -> Vec<Rank> {
+        self.encode(regex, text, |_| true)
+            .map(|(tokens, _)| tokens)
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn decode_bytes<'a>(
+        &'a self,
+        special_token: impl Fn(Rank) -> Option<&'a [u8]>,
+        tokens: &[Rank],
+    ) -> Result<Vec<u8>, DecodeKeyError> {
+        let mut bytes = Vec::with_capacity(tokens.len() * 2);
+        for &token in tokens {
+            match self.token(token).or_else(|| special_token(token)) {
+                Some(token_bytes) => bytes.extend_from_slice(token_bytes),
+                None => return Err(DecodeKeyError { token }),
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+#[derive(Eq, PartialEq, Clone, Copy)]
+struct Merge {
+    start: usize,
+    rank: Rank,
+}
+
+impl Ord for Merge {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        other
+            .rank
+            .cmp(&self.rank)
+            .then_with(|| other.start.cmp(&self.start))
+    }
+}
+
+impl PartialOrd for Merge {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Byte pair encoding of one piece, merging like tiktoken: always the pair
+/// of lowest rank, the leftmost one on ties. Every single byte must have a
+/// rank.
+fn byte_pair_encode(piece: &[u8], rank_of: impl Fn(&[u8]) -> Option<Rank>) -> Vec<Rank> {
+    let rank = |bytes: &[u8]| rank_of(bytes).unwrap_or(Rank::MAX);
+    let len = piece.len();
+    if len == 1 {
+        return vec![rank(piece)];
+    }
+    // Parts still present form a linked list by start offset; `end[start]`
+    // is where the part starting at `start` ends.
+    let mut end: Vec<usize> = (1..=len).collect();
+    let mut prev: Vec<Option<usize>> = (0..len).map(|start| start.checked_sub(1)).collect();
+    let mut merged = vec![false; len];
+    let pair_rank = |start: usize, end: &[usize]| match end[start] {
+        next if next < len => rank(&piece[start..end[next]]),
+        _ => Rank::MAX,
+    };
+    let mut pair_ranks: Vec<Rank> = (0..len).map(|start| pair_rank(start, &end)).collect();
+    let mut heap: BinaryHeap<Merge> = pair_ranks
+        .iter()
+        .enumerate()
+        .filter(|(_, &rank)| rank != Rank::MAX)
+        .map(|(start, &rank)| Merge { start, rank })
+        .collect();
+    while let Some(Merge { start, rank }) = heap.pop() {
+        // Skip entries for parts merged away or pairs changed since.
+        if merged[start] || pair_ranks[start] != rank {
+            continue;
+        }
+        let next = end[start];
+        merged[next] = true;
+        end[start] = end[next];
+        if end[start] < len {
+            prev[end[start]] = Some(start);
+        }
+        for start in std::iter::once(start).chain(prev[start]) {
+            pair_ranks[start] = pair_rank(start, &end);
+            if pair_ranks[start] != Rank::MAX {
+                heap.push(Merge {
+                    start,
+                    rank: pair_ranks[start],
+                });
+            }
+        }
+    }
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    while start < len {
+        tokens.push(rank(&piece[start..end[start]]));
+        start = end[start];
+    }
+    tokens
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_save_snapshot(ptr: *const CoreBPE, path: *const c_char) -> bool {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return false;
+    }
+    let path = match parse_required_string(path, "path") {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let contents = match snapshot_contents(unsafe { &*ptr }) {
+        Some(contents) => contents,
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("Encoding cannot be saved as a snapshot!");
+            return false;
+        }
+    };
+    let file = match File::create(&path) {
+        Ok(file) => file,
+        Err(_) => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to create {}!", path);
+            return false;
+        }
+    };
+    let mut writer = BufWriter::new(file);
+    writer.write_all(&contents).is_ok() && writer.flush().is_ok()
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_load_snapshot(path: *const c_char) -> *mut CoreBPE {
+    let path = match parse_required_string(path, "path") {
+        Ok(path) => path,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(_) => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to open {}!", path);
+            return std::ptr::null_mut();
+        }
+    };
+    let data = match unsafe { memmap2::Mmap::map(&file) } {
+        Ok(data) => data,
+        Err(_) => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to map {}!", path);
+            return std::ptr::null_mut();
+        }
+    };
+    let corebpe = Snapshot::parse(data)
+        .and_then(|(snapshot, header)| CoreBPE::from_snapshot(snapshot, header));
+    match corebpe {
+        Some(corebpe) => Box::into_raw(Box::new(corebpe)),
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("Invalid snapshot {}!", path);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corebpe::{tiktoken_destroy_corebpe, tiktoken_p50k_edit};
+    use crate::testutil::TempPath;
+    use std::collections::HashSet;
+    use tiktoken_rs::tokenizer::Tokenizer;
+
+    /// Text mixing scripts, digits, whitespace runs and special tokens, plus
+    /// pieces long enough to need more than a few merges.
+    fn sample_texts() -> Vec<String> {
+        let alphabet: Vec<char> = "abcxyz ABC 0123456789 \n\t.,!?'-_ éü 日本語 🦀 <|>"
+            .chars()
+            .collect();
+        let mut state: u64 = 12345;
+        let mut random = String::new();
+        for _ in 0..2000 {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            random.push(alphabet[(state >> 33) as usize % alphabet.len()]);
+        }
+        vec![
+            String::new(),
+            "hello world".to_string(),
+            "def fib(n):\n    return n if n < 2 else fib(n - 1) + fib(n - 2)\n".to_string(),
+            "<|endoftext|>Some text<|fim_prefix|> and <|endofprompt|> <|start|>".to_string(),
+            "supercalifragilisticexpialidocious".repeat(8),
+            "!@#$%^&*()".repeat(20),
+            " ".repeat(150) + "x",
+            "ⓐⓑⓒ🦀".repeat(40),
+            random,
+        ]
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        for tokenizer in [
+            Tokenizer::R50kBase,
+            Tokenizer::P50kEdit,
+            Tokenizer::Cl100kBase,
+            Tokenizer::O200kBase,
+            Tokenizer::O200kHarmony,
+        ] {
+            let bpe = tiktoken_rs::bpe_for_tokenizer(tokenizer).unwrap();
+            let original = CoreBPE::from_tokenizer(bpe.clone(), tokenizer).unwrap();
+            let path = TempPath::new(&format!("snapshot-{}", original.name()));
+            let c_path = path.c_path();
+            assert!(tiktoken_corebpe_save_snapshot(&original, c_path.as_ptr()));
+            let loaded = tiktoken_corebpe_load_snapshot(c_path.as_ptr());
+            assert!(!loaded.is_null());
+            let loaded_ref = unsafe { &*loaded };
+            assert_eq!(loaded_ref.name(), original.name());
+            assert_eq!(loaded_ref.tokenizer(), original.tokenizer());
+            assert_eq!(loaded_ref.pattern(), original.pattern());
+            assert!(loaded_ref
+                .special_token_ranks()
+                .eq(original.special_token_ranks()));
+
+            let allowed: HashSet<&str> = ["<|endoftext|>"].into_iter().collect();
+            for text in sample_texts() {
+                let tokens = bpe.encode_ordinary(&text);
+                assert_eq!(loaded_ref.encode_ordinary(&text), tokens);
+                assert_eq!(loaded_ref.count_ordinary(&text), tokens.len());
+                assert_eq!(
+                    loaded_ref.encode_with_special_tokens(&text),
+                    bpe.encode_with_special_tokens(&text)
+                );
+                assert_eq!(
+                    loaded_ref.encode(&text, &allowed).unwrap(),
+                    bpe.encode(&text, &allowed).unwrap()
+                );
+                let tokens = bpe.encode_with_special_tokens(&text);
+                assert_eq!(loaded_ref.decode(&tokens).unwrap(), text);
+            }
+            assert_eq!(
+                loaded_ref.decode_bytes(&[Rank::MAX - 1]).unwrap_err().token,
+                Rank::MAX - 1
+            );
+            assert_eq!(loaded_ref.mergeable_ranks(), original.mergeable_ranks());
+            let mut special_tokens: Vec<&str> = loaded_ref
+                .special_token_ranks()
+                .map(|(token, _)| token)
+                .collect();
+            let mut expected: Vec<&str> = bpe.special_tokens().into_iter().collect();
+            special_tokens.sort_unstable();
+            expected.sort_unstable();
+            assert_eq!(special_tokens, expected);
+            tiktoken_destroy_corebpe(loaded);
+        }
+    }
+
+    #[test]
+    fn test_load_invalid_snapshot() {
+        let corebpe = tiktoken_p50k_edit();
+        let path = TempPath::new("snapshot-invalid");
+        let c_path = path.c_path();
+        assert!(tiktoken_corebpe_save_snapshot(corebpe, c_path.as_ptr()));
+        let contents = std::fs::read(&path).unwrap();
+
+        std::fs::write(&path, &contents[..contents.len() - 1]).unwrap();
+        assert!(tiktoken_corebpe_load_snapshot(c_path.as_ptr()).is_null());
+        let mut bad_magic = contents.clone();
+        bad_magic[0] = b'X';
+        std::fs::write(&path, &bad_magic).unwrap();
+        assert!(tiktoken_corebpe_load_snapshot(c_path.as_ptr()).is_null());
+        let mut bad_version = contents.clone();
+        bad_version[8] = 2;
+        std::fs::write(&path, &bad_version).unwrap();
+        assert!(tiktoken_corebpe_load_snapshot(c_path.as_ptr()).is_null());
+        std::fs::write(&path, b"").unwrap();
+        assert!(tiktoken_corebpe_load_snapshot(c_path.as_ptr()).is_null());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(tiktoken_corebpe_load_snapshot(c_path.as_ptr()).is_null());
+        assert!(tiktoken_corebpe_load_snapshot(std::ptr::null()).is_null());
+        assert!(!tiktoken_corebpe_save_snapshot(corebpe, std::ptr::null()));
+        assert!(!tiktoken_corebpe_save_snapshot(
+            std::ptr::null(),
+            c_path.as_ptr()
+        ));
+        tiktoken_destroy_corebpe(corebpe);
+    }
+}
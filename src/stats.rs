@@ -0,0 +1,329 @@
+#[cfg(feature = "logging")]
+use log::warn;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::ffi::c_char;
+use tiktoken_rs::Rank;
+
+use crate::corebpe::CoreBPE;
+use crate::parse_required_string;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CTiktokenTokenCount {
+    pub token: Rank,
+    pub count: usize,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CTiktokenTextStats {
+    pub num_tokens: usize,
+    pub num_unique_tokens: usize,
+    pub num_bytes: usize,
+    pub num_chars: usize,
+    pub bytes_per_token: f64,
+    pub chars_per_token: f64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CTiktokenTokenLength {
+    pub token: Rank,
+    pub num_bytes: usize,
+}
+
+fn ratio(numerator: usize, denominator: usize) -> f64 {
+    if denominator == 0 {
+        0.0
+    } else {
+        numerator as f64 / denominator as f64
+    }
+}
+
+/// Computes the statistics for `text` and returns the token counts sorted by
+/// descending frequency, ties broken by ascending rank.
+fn text_stats(corebpe: &CoreBPE, text: &str) -> (CTiktokenTextStats, Vec<CTiktokenTokenCount>) {
+    let tokens = corebpe.encode_ordinary(text);
+
+    let mut counts: HashMap<Rank, usize> = HashMap::new();
+    for &token in &tokens {
+        *counts.entry(token).or_insert(0) += 1;
+    }
+
+    let mut frequencies: Vec<CTiktokenTokenCount> = counts
+        .into_iter()
+        .map(|(token, count)| CTiktokenTokenCount { token, count })
+        .collect();
+    frequencies.sort_by(|a, b| b.count.cmp(&a.count).then(a.token.cmp(&b.token)));
+
+    let num_chars = text.chars().count();
+    let stats = CTiktokenTextStats {
+        num_tokens: tokens.len(),
+        num_unique_tokens: frequencies.len(),
+        num_bytes: text.len(),
+        num_chars,
+        bytes_per_token: ratio(text.len(), tokens.len()),
+        chars_per_token: ratio(num_chars, tokens.len()),
+    };
+    (stats, frequencies)
+}
+
+/// Returns the distinct tokens of `text` with their lengths, longest first,
+/// ties broken by ascending rank.
+fn token_lengths(corebpe: &CoreBPE, text: &str) -> Vec<CTiktokenTokenLength> {
+    let mut tokens = corebpe.encode_ordinary(text);
+    tokens.sort_unstable();
+    tokens.dedup();
+    let mut lengths: Vec<CTiktokenTokenLength> = tokens
+        .into_iter()
+        .map(|token| CTiktokenTokenLength {
+            token,
+            num_bytes: corebpe
+                .decode_bytes(&[token])
+                .map_or(0, |bytes| bytes.len()),
+        })
+        .collect();
+    lengths.sort_by_key(|length| Reverse(length.num_bytes));
+    lengths
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_text_stats(
+    ptr: *const CoreBPE,
+    text: *const c_char,
+    stats: *mut CTiktokenTextStats,
+    top_tokens: *mut CTiktokenTokenCount,
+    top_tokens_len: usize,
+    num_top_tokens: *mut usize,
+) -> bool {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return false;
+    }
+    if stats.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for stats!");
+        return false;
+    }
+    if top_tokens.is_null() && top_tokens_len != 0 {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for top_tokens!");
+        return false;
+    }
+    let text = match parse_required_string(text, "text") {
+        Ok(text) => text,
+        Err(_) => return false,
+    };
+    let corebpe = unsafe { &*ptr };
+    let (text_stats, frequencies) = text_stats(corebpe, &text);
+    let num_written = frequencies.len().min(top_tokens_len);
+    unsafe {
+        *stats = text_stats;
+        if num_written > 0 {
+            std::ptr::copy_nonoverlapping(frequencies.as_ptr(), top_tokens, num_written);
+        }
+        if !num_top_tokens.is_null() {
+            *num_top_tokens = num_written;
+        }
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_longest_tokens(
+    ptr: *const CoreBPE,
+    text: *const c_char,
+    longest_tokens: *mut CTiktokenTokenLength,
+    longest_tokens_len: usize,
+) -> usize {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return usize::MAX;
+    }
+    if longest_tokens.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for longest_tokens!");
+        return usize::MAX;
+    }
+    let text = match parse_required_string(text, "text") {
+        Ok(text) => text,
+        Err(_) => return usize::MAX,
+    };
+    let corebpe = unsafe { &*ptr };
+    let lengths = token_lengths(corebpe, &text);
+    let num_written = lengths.len().min(longest_tokens_len);
+    unsafe {
+        std::ptr::copy_nonoverlapping(lengths.as_ptr(), longest_tokens, num_written);
+    }
+    num_written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corebpe::{tiktoken_cl100k_base, tiktoken_destroy_corebpe};
+    use std::ffi::CString;
+
+    #[test]
+    fn test_corebpe_text_stats() {
+        let corebpe = tiktoken_cl100k_base();
+        let text = CString::new("I am a cat. I am a cat.").unwrap();
+        let mut stats = CTiktokenTextStats::default();
+        let mut top_tokens = [CTiktokenTokenCount::default(); 3];
+        let mut num_top_tokens = 0;
+        assert!(tiktoken_corebpe_text_stats(
+            corebpe,
+            text.as_ptr(),
+            &mut stats,
+            top_tokens.as_mut_ptr(),
+            top_tokens.len(),
+            &mut num_top_tokens,
+        ));
+        // [40, 1097, 264, 8415, 13, 358, 1097, 264, 8415, 13]
+        assert_eq!(stats.num_tokens, 10);
+        assert_eq!(stats.num_unique_tokens, 6);
+        assert_eq!(stats.num_bytes, 23);
+        assert_eq!(stats.num_chars, 23);
+        assert_eq!(stats.bytes_per_token, 2.3);
+        assert_eq!(num_top_tokens, 3);
+        let top_tokens: Vec<(Rank, usize)> =
+            top_tokens.iter().map(|t| (t.token, t.count)).collect();
+        assert_eq!(top_tokens, vec![(13, 2), (264, 2), (1097, 2)]);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_corebpe_text_stats_multibyte() {
+        let corebpe = tiktoken_cl100k_base();
+        let text = CString::new("猫です").unwrap();
+        let mut stats = CTiktokenTextStats::default();
+        assert!(tiktoken_corebpe_text_stats(
+            corebpe,
+            text.as_ptr(),
+            &mut stats,
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+        ));
+        assert_eq!(stats.num_bytes, 9);
+        assert_eq!(stats.num_chars, 3);
+        assert_eq!(stats.chars_per_token, 3.0 / stats.num_tokens as f64);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_corebpe_text_stats_empty_text() {
+        let corebpe = tiktoken_cl100k_base();
+        let text = CString::new("").unwrap();
+        let mut stats = CTiktokenTextStats::default();
+        let mut num_top_tokens = usize::MAX;
+        assert!(tiktoken_corebpe_text_stats(
+            corebpe,
+            text.as_ptr(),
+            &mut stats,
+            std::ptr::null_mut(),
+            0,
+            &mut num_top_tokens,
+        ));
+        assert_eq!(stats, CTiktokenTextStats::default());
+        assert_eq!(num_top_tokens, 0);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_corebpe_text_stats_null_arguments() {
+        let corebpe = tiktoken_cl100k_base();
+        let text = CString::new("I am a cat.").unwrap();
+        let mut stats = CTiktokenTextStats::default();
+        assert!(!tiktoken_corebpe_text_stats(
+            std::ptr::null(),
+            text.as_ptr(),
+            &mut stats,
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+        ));
+        assert!(!tiktoken_corebpe_text_stats(
+            corebpe,
+            text.as_ptr(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+        ));
+        assert!(!tiktoken_corebpe_text_stats(
+            corebpe,
+            text.as_ptr(),
+            &mut stats,
+            std::ptr::null_mut(),
+            1,
+            std::ptr::null_mut(),
+        ));
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_corebpe_longest_tokens() {
+        let corebpe = tiktoken_cl100k_base();
+        let text = CString::new("I am a cat. I am a cat.").unwrap();
+        let mut longest_tokens = [CTiktokenTokenLength::default(); 8];
+        assert_eq!(
+            tiktoken_corebpe_longest_tokens(corebpe, text.as_ptr(), longest_tokens.as_mut_ptr(), 8),
+            6
+        );
+        let lengths: Vec<(Rank, usize)> = longest_tokens[..6]
+            .iter()
+            .map(|length| (length.token, length.num_bytes))
+            .collect();
+        // " cat", " am", then " a" and " I", then "." and "I".
+        assert_eq!(
+            lengths,
+            [(8415, 4), (1097, 3), (264, 2), (358, 2), (13, 1), (40, 1)]
+        );
+
+        let mut longest_tokens = [CTiktokenTokenLength::default(); 2];
+        assert_eq!(
+            tiktoken_corebpe_longest_tokens(corebpe, text.as_ptr(), longest_tokens.as_mut_ptr(), 2),
+            2
+        );
+        assert_eq!(longest_tokens[1].token, 1097);
+        assert_eq!(
+            tiktoken_corebpe_longest_tokens(corebpe, text.as_ptr(), longest_tokens.as_mut_ptr(), 0),
+            0
+        );
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_corebpe_longest_tokens_null_arguments() {
+        let corebpe = tiktoken_cl100k_base();
+        let text = CString::new("I am a cat.").unwrap();
+        let mut longest_tokens = [CTiktokenTokenLength::default(); 1];
+        assert_eq!(
+            tiktoken_corebpe_longest_tokens(
+                std::ptr::null(),
+                text.as_ptr(),
+                longest_tokens.as_mut_ptr(),
+                1
+            ),
+            usize::MAX
+        );
+        assert_eq!(
+            tiktoken_corebpe_longest_tokens(
+                corebpe,
+                std::ptr::null(),
+                longest_tokens.as_mut_ptr(),
+                1
+            ),
+            usize::MAX
+        );
+        assert_eq!(
+            tiktoken_corebpe_longest_tokens(corebpe, text.as_ptr(), std::ptr::null_mut(), 1),
+            usize::MAX
+        );
+        tiktoken_destroy_corebpe(corebpe);
+    }
+}
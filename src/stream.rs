@@ -0,0 +1,481 @@
+#[cfg(feature = "logging")]
+use log::warn;
+use std::collections::VecDeque;
+use std::ffi::c_void;
+use tiktoken_rs::Rank;
+
+use crate::bytes::CTiktokenInvalidUtf8Policy;
+use crate::corebpe::CoreBPE;
+use crate::incremental::STABLE_MARGIN;
+
+/// Receives each batch of tokens produced by a stream encoder.
+pub type CTiktokenTokenCallback =
+    Option<unsafe extern "C" fn(tokens: *const Rank, num_tokens: usize, user_data: *mut c_void)>;
+
+/// Encodes text arriving in arbitrary byte chunks, producing the same tokens
+/// as `tiktoken_corebpe_encode_ordinary_bytes` on the whole input.
+///
+/// Only the text after the last pre-tokenizer piece that further input
+/// cannot change is buffered, so memory stays bounded by the longest piece.
+pub struct CTiktokenStreamEncoder {
+    corebpe: *const CoreBPE,
+    policy: CTiktokenInvalidUtf8Policy,
+    /// Bytes of a character split across chunks.
+    partial: Vec<u8>,
+    /// Decoded text whose tokens are not final yet.
+    text: String,
+    ready: VecDeque<Rank>,
+    callback: CTiktokenTokenCallback,
+    user_data: *mut c_void,
+}
+
+impl CTiktokenStreamEncoder {
+    fn new(corebpe: *const CoreBPE, policy: CTiktokenInvalidUtf8Policy) -> Self {
+        Self {
+            corebpe,
+            policy,
+            partial: Vec::new(),
+            text: String::new(),
+            ready: VecDeque::new(),
+            callback: None,
+            user_data: std::ptr::null_mut(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.partial.clear();
+        self.text.clear();
+    }
+
+    /// Encodes the pieces of the buffered text that end at or before
+    /// `limit` and drops them from the buffer.
+    fn encode_text(&mut self, limit: usize, tokens: &mut Vec<Rank>) -> bool {
+        let corebpe = unsafe { &*self.corebpe };
+        let mut encoded_len = 0;
+        for (start, piece) in corebpe.split_ordinary(&self.text) {
+            let end = start + piece.len();
+            if end > limit {
+                break;
+            }
+            match corebpe.encode_piece(piece.as_bytes()) {
+                Some(piece_tokens) => tokens.extend(piece_tokens),
+                None => return false,
+            }
+            encoded_len = end;
+        }
+        self.text.drain(..encoded_len);
+        true
+    }
+
+    /// Handles one invalid UTF-8 sequence according to the policy.
+    fn encode_invalid(&mut self, invalid: &[u8], tokens: &mut Vec<Rank>) -> bool {
+        match self.policy {
+            CTiktokenInvalidUtf8Policy::Error => false,
+            CTiktokenInvalidUtf8Policy::Replace => {
+                self.text.push(char::REPLACEMENT_CHARACTER);
+                true
+            }
+            CTiktokenInvalidUtf8Policy::Bytes => {
+                // Valid runs are encoded on their own, so the text before
+                // the sequence is complete.
+                if !self.encode_text(usize::MAX, tokens) {
+                    return false;
+                }
+                match unsafe { &*self.corebpe }.encode_piece(invalid) {
+                    Some(piece_tokens) => {
+                        tokens.extend(piece_tokens);
+                        true
+                    }
+                    None => false,
+                }
+            }
+        }
+    }
+
+    /// Appends `bytes` to the buffered text. An incomplete character at the
+    /// end is kept for the next chunk, or treated as invalid if `finish`.
+    fn decode(&mut self, mut bytes: &[u8], finish: bool, tokens: &mut Vec<Rank>) -> bool {
+        while !bytes.is_empty() {
+            let (valid_len, invalid_len) = match std::str::from_utf8(bytes) {
+                Ok(_) => (bytes.len(), Some(0)),
+                Err(err) => (err.valid_up_to(), err.error_len()),
+            };
+            let (valid, rest) = bytes.split_at(valid_len);
+            self.text
+                .push_str(std::str::from_utf8(valid).unwrap_or_default());
+            let invalid_len = match invalid_len {
+                Some(invalid_len) => invalid_len,
+                None if finish => rest.len(),
+                None => {
+                    self.partial = rest.to_vec();
+                    return true;
+                }
+            };
+            let (invalid, rest) = rest.split_at(invalid_len);
+            if !invalid.is_empty() && !self.encode_invalid(invalid, tokens) {
+                return false;
+            }
+            bytes = rest;
+        }
+        true
+    }
+
+    fn feed(&mut self, mut bytes: &[u8], finish: bool, tokens: &mut Vec<Rank>) -> bool {
+        if !self.partial.is_empty() {
+            // Move bytes over until the split character is complete or
+            // known to be invalid.
+            let mut partial = std::mem::take(&mut self.partial);
+            while let Some((&byte, rest)) = bytes.split_first() {
+                partial.push(byte);
+                bytes = rest;
+                match std::str::from_utf8(&partial) {
+                    Err(err) if err.error_len().is_none() => continue,
+                    _ => break,
+                }
+            }
+            if !self.decode(&partial, finish && bytes.is_empty(), tokens) {
+                return false;
+            }
+        }
+        if !self.decode(bytes, finish, tokens) {
+            return false;
+        }
+        let limit = match finish {
+            true => usize::MAX,
+            false => self.text.len().saturating_sub(STABLE_MARGIN),
+        };
+        self.encode_text(limit, tokens)
+    }
+
+    fn deliver(&mut self, tokens: Vec<Rank>) {
+        if tokens.is_empty() {
+            return;
+        }
+        match self.callback {
+            Some(callback) => unsafe { callback(tokens.as_ptr(), tokens.len(), self.user_data) },
+            None => self.ready.extend(tokens),
+        }
+    }
+}
+
+/// Creates a stream encoder for `ptr`, which must outlive it.
+#[no_mangle]
+pub extern "C" fn tiktoken_stream_encoder_new(
+    ptr: *const CoreBPE,
+    policy: CTiktokenInvalidUtf8Policy,
+) -> *mut CTiktokenStreamEncoder {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return std::ptr::null_mut();
+    }
+    Box::into_raw(Box::new(CTiktokenStreamEncoder::new(ptr, policy)))
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_stream_encoder_destroy(encoder: *mut CTiktokenStreamEncoder) {
+    if encoder.is_null() {
+        return;
+    }
+    unsafe {
+        drop(Box::from_raw(encoder));
+    }
+}
+
+/// Delivers tokens to `callback` as they are produced instead of queueing
+/// them for `tiktoken_stream_encoder_read`. NULL restores queueing.
+#[no_mangle]
+pub extern "C" fn tiktoken_stream_encoder_set_callback(
+    encoder: *mut CTiktokenStreamEncoder,
+    callback: CTiktokenTokenCallback,
+    user_data: *mut c_void,
+) -> bool {
+    if encoder.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for encoder!");
+        return false;
+    }
+    let encoder = unsafe { &mut *encoder };
+    encoder.callback = callback;
+    encoder.user_data = user_data;
+    true
+}
+
+fn feed(
+    encoder: *mut CTiktokenStreamEncoder,
+    bytes: *const u8,
+    num_bytes: usize,
+    finish: bool,
+) -> bool {
+    if encoder.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for encoder!");
+        return false;
+    }
+    if bytes.is_null() && num_bytes != 0 {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for bytes!");
+        return false;
+    }
+    let bytes = if num_bytes == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(bytes, num_bytes) }
+    };
+    let encoder = unsafe { &mut *encoder };
+    let mut tokens = Vec::new();
+    let fed = encoder.feed(bytes, finish, &mut tokens);
+    encoder.deliver(tokens);
+    if !fed {
+        #[cfg(feature = "logging")]
+        warn!("Invalid UTF-8 sequence provided for bytes!");
+        encoder.reset();
+    }
+    fed
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_stream_encoder_feed(
+    encoder: *mut CTiktokenStreamEncoder,
+    bytes: *const u8,
+    num_bytes: usize,
+) -> bool {
+    feed(encoder, bytes, num_bytes, false)
+}
+
+/// Encodes the buffered remainder of the input. The encoder can then be
+/// fed the next stream.
+#[no_mangle]
+pub extern "C" fn tiktoken_stream_encoder_finish(encoder: *mut CTiktokenStreamEncoder) -> bool {
+    feed(encoder, std::ptr::null(), 0, true)
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_stream_encoder_available(
+    encoder: *const CTiktokenStreamEncoder,
+) -> usize {
+    if encoder.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for encoder!");
+        return usize::MAX;
+    }
+    unsafe { &*encoder }.ready.len()
+}
+
+/// Moves up to `max_tokens` queued tokens into `tokens` and returns how many
+/// were written.
+#[no_mangle]
+pub extern "C" fn tiktoken_stream_encoder_read(
+    encoder: *mut CTiktokenStreamEncoder,
+    tokens: *mut Rank,
+    max_tokens: usize,
+) -> usize {
+    if encoder.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for encoder!");
+        return usize::MAX;
+    }
+    if tokens.is_null() && max_tokens != 0 {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for tokens!");
+        return usize::MAX;
+    }
+    let encoder = unsafe { &mut *encoder };
+    let num_tokens = max_tokens.min(encoder.ready.len());
+    if num_tokens == 0 {
+        return 0;
+    }
+    let tokens = unsafe { std::slice::from_raw_parts_mut(tokens, num_tokens) };
+    for (dst, src) in tokens.iter_mut().zip(encoder.ready.drain(..num_tokens)) {
+        *dst = src;
+    }
+    num_tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::tiktoken_free;
+    use crate::bytes::tiktoken_corebpe_encode_ordinary_bytes;
+    use crate::corebpe::{
+        tiktoken_cl100k_base, tiktoken_destroy_corebpe, tiktoken_o200k_base, tiktoken_r50k_base,
+    };
+
+    const SAMPLE: &[u8] = "Hello, world!  We'll see   what's\n\n  happening\t here: x'll 1234567 \
+                           猫です。Ünïcödé wörds DON'T    stop\r\n\r\n    fn main() { }   "
+        .as_bytes();
+
+    fn encode_one_shot(
+        corebpe: *const CoreBPE,
+        bytes: &[u8],
+        policy: CTiktokenInvalidUtf8Policy,
+    ) -> Vec<Rank> {
+        let mut num_tokens = 0;
+        let tokens = tiktoken_corebpe_encode_ordinary_bytes(
+            corebpe,
+            bytes.as_ptr(),
+            bytes.len(),
+            policy,
+            &mut num_tokens,
+        );
+        assert!(!tokens.is_null());
+        let encoded = unsafe { std::slice::from_raw_parts(tokens, num_tokens) }.to_vec();
+        tiktoken_free(tokens as *mut c_void);
+        encoded
+    }
+
+    fn encode_streamed(
+        encoder: *mut CTiktokenStreamEncoder,
+        bytes: &[u8],
+        chunk_len: usize,
+    ) -> Vec<Rank> {
+        let mut encoded = Vec::new();
+        let mut buffer = [0; 7];
+        let mut drain = |encoder| loop {
+            let num_tokens = tiktoken_stream_encoder_read(encoder, buffer.as_mut_ptr(), 7);
+            if num_tokens == 0 {
+                break;
+            }
+            encoded.extend_from_slice(&buffer[..num_tokens]);
+        };
+        for chunk in bytes.chunks(chunk_len) {
+            assert!(tiktoken_stream_encoder_feed(
+                encoder,
+                chunk.as_ptr(),
+                chunk.len()
+            ));
+            drain(encoder);
+        }
+        assert!(tiktoken_stream_encoder_finish(encoder));
+        drain(encoder);
+        encoded
+    }
+
+    #[test]
+    fn test_stream_encoder_matches_one_shot() {
+        for corebpe in [
+            tiktoken_r50k_base(),
+            tiktoken_cl100k_base(),
+            tiktoken_o200k_base(),
+        ] {
+            let policy = CTiktokenInvalidUtf8Policy::Error;
+            let expected = encode_one_shot(corebpe, SAMPLE, policy);
+            let encoder = tiktoken_stream_encoder_new(corebpe, policy);
+            for chunk_len in [1, 2, 3, 5, 16, 64, SAMPLE.len()] {
+                assert_eq!(encode_streamed(encoder, SAMPLE, chunk_len), expected);
+            }
+            tiktoken_stream_encoder_destroy(encoder);
+            tiktoken_destroy_corebpe(corebpe);
+        }
+    }
+
+    #[test]
+    fn test_stream_encoder_invalid_utf8_policies() {
+        let corebpe = tiktoken_cl100k_base();
+        let mut bytes = b"caf\xc3 ok \xe2\x82 then ".to_vec();
+        bytes.extend_from_slice(SAMPLE);
+        bytes.extend_from_slice(b"\xff\xfe end \xf0\x9f\x98");
+        for policy in [
+            CTiktokenInvalidUtf8Policy::Replace,
+            CTiktokenInvalidUtf8Policy::Bytes,
+        ] {
+            let expected = encode_one_shot(corebpe, &bytes, policy);
+            let encoder = tiktoken_stream_encoder_new(corebpe, policy);
+            for chunk_len in [1, 2, 3, 4, 7, bytes.len()] {
+                assert_eq!(encode_streamed(encoder, &bytes, chunk_len), expected);
+            }
+            tiktoken_stream_encoder_destroy(encoder);
+        }
+
+        let encoder = tiktoken_stream_encoder_new(corebpe, CTiktokenInvalidUtf8Policy::Error);
+        assert!(!tiktoken_stream_encoder_feed(
+            encoder,
+            bytes.as_ptr(),
+            bytes.len()
+        ));
+        // A character split across chunks is not an error until the stream ends.
+        assert!(tiktoken_stream_encoder_feed(
+            encoder,
+            b"ok \xe2".as_ptr(),
+            4
+        ));
+        assert!(!tiktoken_stream_encoder_finish(encoder));
+        tiktoken_stream_encoder_destroy(encoder);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    unsafe extern "C" fn collect_tokens(
+        tokens: *const Rank,
+        num_tokens: usize,
+        user_data: *mut c_void,
+    ) {
+        let collected = &mut *(user_data as *mut Vec<Rank>);
+        collected.extend_from_slice(std::slice::from_raw_parts(tokens, num_tokens));
+    }
+
+    #[test]
+    fn test_stream_encoder_callback() {
+        let corebpe = tiktoken_cl100k_base();
+        let policy = CTiktokenInvalidUtf8Policy::Error;
+        let expected = encode_one_shot(corebpe, SAMPLE, policy);
+        let encoder = tiktoken_stream_encoder_new(corebpe, policy);
+        let mut collected: Vec<Rank> = Vec::new();
+        assert!(tiktoken_stream_encoder_set_callback(
+            encoder,
+            Some(collect_tokens),
+            &mut collected as *mut Vec<Rank> as *mut c_void,
+        ));
+        for chunk in SAMPLE.chunks(10) {
+            assert!(tiktoken_stream_encoder_feed(
+                encoder,
+                chunk.as_ptr(),
+                chunk.len()
+            ));
+        }
+        assert!(tiktoken_stream_encoder_finish(encoder));
+        assert_eq!(collected, expected);
+        assert_eq!(tiktoken_stream_encoder_available(encoder), 0);
+        tiktoken_stream_encoder_destroy(encoder);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_stream_encoder_buffers_little() {
+        let corebpe = tiktoken_cl100k_base();
+        let encoder = tiktoken_stream_encoder_new(corebpe, CTiktokenInvalidUtf8Policy::Error);
+        for _ in 0..1000 {
+            let chunk = b"The quick brown fox jumps over the lazy dog. ";
+            assert!(tiktoken_stream_encoder_feed(
+                encoder,
+                chunk.as_ptr(),
+                chunk.len()
+            ));
+            assert!(unsafe { &*encoder }.text.len() < 64);
+        }
+        assert!(tiktoken_stream_encoder_available(encoder) > 9000);
+        tiktoken_stream_encoder_destroy(encoder);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_stream_encoder_null_arguments() {
+        let policy = CTiktokenInvalidUtf8Policy::Error;
+        assert!(tiktoken_stream_encoder_new(std::ptr::null(), policy).is_null());
+        assert!(!tiktoken_stream_encoder_feed(
+            std::ptr::null_mut(),
+            b"a".as_ptr(),
+            1
+        ));
+        assert!(!tiktoken_stream_encoder_finish(std::ptr::null_mut()));
+        let corebpe = tiktoken_cl100k_base();
+        let encoder = tiktoken_stream_encoder_new(corebpe, policy);
+        assert!(!tiktoken_stream_encoder_feed(encoder, std::ptr::null(), 1));
+        assert!(tiktoken_stream_encoder_feed(encoder, std::ptr::null(), 0));
+        assert_eq!(
+            tiktoken_stream_encoder_read(encoder, std::ptr::null_mut(), 1),
+            usize::MAX
+        );
+        tiktoken_stream_encoder_destroy(encoder);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+}
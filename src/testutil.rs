@@ -0,0 +1,62 @@
+use std::ffi::{CStr, CString};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A file or directory path in the system temp directory for one test,
+/// named `tiktoken-c-<pid>-<name>`. Whatever is at the path is removed when
+/// it is dropped, so files are cleaned up even if the test panics. Names
+/// must be unique across the crate's tests, which run in parallel.
+pub(crate) struct TempPath {
+    path: PathBuf,
+    c_path: CString,
+}
+
+impl TempPath {
+    /// A path with nothing at it yet.
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("tiktoken-c-{}-{}", std::process::id(), name));
+        remove(&path);
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        Self { path, c_path }
+    }
+
+    /// A path with an empty directory at it.
+    pub(crate) fn dir(name: &str) -> Self {
+        let temp = Self::new(name);
+        std::fs::create_dir_all(&temp.path).unwrap();
+        temp
+    }
+
+    /// The path as a C string, for the `extern "C"` functions.
+    pub(crate) fn c_path(&self) -> &CStr {
+        &self.c_path
+    }
+}
+
+fn remove(path: &Path) {
+    if path.is_dir() {
+        let _ = std::fs::remove_dir_all(path);
+    } else {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        remove(&self.path);
+    }
+}
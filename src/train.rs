@@ -0,0 +1,505 @@
+use fancy_regex::Regex;
+#[cfg(feature = "logging")]
+use log::warn;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::ffi::c_char;
+use tiktoken_rs::Rank;
+
+use crate::corebpe::CoreBPE;
+use crate::export::tiktoken_file_contents;
+use crate::file::map_file;
+use crate::{parse_optional_string, parse_required_string};
+
+const END_OF_TEXT: &str = "<|endoftext|>";
+/// Every byte is a token of its own, so no vocabulary can be smaller.
+const MIN_VOCAB_SIZE: usize = 256;
+
+/// Learns byte-level BPE merges from the pre-tokenizer pieces of a corpus.
+pub struct CTiktokenBpeTrainer {
+    pattern: String,
+    regex: Regex,
+    vocab_size: usize,
+    special_tokens: Vec<String>,
+    /// Number of occurrences of each distinct piece.
+    pieces: FxHashMap<Vec<u8>, u64>,
+}
+
+/// Replaces every occurrence of `pair` in `word`, left to right.
+fn merge_pair(word: &[Rank], pair: (Rank, Rank), token: Rank) -> Vec<Rank> {
+    let mut merged = Vec::with_capacity(word.len());
+    let mut index = 0;
+    while index < word.len() {
+        if index + 1 < word.len() && (word[index], word[index + 1]) == pair {
+            merged.push(token);
+            index += 2;
+        } else {
+            merged.push(word[index]);
+            index += 1;
+        }
+    }
+    merged
+}
+
+impl CTiktokenBpeTrainer {
+    fn new(pattern: &str, vocab_size: usize) -> Option<Self> {
+        Some(Self {
+            pattern: pattern.to_string(),
+            regex: Regex::new(pattern).ok()?,
+            vocab_size,
+            special_tokens: Vec::new(),
+            pieces: FxHashMap::default(),
+        })
+    }
+
+    fn add_text(&mut self, text: &str) {
+        for mat in self.regex.find_iter(text).filter_map(|mat| mat.ok()) {
+            let piece = mat.as_str().as_bytes();
+            match self.pieces.get_mut(piece) {
+                Some(count) => *count += 1,
+                None => {
+                    self.pieces.insert(piece.to_vec(), 1);
+                }
+            }
+        }
+    }
+
+    /// Returns the bytes of each ordinary token in rank order: the 256
+    /// single bytes, then one token per merge.
+    ///
+    /// The most frequent adjacent pair is merged until the vocabulary is
+    /// full or no pair is left; ties go to the pair of lowest ranks. A merge
+    /// whose bytes already form a token reuses that token.
+    fn train(&self) -> Vec<Vec<u8>> {
+        let mut vocab: Vec<Vec<u8>> = (0..=u8::MAX).map(|byte| vec![byte]).collect();
+        let mut ranks: FxHashMap<Vec<u8>, Rank> = vocab.iter().cloned().zip(0..).collect();
+        let mut words: Vec<Vec<Rank>> = Vec::with_capacity(self.pieces.len());
+        let mut counts = Vec::with_capacity(self.pieces.len());
+        for (piece, &count) in &self.pieces {
+            words.push(piece.iter().map(|&byte| Rank::from(byte)).collect());
+            counts.push(count);
+        }
+
+        let mut pair_counts: FxHashMap<(Rank, Rank), u64> = FxHashMap::default();
+        let mut occurrences: FxHashMap<(Rank, Rank), FxHashSet<usize>> = FxHashMap::default();
+        for (index, word) in words.iter().enumerate() {
+            for pair in word.windows(2) {
+                let pair = (pair[0], pair[1]);
+                *pair_counts.entry(pair).or_default() += counts[index];
+                occurrences.entry(pair).or_default().insert(index);
+            }
+        }
+        // Entries go stale as counts change; a popped entry is only used if
+        // its count is still current.
+        let mut heap: BinaryHeap<(u64, Reverse<(Rank, Rank)>)> = pair_counts
+            .iter()
+            .map(|(&pair, &count)| (count, Reverse(pair)))
+            .collect();
+
+        while vocab.len() < self.vocab_size {
+            let Some((count, Reverse(pair))) = heap.pop() else {
+                break;
+            };
+            if pair_counts.get(&pair) != Some(&count) {
+                continue;
+            }
+            let mut bytes = vocab[pair.0 as usize].clone();
+            bytes.extend_from_slice(&vocab[pair.1 as usize]);
+            let token = match ranks.get(&bytes) {
+                Some(&token) => token,
+                None => {
+                    let token = vocab.len() as Rank;
+                    ranks.insert(bytes.clone(), token);
+                    vocab.push(bytes);
+                    token
+                }
+            };
+
+            let mut changed = FxHashSet::default();
+            for index in occurrences.remove(&pair).unwrap_or_default() {
+                let merged = merge_pair(&words[index], pair, token);
+                let count = counts[index];
+                for old in words[index].windows(2) {
+                    let old = (old[0], old[1]);
+                    if let Some(pair_count) = pair_counts.get_mut(&old) {
+                        *pair_count -= count;
+                    }
+                    changed.insert(old);
+                }
+                for new in merged.windows(2) {
+                    let new = (new[0], new[1]);
+                    *pair_counts.entry(new).or_default() += count;
+                    occurrences.entry(new).or_default().insert(index);
+                    changed.insert(new);
+                }
+                words[index] = merged;
+            }
+            for pair in changed {
+                match pair_counts.get(&pair) {
+                    Some(&0) => {
+                        pair_counts.remove(&pair);
+                    }
+                    Some(&count) => heap.push((count, Reverse(pair))),
+                    None => {}
+                }
+            }
+        }
+        vocab
+    }
+
+    /// Special tokens with their ranks, numbered after `num_ordinary`
+    /// ordinary tokens. `<|endoftext|>` is used if none were added.
+    fn special_tokens(&self, num_ordinary: usize) -> FxHashMap<String, Rank> {
+        let default = [END_OF_TEXT.to_string()];
+        let tokens = if self.special_tokens.is_empty() {
+            &default[..]
+        } else {
+            &self.special_tokens[..]
+        };
+        tokens.iter().cloned().zip(num_ordinary as Rank..).collect()
+    }
+}
+
+/// Creates a trainer for a vocabulary of `vocab_size` ordinary tokens, using
+/// the o200k_base split pattern when `pattern` is NULL.
+#[no_mangle]
+pub extern "C" fn tiktoken_bpe_trainer_new(
+    pattern: *const c_char,
+    vocab_size: usize,
+) -> *mut CTiktokenBpeTrainer {
+    let pattern = match parse_optional_string(pattern, "pattern") {
+        Ok(Some(pattern)) => pattern,
+        Ok(None) => tiktoken_rs::O200K_BASE_PAT_STR.to_string(),
+        Err(_) => return std::ptr::null_mut(),
+    };
+    if vocab_size < MIN_VOCAB_SIZE || vocab_size > Rank::MAX as usize {
+        #[cfg(feature = "logging")]
+        warn!("Vocabulary size must be at least {}!", MIN_VOCAB_SIZE);
+        return std::ptr::null_mut();
+    }
+    match CTiktokenBpeTrainer::new(&pattern, vocab_size) {
+        Some(trainer) => Box::into_raw(Box::new(trainer)),
+        None => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to compile the split pattern!");
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_bpe_trainer_destroy(trainer: *mut CTiktokenBpeTrainer) {
+    if trainer.is_null() {
+        return;
+    }
+    unsafe {
+        drop(Box::from_raw(trainer));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_bpe_trainer_add_special_token(
+    trainer: *mut CTiktokenBpeTrainer,
+    token: *const c_char,
+) -> bool {
+    if trainer.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for trainer!");
+        return false;
+    }
+    let token = match parse_required_string(token, "token") {
+        Ok(token) => token,
+        Err(_) => return false,
+    };
+    let trainer = unsafe { &mut *trainer };
+    if token.is_empty() || trainer.special_tokens.contains(&token) {
+        #[cfg(feature = "logging")]
+        warn!("Special token is empty or already added!");
+        return false;
+    }
+    trainer.special_tokens.push(token);
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_bpe_trainer_add_text(
+    trainer: *mut CTiktokenBpeTrainer,
+    text: *const c_char,
+) -> bool {
+    if trainer.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for trainer!");
+        return false;
+    }
+    let text = match parse_required_string(text, "text") {
+        Ok(text) => text,
+        Err(_) => return false,
+    };
+    unsafe { &mut *trainer }.add_text(&text);
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_bpe_trainer_add_file(
+    trainer: *mut CTiktokenBpeTrainer,
+    path: *const c_char,
+) -> bool {
+    if trainer.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for trainer!");
+        return false;
+    }
+    let mmap = match map_file(path) {
+        Some(Some(mmap)) => mmap,
+        Some(None) => return true,
+        None => return false,
+    };
+    let text = match std::str::from_utf8(&mmap) {
+        Ok(text) => text,
+        Err(_) => {
+            #[cfg(feature = "logging")]
+            warn!("Invalid UTF-8 sequence in the file!");
+            return false;
+        }
+    };
+    unsafe { &mut *trainer }.add_text(text);
+    true
+}
+
+/// Trains on the text added so far and returns an encoding named `name`.
+/// When `tiktoken_path` is not NULL, the ordinary tokens are also written
+/// there as a `.tiktoken` rank file.
+#[no_mangle]
+pub extern "C" fn tiktoken_bpe_trainer_train(
+    trainer: *const CTiktokenBpeTrainer,
+    name: *const c_char,
+    tiktoken_path: *const c_char,
+) -> *mut CoreBPE {
+    if trainer.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for trainer!");
+        return std::ptr::null_mut();
+    }
+    let name = match parse_required_string(name, "name") {
+        Ok(name) => name,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    let tiktoken_path = match parse_optional_string(tiktoken_path, "tiktoken_path") {
+        Ok(tiktoken_path) => tiktoken_path,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    let trainer = unsafe { &*trainer };
+    let vocab = trainer.train();
+    let special_tokens = trainer.special_tokens(vocab.len());
+    let mergeable_ranks = vocab.into_iter().zip(0..).collect();
+    let corebpe =
+        match CoreBPE::from_ranks(mergeable_ranks, special_tokens, &name, &trainer.pattern) {
+            Some(corebpe) => corebpe,
+            None => {
+                #[cfg(feature = "logging")]
+                warn!("Failed to build the encoding!");
+                return std::ptr::null_mut();
+            }
+        };
+    if let Some(path) = tiktoken_path {
+        if std::fs::write(&path, tiktoken_file_contents(&corebpe)).is_err() {
+            #[cfg(feature = "logging")]
+            warn!("Failed to write {}!", path);
+            return std::ptr::null_mut();
+        }
+    }
+    Box::into_raw(Box::new(corebpe))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corebpe::{tiktoken_corebpe_name, tiktoken_destroy_corebpe, tokenizer_pattern};
+    use crate::testutil::TempPath;
+    use crate::CTiktokenTokenizer;
+    use base64::{engine::general_purpose, Engine as _};
+    use std::ffi::{CStr, CString};
+
+    const CORPUS: &str = "def add(a, b):\n    return a + b\n\n\
+                          def sub(a, b):\n    return a - b\n\n\
+                          def mul(a, b):\n    return a * b\n\n\
+                          # Ünïcödé comments, 猫 and numbers 12345 too\n";
+
+    fn train(corpus: &[&str], vocab_size: usize) -> *mut CoreBPE {
+        let trainer = tiktoken_bpe_trainer_new(std::ptr::null(), vocab_size);
+        for text in corpus {
+            let text = CString::new(*text).unwrap();
+            assert!(tiktoken_bpe_trainer_add_text(trainer, text.as_ptr()));
+        }
+        let name = CString::new("code").unwrap();
+        let corebpe = tiktoken_bpe_trainer_train(trainer, name.as_ptr(), std::ptr::null());
+        tiktoken_bpe_trainer_destroy(trainer);
+        assert!(!corebpe.is_null());
+        corebpe
+    }
+
+    #[test]
+    fn test_merge_pair() {
+        assert_eq!(merge_pair(&[1, 1, 1], (1, 1), 9), [9, 1]);
+        assert_eq!(merge_pair(&[1, 2, 1, 2, 3], (1, 2), 9), [9, 9, 3]);
+        assert_eq!(merge_pair(&[2, 1], (1, 2), 9), [2, 1]);
+    }
+
+    /// Recounts every pair before each merge.
+    fn train_naive(trainer: &CTiktokenBpeTrainer) -> Vec<Vec<u8>> {
+        let mut vocab: Vec<Vec<u8>> = (0..=u8::MAX).map(|byte| vec![byte]).collect();
+        let mut words: Vec<(Vec<Rank>, u64)> = trainer
+            .pieces
+            .iter()
+            .map(|(piece, &count)| (piece.iter().map(|&b| Rank::from(b)).collect(), count))
+            .collect();
+        while vocab.len() < trainer.vocab_size {
+            let mut pair_counts: FxHashMap<(Rank, Rank), u64> = FxHashMap::default();
+            for (word, count) in &words {
+                for pair in word.windows(2) {
+                    *pair_counts.entry((pair[0], pair[1])).or_default() += count;
+                }
+            }
+            let Some((&pair, _)) = pair_counts
+                .iter()
+                .max_by_key(|&(&pair, &count)| (count, Reverse(pair)))
+            else {
+                break;
+            };
+            let bytes = [&vocab[pair.0 as usize][..], &vocab[pair.1 as usize]].concat();
+            let token = match vocab.iter().position(|token| *token == bytes) {
+                Some(token) => token as Rank,
+                None => {
+                    vocab.push(bytes);
+                    vocab.len() as Rank - 1
+                }
+            };
+            for (word, _) in &mut words {
+                *word = merge_pair(word, pair, token);
+            }
+        }
+        vocab
+    }
+
+    #[test]
+    fn test_train_matches_naive_merging() {
+        let pattern = tokenizer_pattern(CTiktokenTokenizer::Cl100kBase).unwrap();
+        let mut trainer = CTiktokenBpeTrainer::new(pattern.to_str().unwrap(), 600).unwrap();
+        trainer.add_text(CORPUS);
+        trainer.add_text("aaaa aaa aa a abab ababab bababa 1111 111 11 ::: :: ....");
+        assert_eq!(trainer.train(), train_naive(&trainer));
+    }
+
+    #[test]
+    fn test_train_learns_frequent_pieces() {
+        let corebpe = train(&[CORPUS; 4], 400);
+        let corebpe_ref = unsafe { &*corebpe };
+        assert_eq!(
+            unsafe { CStr::from_ptr(tiktoken_corebpe_name(corebpe)) },
+            c"code"
+        );
+        for word in ["def", " return", " b", "):\n"] {
+            assert_eq!(corebpe_ref.encode_ordinary(word).len(), 1, "{:?}", word);
+        }
+        let tokens = corebpe_ref.encode_ordinary(CORPUS);
+        assert!(tokens.len() < CORPUS.len() / 2);
+        assert_eq!(corebpe_ref.decode(&tokens).unwrap(), CORPUS);
+        // Unseen text still round-trips through the byte tokens.
+        let unseen = "SELECT * FROM ☃ WHERE x = 'ok';";
+        let tokens = corebpe_ref.encode_ordinary(unseen);
+        assert_eq!(corebpe_ref.decode(&tokens).unwrap(), unseen);
+        assert_eq!(
+            corebpe_ref.encode_with_special_tokens("a<|endoftext|>"),
+            [u32::from(b'a'), corebpe_ref.vocab_size() as Rank - 1]
+        );
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_train_is_deterministic_and_stops_when_exhausted() {
+        let first = unsafe { Box::from_raw(train(&[CORPUS, CORPUS], 300)) };
+        let second = unsafe { Box::from_raw(train(&[CORPUS, CORPUS], 300)) };
+        assert_eq!(first.mergeable_ranks(), second.mergeable_ranks());
+        assert_eq!(first.vocab_size(), 301);
+
+        let small = unsafe { Box::from_raw(train(&["aaab"], 10_000)) };
+        // "aaab" is one piece: merging "aa", "ab" and "aaab" exhausts it.
+        assert_eq!(small.mergeable_ranks().len(), 259);
+        assert_eq!(small.encode_ordinary("aaab"), [258]);
+    }
+
+    #[test]
+    fn test_train_special_tokens_and_tiktoken_file() {
+        let trainer = tiktoken_bpe_trainer_new(
+            tokenizer_pattern(CTiktokenTokenizer::Cl100kBase)
+                .unwrap()
+                .as_ptr(),
+            260,
+        );
+        let corpus = CString::new(CORPUS).unwrap();
+        assert!(tiktoken_bpe_trainer_add_text(trainer, corpus.as_ptr()));
+        for token in ["<|bos|>", "<|eos|>"] {
+            let token = CString::new(token).unwrap();
+            assert!(tiktoken_bpe_trainer_add_special_token(
+                trainer,
+                token.as_ptr()
+            ));
+        }
+        let duplicate = CString::new("<|bos|>").unwrap();
+        assert!(!tiktoken_bpe_trainer_add_special_token(
+            trainer,
+            duplicate.as_ptr()
+        ));
+
+        let dir = TempPath::dir("train");
+        let corpus_path = dir.join("corpus.txt");
+        std::fs::write(&corpus_path, CORPUS).unwrap();
+        let corpus_path = CString::new(corpus_path.to_str().unwrap()).unwrap();
+        assert!(tiktoken_bpe_trainer_add_file(trainer, corpus_path.as_ptr()));
+        let missing = CString::new(dir.join("missing.txt").to_str().unwrap()).unwrap();
+        assert!(!tiktoken_bpe_trainer_add_file(trainer, missing.as_ptr()));
+
+        let tiktoken_path = dir.join("code.tiktoken");
+        let tiktoken_path_c = CString::new(tiktoken_path.to_str().unwrap()).unwrap();
+        let name = CString::new("code").unwrap();
+        let corebpe = tiktoken_bpe_trainer_train(trainer, name.as_ptr(), tiktoken_path_c.as_ptr());
+        let corebpe_ref = unsafe { &*corebpe };
+        assert_eq!(corebpe_ref.special_token_rank("<|bos|>"), Some(260));
+        assert_eq!(corebpe_ref.special_token_rank("<|eos|>"), Some(261));
+        assert_eq!(corebpe_ref.special_token_rank(END_OF_TEXT), None);
+
+        let contents = std::fs::read_to_string(&tiktoken_path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 260);
+        assert_eq!(lines[0], "AA== 0");
+        assert_eq!(lines[b'A' as usize], "QQ== 65");
+        for line in lines {
+            let (token, rank) = line.split_once(' ').unwrap();
+            let token = general_purpose::STANDARD.decode(token).unwrap();
+            assert_eq!(
+                corebpe_ref.mergeable_ranks()[&token],
+                rank.parse::<Rank>().unwrap()
+            );
+        }
+
+        tiktoken_destroy_corebpe(corebpe);
+        tiktoken_bpe_trainer_destroy(trainer);
+    }
+
+    #[test]
+    fn test_trainer_invalid_arguments() {
+        assert!(tiktoken_bpe_trainer_new(std::ptr::null(), 255).is_null());
+        let pattern = CString::new("(unclosed").unwrap();
+        assert!(tiktoken_bpe_trainer_new(pattern.as_ptr(), 300).is_null());
+        let text = CString::new("text").unwrap();
+        assert!(!tiktoken_bpe_trainer_add_text(
+            std::ptr::null_mut(),
+            text.as_ptr()
+        ));
+        let trainer = tiktoken_bpe_trainer_new(std::ptr::null(), 300);
+        assert!(!tiktoken_bpe_trainer_add_text(trainer, std::ptr::null()));
+        assert!(tiktoken_bpe_trainer_train(trainer, std::ptr::null(), std::ptr::null()).is_null());
+        tiktoken_bpe_trainer_destroy(trainer);
+    }
+}
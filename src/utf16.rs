@@ -0,0 +1,427 @@
+#[cfg(feature = "logging")]
+use log::warn;
+use tiktoken_rs::Rank;
+
+use crate::alloc::malloc_copy;
+use crate::corebpe::CoreBPE;
+
+/// How unpaired surrogates in UTF-16 input, or invalid UTF-8 in decoded
+/// output, are handled.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CTiktokenSurrogatePolicy {
+    Error = 0,
+    Replace = 1,
+}
+
+fn utf16_to_string(
+    text: *const u16,
+    text_len: usize,
+    policy: CTiktokenSurrogatePolicy,
+) -> Result<String, ()> {
+    if text_len == 0 {
+        return Ok(String::new());
+    }
+    if text.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for text!");
+        return Err(());
+    }
+    let text = unsafe { std::slice::from_raw_parts(text, text_len) };
+    match policy {
+        CTiktokenSurrogatePolicy::Error => String::from_utf16(text).map_err(|_| {
+            #[cfg(feature = "logging")]
+            warn!("Unpaired surrogate provided in text!");
+        }),
+        CTiktokenSurrogatePolicy::Replace => Ok(String::from_utf16_lossy(text)),
+    }
+}
+
+/// Returns the UTF-16 offset at which each token starts. A token that begins
+/// in the middle of a character is reported at the start of that character.
+fn utf16_offsets(corebpe: &CoreBPE, text: &str, tokens: &[Rank]) -> Option<Vec<usize>> {
+    let mut chars = text.char_indices().peekable();
+    let mut utf16_offset = 0;
+    let mut char_start = 0;
+    let mut byte_offset = 0;
+    let mut offsets = Vec::with_capacity(tokens.len());
+    for &token in tokens {
+        // Advance to the character containing the first byte of this token.
+        while let Some(&(char_byte_offset, c)) = chars.peek() {
+            if char_byte_offset > byte_offset {
+                break;
+            }
+            char_start = utf16_offset;
+            utf16_offset += c.len_utf16();
+            chars.next();
+        }
+        offsets.push(char_start);
+        byte_offset += corebpe.decode_bytes(&[token]).ok()?.len();
+    }
+    Some(offsets)
+}
+
+fn encode_utf16(
+    ptr: *const CoreBPE,
+    text: *const u16,
+    text_len: usize,
+    policy: CTiktokenSurrogatePolicy,
+    num_tokens: *mut usize,
+    offsets: *mut *mut usize,
+    encode: impl Fn(&CoreBPE, &str) -> Vec<Rank>,
+) -> *mut Rank {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return std::ptr::null_mut();
+    }
+    let text = match utf16_to_string(text, text_len, policy) {
+        Ok(text) => text,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    let corebpe = unsafe { &*ptr };
+    let encoded = encode(corebpe, &text);
+    if !offsets.is_null() {
+        let token_offsets = match utf16_offsets(corebpe, &text, &encoded) {
+            Some(token_offsets) => token_offsets,
+            None => {
+                #[cfg(feature = "logging")]
+                warn!("Failed to compute token offsets!");
+                return std::ptr::null_mut();
+            }
+        };
+        unsafe {
+            *offsets = malloc_copy::<usize>(&token_offsets);
+        }
+    }
+    unsafe {
+        if !num_tokens.is_null() {
+            *num_tokens = encoded.len();
+        }
+    };
+    malloc_copy::<Rank>(&encoded)
+}
+
+fn count_utf16(
+    ptr: *const CoreBPE,
+    text: *const u16,
+    text_len: usize,
+    policy: CTiktokenSurrogatePolicy,
+    count: impl Fn(&CoreBPE, &str) -> usize,
+) -> usize {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return usize::MAX;
+    }
+    let text = match utf16_to_string(text, text_len, policy) {
+        Ok(text) => text,
+        Err(_) => return usize::MAX,
+    };
+    let corebpe = unsafe { &*ptr };
+    count(corebpe, &text)
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_encode_ordinary_utf16(
+    ptr: *const CoreBPE,
+    text: *const u16,
+    text_len: usize,
+    policy: CTiktokenSurrogatePolicy,
+    num_tokens: *mut usize,
+    offsets: *mut *mut usize,
+) -> *mut Rank {
+    encode_utf16(
+        ptr,
+        text,
+        text_len,
+        policy,
+        num_tokens,
+        offsets,
+        |corebpe, text| corebpe.encode_ordinary(text),
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_encode_with_special_tokens_utf16(
+    ptr: *const CoreBPE,
+    text: *const u16,
+    text_len: usize,
+    policy: CTiktokenSurrogatePolicy,
+    num_tokens: *mut usize,
+    offsets: *mut *mut usize,
+) -> *mut Rank {
+    encode_utf16(
+        ptr,
+        text,
+        text_len,
+        policy,
+        num_tokens,
+        offsets,
+        |corebpe, text| corebpe.encode_with_special_tokens(text),
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_count_ordinary_utf16(
+    ptr: *const CoreBPE,
+    text: *const u16,
+    text_len: usize,
+    policy: CTiktokenSurrogatePolicy,
+) -> usize {
+    count_utf16(ptr, text, text_len, policy, |corebpe, text| {
+        corebpe.count_ordinary(text)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_count_with_special_tokens_utf16(
+    ptr: *const CoreBPE,
+    text: *const u16,
+    text_len: usize,
+    policy: CTiktokenSurrogatePolicy,
+) -> usize {
+    count_utf16(ptr, text, text_len, policy, |corebpe, text| {
+        corebpe.count_with_special_tokens(text)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn tiktoken_corebpe_decode_utf16(
+    ptr: *const CoreBPE,
+    tokens: *const Rank,
+    num_tokens: usize,
+    policy: CTiktokenSurrogatePolicy,
+    text_len: *mut usize,
+) -> *mut u16 {
+    if ptr.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for CoreBPE!");
+        return std::ptr::null_mut();
+    }
+    if tokens.is_null() {
+        #[cfg(feature = "logging")]
+        warn!("Null pointer provided for tokens!");
+        return std::ptr::null_mut();
+    }
+    let tokens = unsafe { std::slice::from_raw_parts(tokens, num_tokens) };
+
+    let corebpe = unsafe { &*ptr };
+    let decoded = match corebpe.decode_bytes(tokens) {
+        Ok(decoded) => decoded,
+        Err(_) => {
+            #[cfg(feature = "logging")]
+            warn!("Failed to decode bytes!");
+            return std::ptr::null_mut();
+        }
+    };
+    let decoded = match policy {
+        CTiktokenSurrogatePolicy::Error => match String::from_utf8(decoded) {
+            Ok(decoded) => decoded,
+            Err(_) => {
+                #[cfg(feature = "logging")]
+                warn!("Decoded bytes are not valid UTF-8!");
+                return std::ptr::null_mut();
+            }
+        },
+        CTiktokenSurrogatePolicy::Replace => String::from_utf8_lossy(&decoded).into_owned(),
+    };
+    let encoded: Vec<u16> = decoded.encode_utf16().collect();
+    unsafe {
+        if !text_len.is_null() {
+            *text_len = encoded.len();
+        }
+    };
+    malloc_copy::<u16>(&encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::tiktoken_free;
+    use crate::corebpe::{tiktoken_cl100k_base, tiktoken_destroy_corebpe};
+
+    fn utf16(text: &str) -> Vec<u16> {
+        text.encode_utf16().collect()
+    }
+
+    unsafe fn take<T: Copy>(ptr: *mut T, len: usize) -> Vec<T> {
+        let values = std::slice::from_raw_parts(ptr, len).to_vec();
+        tiktoken_free(ptr as *mut libc::c_void);
+        values
+    }
+
+    #[test]
+    fn test_corebpe_encode_ordinary_utf16() {
+        let corebpe = tiktoken_cl100k_base();
+        let text = utf16("I am a cat.");
+        let mut num_tokens = 0;
+        let mut offsets = std::ptr::null_mut();
+        let tokens = tiktoken_corebpe_encode_ordinary_utf16(
+            corebpe,
+            text.as_ptr(),
+            text.len(),
+            CTiktokenSurrogatePolicy::Error,
+            &mut num_tokens,
+            &mut offsets,
+        );
+        assert_eq!(
+            unsafe { take(tokens, num_tokens) },
+            [40, 1097, 264, 8415, 13]
+        );
+        assert_eq!(unsafe { take(offsets, num_tokens) }, [0, 1, 4, 6, 10]);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_corebpe_encode_utf16_offsets_non_bmp() {
+        let corebpe = tiktoken_cl100k_base();
+        // U+1F431 takes two UTF-16 code units and several tokens.
+        let text = utf16("a🐱b");
+        let mut num_tokens = 0;
+        let mut offsets = std::ptr::null_mut();
+        let tokens = tiktoken_corebpe_encode_ordinary_utf16(
+            corebpe,
+            text.as_ptr(),
+            text.len(),
+            CTiktokenSurrogatePolicy::Error,
+            &mut num_tokens,
+            &mut offsets,
+        );
+        let tokens = unsafe { take(tokens, num_tokens) };
+        let offsets = unsafe { take(offsets, num_tokens) };
+        assert_eq!(tokens, unsafe { &*corebpe }.encode_ordinary("a🐱b"));
+        let mut expected = vec![0];
+        expected.resize(num_tokens - 1, 1);
+        expected.push(3);
+        assert_eq!(offsets, expected);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_corebpe_encode_utf16_lone_surrogate() {
+        let corebpe = tiktoken_cl100k_base();
+        let mut text = utf16("cat");
+        text.insert(1, 0xD800);
+        let mut num_tokens = 0;
+        let tokens = tiktoken_corebpe_encode_ordinary_utf16(
+            corebpe,
+            text.as_ptr(),
+            text.len(),
+            CTiktokenSurrogatePolicy::Error,
+            &mut num_tokens,
+            std::ptr::null_mut(),
+        );
+        assert!(tokens.is_null());
+        let count = tiktoken_corebpe_count_ordinary_utf16(
+            corebpe,
+            text.as_ptr(),
+            text.len(),
+            CTiktokenSurrogatePolicy::Replace,
+        );
+        assert_eq!(count, unsafe { &*corebpe }.count_ordinary("c\u{FFFD}at"));
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_corebpe_count_with_special_tokens_utf16() {
+        let corebpe = tiktoken_cl100k_base();
+        let text = utf16("I am a cat. <|endoftext|>");
+        let count = tiktoken_corebpe_count_with_special_tokens_utf16(
+            corebpe,
+            text.as_ptr(),
+            text.len(),
+            CTiktokenSurrogatePolicy::Error,
+        );
+        assert_eq!(count, 7);
+        let mut num_tokens = 0;
+        let tokens = tiktoken_corebpe_encode_with_special_tokens_utf16(
+            corebpe,
+            text.as_ptr(),
+            text.len(),
+            CTiktokenSurrogatePolicy::Error,
+            &mut num_tokens,
+            std::ptr::null_mut(),
+        );
+        assert_eq!(
+            unsafe { take(tokens, num_tokens) },
+            [40, 1097, 264, 8415, 13, 220, 100257]
+        );
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_corebpe_count_ordinary_utf16_empty() {
+        let corebpe = tiktoken_cl100k_base();
+        let count = tiktoken_corebpe_count_ordinary_utf16(
+            corebpe,
+            std::ptr::null(),
+            0,
+            CTiktokenSurrogatePolicy::Error,
+        );
+        assert_eq!(count, 0);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_corebpe_count_ordinary_utf16_null_arguments() {
+        let corebpe = tiktoken_cl100k_base();
+        let text = utf16("cat");
+        let count = tiktoken_corebpe_count_ordinary_utf16(
+            std::ptr::null(),
+            text.as_ptr(),
+            text.len(),
+            CTiktokenSurrogatePolicy::Error,
+        );
+        assert_eq!(count, usize::MAX);
+        let count = tiktoken_corebpe_count_ordinary_utf16(
+            corebpe,
+            std::ptr::null(),
+            1,
+            CTiktokenSurrogatePolicy::Error,
+        );
+        assert_eq!(count, usize::MAX);
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_corebpe_decode_utf16() {
+        let corebpe = tiktoken_cl100k_base();
+        let tokens = [40, 1097, 264, 8415, 13];
+        let mut text_len = 0;
+        let decoded = tiktoken_corebpe_decode_utf16(
+            corebpe,
+            tokens.as_ptr(),
+            tokens.len(),
+            CTiktokenSurrogatePolicy::Error,
+            &mut text_len,
+        );
+        assert_eq!(unsafe { take(decoded, text_len) }, utf16("I am a cat."));
+        tiktoken_destroy_corebpe(corebpe);
+    }
+
+    #[test]
+    fn test_corebpe_decode_utf16_partial_character() {
+        let corebpe = tiktoken_cl100k_base();
+        let tokens = unsafe { &*corebpe }.encode_ordinary("🐱");
+        let partial = &tokens[..1];
+        let mut text_len = 0;
+        let decoded = tiktoken_corebpe_decode_utf16(
+            corebpe,
+            partial.as_ptr(),
+            partial.len(),
+            CTiktokenSurrogatePolicy::Error,
+            &mut text_len,
+        );
+        assert!(decoded.is_null());
+        let decoded = tiktoken_corebpe_decode_utf16(
+            corebpe,
+            partial.as_ptr(),
+            partial.len(),
+            CTiktokenSurrogatePolicy::Replace,
+            &mut text_len,
+        );
+        assert!(unsafe { take(decoded, text_len) }.contains(&0xFFFD));
+        tiktoken_destroy_corebpe(corebpe);
+    }
+}
@@ -2,6 +2,16 @@
 
 #include <stdio.h>
 #include <stdlib.h>
+#include <string.h>
+
+#ifdef _WIN32
+#include <windows.h>
+#else
+#include <pthread.h>
+#endif
+
+#define NUM_THREADS 8
+#define NUM_THREAD_ITERATIONS 100
 
 typedef struct TestCase {
     const char *model;
@@ -10,10 +20,16 @@ typedef struct TestCase {
     size_t expected_len;
 } TestCase;
 
+typedef struct ThreadCase {
+    const CoreBPE *bpe;
+    int failed;
+} ThreadCase;
+
 typedef struct ModelMetadataCase {
     const char *model;
     size_t expected_context_size;
     TiktokenTokenizer expected_tokenizer;
+    const char *expected_encoding_name;
 } ModelMetadataCase;
 
 static int print_tokens(const Rank *tokens, size_t len)
@@ -95,6 +111,204 @@ static int run_test_case(const TestCase *test_case)
     return 0;
 }
 
+static int encode_decode_repeatedly(const CoreBPE *bpe)
+{
+    static const Rank expected_tokens[] = {40, 1097, 264, 8415, 13};
+    const char *text = "I am a cat.";
+    size_t iteration;
+
+    for (iteration = 0; iteration < NUM_THREAD_ITERATIONS; iteration++)
+    {
+        size_t token_count = 0;
+        Rank *tokens = tiktoken_corebpe_encode_ordinary(bpe, text, &token_count);
+        char *decoded;
+
+        if (tokens == NULL || token_count != 5 ||
+            memcmp(tokens, expected_tokens, sizeof(expected_tokens)) != 0)
+        {
+            tiktoken_free(tokens);
+            return 1;
+        }
+
+        if (tiktoken_corebpe_count_ordinary(bpe, text) != token_count)
+        {
+            tiktoken_free(tokens);
+            return 1;
+        }
+
+        decoded = tiktoken_corebpe_decode(bpe, tokens, token_count);
+        tiktoken_free(tokens);
+        if (decoded == NULL || strcmp(decoded, text) != 0)
+        {
+            tiktoken_free(decoded);
+            return 1;
+        }
+        tiktoken_free(decoded);
+    }
+
+    return 0;
+}
+
+#ifdef _WIN32
+static DWORD WINAPI thread_main(LPVOID arg)
+#else
+static void *thread_main(void *arg)
+#endif
+{
+    ThreadCase *thread_case = (ThreadCase *)arg;
+
+    thread_case->failed = encode_decode_repeatedly(thread_case->bpe);
+    return 0;
+}
+
+static int run_thread_test(void)
+{
+    CoreBPE *bpe;
+    ThreadCase thread_cases[NUM_THREADS];
+#ifdef _WIN32
+    HANDLE threads[NUM_THREADS];
+#else
+    pthread_t threads[NUM_THREADS];
+#endif
+    size_t started = 0;
+    size_t i;
+    int failed = 0;
+
+    bpe = tiktoken_get_bpe_from_model("gpt-4");
+    if (bpe == NULL)
+    {
+        fprintf(stderr, "Failed to load model: gpt-4\n");
+        return 1;
+    }
+
+    for (i = 0; i < NUM_THREADS; i++)
+    {
+        thread_cases[i].bpe = bpe;
+        thread_cases[i].failed = 0;
+#ifdef _WIN32
+        threads[i] = CreateThread(NULL, 0, thread_main, &thread_cases[i], 0, NULL);
+        if (threads[i] == NULL)
+#else
+        if (pthread_create(&threads[i], NULL, thread_main, &thread_cases[i]) != 0)
+#endif
+        {
+            fprintf(stderr, "Failed to start thread %zu\n", i);
+            failed = 1;
+            break;
+        }
+        started++;
+    }
+
+    for (i = 0; i < started; i++)
+    {
+#ifdef _WIN32
+        WaitForSingleObject(threads[i], INFINITE);
+        CloseHandle(threads[i]);
+#else
+        pthread_join(threads[i], NULL);
+#endif
+        if (thread_cases[i].failed)
+        {
+            fprintf(stderr, "Thread %zu produced unexpected results\n", i);
+            failed = 1;
+        }
+    }
+
+    tiktoken_destroy_corebpe(bpe);
+
+    if (!failed)
+    {
+        printf("Shared CoreBPE test passed with %d threads\n", NUM_THREADS);
+    }
+    return failed;
+}
+
+static int run_bytes_test(void)
+{
+    static const uint8_t text[] = {'c', 'a', 'f', 0xe9, ' ', 0x00, 0xff};
+    CoreBPE *bpe;
+    Rank *tokens;
+    uint8_t *decoded;
+    size_t token_count = 0;
+    size_t byte_count = 0;
+    int failed;
+
+    bpe = tiktoken_cl100k_base();
+    if (tiktoken_corebpe_count_ordinary_bytes(bpe, text, sizeof(text),
+                                              TIKTOKEN_INVALID_UTF8_ERROR) != (size_t)-1)
+    {
+        fprintf(stderr, "Invalid UTF-8 was accepted\n");
+        tiktoken_destroy_corebpe(bpe);
+        return 1;
+    }
+
+    tokens = tiktoken_corebpe_encode_ordinary_bytes(bpe, text, sizeof(text),
+                                                    TIKTOKEN_INVALID_UTF8_BYTES, &token_count);
+    decoded = tiktoken_corebpe_decode_bytes(bpe, tokens, token_count, &byte_count);
+    failed = tokens == NULL || decoded == NULL || byte_count != sizeof(text) ||
+             memcmp(decoded, text, sizeof(text)) != 0;
+    tiktoken_free(decoded);
+    tiktoken_free(tokens);
+    tiktoken_destroy_corebpe(bpe);
+
+    if (failed)
+    {
+        fprintf(stderr, "Raw byte round trip failed\n");
+        return 1;
+    }
+    printf("Raw byte round trip test passed\n");
+    return 0;
+}
+
+typedef struct
+{
+    Rank tokens[16];
+    size_t count;
+} TokenBuffer;
+
+static void collect_tokens(const Rank *tokens, size_t num_tokens, void *user_data)
+{
+    TokenBuffer *buffer = (TokenBuffer *)user_data;
+    size_t i;
+
+    for (i = 0; i < num_tokens && buffer->count < 16; i++)
+    {
+        buffer->tokens[buffer->count++] = tokens[i];
+    }
+}
+
+static int run_stream_test(void)
+{
+    static const char text[] = "I am a cat.";
+    static const Rank expected[] = {40, 1097, 264, 8415, 13};
+    TokenBuffer buffer = {{0}, 0};
+    TiktokenStreamEncoder *encoder;
+    CoreBPE *bpe;
+    size_t i;
+    int failed;
+
+    bpe = tiktoken_cl100k_base();
+    encoder = tiktoken_stream_encoder_new(bpe, TIKTOKEN_INVALID_UTF8_ERROR);
+    failed = encoder == NULL || !tiktoken_stream_encoder_set_callback(encoder, collect_tokens, &buffer);
+    for (i = 0; !failed && i < strlen(text); i++)
+    {
+        failed = !tiktoken_stream_encoder_feed(encoder, (const uint8_t *)text + i, 1);
+    }
+    failed = failed || !tiktoken_stream_encoder_finish(encoder) ||
+             buffer.count != sizeof(expected) / sizeof(expected[0]) ||
+             memcmp(buffer.tokens, expected, sizeof(expected)) != 0;
+    tiktoken_stream_encoder_destroy(encoder);
+    tiktoken_destroy_corebpe(bpe);
+
+    if (failed)
+    {
+        fprintf(stderr, "Stream encoder test failed\n");
+        return 1;
+    }
+    printf("Stream encoder test passed\n");
+    return 0;
+}
+
 int main(void)
 {
     static const Rank gpt4_tokens[] = {40, 1097, 264, 8415, 13};
@@ -107,11 +321,11 @@ int main(void)
         {"gpt-oss-120b", "I am a cat.", o200k_tokens, sizeof(o200k_tokens) / sizeof(o200k_tokens[0])},
     };
     static const ModelMetadataCase metadata_cases[] = {
-        {"gpt-4", 8192, TIKTOKEN_TOKENIZER_CL100K_BASE},
-        {"gpt-4o", 128000, TIKTOKEN_TOKENIZER_O200K_BASE},
-        {"gpt-5", 400000, TIKTOKEN_TOKENIZER_O200K_BASE},
-        {"gpt2", 0, TIKTOKEN_TOKENIZER_GPT2},
-        {"gpt-oss-20b", 131072, TIKTOKEN_TOKENIZER_O200K_HARMONY},
+        {"gpt-4", 8192, TIKTOKEN_TOKENIZER_CL100K_BASE, "cl100k_base"},
+        {"gpt-4o", 128000, TIKTOKEN_TOKENIZER_O200K_BASE, "o200k_base"},
+        {"gpt-5", 400000, TIKTOKEN_TOKENIZER_O200K_BASE, "o200k_base"},
+        {"gpt2", 0, TIKTOKEN_TOKENIZER_GPT2, "gpt2"},
+        {"gpt-oss-20b", 131072, TIKTOKEN_TOKENIZER_O200K_HARMONY, "o200k_harmony"},
     };
     size_t i;
 
@@ -121,6 +335,8 @@ int main(void)
     {
         const ModelMetadataCase *test_case = &metadata_cases[i];
         TiktokenTokenizer tokenizer = tiktoken_get_tokenizer(test_case->model);
+        CoreBPE *bpe;
+        const char *encoding_name;
 
         if (tokenizer != test_case->expected_tokenizer)
         {
@@ -143,6 +359,27 @@ int main(void)
                 return 1;
             }
         }
+
+        bpe = tiktoken_get_bpe_from_model(test_case->model);
+        if (bpe == NULL)
+        {
+            fprintf(stderr, "Failed to load model: %s\n", test_case->model);
+            return 1;
+        }
+
+        encoding_name = tiktoken_corebpe_name(bpe);
+        if (encoding_name == NULL || strcmp(encoding_name, test_case->expected_encoding_name) != 0 ||
+            tiktoken_corebpe_tokenizer(bpe) != test_case->expected_tokenizer)
+        {
+            fprintf(stderr, "Encoding mismatch for %s: expected %s, got %s\n",
+                    test_case->model,
+                    test_case->expected_encoding_name,
+                    encoding_name != NULL ? encoding_name : "(null)");
+            tiktoken_destroy_corebpe(bpe);
+            return 1;
+        }
+
+        tiktoken_destroy_corebpe(bpe);
     }
 
     for (i = 0; i < sizeof(test_cases) / sizeof(test_cases[0]); i++)
@@ -153,6 +390,21 @@ int main(void)
         }
     }
 
+    if (run_bytes_test() != 0)
+    {
+        return 1;
+    }
+
+    if (run_stream_test() != 0)
+    {
+        return 1;
+    }
+
+    if (run_thread_test() != 0)
+    {
+        return 1;
+    }
+
     printf("All tests passed successfully\n");
     return 0;
 }
\ No newline at end of file
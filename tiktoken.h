@@ -6,12 +6,25 @@
 #include <stdint.h>
 #include <stdlib.h>
 
+/*
+ * Thread safety
+ *
+ * Functions documented as "thread-safe" may be called concurrently from any
+ * number of threads. Functions taking a `const CoreBPE *` or
+ * `const TiktokenPriceTable *` only read from the handle, so a single handle
+ * may be shared by many threads without locking.
+ *
+ * Functions documented as "requires exclusive access" mutate or free their
+ * first argument; the caller must ensure no other thread uses that object at
+ * the same time. Objects are never shared implicitly between handles.
+ */
+
 #ifdef __cplusplus
 extern "C"
 {
 #endif
 
-  typedef void CoreBPE;
+  typedef struct CoreBPE CoreBPE;
   typedef uint32_t Rank;
 
   typedef enum TiktokenTokenizer
@@ -26,18 +39,138 @@ extern "C"
     TIKTOKEN_TOKENIZER_GPT2 = 7,
   } TiktokenTokenizer;
 
-  typedef struct CChatCompletionRequestMessage CChatCompletionRequestMessage;
+  typedef enum TiktokenSurrogatePolicy
+  {
+    TIKTOKEN_SURROGATE_ERROR = 0,
+    TIKTOKEN_SURROGATE_REPLACE = 1,
+  } TiktokenSurrogatePolicy;
+
+  typedef enum TiktokenInvalidUtf8Policy
+  {
+    TIKTOKEN_INVALID_UTF8_ERROR = 0,
+    TIKTOKEN_INVALID_UTF8_REPLACE = 1,
+    TIKTOKEN_INVALID_UTF8_BYTES = 2,
+  } TiktokenInvalidUtf8Policy;
+
+  typedef enum TiktokenLossyDecodePolicy
+  {
+    TIKTOKEN_LOSSY_DECODE_REPLACE = 0,
+    TIKTOKEN_LOSSY_DECODE_ESCAPE = 1,
+    TIKTOKEN_LOSSY_DECODE_DROP = 2,
+  } TiktokenLossyDecodePolicy;
+
+  /*
+   * Most entries written by the *_all_encodings functions: one per built-in
+   * encoding.
+   */
+#define TIKTOKEN_NUM_BUILTIN_ENCODINGS 6
+
+  typedef enum TiktokenTokenFormat
+  {
+    TIKTOKEN_TOKEN_FORMAT_UINT16 = 0,
+    TIKTOKEN_TOKEN_FORMAT_UINT32 = 1,
+  } TiktokenTokenFormat;
+
+  typedef enum TiktokenPackingStrategy
+  {
+    TIKTOKEN_PACKING_CONCATENATE = 0,
+    TIKTOKEN_PACKING_BEST_FIT = 1,
+  } TiktokenPackingStrategy;
 
+  /* Rank value meaning "no token", e.g. no separator when packing. */
+#define TIKTOKEN_NO_TOKEN UINT32_MAX
+
+  typedef struct TiktokenShardInfo
+  {
+    TiktokenTokenFormat format;
+    bool eot_separated;
+    uint32_t vocab_size;
+    Rank eot_token;
+    uint64_t num_tokens;
+    uint64_t num_documents;
+  } TiktokenShardInfo;
+
+  typedef struct TiktokenEncodingCount
+  {
+    TiktokenTokenizer tokenizer;
+    const char *name;
+    size_t count;
+  } TiktokenEncodingCount;
+
+  typedef struct TiktokenEncodingDigest
+  {
+    TiktokenTokenizer tokenizer;
+    const char *name;
+    const char *sha256;
+    uint64_t size;
+  } TiktokenEncodingDigest;
+
+  typedef struct TiktokenTokenCount
+  {
+    Rank token;
+    size_t count;
+  } TiktokenTokenCount;
+
+  typedef struct TiktokenTextStats
+  {
+    size_t num_tokens;
+    size_t num_unique_tokens;
+    size_t num_bytes;
+    size_t num_chars;
+    double bytes_per_token;
+    double chars_per_token;
+  } TiktokenTextStats;
+
+  typedef struct TiktokenTokenLength
+  {
+    Rank token;
+    size_t num_bytes;
+  } TiktokenTokenLength;
+
+  typedef struct TiktokenCountEstimate
+  {
+    size_t count;
+    size_t error_bound;
+    bool exact;
+  } TiktokenCountEstimate;
+
+  typedef struct TiktokenCountCacheStats
+  {
+    uint64_t hits;
+    uint64_t misses;
+    uint64_t evictions;
+    size_t entries;
+    size_t bytes;
+  } TiktokenCountCacheStats;
+
+  typedef struct CChatCompletionRequestMessage CChatCompletionRequestMessage;
+  typedef struct TiktokenPriceTable TiktokenPriceTable;
+  typedef struct TiktokenCountCache TiktokenCountCache;
+  typedef struct TiktokenIncrementalCounter TiktokenIncrementalCounter;
+  typedef struct TiktokenStreamEncoder TiktokenStreamEncoder;
+  typedef struct TiktokenShardWriter TiktokenShardWriter;
+  typedef struct TiktokenShardReader TiktokenShardReader;
+  typedef struct TiktokenCorpusWriter TiktokenCorpusWriter;
+  typedef struct TiktokenBpeTrainer TiktokenBpeTrainer;
+  typedef void (*TiktokenTokenCallback)(const Rank *tokens, size_t num_tokens, void *user_data);
+
+  /* Thread-safe. */
   const char *tiktoken_c_version(void);
 
+  /* Not thread-safe: call once, before any other function. */
   void tiktoken_init_logger(void);
 
+  /* Thread-safe. */
   size_t tiktoken_get_context_size(const char *model);
 
+  /* Thread-safe. */
   TiktokenTokenizer tiktoken_get_tokenizer(const char *model);
 
+  /* Thread-safe. */
   CChatCompletionRequestMessage *tiktoken_chat_message_new(const char *role);
 
+  /* The setters below require exclusive access to `message`. */
+
   bool tiktoken_chat_message_set_role(CChatCompletionRequestMessage *message, const char *role);
 
   bool tiktoken_chat_message_set_content(CChatCompletionRequestMessage *message, const char *content);
@@ -59,10 +192,22 @@ extern "C"
   bool tiktoken_chat_message_set_refusal(CChatCompletionRequestMessage *message,
                                          const char *refusal);
 
+  /* Requires exclusive access to `message`. */
   void tiktoken_chat_message_destroy(CChatCompletionRequestMessage *message);
 
+  /*
+   * Constructors are thread-safe; each call returns an independent handle.
+   * Each built-in constructor exists only when its encoding is compiled in
+   * (cargo features enc-r50k, enc-p50k, enc-cl100k and enc-o200k, all on by
+   * default) and always uses the compiled-in tables.
+   * tiktoken_get_bpe_from_model() and tiktoken_get_bpe_from_tokenizer()
+   * prefer an encoding whose rank file is in the data directory, fall back to
+   * the compiled-in one, and return NULL when there is neither.
+   */
   CoreBPE *tiktoken_get_bpe_from_model(const char *model);
 
+  CoreBPE *tiktoken_get_bpe_from_tokenizer(TiktokenTokenizer tokenizer);
+
   CoreBPE *tiktoken_r50k_base(void);
 
   CoreBPE *tiktoken_p50k_base(void);
@@ -75,36 +220,529 @@ extern "C"
 
   CoreBPE *tiktoken_o200k_harmony(void);
 
-  Rank *tiktoken_corebpe_encode_ordinary(CoreBPE *ptr, const char *text, size_t *num_tokens);
+  /* Metadata getters are thread-safe on a shared handle. */
+  const char *tiktoken_corebpe_name(const CoreBPE *ptr);
+
+  TiktokenTokenizer tiktoken_corebpe_tokenizer(const CoreBPE *ptr);
+
+  const char *tiktoken_corebpe_pattern(const CoreBPE *ptr);
+
+  size_t tiktoken_corebpe_special_tokens_len(const CoreBPE *ptr);
+
+  const char *tiktoken_corebpe_special_token(const CoreBPE *ptr, size_t index, Rank *rank);
+
+  /* Thread-safe. Split pattern of a built-in tokenizer, NULL for UNKNOWN. */
+  const char *tiktoken_tokenizer_pattern(TiktokenTokenizer tokenizer);
+
+  /*
+   * Thread-safe. Writes the built-in encodings compiled into the library, up
+   * to `tokenizers_len` of them, and returns the number written.
+   */
+  size_t tiktoken_compiled_encodings(TiktokenTokenizer *tokenizers, size_t tokenizers_len);
+
+  /*
+   * Thread-safe. Whether the encoding of `tokenizer` is compiled in or its
+   * rank file is in the data directory.
+   */
+  bool tiktoken_encoding_available(TiktokenTokenizer tokenizer);
+
+  /*
+   * Thread-safe. Cargo feature that compiles in `tokenizer`, e.g.
+   * "enc-o200k", NULL for UNKNOWN. The string is static.
+   */
+  const char *tiktoken_encoding_feature(TiktokenTokenizer tokenizer);
+
+  /*
+   * Thread-safe. Sets the directory that encodings are loaded from, as
+   * <name>.tiktoken files (r50k_base, p50k_base, cl100k_base, o200k_base), on
+   * first use. A rank file found there takes precedence over the compiled-in
+   * encoding in model and tokenizer lookups. NULL goes back to the
+   * TIKTOKEN_C_DATA_DIR environment variable. Returns false if `path` is not
+   * a directory. Encodings already loaded are kept for the lifetime of the
+   * process.
+   */
+  bool tiktoken_set_data_dir(const char *path);
+
+  /* Thread-safe. The data directory in effect, NULL if none; malloc'd. */
+  char *tiktoken_data_dir(void);
+
+  /* Encode, count and decode functions are thread-safe on a shared handle. */
+  Rank *tiktoken_corebpe_encode_ordinary(const CoreBPE *ptr, const char *text, size_t *num_tokens);
 
-  size_t tiktoken_corebpe_count_ordinary(CoreBPE *ptr, const char *text);
+  size_t tiktoken_corebpe_count_ordinary(const CoreBPE *ptr, const char *text);
 
-  Rank *tiktoken_corebpe_encode(CoreBPE *ptr,
+  Rank *tiktoken_corebpe_encode(const CoreBPE *ptr,
                                 const char *text,
                                 const char *const *allowed_special,
                                 size_t allowed_special_len,
                                 size_t *num_tokens);
 
-  size_t tiktoken_corebpe_count(CoreBPE *ptr,
+  size_t tiktoken_corebpe_count(const CoreBPE *ptr,
                                 const char *text,
                                 const char *const *allowed_special,
                                 size_t allowed_special_len);
 
-  Rank *tiktoken_corebpe_encode_with_special_tokens(CoreBPE *ptr,
+  Rank *tiktoken_corebpe_encode_with_special_tokens(const CoreBPE *ptr,
                                                     const char *text,
                                                     size_t *num_tokens);
 
-  size_t tiktoken_corebpe_count_with_special_tokens(CoreBPE *ptr, const char *text);
+  size_t tiktoken_corebpe_count_with_special_tokens(const CoreBPE *ptr, const char *text);
 
-  char *tiktoken_corebpe_decode(CoreBPE *ptr, const Rank *tokens, size_t num_tokens);
+  char *tiktoken_corebpe_decode(const CoreBPE *ptr, const Rank *tokens, size_t num_tokens);
 
-  uint8_t *tiktoken_corebpe_decode_bytes(CoreBPE *ptr,
+  uint8_t *tiktoken_corebpe_decode_bytes(const CoreBPE *ptr,
                                          const Rank *tokens,
                                          size_t num_tokens,
                                          size_t *num_bytes);
 
+  /*
+   * Thread-safe on a shared handle. Unlike tiktoken_corebpe_decode(), invalid
+   * UTF-8 does not fail the call: TIKTOKEN_LOSSY_DECODE_REPLACE substitutes
+   * U+FFFD for each invalid sequence, TIKTOKEN_LOSSY_DECODE_ESCAPE writes each
+   * invalid byte as \xNN and TIKTOKEN_LOSSY_DECODE_DROP omits it. With
+   * `escape_nul`, NUL bytes are written as \x00. The result is NUL-terminated
+   * and `text_len` receives its length without the terminator. Returns NULL
+   * only for NULL arguments or tokens outside the vocabulary.
+   */
+  char *tiktoken_corebpe_decode_lossy(const CoreBPE *ptr,
+                                      const Rank *tokens,
+                                      size_t num_tokens,
+                                      TiktokenLossyDecodePolicy policy,
+                                      bool escape_nul,
+                                      size_t *text_len);
+
+  /*
+   * Thread-safe on a shared handle. Decodes every token into one packed
+   * buffer: token i spans bytes[offsets[i]] to bytes[offsets[i + 1]], so
+   * `offsets` has num_tokens + 1 entries. When `is_special` is not NULL it
+   * receives one flag per token marking special tokens. `bytes` is NULL when
+   * the tokens decode to no bytes. All output arrays are malloc'd.
+   */
+  bool tiktoken_corebpe_decode_tokens(const CoreBPE *ptr,
+                                      const Rank *tokens,
+                                      size_t num_tokens,
+                                      uint8_t **bytes,
+                                      size_t *num_bytes,
+                                      size_t **offsets,
+                                      bool **is_special);
+
+  /*
+   * Thread-safe on a shared handle. Produce the first `limit` tokens of
+   * tiktoken_corebpe_encode_ordinary(), stopping early. `text_offset`
+   * receives the number of bytes those tokens cover, which equals
+   * strlen(text) only if the whole text fits within `limit`.
+   */
+  size_t tiktoken_corebpe_count_ordinary_up_to(const CoreBPE *ptr,
+                                               const char *text,
+                                               size_t limit,
+                                               size_t *text_offset);
+
+  Rank *tiktoken_corebpe_encode_ordinary_up_to(const CoreBPE *ptr,
+                                               const char *text,
+                                               size_t limit,
+                                               size_t *num_tokens,
+                                               size_t *text_offset);
+
+  /*
+   * Thread-safe on a shared handle. Estimates the tiktoken_corebpe_count_ordinary()
+   * result without running BPE: the text is split approximately and each
+   * piece is estimated from its length, with no vocabulary lookups. For typical
+   * text the exact count lies within `error_bound` of `count`; this is not a
+   * guarantee. The hybrid variant counts exactly when strlen(text) is at most
+   * `exact_threshold`, setting `exact` and an `error_bound` of 0.
+   */
+  bool tiktoken_corebpe_estimate_count_ordinary(const CoreBPE *ptr,
+                                                const char *text,
+                                                TiktokenCountEstimate *estimate);
+
+  bool tiktoken_corebpe_count_ordinary_hybrid(const CoreBPE *ptr,
+                                              const char *text,
+                                              size_t exact_threshold,
+                                              TiktokenCountEstimate *estimate);
+
+  /*
+   * Keeps the tiktoken_corebpe_encode_ordinary() tokens of a growing text,
+   * re-encoding only the pre-tokenizer pieces near its end on each change.
+   * `ptr` must outlive the counter. Returns NULL if `ptr` is NULL.
+   */
+  TiktokenIncrementalCounter *tiktoken_incremental_counter_new(const CoreBPE *ptr);
+
+  /*
+   * Require exclusive access to `counter`. Truncation to `text_len` bytes
+   * fails unless it falls on a character boundary within the text.
+   */
+  bool tiktoken_incremental_counter_append(TiktokenIncrementalCounter *counter,
+                                           const char *text);
+
+  bool tiktoken_incremental_counter_truncate(TiktokenIncrementalCounter *counter,
+                                             size_t text_len);
+
+  void tiktoken_incremental_counter_destroy(TiktokenIncrementalCounter *counter);
+
+  /* Thread-safe on a shared counter. The `num_tokens` output may be NULL. */
+  size_t tiktoken_incremental_counter_count(const TiktokenIncrementalCounter *counter);
+
+  size_t tiktoken_incremental_counter_text_len(const TiktokenIncrementalCounter *counter);
+
+  Rank *tiktoken_incremental_counter_tokens(const TiktokenIncrementalCounter *counter,
+                                            size_t *num_tokens);
+
+  /*
+   * Encodes input arriving in arbitrary byte chunks, which may split UTF-8
+   * characters, into the tokens tiktoken_corebpe_encode_ordinary_bytes()
+   * gives for the whole input under the same policy. Tokens of a piece are
+   * produced once later input can no longer change it; finish produces the
+   * rest and readies the encoder for a new stream. If a call fails, the
+   * buffered input is discarded. `ptr` must outlive the encoder.
+   */
+  TiktokenStreamEncoder *tiktoken_stream_encoder_new(const CoreBPE *ptr,
+                                                     TiktokenInvalidUtf8Policy policy);
+
+  /*
+   * Require exclusive access to `encoder`. When a callback is set, tokens are
+   * passed to it during feed and finish instead of being queued for read.
+   */
+  bool tiktoken_stream_encoder_set_callback(TiktokenStreamEncoder *encoder,
+                                            TiktokenTokenCallback callback,
+                                            void *user_data);
+
+  bool tiktoken_stream_encoder_feed(TiktokenStreamEncoder *encoder,
+                                    const uint8_t *bytes,
+                                    size_t num_bytes);
+
+  bool tiktoken_stream_encoder_finish(TiktokenStreamEncoder *encoder);
+
+  size_t tiktoken_stream_encoder_read(TiktokenStreamEncoder *encoder,
+                                      Rank *tokens,
+                                      size_t max_tokens);
+
+  void tiktoken_stream_encoder_destroy(TiktokenStreamEncoder *encoder);
+
+  /* Thread-safe on a shared encoder. */
+  size_t tiktoken_stream_encoder_available(const TiktokenStreamEncoder *encoder);
+
+  /*
+   * Thread-safe on a shared handle. Memory-map the file at `path` and count
+   * or encode it as tiktoken_corebpe_encode_ordinary_bytes() would. Large
+   * files are split after line breaks where the split cannot change the
+   * tokens and encoded on up to `num_threads` threads (0 for one per core).
+   * The encode variant writes packed little-endian token ids to
+   * `output_path`; it fails, removing the output, if a token does not fit
+   * in `format`.
+   */
+  size_t tiktoken_corebpe_count_file(const CoreBPE *ptr,
+                                     const char *path,
+                                     TiktokenInvalidUtf8Policy policy,
+                                     size_t num_threads);
+
+  bool tiktoken_corebpe_encode_file(const CoreBPE *ptr,
+                                    const char *input_path,
+                                    const char *output_path,
+                                    TiktokenTokenFormat format,
+                                    TiktokenInvalidUtf8Policy policy,
+                                    size_t num_threads,
+                                    size_t *num_tokens);
+
+  /*
+   * Token shard files: a header with the encoding name, vocabulary size,
+   * token and document counts, the packed tokens, and document start
+   * offsets. With `eot_separated`, each document is followed by the
+   * encoding's <|endoftext|> token. Fails if the vocabulary does not fit
+   * `format`, or if `eot_separated` is set and the encoding has no
+   * <|endoftext|>. `ptr` must outlive the writer.
+   */
+  TiktokenShardWriter *tiktoken_shard_writer_new(const CoreBPE *ptr,
+                                                 const char *path,
+                                                 TiktokenTokenFormat format,
+                                                 bool eot_separated);
+
+  /*
+   * Require exclusive access to `writer`. Documents are added as tokens or
+   * as text encoded with tiktoken_corebpe_encode_ordinary(). Finish writes
+   * the offsets and header; destroying an unfinished writer removes the file.
+   */
+  bool tiktoken_shard_writer_add_tokens(TiktokenShardWriter *writer,
+                                        const Rank *tokens,
+                                        size_t num_tokens);
+
+  bool tiktoken_shard_writer_add_document(TiktokenShardWriter *writer, const char *text);
+
+  bool tiktoken_shard_writer_finish(TiktokenShardWriter *writer);
+
+  void tiktoken_shard_writer_destroy(TiktokenShardWriter *writer);
+
+  /* Thread-safe. Returns NULL if the file is not a valid shard. */
+  TiktokenShardReader *tiktoken_shard_reader_open(const char *path);
+
+  /* Requires exclusive access: no other thread may still be using `reader`. */
+  void tiktoken_shard_reader_destroy(TiktokenShardReader *reader);
+
+  /* Thread-safe on a shared reader. */
+  bool tiktoken_shard_reader_info(const TiktokenShardReader *reader, TiktokenShardInfo *info);
+
+  const char *tiktoken_shard_reader_encoding_name(const TiktokenShardReader *reader);
+
+  size_t tiktoken_shard_reader_read(const TiktokenShardReader *reader,
+                                    uint64_t offset,
+                                    Rank *tokens,
+                                    size_t max_tokens);
+
+  Rank *tiktoken_shard_reader_document(const TiktokenShardReader *reader,
+                                       uint64_t index,
+                                       size_t *num_tokens);
+
+  /*
+   * Tokenizes documents into EOT-separated shard files named
+   * shard-NNNNN.bin in `dir`, each holding whole documents and at most
+   * `max_shard_tokens` tokens unless one document alone is longer, and
+   * records completed shards in dir/index.json. Documents are encoded in
+   * batches on up to `num_threads` threads (0 for one per core); the output
+   * does not depend on the thread count. If `dir` already holds an index
+   * with the same encoding, format and shard size, writing resumes after
+   * its last completed shard. `ptr` must outlive the writer.
+   */
+  TiktokenCorpusWriter *tiktoken_corpus_writer_open(const CoreBPE *ptr,
+                                                    const char *dir,
+                                                    TiktokenTokenFormat format,
+                                                    uint64_t max_shard_tokens,
+                                                    size_t num_threads);
+
+  /* Thread-safe on a shared writer. Documents to skip when resuming. */
+  uint64_t tiktoken_corpus_writer_committed_documents(const TiktokenCorpusWriter *writer);
+
+  /* Require exclusive access to `writer`. After a failure, later calls fail. */
+  bool tiktoken_corpus_writer_add_document(TiktokenCorpusWriter *writer, const char *text);
+
+  bool tiktoken_corpus_writer_finish(TiktokenCorpusWriter *writer);
+
+  void tiktoken_corpus_writer_destroy(TiktokenCorpusWriter *writer);
+
+  /*
+   * Thread-safe. Packs documents[i] (num_tokens[i] tokens each, followed by
+   * `separator` unless it is TIKTOKEN_NO_TOKEN) into sequences of `seq_len`
+   * tokens and returns the number of sequences, or SIZE_MAX on failure.
+   * TIKTOKEN_PACKING_CONCATENATE keeps the input order and splits documents
+   * at sequence boundaries; TIKTOKEN_PACKING_BEST_FIT keeps documents of up
+   * to `seq_len` tokens whole and reorders them to minimize padding. Each
+   * output array has num_sequences * seq_len entries: `segment_ids` numbers
+   * the documents within each sequence from 1 and is 0 for padding, and
+   * `mask` is true for real tokens and false for `pad_token`. `segment_ids`
+   * and `mask` may be NULL. All output arrays are malloc'd.
+   */
+  size_t tiktoken_pack_sequences(const Rank *const *documents,
+                                 const size_t *num_tokens,
+                                 size_t num_documents,
+                                 size_t seq_len,
+                                 TiktokenPackingStrategy strategy,
+                                 Rank separator,
+                                 Rank pad_token,
+                                 Rank **tokens,
+                                 uint32_t **segment_ids,
+                                 bool **mask);
+
+  /*
+   * Trains a byte-level BPE vocabulary of `vocab_size` ordinary tokens (at
+   * least 256) on text split with `pattern`, or with the o200k_base pattern
+   * when it is NULL. Other built-in patterns are available from
+   * tiktoken_tokenizer_pattern().
+   */
+  TiktokenBpeTrainer *tiktoken_bpe_trainer_new(const char *pattern, size_t vocab_size);
+
+  void tiktoken_bpe_trainer_destroy(TiktokenBpeTrainer *trainer);
+
+  /*
+   * Require exclusive access to `trainer`. Special tokens are numbered after
+   * the ordinary tokens in the order they are added; <|endoftext|> is used
+   * when none are. Files must be valid UTF-8.
+   */
+  bool tiktoken_bpe_trainer_add_special_token(TiktokenBpeTrainer *trainer, const char *token);
+
+  bool tiktoken_bpe_trainer_add_text(TiktokenBpeTrainer *trainer, const char *text);
+
+  bool tiktoken_bpe_trainer_add_file(TiktokenBpeTrainer *trainer, const char *path);
+
+  /*
+   * Thread-safe on a shared trainer. Trains on the text added so far and
+   * returns a new encoding named `name`, with fewer ordinary tokens than
+   * requested if the corpus runs out of pairs to merge. When `tiktoken_path`
+   * is not NULL, the ordinary tokens are also written there as a .tiktoken
+   * rank file.
+   */
+  CoreBPE *tiktoken_bpe_trainer_train(const TiktokenBpeTrainer *trainer,
+                                      const char *name,
+                                      const char *tiktoken_path);
+
+  /*
+   * Thread-safe on a shared handle. Returns a new encoding named `name` with
+   * the tokens of `ptr` plus `tokens` as ordinary tokens and
+   * `special_tokens` as special tokens. A NULL rank array, or a rank of
+   * TIKTOKEN_NO_TOKEN, assigns the next free rank after the highest one in
+   * use, ordinary tokens first. Returns NULL if a token or rank is already
+   * taken, or if the split pattern does not yield an ordinary token as one
+   * piece, since it would never be produced; use special tokens for those.
+   */
+  CoreBPE *tiktoken_corebpe_extend(const CoreBPE *ptr,
+                                   const char *name,
+                                   const char *const *tokens,
+                                   const Rank *token_ranks,
+                                   size_t num_tokens,
+                                   const char *const *special_tokens,
+                                   const Rank *special_token_ranks,
+                                   size_t num_special_tokens);
+
+  /*
+   * Thread-safe on a shared handle. Write the encoding to `path` as a
+   * .tiktoken rank file (ordinary tokens only), as a Hugging Face
+   * tokenizer.json byte-level BPE model, or as a JSON description with the
+   * name, pattern, special tokens and ranks.
+   */
+  bool tiktoken_corebpe_save_tiktoken(const CoreBPE *ptr, const char *path);
+
+  bool tiktoken_corebpe_save_tokenizer_json(const CoreBPE *ptr, const char *path);
+
+  bool tiktoken_corebpe_save_json(const CoreBPE *ptr, const char *path);
+
+  /*
+   * Thread-safe on a shared handle. Write the encoding to `path` as a
+   * binary snapshot. Fails for encodings without a token for every byte.
+   */
+  bool tiktoken_corebpe_save_snapshot(const CoreBPE *ptr, const char *path);
+
+  /*
+   * Thread-safe. Map a snapshot written by tiktoken_corebpe_save_snapshot()
+   * and return an encoding that reads its tables from the mapping. Extending,
+   * saving and tiktoken_corebpe_sha256() copy the ordinary tokens into
+   * memory on first use; every other function reads the mapping. The file
+   * must not be modified while the handle is alive: truncating it raises
+   * SIGBUS on the next read past the new end. Replace it by renaming a new
+   * file over it instead. Returns NULL if the file is missing or not a valid
+   * snapshot. Free with tiktoken_destroy_corebpe().
+   */
+  CoreBPE *tiktoken_corebpe_load_snapshot(const char *path);
+
+  /*
+   * Thread-safe. Fills `digests` with the SHA-256 (64 lowercase hex digits)
+   * and size in bytes of the rank data of each compiled-in encoding, in the
+   * order of tiktoken_count_all_encodings(), and returns the number of
+   * entries written. The rank data is the .tiktoken file the encoding was
+   * built from, as tiktoken_corebpe_save_tiktoken() writes it. Strings are
+   * static. The first call computes the digests.
+   */
+  size_t tiktoken_builtin_encoding_digests(TiktokenEncodingDigest *digests, size_t digests_len);
+
+  /*
+   * Thread-safe on a shared handle. SHA-256 (64 lowercase hex digits) of the
+   * encoding's ordinary tokens written as a .tiktoken file, as
+   * tiktoken_corebpe_save_tiktoken() writes it, so built-in encodings match
+   * tiktoken_builtin_encoding_digests(). The pattern and special tokens are
+   * not covered. Malloc'd.
+   */
+  char *tiktoken_corebpe_sha256(const CoreBPE *ptr);
+
+  /* Thread-safe. SHA-256 of a file as 64 lowercase hex digits; malloc'd. */
+  char *tiktoken_file_sha256(const char *path);
+
+  /*
+   * Thread-safe. Returns true if the file at `path` has the SHA-256
+   * `expected_sha256`, given as 64 hex digits in either case.
+   */
+  bool tiktoken_verify_rank_file(const char *path, const char *expected_sha256);
+
+  /*
+   * UTF-16 variants; thread-safe on a shared handle. `text` is not
+   * NUL-terminated. With TIKTOKEN_SURROGATE_REPLACE, unpaired surrogates in
+   * the input (or invalid UTF-8 in decoded output) become U+FFFD; with
+   * TIKTOKEN_SURROGATE_ERROR the call fails. When `offsets` is not NULL it
+   * receives a malloc'd array of the UTF-16 offset at which each token starts.
+   */
+  Rank *tiktoken_corebpe_encode_ordinary_utf16(const CoreBPE *ptr,
+                                               const uint16_t *text,
+                                               size_t text_len,
+                                               TiktokenSurrogatePolicy policy,
+                                               size_t *num_tokens,
+                                               size_t **offsets);
+
+  Rank *tiktoken_corebpe_encode_with_special_tokens_utf16(const CoreBPE *ptr,
+                                                          const uint16_t *text,
+                                                          size_t text_len,
+                                                          TiktokenSurrogatePolicy policy,
+                                                          size_t *num_tokens,
+                                                          size_t **offsets);
+
+  size_t tiktoken_corebpe_count_ordinary_utf16(const CoreBPE *ptr,
+                                               const uint16_t *text,
+                                               size_t text_len,
+                                               TiktokenSurrogatePolicy policy);
+
+  size_t tiktoken_corebpe_count_with_special_tokens_utf16(const CoreBPE *ptr,
+                                                          const uint16_t *text,
+                                                          size_t text_len,
+                                                          TiktokenSurrogatePolicy policy);
+
+  uint16_t *tiktoken_corebpe_decode_utf16(const CoreBPE *ptr,
+                                          const Rank *tokens,
+                                          size_t num_tokens,
+                                          TiktokenSurrogatePolicy policy,
+                                          size_t *text_len);
+
+  /*
+   * Raw byte variants; thread-safe on a shared handle. `bytes` is not
+   * NUL-terminated and need not be valid UTF-8. TIKTOKEN_INVALID_UTF8_ERROR
+   * fails on invalid UTF-8, TIKTOKEN_INVALID_UTF8_REPLACE substitutes U+FFFD
+   * for each invalid sequence, and TIKTOKEN_INVALID_UTF8_BYTES encodes invalid
+   * sequences as raw bytes so that decoding returns the exact input.
+   */
+  Rank *tiktoken_corebpe_encode_ordinary_bytes(const CoreBPE *ptr,
+                                               const uint8_t *bytes,
+                                               size_t num_bytes,
+                                               TiktokenInvalidUtf8Policy policy,
+                                               size_t *num_tokens);
+
+  Rank *tiktoken_corebpe_encode_with_special_tokens_bytes(const CoreBPE *ptr,
+                                                          const uint8_t *bytes,
+                                                          size_t num_bytes,
+                                                          TiktokenInvalidUtf8Policy policy,
+                                                          size_t *num_tokens);
+
+  size_t tiktoken_corebpe_count_ordinary_bytes(const CoreBPE *ptr,
+                                               const uint8_t *bytes,
+                                               size_t num_bytes,
+                                               TiktokenInvalidUtf8Policy policy);
+
+  size_t tiktoken_corebpe_count_with_special_tokens_bytes(const CoreBPE *ptr,
+                                                          const uint8_t *bytes,
+                                                          size_t num_bytes,
+                                                          TiktokenInvalidUtf8Policy policy);
+
+  /*
+   * Thread-safe on a shared handle. Tokens are produced as by
+   * tiktoken_corebpe_encode_ordinary(). Up to `top_tokens_len` of the most
+   * frequent tokens are written to `top_tokens`, most frequent first.
+   */
+  bool tiktoken_corebpe_text_stats(const CoreBPE *ptr,
+                                   const char *text,
+                                   TiktokenTextStats *stats,
+                                   TiktokenTokenCount *top_tokens,
+                                   size_t top_tokens_len,
+                                   size_t *num_top_tokens);
+
+  /*
+   * Thread-safe on a shared handle. Writes up to `longest_tokens_len` of the
+   * distinct tokens of `text` to `longest_tokens`, longest byte sequence
+   * first (ties by ascending rank), and returns the number written.
+   */
+  size_t tiktoken_corebpe_longest_tokens(const CoreBPE *ptr,
+                                         const char *text,
+                                         TiktokenTokenLength *longest_tokens,
+                                         size_t longest_tokens_len);
+
+  /* Thread-safe. */
   size_t tiktoken_get_text_completion_max_tokens(const char *model, const char *prompt);
 
+  /*
+   * Thread-safe. Messages are only read, so they may be shared between threads
+   * as long as no thread modifies them concurrently.
+   */
   size_t tiktoken_num_tokens_from_messages(const char *model,
                                            uint32_t num_messages,
                  CChatCompletionRequestMessage *const *messages);
@@ -113,8 +751,103 @@ extern "C"
                                                  uint32_t num_messages,
                    CChatCompletionRequestMessage *const *messages);
 
+  /*
+   * Thread-safe. Creates a least recently used cache of token counts holding
+   * at most `max_entries` entries and `max_bytes` bytes of entry memory; 0
+   * leaves that limit off, but at least one must be set. Entries are keyed by
+   * encoding and a SHA-256 digest of the text, so their size does not depend
+   * on the text length. Only handles of the same built-in encoding share
+   * entries. The cache locks internally, so it may be shared by any number of
+   * threads and handles.
+   */
+  TiktokenCountCache *tiktoken_count_cache_new(size_t max_entries, size_t max_bytes);
+
+  /*
+   * Releases the caller's reference; handles the cache is attached to keep it
+   * alive until they are destroyed or detached.
+   */
+  void tiktoken_count_cache_destroy(TiktokenCountCache *cache);
+
+  /* Thread-safe. Drops all entries but keeps the hit and miss counters. */
+  void tiktoken_count_cache_clear(const TiktokenCountCache *cache);
+
+  /* Thread-safe. */
+  bool tiktoken_count_cache_stats(const TiktokenCountCache *cache,
+                                  TiktokenCountCacheStats *stats);
+
+  /*
+   * Requires exclusive access to `ptr`. Routes tiktoken_corebpe_count_ordinary()
+   * and tiktoken_corebpe_count_with_special_tokens() through `cache`; pass
+   * NULL to detach.
+   */
+  bool tiktoken_corebpe_set_count_cache(CoreBPE *ptr, const TiktokenCountCache *cache);
+
+  /* Thread-safe, as tiktoken_num_tokens_from_messages(). */
+  size_t tiktoken_num_tokens_from_messages_cached(const TiktokenCountCache *cache,
+                                                  const char *model,
+                                                  uint32_t num_messages,
+                                                  CChatCompletionRequestMessage *const *messages);
+
+  /*
+   * Thread-safe. Writes one entry per available encoding (r50k_base,
+   * p50k_base, p50k_edit, cl100k_base, o200k_base, o200k_harmony), up to
+   * `counts_len`, and returns the number written. An encoding is available
+   * if it is compiled in or its rank file is in the data directory; one
+   * that fails to load is left out. `name` points to a static string.
+   */
+  size_t tiktoken_count_all_encodings(const char *text,
+                                      TiktokenEncodingCount *counts,
+                                      size_t counts_len);
+
+  size_t tiktoken_num_tokens_from_messages_all_encodings(uint32_t num_messages,
+                                                         CChatCompletionRequestMessage *const *messages,
+                                                         TiktokenEncodingCount *counts,
+                                                         size_t counts_len);
+
+  /* Constructors are thread-safe; each call returns an independent table. */
+  TiktokenPriceTable *tiktoken_price_table_new(void);
+
+  TiktokenPriceTable *tiktoken_price_table_from_json(const char *json);
+
+  TiktokenPriceTable *tiktoken_price_table_from_toml(const char *toml);
+
+  TiktokenPriceTable *tiktoken_price_table_load(const char *path);
+
+  /*
+   * Requires exclusive access to `table`. A negative or NaN `cached_input`
+   * means the model has no cached input rate, so the `input` rate is used.
+   */
+  bool tiktoken_price_table_set(TiktokenPriceTable *table,
+                                const char *model,
+                                double input,
+                                double cached_input,
+                                double output);
+
+  /* Requires exclusive access to `table`. */
+  void tiktoken_price_table_destroy(TiktokenPriceTable *table);
+
+  /*
+   * Thread-safe on a shared table. `model` matches a table entry exactly,
+   * without a trailing -YYYY-MM-DD date, or through the longest entry ending
+   * in `*` that prefixes it; otherwise the estimate fails.
+   */
+  double tiktoken_estimate_cost(const TiktokenPriceTable *table,
+                                const char *model,
+                                size_t input_tokens,
+                                size_t cached_input_tokens,
+                                size_t output_tokens);
+
+  double tiktoken_estimate_chat_cost(const TiktokenPriceTable *table,
+                                     const char *model,
+                                     uint32_t num_messages,
+                                     CChatCompletionRequestMessage *const *messages,
+                                     size_t cached_input_tokens,
+                                     size_t completion_tokens);
+
+  /* Thread-safe; each buffer must be freed exactly once. */
   void tiktoken_free(void *ptr);
 
+  /* Requires exclusive access: no other thread may still be using `ptr`. */
   void tiktoken_destroy_corebpe(CoreBPE *ptr);
 
 #ifdef __cplusplus